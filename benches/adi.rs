@@ -0,0 +1,107 @@
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::path::PathBuf;
+
+use anisette_rs::bench::{self, BENCH_DSID, BenchLibraries};
+use anisette_rs::{Adi, ReplayTransport};
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+
+// Usage:
+// [ANISETTE_BENCH_LIBS=<dir with both .so>] [ANISETTE_BENCH_RECORDING=<file>] cargo bench --features bench
+// gsa_exchange always runs, offline, against the canned responses in
+// fixtures/gsa-canned.json. The OTP and provisioning benches replay
+// ANISETTE_BENCH_RECORDING, which must match the libraries; set
+// ANISETTE_BENCH_RECORD=1 once to record it from GSA.
+
+const CANNED_GSA: &[u8] = include_bytes!("fixtures/gsa-canned.json");
+
+fn benches(c: &mut Criterion) {
+    c.bench_function("gsa_exchange", |b| {
+        b.iter_batched(
+            || ReplayTransport::from_bytes(CANNED_GSA).expect("parse canned responses"),
+            |transport| bench::gsa_exchange(transport).expect("gsa_exchange"),
+            BatchSize::SmallInput,
+        )
+    });
+
+    let Some(libraries) = BenchLibraries::from_env().expect("read bench libraries") else {
+        eprintln!("ANISETTE_BENCH_LIBS is not set, skipping benches");
+        return;
+    };
+
+    c.bench_function("library_load", |b| {
+        b.iter_batched(
+            || libraries.clone(),
+            |libraries| libraries.into_emu().expect("load libraries"),
+            BatchSize::LargeInput,
+        )
+    });
+
+    c.bench_function("adi_new", |b| {
+        b.iter_batched(
+            || libraries.clone(),
+            |libraries| libraries.into_adi().expect("Adi::new"),
+            BatchSize::LargeInput,
+        )
+    });
+
+    let Some(recording) = env::var_os("ANISETTE_BENCH_RECORDING").map(PathBuf::from) else {
+        eprintln!("ANISETTE_BENCH_RECORDING is not set, skipping OTP and provisioning benches");
+        return;
+    };
+    if env::var_os("ANISETTE_BENCH_RECORD").is_some() {
+        bench::record_provisioning(libraries.clone(), &recording).expect("record provisioning");
+    } else if !recording.exists() {
+        eprintln!(
+            "{} does not exist and ANISETTE_BENCH_RECORD is not set, skipping OTP and provisioning benches",
+            recording.display()
+        );
+        return;
+    }
+    let cassette = fs::read(&recording).expect("read recording");
+    let replay = || ReplayTransport::from_bytes(&cassette).expect("parse recording");
+
+    c.bench_function("provisioning_round_trip", |b| {
+        b.iter_batched(
+            || (libraries.clone().into_adi().expect("Adi::new"), replay()),
+            |(mut adi, transport)| {
+                bench::provision(&mut adi, transport).expect("provision");
+                adi
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    let mut adi = provisioned(&libraries, replay());
+    report_otp_cost(&mut adi);
+    c.bench_function("request_otp", |b| {
+        b.iter(|| black_box(adi.request_otp(BENCH_DSID).expect("request_otp")))
+    });
+}
+
+fn provisioned(libraries: &BenchLibraries, transport: ReplayTransport) -> Adi {
+    let mut adi = libraries.clone().into_adi().expect("Adi::new");
+    bench::provision(&mut adi, transport).expect("provision");
+    adi
+}
+
+/// Guest instructions and memory behind one OTP, which wall-clock time alone
+/// cannot separate from host noise.
+fn report_otp_cost(adi: &mut Adi) {
+    adi.set_instruction_counting(true)
+        .expect("enable instruction counting");
+    let before = bench::guest_counters(adi);
+    adi.request_otp(BENCH_DSID).expect("request_otp");
+    let after = bench::guest_counters(adi);
+    adi.set_instruction_counting(false)
+        .expect("disable instruction counting");
+    eprintln!(
+        "request_otp: {} guest instructions, {} bytes allocated",
+        after.instructions - before.instructions,
+        after.bytes_allocated - before.bytes_allocated
+    );
+}
+
+criterion_group!(adi_benches, benches);
+criterion_main!(adi_benches);
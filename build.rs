@@ -1,11 +1,21 @@
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+const UNICORN_LIBS: [&str; 4] = [
+    "libunicorn.a",
+    "libunicorn-common.a",
+    "libaarch64-softmmu.a",
+    "libarm-softmmu.a",
+];
 
 fn main() {
     println!("cargo:rerun-if-env-changed=UNICORN_DIR");
     println!("cargo:rerun-if-env-changed=UNICORN_BUILD_DIR");
     println!("cargo:rerun-if-env-changed=UNICORN_INCLUDE_DIR");
 
+    bundle_apple_root();
+    async_provisioning();
+
     let target = env::var("TARGET").unwrap_or_default();
     if target != "wasm32-unknown-emscripten" {
         return;
@@ -15,27 +25,27 @@ fn main() {
     let unicorn_dir = env::var("UNICORN_DIR")
         .map(PathBuf::from)
         .unwrap_or_else(|_| manifest_dir.join("../unicorn"));
-    let unicorn_build_dir = env::var("UNICORN_BUILD_DIR")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| unicorn_dir.join("build"));
+    let unicorn_build_dir = match env::var("UNICORN_BUILD_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => {
+            let prebuilt = unicorn_dir.join("build");
+            if missing_unicorn_lib(&prebuilt).is_none() {
+                prebuilt
+            } else {
+                build_vendored_unicorn(&unicorn_dir).unwrap_or(prebuilt)
+            }
+        }
+    };
     let unicorn_include_dir = env::var("UNICORN_INCLUDE_DIR")
         .map(PathBuf::from)
         .unwrap_or_else(|_| unicorn_dir.join("include"));
 
-    let required_libs = [
-        "libunicorn.a",
-        "libunicorn-common.a",
-        "libaarch64-softmmu.a",
-        "libarm-softmmu.a",
-    ];
-    for lib in required_libs {
-        let path = unicorn_build_dir.join(lib);
-        if !path.exists() {
-            panic!(
-                "missing unicorn static library: {}. run `bash test/rebuild-unicorn.sh` first",
-                path.display()
-            );
-        }
+    if let Some(path) = missing_unicorn_lib(&unicorn_build_dir) {
+        panic!(
+            "missing unicorn static library: {}. run `bash script/rebuild-unicorn.sh` first, \
+             or enable the `vendored-unicorn` feature to build it with cmake",
+            path.display()
+        );
     }
 
     println!("cargo:rustc-link-arg=--no-entry");
@@ -60,3 +70,85 @@ fn main() {
         println!("cargo:rustc-link-lib=static={lib}");
     }
 }
+
+fn missing_unicorn_lib(build_dir: &Path) -> Option<PathBuf> {
+    UNICORN_LIBS
+        .iter()
+        .map(|lib| build_dir.join(lib))
+        .find(|path| !path.exists())
+}
+
+/// Builds the Unicorn static libraries from the source tree in `UNICORN_DIR`
+/// into `OUT_DIR`, with the same options as `script/rebuild-unicorn.sh`. The
+/// `cmake` crate wraps the calls in `emcmake`/`emmake`, so this only needs
+/// cmake and an activated emsdk, not bash.
+#[cfg(feature = "vendored-unicorn")]
+fn build_vendored_unicorn(source_dir: &Path) -> Option<PathBuf> {
+    if !source_dir.join("CMakeLists.txt").exists() {
+        println!(
+            "cargo:warning=vendored-unicorn enabled but no unicorn source tree at {}",
+            source_dir.display()
+        );
+        return None;
+    }
+    let out_dir = cmake::Config::new(source_dir)
+        .profile("Release")
+        .define("BUILD_SHARED_LIBS", "OFF")
+        .define("UNICORN_BUILD_TESTS", "OFF")
+        .define("UNICORN_INSTALL", "OFF")
+        .define("UNICORN_LEGACY_STATIC_ARCHIVE", "ON")
+        .define("UNICORN_INTERPRETER", "ON")
+        .define("UNICORN_ARCH", "arm;aarch64")
+        .cflag("-DUSE_STATIC_CODE_GEN_BUFFER")
+        // The default `install` target does not exist with UNICORN_INSTALL=OFF.
+        .build_target("all")
+        .build();
+    Some(out_dir.join("build"))
+}
+
+#[cfg(not(feature = "vendored-unicorn"))]
+fn build_vendored_unicorn(_source_dir: &Path) -> Option<PathBuf> {
+    None
+}
+
+/// Enables `cfg(apple_root_bundled)` when the `bundled-apple-root` feature is on.
+/// Native builds embed the certificate for provisioning, so a missing PEM fails
+/// the build rather than every provisioning run; wasm32 has no native TLS client
+/// and skips it.
+fn bundle_apple_root() {
+    println!("cargo:rustc-check-cfg=cfg(apple_root_bundled)");
+    println!("cargo:rerun-if-changed=certs/apple-root.pem");
+    if env::var_os("CARGO_FEATURE_BUNDLED_APPLE_ROOT").is_none()
+        || env::var("CARGO_CFG_TARGET_ARCH").is_ok_and(|arch| arch == "wasm32")
+    {
+        return;
+    }
+
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap_or_default());
+    let pem = manifest_dir.join("certs/apple-root.pem");
+    if !pem.exists() {
+        panic!(
+            "bundled-apple-root is enabled but {} is missing. restore it from git or run \
+             `bash script/fetch-apple-root.sh`, or build without the feature and pass \
+             HttpOptions::apple_root_pem at runtime",
+            pem.display()
+        );
+    }
+    println!("cargo:rustc-cfg=apple_root_bundled");
+}
+
+/// `cfg(async_provisioning)`: `AsyncProvisioningSession` exists natively with the
+/// `tokio` feature and always on `wasm32-unknown-unknown`, where `fetch` is async-only.
+fn async_provisioning() {
+    println!("cargo:rustc-check-cfg=cfg(async_provisioning)");
+    let arch = env::var("CARGO_CFG_TARGET_ARCH").unwrap_or_default();
+    let os = env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    let enabled = if arch == "wasm32" {
+        os != "emscripten"
+    } else {
+        env::var_os("CARGO_FEATURE_TOKIO").is_some()
+    };
+    if enabled {
+        println!("cargo:rustc-cfg=async_provisioning");
+    }
+}
@@ -1,14 +1,20 @@
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use anisette_rs::{Adi, AdiInit, Device, ProvisioningSession, init_idbfs_for_path, sync_idbfs};
+use anisette_rs::{
+    Adi, AdiInit, Device, ProvisioningSession, RecordingTransport, ReplayTransport,
+    ReqwestTransport, init_idbfs_for_path, sync_idbfs,
+};
 use anyhow::{Context, Result};
-use base64::{Engine as _, engine::general_purpose::STANDARD};
-use serde_json::json;
+use tracing_subscriber::EnvFilter;
 
 fn main() -> Result<()> {
     // Usage:
     // cargo run --example anisette -- <libstoreservicescore.so> <libCoreADI.so> <library_path> [dsid] [apple_root_pem]
+    // Logging follows RUST_LOG, e.g. RUST_LOG=anisette_rs::adi=debug,anisette_rs::stub=trace.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
     let storeservices_path = std::env::args()
         .nth(1)
         .unwrap_or_else(|| "libstoreservicescore.so".to_string());
@@ -41,6 +47,8 @@ fn main() -> Result<()> {
         library_path: library_path.clone(),
         provisioning_path: Some(library_path.clone()),
         identifier: None,
+        fault_dump_dir: std::env::var_os("ANISETTE_CORE_DIR").map(PathBuf::from),
+        ..Default::default()
     })?;
 
     if !device.initialized {
@@ -66,22 +74,33 @@ fn main() -> Result<()> {
         signed as u64
     };
 
-    let is_provisioned = adi.is_machine_provisioned(dsid)?;
-
-    if !is_provisioned {
+    let mut routing_info = None;
+    let provisioned = adi.ensure_provisioned(dsid, |adi, dsid| {
         println!("Provisioning...");
-        let mut provisioning_session =
-            ProvisioningSession::new(&mut adi, &device.data, apple_root_pem)?;
+        // ANISETTE_RECORD=<file> saves the GSA exchange; ANISETTE_REPLAY=<file> serves it
+        // back offline.
+        let mut provisioning_session = if let Some(path) = std::env::var_os("ANISETTE_REPLAY") {
+            ProvisioningSession::with_transport(adi, &device.data, ReplayTransport::load(path)?)
+        } else if let Some(path) = std::env::var_os("ANISETTE_RECORD") {
+            let transport = ReqwestTransport::new(&apple_root_pem.clone().into())?;
+            let transport = RecordingTransport::new(transport, path);
+            ProvisioningSession::with_transport(adi, &device.data, transport)
+        } else {
+            ProvisioningSession::new(adi, &device.data, apple_root_pem.clone())?
+        };
         provisioning_session.provision(dsid)?;
-    } else {
+        routing_info = provisioning_session.routing_info().map(str::to_string);
+        Ok::<_, anyhow::Error>(())
+    })?;
+    if !provisioned {
         println!("(Already provisioned)");
     }
+    if routing_info.is_some() && routing_info != device.data.routing_info {
+        device.data.routing_info = routing_info;
+        device.persist()?;
+    }
 
-    let otp = adi.request_otp(dsid)?;
-    let headers = json!({
-      "X-Apple-I-MD": STANDARD.encode(otp.otp),
-      "X-Apple-I-MD-M": STANDARD.encode(otp.machine_id),
-    });
+    let headers = adi.get_anisette_headers(dsid, &device.data)?;
 
     let _ = sync_idbfs(false);
     println!("{}", serde_json::to_string_pretty(&headers)?);
@@ -0,0 +1,297 @@
+/* Generated by cbindgen from src/exports.rs; do not edit. */
+
+#ifndef ANISETTE_H
+#define ANISETTE_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Version of the C ABI described by `anisette.h`.
+ */
+#define ANISETTE_ABI_VERSION 1
+
+/**
+ * Return codes of the C/WASM exports, so bindings can branch on the kind of
+ * failure instead of matching `anisette_last_error` text. Exports return 0 (or a
+ * documented non-negative value) on success and one of the negative codes on
+ * failure.
+ */
+enum AnisetteStatus {
+  ANISETTE_STATUS_OK = 0,
+  /**
+   * Any failure without a more specific status.
+   */
+  ANISETTE_STATUS_ERROR = -1,
+  /**
+   * A null pointer, invalid UTF-8 or malformed JSON was passed in.
+   */
+  ANISETTE_STATUS_INVALID_ARGUMENT = -2,
+  /**
+   * No `anisette_init_*` call has succeeded yet.
+   */
+  ANISETTE_STATUS_NOT_INITIALIZED = -3,
+  /**
+   * The machine needs (re-)provisioning for this DSID.
+   */
+  ANISETTE_STATUS_NOT_PROVISIONED = -4,
+  /**
+   * An ADI entry point failed; `anisette_last_adi_code` has its return code.
+   */
+  ANISETTE_STATUS_ADI_ERROR = -5,
+  ANISETTE_STATUS_IO_ERROR = -6,
+  /**
+   * The provisioning servers could not be reached or rejected a request.
+   */
+  ANISETTE_STATUS_HTTP_ERROR = -7,
+  /**
+   * The call needs an earlier one first, e.g. resuming without a provisioning flow.
+   */
+  ANISETTE_STATUS_INVALID_STATE = -8,
+  /**
+   * The emulator itself failed (bad library, unhandled import, ...).
+   */
+  ANISETTE_STATUS_EMULATOR_ERROR = -9,
+  /**
+   * `adi.pb` no longer matches the checksum recorded when it was written.
+   */
+  ANISETTE_STATUS_PROVISIONING_CORRUPTED = -10,
+};
+typedef int32_t AnisetteStatus;
+
+/**
+ * Severity of a line passed to a [`LogCallback`].
+ */
+enum AnisetteLogLevel {
+  ANISETTE_LOG_LEVEL_TRACE = 0,
+  ANISETTE_LOG_LEVEL_DEBUG = 1,
+  ANISETTE_LOG_LEVEL_INFO = 2,
+  ANISETTE_LOG_LEVEL_WARN = 3,
+  ANISETTE_LOG_LEVEL_ERROR = 4,
+};
+typedef int32_t AnisetteLogLevel;
+
+/**
+ * Receives log output in place of stdout/stderr, e.g. to forward it to the
+ * browser console or logcat. `message` is UTF-8, not NUL-terminated, and only
+ * valid during the call.
+ */
+typedef void (*AnisetteLogCallback)(int32_t level, const uint8_t *message, size_t len);
+
+/**
+ * Receives the outcome of [`sync_idbfs_with_callback`]: `error` is null on
+ * success, otherwise a NUL-terminated message valid only during the call.
+ */
+typedef void (*AnisetteIdbfsSyncCallback)(void *user_data, const char *error);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * The [`ANISETTE_ABI_VERSION`] this library was built with; compare it against
+ * the header's before calling anything else.
+ */
+uint32_t anisette_abi_version(void);
+
+int32_t anisette_init_from_files(const char *storeservices_path,
+                                 const char *coreadi_path,
+                                 const char *library_path,
+                                 const char *provisioning_path,
+                                 const char *identifier);
+
+int32_t anisette_init_from_blobs(const uint8_t *storeservices_ptr,
+                                 size_t storeservices_len,
+                                 const uint8_t *coreadi_ptr,
+                                 size_t coreadi_len,
+                                 const char *library_path,
+                                 const char *provisioning_path,
+                                 const char *identifier);
+
+int32_t anisette_set_identifier(const char *identifier);
+
+int32_t anisette_set_provisioning_path(const char *path);
+
+/**
+ * Keeps provisioning of the next init under `./profiles/<profile>/anisette`
+ * (where `anisette_idbfs_mount` mounts that profile) while the guest still
+ * uses `./anisette`. Null clears it.
+ */
+int32_t anisette_set_profile(const char *profile);
+
+int32_t anisette_is_machine_provisioned(uint64_t dsid);
+
+int32_t anisette_start_provisioning(uint64_t dsid, const uint8_t *spim_ptr, size_t spim_len);
+
+const uint8_t *anisette_get_cpim_ptr(void);
+
+size_t anisette_get_cpim_len(void);
+
+uint32_t anisette_get_session(void);
+
+int32_t anisette_end_provisioning(uint32_t session,
+                                  const uint8_t *ptm_ptr,
+                                  size_t ptm_len,
+                                  const uint8_t *tk_ptr,
+                                  size_t tk_len);
+
+/**
+ * Starts provisioning `dsid` without blocking on HTTP: the host reads the request
+ * from `anisette_provision_request_ptr/len`, sends it however it likes (e.g. an
+ * awaited `fetch`) and hands the response to `anisette_provision_resume`.
+ * `device_json` is the `device.json` contents.
+ */
+int32_t anisette_provision_begin(uint64_t dsid, const char *device_json);
+
+/**
+ * Feeds the response to the pending request. `headers_json` is an optional
+ * `{ [name]: value }` object of response headers. Returns 1 when another request
+ * is pending, 0 once the device is provisioned and a negative status on error,
+ * which abandons the flow.
+ */
+int32_t anisette_provision_resume(uint32_t status,
+                                  const uint8_t *body_ptr,
+                                  size_t body_len,
+                                  const char *headers_json);
+
+const uint8_t *anisette_provision_request_ptr(void);
+
+size_t anisette_provision_request_len(void);
+
+/**
+ * Runs the whole provisioning exchange for `dsid` in one blocking call: over
+ * reqwest on native builds, and through the host's synchronous
+ * `anisette_http_get` / `anisette_http_post` callbacks on WASM. `device_json` is
+ * the `device.json` contents. Hosts that can only do async HTTP should use
+ * `anisette_provision_begin` instead.
+ */
+int32_t anisette_provision(uint64_t dsid, const char *device_json);
+
+int32_t anisette_request_otp(uint64_t dsid);
+
+/**
+ * Like `anisette_request_otp`, but writes the OTP and machine ID straight into
+ * the caller's buffers and their lengths to `otp_len` / `mid_len`, skipping the
+ * internal copies. Returns `InvalidArgument` if a buffer is too small.
+ */
+int32_t anisette_request_otp_into(uint64_t dsid,
+                                  uint8_t *otp_ptr,
+                                  size_t otp_capacity,
+                                  size_t *otp_len,
+                                  uint8_t *mid_ptr,
+                                  size_t mid_capacity,
+                                  size_t *mid_len);
+
+const uint8_t *anisette_get_otp_ptr(void);
+
+size_t anisette_get_otp_len(void);
+
+const uint8_t *anisette_get_mid_ptr(void);
+
+size_t anisette_get_mid_len(void);
+
+/**
+ * Copies the `adi.pb` for `dsid` into a buffer read with
+ * `anisette_export_provisioning_ptr`/`_len`, so hosts can store it themselves.
+ * Returns `NotProvisioned` if there is none.
+ */
+int32_t anisette_export_provisioning(uint64_t dsid);
+
+const uint8_t *anisette_export_provisioning_ptr(void);
+
+size_t anisette_export_provisioning_len(void);
+
+/**
+ * Replaces the `adi.pb` for `dsid` with bytes from `anisette_export_provisioning`.
+ */
+int32_t anisette_import_provisioning(uint64_t dsid, const uint8_t *data_ptr, size_t data_len);
+
+int32_t anisette_fs_write_file(const char *path, const uint8_t *data_ptr, size_t data_len);
+
+int32_t anisette_fs_read_file(const char *path);
+
+const uint8_t *anisette_fs_read_ptr(void);
+
+size_t anisette_fs_read_len(void);
+
+int32_t anisette_idbfs_sync(int32_t populate_from_storage);
+
+/**
+ * Syncs IDBFS like `anisette_idbfs_sync`, but reports the outcome through
+ * `callback(user_data, error)` once `FS.syncfs` has finished; `error` is null on
+ * success. Use it to know whether `adi.pb` persisted before reporting success.
+ */
+int32_t anisette_idbfs_sync_with_callback(int32_t populate_from_storage,
+                                          AnisetteIdbfsSyncCallback callback,
+                                          void *user_data);
+
+/**
+ * Mounts `path` from IndexedDB, or with a non-null `profile` mounts it under
+ * `/profiles/<profile>` in that profile's own database, and starts loading it.
+ * Several paths can be mounted; mounting one again is a no-op.
+ */
+int32_t anisette_idbfs_mount(const char *path, const char *profile);
+
+/**
+ * Makes OPFS the persistence backend and starts loading `path` (the
+ * provisioning directory) from it; `anisette_idbfs_sync` then syncs OPFS instead
+ * of IDBFS. Fails with `IoError` where OPFS is unavailable.
+ */
+int32_t anisette_use_opfs(const char *path);
+
+const uint8_t *anisette_last_error_ptr(void);
+
+size_t anisette_last_error_len(void);
+
+/**
+ * Raw return code of the ADI call behind the last failure (e.g. -45061), or 0
+ * when it did not come from an ADI entry point.
+ */
+int32_t anisette_last_adi_code(void);
+
+/**
+ * Frees the output buffers (CPIM, OTP/MID, file reads, `adi.pb`, last error)
+ * without touching the ADI instance or a provisioning flow in progress.
+ * Pointers from earlier `*_ptr` calls are invalid afterwards.
+ */
+void anisette_clear_buffers(void);
+
+/**
+ * Disposes the ADI instance, releasing the emulator and its guest address space,
+ * and frees every buffer. Any open provisioning session is aborted. Call an
+ * `anisette_init_*` export again to start over.
+ */
+void anisette_shutdown(void);
+
+/**
+ * Sends the crate's log output to `callback(level, msg_ptr, msg_len)` instead
+ * of stdout/stderr; levels are those of [`LogLevel`](crate::LogLevel). Null
+ * restores the default.
+ */
+void anisette_set_log_callback(AnisetteLogCallback callback);
+
+/**
+ * Shows log output at `level` and above (0 = trace … 4 = error; the default is
+ * 3, warnings), e.g. 0 to capture a full trace of a failing provisioning run.
+ */
+int32_t anisette_set_log_level(int32_t level);
+
+/**
+ * Runs one JSON request such as `{"cmd":"otp","dsid":-2}` (see `CallRequest`
+ * for the commands) and returns a NUL-terminated JSON response: either
+ * `{"ok":true,"result":...}` or `{"ok":false,"status":<AnisetteStatus>,
+ * "error":"...","adi_code":<n>,"report":<ErrorReport>}`, where `report` is null
+ * for argument errors and includes the emulator's `flight_record` when a
+ * library call failed. For hosts where passing many pointer/length
+ * pairs is awkward. The response stays valid until the next `anisette_call`.
+ */
+const char *anisette_call(const char *request);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* ANISETTE_H */
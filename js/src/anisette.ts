@@ -39,6 +39,8 @@ export class Anisette {
   private wasmModule: any;
   private identifier: string;
   private httpClient: HttpClient | undefined;
+  private storage: "idbfs" | "opfs";
+  private profile: string | undefined;
 
   private constructor(
     bridge: WasmBridge,
@@ -52,6 +54,8 @@ export class Anisette {
     wasmModule: any,
     identifier: string,
     httpClient: HttpClient | undefined,
+    storage: "idbfs" | "opfs",
+    profile: string | undefined,
   ) {
     this.bridge = bridge;
     this.device = device;
@@ -63,6 +67,8 @@ export class Anisette {
     this.wasmModule = wasmModule;
     this.identifier = identifier;
     this.httpClient = httpClient;
+    this.storage = storage;
+    this.profile = profile;
   }
 
   // ---- factory methods ----
@@ -92,17 +98,29 @@ export class Anisette {
   ): Promise<Anisette> {
     const bridge = new WasmBridge(wasmModule);
     const initOpts = options.init ?? {};
-    const libraryPath = normalizeAdiPath(initOpts.libraryPath ?? DEFAULT_LIBRARY_PATH);
-    const provisioningPath = normalizeAdiPath(initOpts.provisioningPath ?? libraryPath);
+    const libraryPath = profilePath(
+      normalizeAdiPath(initOpts.libraryPath ?? DEFAULT_LIBRARY_PATH),
+      initOpts.profile
+    );
+    const provisioningPath = initOpts.provisioningPath
+      ? profilePath(normalizeAdiPath(initOpts.provisioningPath), initOpts.profile)
+      : libraryPath;
     const dsid = options.dsid ?? DEFAULT_DSID;
 
-    // Mount + load persisted IDBFS first so file state is stable before init.
-    mountIdbfsPaths(bridge, libraryPath, provisioningPath);
-    try {
-      await bridge.syncIdbfsFromStorage();
-    } catch (err) {
-      console.log("[anisette] Failed to sync IDBFS from storage:", err);
-      // Ignore errors - might be first run with no existing data
+    const storage =
+      initOpts.storage === "opfs" && bridge.isOpfsAvailable() ? "opfs" : "idbfs";
+
+    // Mount + load persisted storage first so file state is stable before init.
+    if (storage === "opfs") {
+      await mountOpfsPaths(bridge, libraryPath, provisioningPath);
+    } else {
+      mountIdbfsPaths(bridge, libraryPath, provisioningPath);
+      try {
+        await bridge.syncIdbfsFromStorage();
+      } catch (err) {
+        console.log("[anisette] Failed to sync IDBFS from storage:", err);
+        // Ignore errors - might be first run with no existing data
+      }
     }
 
     // Load device config from explicit bytes first, then from persisted VFS.
@@ -121,12 +139,14 @@ export class Anisette {
     const deviceJsonBytes = initOpts.deviceJsonBytes ?? encodeUtf8(JSON.stringify(device.toJson(), null, 2));
     bridge.writeVirtualFile(joinPath(libraryPath, "device.json"), deviceJsonBytes);
 
-    // Initialize WASM ADI
+    // Initialize WASM ADI. The guest keeps its unprofiled paths; the profile
+    // moves where its files are stored.
+    bridge.setProfile(initOpts.profile ?? null);
     bridge.initFromBlobs(
       libs.storeservicescore,
       libs.coreadi,
-      libraryPath,
-      provisioningPath,
+      guestPath(libraryPath, initOpts.profile),
+      guestPath(provisioningPath, initOpts.profile),
       identifier
     );
 
@@ -136,7 +156,7 @@ export class Anisette {
       options.httpClient
     );
 
-    return new Anisette(bridge, device, provisioning, dsid, provisioningPath, libraryPath, libs, wasmModule, identifier, options.httpClient);
+    return new Anisette(bridge, device, provisioning, dsid, provisioningPath, libraryPath, libs, wasmModule, identifier, options.httpClient, storage, initOpts.profile);
   }
 
   // ---- public API ----
@@ -149,8 +169,14 @@ export class Anisette {
   /** Run the provisioning flow against Apple servers. */
   async provision(): Promise<void> {
     await this.provisioning.provision(this.dsid);
-    // Sync provisioning state to IndexedDB (browser only)
-    if (this.bridge.isIdbfsAvailable()) {
+    // Sync provisioning state to OPFS / IndexedDB (browser only)
+    if (this.storage === "opfs") {
+      try {
+        await this.bridge.syncOpfsToStorage();
+      } catch (err) {
+        console.error("[anisette] Failed to sync to OPFS:", err);
+      }
+    } else if (this.bridge.isIdbfsAvailable()) {
       try {
         await this.bridge.syncIdbfsToStorage();
       } catch (err) {
@@ -159,6 +185,11 @@ export class Anisette {
     }
   }
 
+  /** Release the emulator and its memory. The instance cannot be used afterwards. */
+  dispose(): void {
+    this.bridge.shutdown();
+  }
+
   /** Read adi.pb from the WASM VFS for persistence. */
   getAdiPb(): Uint8Array {
     return this.bridge.readVirtualFile(joinPath(this.provisioningPath, "adi.pb"));
@@ -175,8 +206,9 @@ export class Anisette {
 
     this.bridge = new WasmBridge(this.wasmModule);
 
-    // Mount + load persisted IDBFS in browser environment
-    if (this.bridge.isIdbfsAvailable()) {
+    // Mount + load persisted IDBFS in browser environment. OPFS-backed paths stay
+    // mounted in the module's FS, so there is nothing to reload for them.
+    if (this.storage === "idbfs" && this.bridge.isIdbfsAvailable()) {
       mountIdbfsPaths(this.bridge, this.libraryPath, this.provisioningPath);
       try {
         await this.bridge.syncIdbfsFromStorage();
@@ -190,7 +222,14 @@ export class Anisette {
     }
     this.bridge.writeVirtualFile(joinPath(this.libraryPath, "device.json"), deviceJsonBytes);
 
-    this.bridge.initFromBlobs(this.libs.storeservicescore, this.libs.coreadi, this.libraryPath, this.provisioningPath, this.identifier);
+    this.bridge.setProfile(this.profile ?? null);
+    this.bridge.initFromBlobs(
+      this.libs.storeservicescore,
+      this.libs.coreadi,
+      guestPath(this.libraryPath, this.profile),
+      guestPath(this.provisioningPath, this.profile),
+      this.identifier
+    );
 
     this.provisioning = new ProvisioningSession(this.bridge, this.device, this.httpClient);
 
@@ -233,6 +272,23 @@ function joinPath(base: string, file: string): string {
   return `${b}${file}`;
 }
 
+/** Moves `path` under `/profiles/<profile>/`; mirrors `init_idbfs_for_profile`. */
+function profilePath(path: string, profile: string | undefined): string {
+  if (profile === undefined) return path;
+  if (!/^[A-Za-z0-9._-]+$/.test(profile) || profile === "." || profile === "..") {
+    throw new Error(`Invalid profile name '${profile}'`);
+  }
+  const rest = path.replace(/^\.?\/+/, "");
+  return normalizeAdiPath(`/profiles/${profile}/${rest}`);
+}
+
+/** Undoes `profilePath`: the path the guest uses for a profile's `path`. */
+function guestPath(path: string, profile: string | undefined): string {
+  if (profile === undefined) return path;
+  const prefix = `./profiles/${profile}/`;
+  return path.startsWith(prefix) ? `./${path.slice(prefix.length)}` : path;
+}
+
 function normalizeAdiPath(path: string): string {
   const trimmed = path.trim().replace(/\\/g, "/");
   if (!trimmed || trimmed === "." || trimmed === "./" || trimmed === "/") {
@@ -253,6 +309,17 @@ function normalizeAdiPath(path: string): string {
   return `./${noTrail}/`;
 }
 
+async function mountOpfsPaths(
+  bridge: WasmBridge,
+  libraryPath: string,
+  provisioningPath: string
+): Promise<void> {
+  const paths = new Set([libraryPath, provisioningPath]);
+  for (const path of paths) {
+    await bridge.initOpfs(path);
+  }
+}
+
 function mountIdbfsPaths(
   bridge: WasmBridge,
   libraryPath: string,
@@ -1,7 +1,8 @@
 // Public entry point — re-exports everything users need
 
 export { Anisette } from "./anisette.js";
-export { WasmBridge } from "./wasm-bridge.js";
+export { WasmBridge, AnisetteError, AnisetteStatus } from "./wasm-bridge.js";
+export type { ErrorReport } from "./wasm-bridge.js";
 export { Device } from "./device.js";
 export { LibraryStore } from "./library.js";
 export { ProvisioningSession } from "./provisioning.js";
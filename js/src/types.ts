@@ -37,6 +37,17 @@ export interface InitOptions {
   adiPb?: Uint8Array;
   /** Existing device.json bytes to restore into the WASM VFS */
   deviceJsonBytes?: Uint8Array;
+  /**
+   * Browser storage for the VFS paths (default "idbfs"). "opfs" is faster and
+   * works in Workers; it falls back to IDBFS where OPFS is unavailable.
+   */
+  storage?: "idbfs" | "opfs";
+  /**
+   * Device profile name (letters, digits, "-", "_", "."). Each profile keeps its
+   * files under `/profiles/<profile>/` in its own IndexedDB database, so one app
+   * can run several virtual devices.
+   */
+  profile?: string;
 }
 
 /** Raw device.json structure as stored on disk / in WASM VFS */
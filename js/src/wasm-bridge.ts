@@ -11,9 +11,66 @@ export interface RequestOtpResult {
   machineId: Uint8Array;
 }
 
+/** Capacity of each reusable OTP / machine ID output buffer. */
+const OTP_BUFFER_SIZE = 1024;
+
+/** Status codes returned by the exports; mirrors `AnisetteStatus` in Rust. */
+export const AnisetteStatus = {
+  Ok: 0,
+  Error: -1,
+  InvalidArgument: -2,
+  NotInitialized: -3,
+  NotProvisioned: -4,
+  AdiError: -5,
+  IoError: -6,
+  HttpError: -7,
+  InvalidState: -8,
+  EmulatorError: -9,
+  ProvisioningCorrupted: -10,
+} as const;
+
+/** Structured error from `anisette_call`; mirrors `ErrorReport` in Rust. */
+export interface ErrorReport {
+  code: number;
+  category:
+    | "emulation"
+    | "io"
+    | "protocol"
+    | "not_provisioned"
+    | "configuration"
+    | null;
+  message: string;
+  adi_code: number | null;
+  /** Guest calls the error happened in, outermost first. */
+  context: string[];
+  retryable: boolean;
+  /** Emulator events leading up to the failure, oldest first; omitted when empty. */
+  flight_record?: string[];
+}
+
+/**
+ * A failed export call. `status` is one of {@link AnisetteStatus}; `adiCode`
+ * is the raw ADI return code (e.g. -45061) or 0. `report` is set for failures
+ * from {@link WasmBridge.call} that came from the emulator or provisioning.
+ */
+export class AnisetteError extends Error {
+  constructor(
+    message: string,
+    readonly status: number,
+    readonly adiCode: number,
+    readonly report: ErrorReport | null = null
+  ) {
+    super(message);
+    this.name = "AnisetteError";
+  }
+}
+
 export class WasmBridge {
   // eslint-disable-next-line @typescript-eslint/no-explicit-any
   private m: any;
+  private logCallbackPtr = 0;
+  /** OTP buffer, machine ID buffer and two size_t lengths, reused across calls. */
+  private otpOutputPtr = 0;
 
   // eslint-disable-next-line @typescript-eslint/no-explicit-any
   constructor(wasmModule: any) {
@@ -55,15 +112,117 @@ export class WasmBridge {
     return new TextDecoder("utf-8").decode(bytes);
   }
 
+  getLastAdiCode(): number {
+    return this.m._anisette_last_adi_code() as number;
+  }
+
+  private error(result: number, context: string): AnisetteError {
+    const msg = this.getLastError();
+    return new AnisetteError(
+      `${context}: ${msg || "unknown error"}`,
+      result,
+      this.getLastAdiCode()
+    );
+  }
+
   private check(result: number, context: string): void {
     if (result !== 0) {
-      const msg = this.getLastError();
-      throw new Error(`${context}: ${msg || "unknown error"}`);
+      throw this.error(result, context);
     }
   }
 
   // ---- public API ----
 
+  /**
+   * Receive the crate's log output (level 0 = trace … 4 = error) instead of
+   * it going to stdout/stderr. Pass `null` to restore the default.
+   */
+  setLogCallback(
+    callback: ((level: number, message: string) => void) | null
+  ): void {
+    const previous = this.logCallbackPtr;
+    this.logCallbackPtr = callback
+      ? (this.m.addFunction((level: number, ptr: number, len: number) => {
+          const message = new TextDecoder("utf-8").decode(
+            this.readBytes(ptr, len)
+          );
+          callback(level, message);
+        }, "viii") as number)
+      : 0;
+    this.m._anisette_set_log_callback(this.logCallbackPtr);
+    if (previous) this.m.removeFunction(previous);
+  }
+
+  /**
+   * Show log output at `level` and above (0 = trace … 4 = error, default 3).
+   * Combine with `setLogCallback` to capture a trace of a failing run.
+   */
+  setLogLevel(level: number): void {
+    this.check(this.m._anisette_set_log_level(level) as number, "anisette_set_log_level");
+  }
+
+  /**
+   * Free the buffered CPIM/OTP/error output; the ADI instance stays loaded.
+   */
+  clearBuffers(): void {
+    this.m._anisette_clear_buffers();
+  }
+
+  /**
+   * Dispose the ADI instance and release the emulator's guest memory. Call
+   * `initFromBlobs` again before further use.
+   */
+  shutdown(): void {
+    this.m._anisette_shutdown();
+    this.setLogCallback(null);
+    this.free(this.otpOutputPtr);
+    this.otpOutputPtr = 0;
+  }
+
+  /**
+   * Run a JSON request through `anisette_call`, e.g. `{ cmd: "otp", dsid: -2 }`.
+   * Returns the command's `result`; failures throw an AnisetteError.
+   */
+  call<T = unknown>(request: { cmd: string } & Record<string, unknown>): T {
+    const requestPtr = this.allocCString(JSON.stringify(request));
+    let response: {
+      ok: boolean;
+      result?: T;
+      status?: number;
+      error?: string;
+      adi_code?: number;
+      report?: ErrorReport | null;
+    };
+    try {
+      const ptr = this.m._anisette_call(requestPtr) as number;
+      response = JSON.parse(this.m.UTF8ToString(ptr) as string);
+    } finally {
+      this.free(requestPtr);
+    }
+    if (!response.ok) {
+      throw new AnisetteError(
+        `anisette_call(${request.cmd}): ${response.error || "unknown error"}`,
+        response.status ?? AnisetteStatus.Error,
+        response.adi_code ?? 0,
+        response.report ?? null
+      );
+    }
+    return response.result as T;
+  }
+
+  /**
+   * Keep provisioning of the next init under `./profiles/<profile>/anisette`
+   * while the guest still uses `./anisette`; `null` clears it.
+   */
+  setProfile(profile: string | null): void {
+    const ptr = this.allocCString(profile);
+    try {
+      this.check(this.m._anisette_set_profile(ptr) as number, "anisette_set_profile");
+    } finally {
+      this.free(ptr);
+    }
+  }
+
   /**
    * Initialize ADI from in-memory library blobs.
    */
@@ -141,9 +300,7 @@ export class WasmBridge {
   isMachineProvisioned(dsid: bigint): boolean {
     const result = this.m._anisette_is_machine_provisioned(dsid) as number;
     if (result < 0) {
-      throw new Error(
-        `anisette_is_machine_provisioned: ${this.getLastError()}`
-      );
+      throw this.error(result, "anisette_is_machine_provisioned");
     }
     return result === 1;
   }
@@ -202,13 +359,26 @@ export class WasmBridge {
    * Request OTP — returns OTP bytes and machine ID bytes.
    */
   requestOtp(dsid: bigint): RequestOtpResult {
-    const result = this.m._anisette_request_otp(dsid) as number;
-    this.check(result, "anisette_request_otp");
-
-    const otpPtr = this.m._anisette_get_otp_ptr() as number;
-    const otpLen = this.m._anisette_get_otp_len() as number;
-    const midPtr = this.m._anisette_get_mid_ptr() as number;
-    const midLen = this.m._anisette_get_mid_len() as number;
+    if (!this.otpOutputPtr) {
+      this.otpOutputPtr = this.m._malloc(2 * OTP_BUFFER_SIZE + 8) as number;
+    }
+    const otpPtr = this.otpOutputPtr;
+    const midPtr = otpPtr + OTP_BUFFER_SIZE;
+    const lenPtr = midPtr + OTP_BUFFER_SIZE;
+    const result = this.m._anisette_request_otp_into(
+      dsid,
+      otpPtr,
+      OTP_BUFFER_SIZE,
+      lenPtr,
+      midPtr,
+      OTP_BUFFER_SIZE,
+      lenPtr + 4
+    ) as number;
+    this.check(result, "anisette_request_otp_into");
+
+    const lengths = new DataView((this.m.HEAPU8 as Uint8Array).buffer);
+    const otpLen = lengths.getUint32(lenPtr, true);
+    const midLen = lengths.getUint32(lenPtr + 4, true);
 
     return {
       otp: this.readBytes(otpPtr, otpLen),
@@ -216,6 +386,37 @@ export class WasmBridge {
     };
   }
 
+  /**
+   * The adi.pb for `dsid`, or null if it has not been provisioned. Lets hosts
+   * persist provisioning themselves instead of going through IDBFS.
+   */
+  exportProvisioning(dsid: bigint): Uint8Array | null {
+    const result = this.m._anisette_export_provisioning(dsid) as number;
+    if (result === AnisetteStatus.NotProvisioned) return null;
+    this.check(result, "anisette_export_provisioning");
+
+    const ptr = this.m._anisette_export_provisioning_ptr() as number;
+    const len = this.m._anisette_export_provisioning_len() as number;
+    return this.readBytes(ptr, len);
+  }
+
+  /**
+   * Restore an adi.pb from `exportProvisioning`.
+   */
+  importProvisioning(dsid: bigint, data: Uint8Array): void {
+    const dataPtr = this.allocBytes(data);
+    try {
+      const result = this.m._anisette_import_provisioning(
+        dsid,
+        dataPtr,
+        data.length
+      ) as number;
+      this.check(result, "anisette_import_provisioning");
+    } finally {
+      this.free(dataPtr);
+    }
+  }
+
   /**
    * Check if IDBFS is available (browser environment only).
    */
@@ -300,6 +501,37 @@ export class WasmBridge {
     });
   }
 
+  /**
+   * Check if OPFS is available (browsers, including Workers).
+   */
+  isOpfsAvailable(): boolean {
+    return typeof navigator !== "undefined" && !!navigator.storage?.getDirectory;
+  }
+
+  /**
+   * Persist `path` in the origin private file system instead of IDBFS and load
+   * what is already stored there.
+   */
+  async initOpfs(path: string): Promise<void> {
+    const pathPtr = this.allocCString(this.normalizeMountPath(path));
+    try {
+      const result = this.m._anisette_use_opfs(pathPtr) as number;
+      this.check(result, "anisette_use_opfs");
+    } finally {
+      this.free(pathPtr);
+    }
+    await this.m.anisetteOpfsQueue;
+  }
+
+  /**
+   * Write changes under the OPFS-backed paths back to OPFS (async).
+   */
+  async syncOpfsToStorage(): Promise<void> {
+    const result = this.m._anisette_idbfs_sync(0) as number;
+    this.check(result, "anisette_idbfs_sync");
+    await this.m.anisetteOpfsQueue;
+  }
+
   private normalizeMountPath(path: string): string {
     const trimmed = path.trim();
     const noSlash = trimmed.replace(/\/+$/, "");
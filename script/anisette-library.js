@@ -0,0 +1,209 @@
+// Emscripten JS library linked with --js-library. Every function here is an
+// `extern "C"` import on the Rust side; strings cross as UTF-8 pointers, so
+// payloads are never spliced into script source.
+
+addToLibrary({
+  // Calls the host's `name` callback (a global or a Module property) with the
+  // parsed JSON request. Returns a malloc'd UTF-8 JSON response the caller frees,
+  // or 0 when the callback is missing or returned nothing.
+  anisette_js_http__deps: ["$UTF8ToString", "$stringToNewUTF8"],
+  anisette_js_http: function (namePtr, requestPtr) {
+    var name = UTF8ToString(namePtr);
+    var fn = typeof globalThis[name] === "function" ? globalThis[name] : null;
+    if (!fn && typeof Module !== "undefined" && typeof Module[name] === "function") {
+      fn = Module[name];
+    }
+    if (!fn) {
+      return 0;
+    }
+    var response = fn(JSON.parse(UTF8ToString(requestPtr)));
+    if (response === undefined || response === null || response === "") {
+      return 0;
+    }
+    if (typeof response !== "string") {
+      response = JSON.stringify(response);
+    }
+    return stringToNewUTF8(response);
+  },
+
+  anisette_js_idbfs_mount__deps: ["$UTF8ToString"],
+  anisette_js_idbfs_mount: function (pathPtr) {
+    if (typeof FS === "undefined" || typeof IDBFS === "undefined") {
+      console.warn("[anisette-rs] FS/IDBFS unavailable");
+      return;
+    }
+    var mp = UTF8ToString(pathPtr);
+    try { FS.mkdirTree(mp); } catch (_e) {}
+    var root;
+    try {
+      root = FS.mount(IDBFS, {}, mp);
+    } catch (_e) {
+      root = FS.lookupPath(mp).node;
+    }
+    // Load only this mount: FS.syncfs(true) would also reload the other mounts
+    // over changes not synced yet.
+    IDBFS.syncfs(root.mount, true, function (err) {
+      if (err) {
+        console.error("[anisette-rs] IDBFS initial sync failed", err);
+      } else {
+        console.log("[anisette-rs] IDBFS ready at " + mp);
+      }
+    });
+  },
+
+  anisette_js_idbfs_sync: function (populate) {
+    if (typeof FS === "undefined") {
+      return;
+    }
+    FS.syncfs(!!populate, function (err) {
+      if (err) {
+        console.error("[anisette-rs] IDBFS sync failed", err);
+      } else {
+        console.log("[anisette-rs] IDBFS sync done");
+      }
+    });
+  },
+
+  // Like anisette_js_idbfs_sync, but reports completion through the wasm table
+  // function `callback(userData, errorPtr)`; errorPtr is 0 on success and the
+  // message is freed once the callback returns.
+  anisette_js_idbfs_sync_cb__deps: ["$stringToNewUTF8", "$getWasmTableEntry", "free"],
+  anisette_js_idbfs_sync_cb: function (populate, callback, userData) {
+    var done = function (message) {
+      var errorPtr = message ? stringToNewUTF8(message) : 0;
+      try {
+        getWasmTableEntry(callback)(userData, errorPtr);
+      } finally {
+        if (errorPtr) _free(errorPtr);
+      }
+    };
+    if (typeof FS === "undefined") {
+      done("FS unavailable");
+      return;
+    }
+    FS.syncfs(!!populate, function (err) {
+      done(err ? String(err.message || err) : null);
+    });
+  },
+
+  // Debounced persistence sync, scheduled when the guest closes a rewritten
+  // adi.pb. Goes through the exported anisette_idbfs_sync so the active backend
+  // (IDBFS or OPFS) is used. Builds that do not export it (the Node glue) have
+  // nothing to persist to, so the sync is skipped.
+  anisette_js_schedule_sync: function (delayMs) {
+    if (typeof Module["_anisette_idbfs_sync"] !== "function") {
+      return;
+    }
+    if (Module.anisetteSyncTimer) {
+      clearTimeout(Module.anisetteSyncTimer);
+    }
+    Module.anisetteSyncTimer = setTimeout(function () {
+      Module.anisetteSyncTimer = 0;
+      try {
+        Module["_anisette_idbfs_sync"](0);
+      } catch (err) {
+        console.error("[anisette-rs] scheduled sync failed", err);
+      }
+    }, delayMs);
+  },
+
+  // OPFS persistence (OpfsBackend): each mounted MEMFS directory is mirrored to
+  // the same path in the origin private file system. Copies are queued on
+  // `Module.anisetteOpfsQueue` so they never interleave; await it to know when
+  // the last sync has finished.
+  $anisetteOpfsQueue__deps: ["$anisetteOpfsCopy"],
+  $anisetteOpfsQueue: function (roots, populate) {
+    Module.anisetteOpfsQueue = (Module.anisetteOpfsQueue || Promise.resolve())
+      .then(function () {
+        return Promise.all(roots.map(function (mp) {
+          return anisetteOpfsCopy(mp, populate);
+        }));
+      })
+      .catch(function (err) {
+        console.error("[anisette-rs] OPFS sync failed", err);
+      });
+    return Module.anisetteOpfsQueue;
+  },
+
+  $anisetteOpfsCopy: async function (mp, populate) {
+    var dir = await navigator.storage.getDirectory();
+    var parts = mp.split("/").filter(Boolean);
+    for (var i = 0; i < parts.length; i++) {
+      dir = await dir.getDirectoryHandle(parts[i], { create: true });
+    }
+
+    var toMemfs = async function (handle, path) {
+      for await (var entry of handle.values()) {
+        var child = path + "/" + entry.name;
+        if (entry.kind === "directory") {
+          try { FS.mkdirTree(child); } catch (_e) {}
+          await toMemfs(entry, child);
+        } else {
+          var file = await entry.getFile();
+          FS.writeFile(child, new Uint8Array(await file.arrayBuffer()));
+        }
+      }
+    };
+
+    var toOpfs = async function (path, handle) {
+      var names = FS.readdir(path).filter(function (name) {
+        return name !== "." && name !== "..";
+      });
+      for await (var existing of handle.keys()) {
+        if (names.indexOf(existing) < 0) {
+          await handle.removeEntry(existing, { recursive: true });
+        }
+      }
+      for (var j = 0; j < names.length; j++) {
+        var child = path + "/" + names[j];
+        if (FS.isDir(FS.stat(child).mode)) {
+          await toOpfs(child, await handle.getDirectoryHandle(names[j], { create: true }));
+          continue;
+        }
+        var data = FS.readFile(child);
+        var fileHandle = await handle.getFileHandle(names[j], { create: true });
+        if (fileHandle.createWritable) {
+          var writable = await fileHandle.createWritable();
+          await writable.write(data);
+          await writable.close();
+        } else {
+          // Workers in some browsers only offer synchronous access handles.
+          var access = await fileHandle.createSyncAccessHandle();
+          access.truncate(0);
+          access.write(data, { at: 0 });
+          access.flush();
+          access.close();
+        }
+      }
+    };
+
+    if (populate) {
+      await toMemfs(dir, mp);
+    } else {
+      await toOpfs(mp, dir);
+    }
+  },
+
+  // Returns 0 once `path` is registered and its initial load is queued, or -1
+  // when OPFS is unavailable.
+  anisette_js_opfs_mount__deps: ["$UTF8ToString", "$anisetteOpfsQueue"],
+  anisette_js_opfs_mount: function (pathPtr) {
+    if (typeof FS === "undefined" || typeof navigator === "undefined" ||
+        !navigator.storage || !navigator.storage.getDirectory) {
+      return -1;
+    }
+    var mp = UTF8ToString(pathPtr);
+    try { FS.mkdirTree(mp); } catch (_e) {}
+    Module.anisetteOpfsRoots = Module.anisetteOpfsRoots || [];
+    if (Module.anisetteOpfsRoots.indexOf(mp) < 0) {
+      Module.anisetteOpfsRoots.push(mp);
+    }
+    anisetteOpfsQueue([mp], true);
+    return 0;
+  },
+
+  anisette_js_opfs_sync__deps: ["$anisetteOpfsQueue"],
+  anisette_js_opfs_sync: function (populate) {
+    anisetteOpfsQueue(Module.anisetteOpfsRoots || [], !!populate);
+  },
+});
@@ -4,6 +4,9 @@ set -euo pipefail
 BUILD_MODE="debug"
 if [[ "${1:-}" == "--release" ]]; then
   BUILD_MODE="release"
+elif [[ "${1:-}" == "--minimal" ]]; then
+  # Size-optimized browser build; see the `minimal` feature in Cargo.toml.
+  BUILD_MODE="release-minimal"
 fi
 
 ROOT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")/.." && pwd)"
@@ -13,13 +16,14 @@ DIST_DIR="${ROOT_DIR}/js/src"
 UNICORN_BUILD_DIR="${UNICORN_BUILD_DIR:-${ROOT_DIR}/../unicorn/build}"
 NODE_DIST_JS="${DIST_DIR}/anisette_rs.node.js"
 NODE_DIST_WASM="${DIST_DIR}/anisette_rs.node.wasm"
+JS_LIBRARY="${ROOT_DIR}/script/anisette-library.js"
 
 
 
-WEB_EXPORTED_FUNCTIONS='["_malloc","_free","_anisette_init_from_blobs","_anisette_is_machine_provisioned","_anisette_start_provisioning","_anisette_end_provisioning","_anisette_request_otp","_anisette_get_cpim_ptr","_anisette_get_cpim_len","_anisette_get_session","_anisette_get_otp_ptr","_anisette_get_otp_len","_anisette_get_mid_ptr","_anisette_get_mid_len","_anisette_last_error_ptr","_anisette_last_error_len","_anisette_fs_write_file","_anisette_fs_read_file","_anisette_fs_read_ptr","_anisette_fs_read_len","_anisette_idbfs_sync","_anisette_set_identifier","_anisette_set_provisioning_path"]'
-NODE_EXPORTED_FUNCTIONS='["_malloc","_free","_anisette_init_from_blobs","_anisette_is_machine_provisioned","_anisette_start_provisioning","_anisette_end_provisioning","_anisette_request_otp","_anisette_get_cpim_ptr","_anisette_get_cpim_len","_anisette_get_session","_anisette_get_otp_ptr","_anisette_get_otp_len","_anisette_get_mid_ptr","_anisette_get_mid_len","_anisette_last_error_ptr","_anisette_last_error_len","_anisette_fs_write_file","_anisette_fs_read_file","_anisette_fs_read_ptr","_anisette_fs_read_len","_anisette_set_identifier","_anisette_set_provisioning_path"]'
-WEB_EXPORTED_RUNTIME_METHODS='["FS","HEAPU8","UTF8ToString","stringToUTF8","lengthBytesUTF8"]'
-NODE_EXPORTED_RUNTIME_METHODS='["HEAPU8","UTF8ToString","stringToUTF8","lengthBytesUTF8"]'
+WEB_EXPORTED_FUNCTIONS='["_malloc","_free","_anisette_init_from_blobs","_anisette_is_machine_provisioned","_anisette_start_provisioning","_anisette_end_provisioning","_anisette_request_otp","_anisette_request_otp_into","_anisette_get_cpim_ptr","_anisette_get_cpim_len","_anisette_get_session","_anisette_get_otp_ptr","_anisette_get_otp_len","_anisette_get_mid_ptr","_anisette_get_mid_len","_anisette_last_error_ptr","_anisette_last_error_len","_anisette_fs_write_file","_anisette_fs_read_file","_anisette_fs_read_ptr","_anisette_fs_read_len","_anisette_idbfs_sync","_anisette_idbfs_mount","_anisette_set_identifier","_anisette_set_provisioning_path","_anisette_set_profile","_anisette_provision_begin","_anisette_provision_resume","_anisette_provision_request_ptr","_anisette_provision_request_len","_anisette_provision","_anisette_last_adi_code","_anisette_set_log_callback","_anisette_abi_version","_anisette_clear_buffers","_anisette_shutdown","_anisette_export_provisioning","_anisette_export_provisioning_ptr","_anisette_export_provisioning_len","_anisette_import_provisioning","_anisette_call","_anisette_set_log_level","_anisette_use_opfs","_anisette_idbfs_sync_with_callback"]'
+NODE_EXPORTED_FUNCTIONS='["_malloc","_free","_anisette_init_from_blobs","_anisette_is_machine_provisioned","_anisette_start_provisioning","_anisette_end_provisioning","_anisette_request_otp","_anisette_request_otp_into","_anisette_get_cpim_ptr","_anisette_get_cpim_len","_anisette_get_session","_anisette_get_otp_ptr","_anisette_get_otp_len","_anisette_get_mid_ptr","_anisette_get_mid_len","_anisette_last_error_ptr","_anisette_last_error_len","_anisette_fs_write_file","_anisette_fs_read_file","_anisette_fs_read_ptr","_anisette_fs_read_len","_anisette_set_identifier","_anisette_set_provisioning_path","_anisette_set_profile","_anisette_provision_begin","_anisette_provision_resume","_anisette_provision_request_ptr","_anisette_provision_request_len","_anisette_provision","_anisette_last_adi_code","_anisette_set_log_callback","_anisette_abi_version","_anisette_clear_buffers","_anisette_shutdown","_anisette_export_provisioning","_anisette_export_provisioning_ptr","_anisette_export_provisioning_len","_anisette_import_provisioning","_anisette_call","_anisette_set_log_level","_anisette_use_opfs","_anisette_idbfs_sync_with_callback"]'
+WEB_EXPORTED_RUNTIME_METHODS='["FS","HEAPU8","UTF8ToString","stringToUTF8","lengthBytesUTF8","addFunction","removeFunction"]'
+NODE_EXPORTED_RUNTIME_METHODS='["HEAPU8","UTF8ToString","stringToUTF8","lengthBytesUTF8","addFunction","removeFunction"]'
 
 # if [[ -f "${EMSDK_DIR}/emsdk_env.sh" ]]; then
 #   # shellcheck disable=SC1090
@@ -38,11 +42,18 @@ mkdir -p "${DIST_DIR}"
 pushd "${ROOT_DIR}" >/dev/null
 if [[ "${BUILD_MODE}" == "release" ]]; then
   cargo build --release --target wasm32-unknown-emscripten
+elif [[ "${BUILD_MODE}" == "release-minimal" ]]; then
+  cargo build --profile release-minimal --features minimal --target wasm32-unknown-emscripten
 else
   cargo build --target wasm32-unknown-emscripten
 fi
 popd >/dev/null
 
+EMCC_FLAGS=(-sASSERTIONS=1)
+if [[ "${BUILD_MODE}" == "release-minimal" ]]; then
+  EMCC_FLAGS=(-Oz -sASSERTIONS=0)
+fi
+
 EMCC_INPUTS=(
   "${TARGET_DIR}/libanisette_rs.a"
   "${UNICORN_BUILD_DIR}/libunicorn.a"
@@ -60,6 +71,7 @@ done
 
 emcc \
   "${EMCC_INPUTS[@]}" \
+  --js-library "${JS_LIBRARY}" \
   -lidbfs.js \
   -o "${DIST_DIR}/anisette_rs.js" \
   -sMODULARIZE=1 \
@@ -68,15 +80,17 @@ emcc \
   -sWASM=1 \
   -sSINGLE_FILE=1 \
   -sALLOW_MEMORY_GROWTH=1 \
+  -sALLOW_TABLE_GROWTH=1 \
   -sINITIAL_MEMORY=268435456 \
   -sWASM_BIGINT=1 \
   -sFORCE_FILESYSTEM=1 \
-  -sASSERTIONS=1 \
+  "${EMCC_FLAGS[@]}" \
   -sEXPORTED_FUNCTIONS="${WEB_EXPORTED_FUNCTIONS}" \
   -sEXPORTED_RUNTIME_METHODS="${WEB_EXPORTED_RUNTIME_METHODS}"
 
 emcc \
   "${EMCC_INPUTS[@]}" \
+  --js-library "${JS_LIBRARY}" \
   -o "${NODE_DIST_JS}" \
   -sMODULARIZE=1 \
   -sEXPORT_ES6=1 \
@@ -84,10 +98,11 @@ emcc \
   -sWASM=1 \
   -sSINGLE_FILE=1 \
   -sALLOW_MEMORY_GROWTH=1 \
+  -sALLOW_TABLE_GROWTH=1 \
   -sINITIAL_MEMORY=268435456 \
   -sWASM_BIGINT=1 \
   -sFORCE_FILESYSTEM=0 \
-  -sASSERTIONS=1 \
+  "${EMCC_FLAGS[@]}" \
   -sEXPORTED_FUNCTIONS="${NODE_EXPORTED_FUNCTIONS}" \
   -sEXPORTED_RUNTIME_METHODS="${NODE_EXPORTED_RUNTIME_METHODS}"
 
@@ -0,0 +1,25 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+# Re-fetches Apple's public root CA ("Apple Root CA") into certs/apple-root.pem,
+# which is checked in and embedded by the default `bundled-apple-root` feature.
+# The fingerprint check doubles as a way to verify the committed copy.
+
+ROOT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")/.." && pwd)"
+CERT_URL="https://www.apple.com/appleca/AppleIncRootCertificate.cer"
+EXPECTED_SHA256="B0:B1:73:0E:CB:C7:FF:45:05:14:2C:49:F1:29:5E:6E:DA:6B:CA:ED:7E:2C:68:C5:BE:91:B5:A1:10:01:F0:24"
+OUT="${ROOT_DIR}/certs/apple-root.pem"
+
+TMP="$(mktemp)"
+trap 'rm -f "${TMP}"' EXIT
+
+curl -fsSL "${CERT_URL}" | openssl x509 -inform der -out "${TMP}"
+FINGERPRINT="$(openssl x509 -in "${TMP}" -noout -fingerprint -sha256 | cut -d= -f2)"
+if [[ "${FINGERPRINT}" != "${EXPECTED_SHA256}" ]]; then
+  echo "unexpected certificate fingerprint: ${FINGERPRINT}"
+  exit 1
+fi
+
+mkdir -p "$(dirname "${OUT}")"
+mv "${TMP}" "${OUT}"
+echo "wrote ${OUT}"
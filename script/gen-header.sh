@@ -0,0 +1,11 @@
+#!/usr/bin/env bash
+# Regenerates include/anisette.h; needs `cargo install cbindgen`.
+set -euo pipefail
+
+ROOT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")/.." && pwd)"
+
+cbindgen \
+  --config "${ROOT_DIR}/cbindgen.toml" \
+  --crate anisette-rs \
+  --output "${ROOT_DIR}/include/anisette.h" \
+  "${ROOT_DIR}"
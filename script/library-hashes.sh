@@ -0,0 +1,17 @@
+#!/usr/bin/env bash
+set -euo pipefail
+
+# Prints KNOWN_GOOD_LIBRARIES entries (src/library.rs) for the ADI libraries in
+# a directory, e.g. an APK's lib/arm64-v8a. Only add them once that build has
+# provisioned and produced OTPs under the emulator.
+
+if [[ $# -ne 2 ]]; then
+  echo "usage: $0 <library dir> <version>"
+  exit 1
+fi
+
+for name in libstoreservicescore.so libCoreADI.so; do
+  sha256="$(sha256sum "$1/${name}" | cut -d' ' -f1)"
+  printf '    KnownLibrary {\n        name: "%s",\n        version: "%s",\n        sha256: "%s",\n    },\n' \
+    "${name}" "$2" "${sha256}"
+done
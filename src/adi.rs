@@ -1,14 +1,93 @@
-use crate::debug::debug_print;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::bundle::{read_bundle, write_bundle};
+use crate::clock::GuestClock;
+use crate::constants::GUEST_ADI_PB_PATH;
+use crate::debug::{debug_print, redacted};
+use crate::device::DeviceData;
 use crate::emu::{EmuCore, alloc_c_string, ensure_zero_return};
-use crate::errors::VmError;
-use crate::util::bytes_to_hex;
+use crate::errors::{AdiErrorCode, VmError};
+use crate::file_lock::{FileLock, write_atomic};
+use crate::identifier::AdiIdentifier;
+use crate::integrity::{record_checksum, sum_path, verify_checksum};
+use crate::library::{LibraryCheck, LibraryInfo, describe_library, sha256_hex, verify_library};
+use crate::metrics::{AdiMetrics, CallSample, StubStats};
+use crate::overrides::StubOverride;
+use crate::pthread::PthreadOptions;
+use crate::state::{
+    ProvisioningState, collect_state_files, is_adi_pb, provisioned_namespaces, read_state_file,
+    restore_state_files,
+};
+use crate::vfs::GuestFs;
 
+#[derive(Default)]
 pub struct AdiInit {
     pub storeservicescore: Vec<u8>,
     pub coreadi: Vec<u8>,
     pub library_path: String,
     pub provisioning_path: Option<String>,
     pub identifier: Option<String>,
+    pub guest_fs: Option<Box<dyn GuestFs>>,
+    /// Keep a separate `adi.pb` per DSID under `{provisioning_path}/{dsid}/`.
+    pub per_dsid_provisioning: bool,
+    /// Variables visible to the guest through `getenv`.
+    pub environment: HashMap<String, String>,
+    /// Serial the library reads through `__system_property_get`, usually
+    /// [`DeviceData::serial_number`](crate::DeviceData::serial_number). Changing it
+    /// on an already provisioned `adi.pb` may invalidate that provisioning.
+    pub serial_number: Option<String>,
+    /// Seed the guest's random source for reproducible runs (tests only).
+    pub random_seed: Option<u64>,
+    /// Whether to check the library blobs against the known-good build table.
+    pub library_check: LibraryCheck,
+    /// Move a corrupted `adi.pb` aside instead of failing every call; see
+    /// [`Adi::set_corrupted_provisioning_recovery`].
+    pub recover_corrupted_provisioning: bool,
+    /// Write an ELF core dump here when a call hits an emulation fault; see
+    /// [`EmuCore::set_fault_dump_dir`]. Ignored with the `minimal` feature.
+    pub fault_dump_dir: Option<PathBuf>,
+    /// Keep provisioning under `./profiles/{profile}/anisette` instead of
+    /// `./anisette`; see [`EmuCore::set_profile`].
+    pub profile: Option<String>,
+}
+
+/// Where Android apps keep native libraries, relative to an extracted APK.
+const APK_LIBRARY_SUBDIR: &str = "lib/arm64-v8a";
+
+impl AdiInit {
+    /// Reads `libstoreservicescore.so` and `libCoreADI.so` from `dir` (or its
+    /// `lib/arm64-v8a/` subdirectory) and uses `dir` as the library path.
+    pub fn from_library_dir(dir: impl AsRef<Path>) -> Result<Self, VmError> {
+        let dir = dir.as_ref();
+        Ok(Self {
+            storeservicescore: read_library(dir, "libstoreservicescore.so")?,
+            coreadi: read_library(dir, "libCoreADI.so")?,
+            library_path: dir.to_string_lossy().into_owned(),
+            ..Default::default()
+        })
+    }
+}
+
+fn read_library(dir: &Path, name: &str) -> Result<Vec<u8>, VmError> {
+    let candidates: [PathBuf; 2] = [dir.join(name), dir.join(APK_LIBRARY_SUBDIR).join(name)];
+    for candidate in &candidates {
+        if candidate.is_file() {
+            return Ok(fs::read(candidate)?);
+        }
+    }
+    Err(VmError::Io(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{name} not found in {}", dir.display()),
+    )))
 }
 
 pub struct ProvisioningStartResult {
@@ -16,13 +95,35 @@ pub struct ProvisioningStartResult {
     pub session: u32,
 }
 
+#[derive(Debug, Clone)]
 pub struct OtpResult {
     pub otp: Vec<u8>,
     pub machine_id: Vec<u8>,
 }
 
+pub struct SynchronizeResult {
+    pub mid: Vec<u8>,
+    pub srm: Vec<u8>,
+}
+
+/// Stored provisioning state for one DSID, as reported by [`Adi::provisioning_info`].
+#[derive(Debug, Clone, Serialize)]
+pub struct ProvisioningInfo {
+    pub dsid: u64,
+    /// Guest path of the `adi.pb` that was inspected.
+    pub path: String,
+    pub exists: bool,
+    pub size: u64,
+    /// Not every host filesystem records a creation time.
+    pub created: Option<SystemTime>,
+    pub modified: Option<SystemTime>,
+    /// Android ID last passed to [`Adi::set_identifier`], if any.
+    pub identifier: Option<String>,
+}
+
 pub struct Adi {
     core: EmuCore,
+    storeservices_idx: usize,
     p_load_library_with_path: u64,
     p_set_android_id: u64,
     p_set_provisioning_path: u64,
@@ -30,33 +131,73 @@ pub struct Adi {
     p_provisioning_start: u64,
     p_provisioning_end: u64,
     p_otp_request: u64,
+    p_synchronize: u64,
+    p_provisioning_destroy: u64,
+    p_dispose: u64,
+    per_dsid_provisioning: bool,
+    /// Sessions started by `start_provisioning` and not yet ended or destroyed.
+    session_dsids: HashMap<u32, u64>,
+    /// DSIDs seen provisioned during this run (per-DSID mode also scans the disk).
+    known_provisioned: BTreeSet<u64>,
+    recover_corrupted_provisioning: bool,
+    identifier: Option<AdiIdentifier>,
+    otp_cache_ttl: Option<Duration>,
+    otp_cache: HashMap<u64, (Instant, OtpResult)>,
+    metrics: AdiMetrics,
+    /// [`EmuCore::flight_record`] if the last call failed.
+    last_flight_record: Vec<String>,
 }
 
 impl Adi {
     pub fn new(init: AdiInit) -> Result<Self, VmError> {
-        debug_print(format!("Constructing ADI for '{}'", init.library_path));
+        debug_print!(format!("Constructing ADI for '{}'", init.library_path));
+        verify_library(
+            "libstoreservicescore.so",
+            &init.storeservicescore,
+            init.library_check,
+        )?;
+        verify_library("libCoreADI.so", &init.coreadi, init.library_check)?;
+
         let mut core = EmuCore::new_arm64()?;
         core.set_library_root(&init.library_path);
+        if let Some(guest_fs) = init.guest_fs {
+            core.set_guest_fs(guest_fs);
+        }
+        if init.random_seed.is_some() {
+            core.set_random_seed(init.random_seed);
+        }
+        for (name, value) in init.environment {
+            core.set_env_var(name, value);
+        }
+        core.set_serial_number(init.serial_number);
+        core.set_profile(init.profile.as_deref())?;
+        core.set_fault_dump_dir(init.fault_dump_dir);
+        core.set_library_tag(Some(sha256_hex(&init.coreadi)[..16].to_string()));
         core.register_library_blob("libstoreservicescore.so", init.storeservicescore);
         core.register_library_blob("libCoreADI.so", init.coreadi);
 
         let storeservices_idx = core.load_library("libstoreservicescore.so")?;
 
-        debug_print("Loading Android-specific symbols...");
+        debug_print!("Loading Android-specific symbols...");
         let p_load_library_with_path =
             core.resolve_symbol_by_name(storeservices_idx, "kq56gsgHG6")?;
         let p_set_android_id = core.resolve_symbol_by_name(storeservices_idx, "Sph98paBcz")?;
         let p_set_provisioning_path =
             core.resolve_symbol_by_name(storeservices_idx, "nf92ngaK92")?;
 
-        debug_print("Loading ADI symbols...");
+        debug_print!("Loading ADI symbols...");
         let p_get_login_code = core.resolve_symbol_by_name(storeservices_idx, "aslgmuibau")?;
         let p_provisioning_start = core.resolve_symbol_by_name(storeservices_idx, "rsegvyrt87")?;
         let p_provisioning_end = core.resolve_symbol_by_name(storeservices_idx, "uv5t6nhkui")?;
         let p_otp_request = core.resolve_symbol_by_name(storeservices_idx, "qi864985u0")?;
+        let p_synchronize = core.resolve_symbol_by_name(storeservices_idx, "tn46gtiuhw")?;
+        let p_provisioning_destroy =
+            core.resolve_symbol_by_name(storeservices_idx, "fy34trz2st")?;
+        let p_dispose = core.resolve_symbol_by_name(storeservices_idx, "jk24uiwqrg")?;
 
         let mut adi = Self {
             core,
+            storeservices_idx,
             p_load_library_with_path,
             p_set_android_id,
             p_set_provisioning_path,
@@ -64,6 +205,18 @@ impl Adi {
             p_provisioning_start,
             p_provisioning_end,
             p_otp_request,
+            p_synchronize,
+            p_provisioning_destroy,
+            p_dispose,
+            per_dsid_provisioning: init.per_dsid_provisioning,
+            session_dsids: HashMap::new(),
+            known_provisioned: BTreeSet::new(),
+            recover_corrupted_provisioning: init.recover_corrupted_provisioning,
+            identifier: None,
+            otp_cache_ttl: None,
+            otp_cache: HashMap::new(),
+            metrics: AdiMetrics::default(),
+            last_flight_record: Vec::new(),
         };
 
         adi.load_library_with_path(&init.library_path)?;
@@ -79,37 +232,467 @@ impl Adi {
         Ok(adi)
     }
 
-    pub fn set_identifier(&mut self, identifier: &str) -> Result<(), VmError> {
+    /// Builds an `Adi` from a directory containing both native libraries; see
+    /// [`AdiInit::from_library_dir`].
+    pub fn from_library_dir(dir: impl AsRef<Path>) -> Result<Self, VmError> {
+        Self::new(AdiInit::from_library_dir(dir)?)
+    }
+
+    /// Build information for libstoreservicescore.so and libCoreADI.so, in that order.
+    pub fn library_info(&self) -> Result<Vec<LibraryInfo>, VmError> {
+        ["libstoreservicescore.so", "libCoreADI.so"]
+            .into_iter()
+            .filter_map(|name| self.core.library_blob(name).map(|data| (name, data)))
+            .map(|(name, data)| describe_library(name, data))
+            .collect()
+    }
+
+    /// Calls an export of libstoreservicescore.so by (obfuscated) name and returns X0.
+    ///
+    /// Use [`Adi::emu_mut`] to allocate argument buffers and read results back.
+    pub fn call_symbol(&mut self, name: &str, args: &[u64]) -> Result<u64, VmError> {
+        let address = self
+            .core
+            .resolve_symbol_by_name(self.storeservices_idx, name)?;
+        self.core
+            .invoke_cdecl(address, args)
+            .map_err(|err| err.in_call(name))
+    }
+
+    /// Like [`Adi::call_symbol`] for an export of another registered library,
+    /// e.g. `libCoreADI.so`; the library is loaded on first use.
+    pub fn call_symbol_in(
+        &mut self,
+        library: &str,
+        name: &str,
+        args: &[u64],
+    ) -> Result<u64, VmError> {
+        let library_index = self.core.load_library(library)?;
+        let address = self.core.resolve_symbol_by_name(library_index, name)?;
+        self.core
+            .invoke_cdecl(address, args)
+            .map_err(|err| err.in_call(format!("{library}:{name}")))
+    }
+
+    /// The underlying emulator, for guest memory access alongside [`Adi::call_symbol`].
+    pub fn emu_mut(&mut self) -> &mut EmuCore {
+        &mut self.core
+    }
+
+    /// DSIDs with provisioning data. With per-DSID provisioning this lists every
+    /// account that has its own `adi.pb`; otherwise only DSIDs observed provisioned
+    /// by this instance are known.
+    pub fn provisioned_dsids(&mut self) -> Result<Vec<u64>, VmError> {
+        let mut dsids = self.known_provisioned.clone();
+        if self.per_dsid_provisioning {
+            let root = self.core.provisioning_root().to_string();
+            for namespace in provisioned_namespaces(self.core.guest_fs_mut(), &root)? {
+                if let Ok(dsid) = namespace.parse::<i64>() {
+                    dsids.insert(dsid as u64);
+                }
+            }
+        }
+        Ok(dsids.into_iter().collect())
+    }
+
+    /// Bundles every `adi.pb` (including per-DSID copies) and optionally the device
+    /// identity into one checksummed blob, for moving provisioning between machines.
+    pub fn export_state(&mut self, device: Option<&DeviceData>) -> Result<Vec<u8>, VmError> {
+        let root = self.core.provisioning_root().to_string();
+        let state = ProvisioningState {
+            files: collect_state_files(self.core.guest_fs_mut(), &root)?,
+            device: device.cloned(),
+        };
+        state.to_bytes()
+    }
+
+    /// Restores a blob from [`Adi::export_state`] into the guest filesystem and
+    /// returns the device identity it carried, which the caller should persist.
+    pub fn import_state(&mut self, blob: &[u8]) -> Result<Option<DeviceData>, VmError> {
+        let state = ProvisioningState::from_bytes(blob)?;
+        self.restore_provisioning(&state.files)?;
+        Ok(state.device)
+    }
+
+    /// The raw `adi.pb` for `dsid`, or `None` if it has not been provisioned. Lets
+    /// hosts keep provisioning in their own storage instead of the guest filesystem.
+    pub fn provisioning_data(&mut self, dsid: u64) -> Result<Option<Vec<u8>>, VmError> {
+        self.select_dsid_namespace(dsid);
+        let path = self.core.mapped_guest_path(GUEST_ADI_PB_PATH);
+        let _lock = self.lock_provisioning()?;
+        match read_state_file(self.core.guest_fs_mut(), &path) {
+            Ok(data) => Ok(Some(data)),
+            Err(VmError::Io(err)) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Replaces the `adi.pb` for `dsid` with `data` from [`Adi::provisioning_data`].
+    pub fn set_provisioning_data(&mut self, dsid: u64, data: &[u8]) -> Result<(), VmError> {
+        self.select_dsid_namespace(dsid);
+        let path = self.core.mapped_guest_path(GUEST_ADI_PB_PATH);
+        let _lock = self.lock_provisioning()?;
+        let files = BTreeMap::from([(path, data.to_vec())]);
+        self.restore_provisioning(&files)
+    }
+
+    /// Like [`Adi::export_state`], but writes a zip to `path` holding `device.json`,
+    /// the `adi.pb` files and a `manifest.json` with checksums and the library
+    /// builds in use, for backups and moving between servers.
+    pub fn export_bundle(
+        &mut self,
+        device: Option<&DeviceData>,
+        path: impl AsRef<Path>,
+    ) -> Result<(), VmError> {
+        let root = self.core.provisioning_root().to_string();
+        let state = ProvisioningState {
+            files: collect_state_files(self.core.guest_fs_mut(), &root)?,
+            device: device.cloned(),
+        };
+        let bundle = write_bundle(&state, &self.library_info()?)?;
+        write_atomic(path.as_ref(), &bundle)?;
+        Ok(())
+    }
+
+    /// Restores a bundle from [`Adi::export_bundle`] and returns the device identity
+    /// it carried, which the caller should persist. Bundles made with other library
+    /// builds are still imported, with a debug message.
+    pub fn import_bundle(&mut self, path: impl AsRef<Path>) -> Result<Option<DeviceData>, VmError> {
+        let (state, libraries) = read_bundle(&fs::read(path.as_ref())?)?;
+        let current = self.library_info()?;
+        for library in &libraries {
+            let matches = current
+                .iter()
+                .any(|info| info.name == library.name && info.sha256 == library.sha256);
+            if !matches {
+                debug_print!(format!(
+                    "Bundle was made with a different {} ({})",
+                    library.name, library.sha256
+                ));
+            }
+        }
+        self.restore_provisioning(&state.files)?;
+        Ok(state.device)
+    }
+
+    /// Writes restored state files and checksums the `adi.pb`s among them, so a
+    /// checksum from before the restore is not mistaken for corruption. Guest paths
+    /// are moved under the profile's directory, if one is set.
+    fn restore_provisioning(&mut self, files: &BTreeMap<String, Vec<u8>>) -> Result<(), VmError> {
+        let files: BTreeMap<String, Vec<u8>> = files
+            .iter()
+            .map(|(path, data)| (self.core.storage_path(path), data.clone()))
+            .collect();
+        restore_state_files(self.core.guest_fs_mut(), &files)?;
+        let library = self.core.library_tag().map(str::to_string);
+        for path in files.keys().filter(|path| is_adi_pb(path)) {
+            record_checksum(self.core.guest_fs_mut(), path, library.as_deref())?;
+        }
+        self.otp_cache.clear();
+        Ok(())
+    }
+
+    /// When enabled, an ADI call failing because `adi.pb` is corrupted (or an
+    /// `adi.pb` that no longer matches its recorded checksum) moves the file aside
+    /// (`adi.pb.corrupt-<unix time>`) and reports [`VmError::ProvisioningReset`] or
+    /// [`VmError::ProvisioningCorrupted`] (or `false` from `is_machine_provisioned`)
+    /// so the caller re-provisions. When disabled, a checksum mismatch is reported
+    /// as [`VmError::ProvisioningCorrupted`] and the file is left alone.
+    pub fn set_corrupted_provisioning_recovery(&mut self, enabled: bool) {
+        self.recover_corrupted_provisioning = enabled;
+    }
+
+    /// Returns true if the failure was handled by resetting the provisioning data.
+    fn recover_if_corrupted(&mut self, dsid: u64, code: AdiErrorCode) -> Result<bool, VmError> {
+        if !self.recover_corrupted_provisioning || !code.is_corrupted_provisioning() {
+            return Ok(false);
+        }
+
+        self.core.close_guest_files();
+        let _lock = self.lock_provisioning()?;
+        self.move_corrupted_aside(dsid)?;
+        Ok(true)
+    }
+
+    /// Checks the current `adi.pb` against the checksum recorded when the guest
+    /// wrote it, resetting it first if recovery is enabled.
+    fn verify_provisioning(&mut self, dsid: u64) -> Result<(), VmError> {
+        let adi_pb = self.core.mapped_guest_path(GUEST_ADI_PB_PATH);
+        let _lock = self.lock_provisioning()?;
+        let library = self.core.library_tag().map(str::to_string);
+        let Some(reason) = verify_checksum(self.core.guest_fs_mut(), &adi_pb, library.as_deref())?
+        else {
+            return Ok(());
+        };
+        if self.recover_corrupted_provisioning {
+            self.core.close_guest_files();
+            self.move_corrupted_aside(dsid)?;
+        }
+        Err(VmError::ProvisioningCorrupted {
+            path: adi_pb,
+            reason,
+        })
+    }
+
+    /// Expects the provisioning lock to be held.
+    fn move_corrupted_aside(&mut self, dsid: u64) -> Result<(), VmError> {
+        let adi_pb = self.core.mapped_guest_path(GUEST_ADI_PB_PATH);
+        let timestamp = Utc::now().timestamp();
+        let backup = format!("{adi_pb}.corrupt-{timestamp}");
+        debug_print!(format!(
+            "Corrupted provisioning data, moving {adi_pb} to {backup}"
+        ));
+        let guest_fs = self.core.guest_fs_mut();
+        match guest_fs.rename(&adi_pb, &backup) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        match guest_fs.remove_file(&sum_path(&adi_pb)) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        self.known_provisioned.remove(&dsid);
+        self.otp_cache.remove(&dsid);
+        Ok(())
+    }
+
+    /// Reuse the last OTP/MID per DSID for `ttl` instead of calling into the library
+    /// for every request. `None` (the default) disables caching.
+    pub fn set_otp_cache_ttl(&mut self, ttl: Option<Duration>) {
+        self.otp_cache_ttl = ttl;
+        self.otp_cache.clear();
+    }
+
+    pub fn set_per_dsid_provisioning(&mut self, enabled: bool) {
+        self.per_dsid_provisioning = enabled;
+        if !enabled {
+            self.core.set_provisioning_namespace(None);
+        }
+    }
+
+    pub fn set_pthread_options(&mut self, options: PthreadOptions) {
+        self.core.set_pthread_options(options);
+    }
+
+    /// Replaces the time source seen by the guest's time-related imports.
+    pub fn set_clock(&mut self, clock: Box<dyn GuestClock>) {
+        self.core.set_clock(clock);
+    }
+
+    /// Writes a JSON-lines trace of every stubbed import call to `writer`.
+    pub fn set_syscall_trace(&mut self, writer: Option<Box<dyn Write>>) {
+        self.core.set_syscall_trace(writer);
+    }
+
+    /// Replaces the built-in stub for an imported symbol, e.g. a custom `gettimeofday`.
+    pub fn set_stub_override(&mut self, symbol: impl Into<String>, stub: StubOverride) {
+        self.core.set_stub_override(symbol, stub);
+    }
+
+    pub fn clear_stub_override(&mut self, symbol: &str) -> bool {
+        self.core.clear_stub_override(symbol)
+    }
+
+    /// Hands a buffer the library allocated for us back via `ADIDispose`. The data
+    /// has already been copied out, so a failure is only logged.
+    fn dispose(&mut self, ptr: u64) {
+        if ptr == 0 {
+            return;
+        }
+        match self.core.invoke_cdecl(self.p_dispose, &[ptr]) {
+            Ok(ret) => {
+                if let Err(err) = ensure_zero_return("ADIDispose", ret) {
+                    debug_print!(format!("Failed to dispose 0x{ptr:X}: {err}"));
+                }
+            }
+            Err(err) => debug_print!(format!("Failed to dispose 0x{ptr:X}: {err}")),
+        }
+    }
+
+    /// Calls into the library while holding an exclusive lock on the current
+    /// `adi.pb`, so another process sharing the provisioning path cannot interleave
+    /// its writes with ours.
+    fn invoke_locked(
+        &mut self,
+        name: &'static str,
+        detail: fmt::Arguments<'_>,
+        address: u64,
+        args: &[u64],
+    ) -> Result<u64, VmError> {
+        let _lock = self.lock_provisioning()?;
+        self.invoke(name, detail, address, args)
+    }
+
+    /// Calls the ADI entry point `name`, recording it in [`Adi::metrics`]. Errors
+    /// are wrapped with the name and `detail` (e.g. the DSID) for context.
+    fn invoke(
+        &mut self,
+        name: &'static str,
+        detail: fmt::Arguments<'_>,
+        address: u64,
+        args: &[u64],
+    ) -> Result<u64, VmError> {
+        let detail = detail.to_string();
+        let _span = tracing::debug_span!("adi_call", call = name, detail = %detail).entered();
+        let (instructions, allocated) = self.core.counters();
+        self.last_flight_record.clear();
+        let started = Instant::now();
+        let result = self.core.invoke_cdecl(address, args);
+        let duration = started.elapsed();
+        let (instructions_after, allocated_after) = self.core.counters();
+        tracing::debug!(?duration, ok = result.is_ok(), "ADI call finished");
+        self.metrics.record(
+            name,
+            CallSample {
+                duration,
+                instructions: instructions_after - instructions,
+                bytes_allocated: allocated_after - allocated,
+                ok: result.is_ok(),
+            },
+        );
+        if let Err(err) = &result {
+            self.last_flight_record = self.core.flight_record();
+            debug_print!(format!(
+                "{name} failed: {err}\nflight record:\n{}",
+                self.last_flight_record.join("\n")
+            ));
+        }
+        result.map_err(|err| {
+            if detail.is_empty() {
+                err.in_call(name)
+            } else {
+                err.in_call(format!("{name}({detail})"))
+            }
+        })
+    }
+
+    /// Call counts, timings and emulator counters per ADI entry point.
+    pub fn metrics(&self) -> &AdiMetrics {
+        &self.metrics
+    }
+
+    /// Hit counts and time spent per import stub, to see which libc calls
+    /// dominate emulation cost.
+    pub fn stub_stats(&self) -> &StubStats {
+        self.core.stub_stats()
+    }
+
+    /// Clears both [`Adi::metrics`] and [`Adi::stub_stats`].
+    pub fn reset_metrics(&mut self) {
+        self.metrics = AdiMetrics::default();
+        self.core.reset_stub_stats();
+    }
+
+    /// Stub calls, allocations and `emu_start` transitions leading up to the
+    /// failure of the last ADI call; empty if it succeeded.
+    pub fn last_flight_record(&self) -> &[String] {
+        &self.last_flight_record
+    }
+
+    pub(crate) fn take_flight_record(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.last_flight_record)
+    }
+
+    /// Whether [`CallMetrics::instructions`](crate::CallMetrics::instructions) is
+    /// counted; see [`EmuCore::set_instruction_counting`].
+    pub fn set_instruction_counting(&mut self, enabled: bool) -> Result<(), VmError> {
+        self.core.set_instruction_counting(enabled)
+    }
+
+    fn lock_provisioning(&self) -> Result<Option<FileLock>, VmError> {
+        let adi_pb = self.core.mapped_guest_path(GUEST_ADI_PB_PATH);
+        let Some(path) = self.core.host_path(&adi_pb) else {
+            return Ok(None);
+        };
+        // The library creates the directory itself on first provisioning.
+        if !path.parent().is_some_and(Path::is_dir) {
+            return Ok(None);
+        }
+        Ok(Some(FileLock::exclusive(&path)?))
+    }
+
+    fn select_dsid_namespace(&mut self, dsid: u64) {
+        if self.per_dsid_provisioning {
+            self.core
+                .set_provisioning_namespace(Some((dsid as i64).to_string()));
+        }
+    }
+
+    /// Sets the Android ID; accepts a 16-character hex string, 8 raw bytes, or a `Uuid`.
+    pub fn set_identifier<I>(&mut self, identifier: I) -> Result<(), VmError>
+    where
+        I: TryInto<AdiIdentifier>,
+        VmError: From<I::Error>,
+    {
+        let identifier = identifier.try_into()?;
         if identifier.is_empty() {
-            debug_print("Skipping empty identifier");
+            debug_print!("Skipping empty identifier");
             return Ok(());
         }
-        debug_print(format!("Setting identifier {identifier}"));
-        let bytes = identifier.as_bytes();
+        debug_print!(format!("Setting identifier {identifier}"));
+        let bytes = identifier.as_str().as_bytes();
         let p_identifier = self.core.alloc_data(bytes)?;
-        let ret = self
-            .core
-            .invoke_cdecl(self.p_set_android_id, &[p_identifier, bytes.len() as u64])?;
-        debug_print(format!(
+        let ret = self.invoke(
+            "ADISetAndroidID",
+            format_args!(""),
+            self.p_set_android_id,
+            &[p_identifier, bytes.len() as u64],
+        )?;
+        debug_print!(format!(
             "{}: {:X}={}",
             "pADISetAndroidID", ret, ret as u32 as i32
         ));
-        ensure_zero_return("ADISetAndroidID", ret)
+        ensure_zero_return("ADISetAndroidID", ret)?;
+        self.identifier = Some(identifier);
+        Ok(())
+    }
+
+    /// Looks at the stored `adi.pb` for `dsid` without calling into the library.
+    pub fn provisioning_info(&mut self, dsid: u64) -> Result<ProvisioningInfo, VmError> {
+        self.select_dsid_namespace(dsid);
+        let path = self.core.mapped_guest_path(GUEST_ADI_PB_PATH);
+        let metadata = match self.core.guest_fs_mut().metadata(&path) {
+            Ok(metadata) => Some(metadata),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(ProvisioningInfo {
+            dsid,
+            path,
+            exists: metadata.is_some(),
+            size: metadata.map_or(0, |metadata| metadata.size),
+            created: metadata.and_then(|metadata| metadata.created),
+            modified: metadata.and_then(|metadata| metadata.modified),
+            identifier: self
+                .identifier
+                .as_ref()
+                .map(|identifier| identifier.as_str().to_string()),
+        })
     }
 
     pub fn set_provisioning_path(&mut self, path: &str) -> Result<(), VmError> {
         let p_path = alloc_c_string(&mut self.core, path)?;
-        let ret = self
-            .core
-            .invoke_cdecl(self.p_set_provisioning_path, &[p_path])?;
+        let ret = self.invoke(
+            "ADISetProvisioningPath",
+            format_args!("{path}"),
+            self.p_set_provisioning_path,
+            &[p_path],
+        )?;
         ensure_zero_return("ADISetProvisioningPath", ret)
     }
 
     pub fn load_library_with_path(&mut self, path: &str) -> Result<(), VmError> {
         let p_path = alloc_c_string(&mut self.core, path)?;
-        let ret = self
-            .core
-            .invoke_cdecl(self.p_load_library_with_path, &[p_path])?;
+        let ret = self.invoke(
+            "ADILoadLibraryWithPath",
+            format_args!("{path}"),
+            self.p_load_library_with_path,
+            &[p_path],
+        )?;
         ensure_zero_return("ADILoadLibraryWithPath", ret)
     }
     pub fn start_provisioning(
@@ -117,18 +700,25 @@ impl Adi {
         dsid: u64,
         server_provisioning_intermediate_metadata: &[u8],
     ) -> Result<ProvisioningStartResult, VmError> {
-        debug_print("ADI.start_provisioning");
-        let p_cpim = self.core.alloc_temporary(8)?;
-        let p_cpim_len = self.core.alloc_temporary(4)?;
-        let p_session = self.core.alloc_temporary(4)?;
+        debug_print!("ADI.start_provisioning");
+        self.select_dsid_namespace(dsid);
+        self.core.reset_scratch();
+        let p_cpim = self.core.alloc_scratch(8)?;
+        let p_cpim_len = self.core.alloc_scratch(4)?;
+        let p_session = self.core.alloc_scratch(4)?;
         let p_spim = self
             .core
-            .alloc_data(server_provisioning_intermediate_metadata)?;
+            .alloc_scratch_data(server_provisioning_intermediate_metadata)?;
 
-        debug_print(format!("0x{dsid:X}"));
-        debug_print(bytes_to_hex(server_provisioning_intermediate_metadata));
+        debug_print!(format!("0x{dsid:X}"));
+        debug_print!(format!(
+            "spim {}",
+            redacted(server_provisioning_intermediate_metadata)
+        ));
 
-        let ret = self.core.invoke_cdecl(
+        let ret = self.invoke_locked(
+            "ADIProvisioningStart",
+            format_args!("dsid={}", dsid as i64),
             self.p_provisioning_start,
             &[
                 dsid,
@@ -139,7 +729,7 @@ impl Adi {
                 p_session,
             ],
         )?;
-        debug_print(format!(
+        debug_print!(format!(
             "{}: {:X}={}",
             "pADIProvisioningStart", ret, ret as u32 as i32
         ));
@@ -149,32 +739,49 @@ impl Adi {
         let cpim_len = self.core.read_u32(p_cpim_len)? as usize;
         let cpim = self.core.read_data(cpim_ptr, cpim_len)?;
         let session = self.core.read_u32(p_session)?;
+        self.dispose(cpim_ptr);
 
-        debug_print(format!("Wrote data to 0x{cpim_ptr:X}"));
-        debug_print(format!("{} {} {}", cpim_len, bytes_to_hex(&cpim), session));
+        debug_print!(format!("Wrote data to 0x{cpim_ptr:X}"));
+        debug_print!(format!("cpim {} session {session}", redacted(&cpim)));
+        self.session_dsids.insert(session, dsid);
 
         Ok(ProvisioningStartResult { cpim, session })
     }
 
     pub fn is_machine_provisioned(&mut self, dsid: u64) -> Result<bool, VmError> {
-        debug_print("ADI.is_machine_provisioned");
-        let ret = self.core.invoke_cdecl(self.p_get_login_code, &[dsid])?;
+        debug_print!("ADI.is_machine_provisioned");
+        self.select_dsid_namespace(dsid);
+        match self.verify_provisioning(dsid) {
+            Err(VmError::ProvisioningCorrupted { .. }) if self.recover_corrupted_provisioning => {
+                return Ok(false);
+            }
+            result => result?,
+        }
+        let ret = self.invoke_locked(
+            "ADIGetLoginCode",
+            format_args!("dsid={}", dsid as i64),
+            self.p_get_login_code,
+            &[dsid],
+        )?;
         let code = ret as u32 as i32;
 
         if code == 0 {
+            self.known_provisioned.insert(dsid);
             return Ok(true);
         }
-        if code == -45061 {
+        let error_code = AdiErrorCode::from_raw(code);
+        if error_code.is_not_provisioned() || self.recover_if_corrupted(dsid, error_code)? {
+            self.known_provisioned.remove(&dsid);
             return Ok(false);
         }
 
-        debug_print(format!(
+        debug_print!(format!(
             "Unknown errorCode in is_machine_provisioned: {code}=0x{code:X}"
         ));
 
         Err(VmError::AdiCallFailed {
             name: "ADIGetLoginCode",
-            code,
+            code: error_code,
         })
     }
 
@@ -184,10 +791,16 @@ impl Adi {
         persistent_token_metadata: &[u8],
         trust_key: &[u8],
     ) -> Result<(), VmError> {
-        let p_ptm = self.core.alloc_data(persistent_token_metadata)?;
-        let p_tk = self.core.alloc_data(trust_key)?;
+        if let Some(&dsid) = self.session_dsids.get(&session) {
+            self.select_dsid_namespace(dsid);
+        }
+        self.core.reset_scratch();
+        let p_ptm = self.core.alloc_scratch_data(persistent_token_metadata)?;
+        let p_tk = self.core.alloc_scratch_data(trust_key)?;
 
-        let ret = self.core.invoke_cdecl(
+        let ret = self.invoke_locked(
+            "ADIProvisioningEnd",
+            format_args!("session={session}"),
             self.p_provisioning_end,
             &[
                 session as u64,
@@ -198,46 +811,313 @@ impl Adi {
             ],
         )?;
 
-        debug_print(format!("0x{session:X}"));
-        debug_print(format!(
-            "{} {}",
-            bytes_to_hex(persistent_token_metadata),
-            persistent_token_metadata.len()
-        ));
-        debug_print(format!("{} {}", bytes_to_hex(trust_key), trust_key.len()));
-        debug_print(format!(
+        debug_print!(format!("0x{session:X}"));
+        debug_print!(format!("ptm {}", redacted(persistent_token_metadata)));
+        debug_print!(format!("tk {}", redacted(trust_key)));
+        debug_print!(format!(
             "{}: {:X}={}",
             "pADIProvisioningEnd", ret, ret as u32 as i32
         ));
 
-        ensure_zero_return("ADIProvisioningEnd", ret)
+        ensure_zero_return("ADIProvisioningEnd", ret)?;
+        if let Some(dsid) = self.session_dsids.remove(&session) {
+            self.known_provisioned.insert(dsid);
+        }
+        self.otp_cache.clear();
+        Ok(())
+    }
+
+    /// Releases a session from `start_provisioning` that will never be ended,
+    /// e.g. because the network round-trip to Apple failed.
+    pub fn abort_provisioning(&mut self, session: u32) -> Result<(), VmError> {
+        debug_print!("ADI.abort_provisioning");
+        if let Some(dsid) = self.session_dsids.remove(&session) {
+            self.select_dsid_namespace(dsid);
+        }
+        let ret = self.invoke_locked(
+            "ADIProvisioningDestroy",
+            format_args!("session={session}"),
+            self.p_provisioning_destroy,
+            &[session as u64],
+        )?;
+        debug_print!(format!(
+            "{}: {:X}={}",
+            "pADIProvisioningDestroy", ret, ret as u32 as i32
+        ));
+        ensure_zero_return("ADIProvisioningDestroy", ret)
     }
 
     pub fn request_otp(&mut self, dsid: u64) -> Result<OtpResult, VmError> {
-        debug_print("ADI.request_otp");
-        let p_otp = self.core.alloc_temporary(8)?;
-        let p_otp_len = self.core.alloc_temporary(4)?;
-        let p_mid = self.core.alloc_temporary(8)?;
-        let p_mid_len = self.core.alloc_temporary(4)?;
+        let Some(ttl) = self.otp_cache_ttl else {
+            return self.request_fresh_otp(dsid);
+        };
+        if let Some((created, otp)) = self.otp_cache.get(&dsid)
+            && created.elapsed() < ttl
+        {
+            debug_print!("ADI.request_otp (cached)");
+            return Ok(otp.clone());
+        }
 
-        let ret = self.core.invoke_cdecl(
+        let otp = self.request_fresh_otp(dsid)?;
+        self.otp_cache.insert(dsid, (Instant::now(), otp.clone()));
+        Ok(otp)
+    }
+
+    /// Like [`Adi::request_otp`], but copies the OTP and machine ID straight from
+    /// guest memory into `otp_buf` and `mid_buf` and returns their lengths. Fails
+    /// with [`VmError::BufferTooSmall`] if either does not fit.
+    pub fn request_otp_into(
+        &mut self,
+        dsid: u64,
+        otp_buf: &mut [u8],
+        mid_buf: &mut [u8],
+    ) -> Result<(usize, usize), VmError> {
+        if self.otp_cache_ttl.is_some() {
+            let otp = self.request_otp(dsid)?;
+            output_slice("OTP", otp_buf, otp.otp.len())?.copy_from_slice(&otp.otp);
+            output_slice("machine ID", mid_buf, otp.machine_id.len())?
+                .copy_from_slice(&otp.machine_id);
+            return Ok((otp.otp.len(), otp.machine_id.len()));
+        }
+
+        self.request_otp_with(dsid, |core, (otp_ptr, otp_len), (mid_ptr, mid_len)| {
+            core.read_into(otp_ptr, output_slice("OTP", otp_buf, otp_len)?)?;
+            core.read_into(mid_ptr, output_slice("machine ID", mid_buf, mid_len)?)?;
+            Ok((otp_len, mid_len))
+        })
+    }
+
+    fn request_fresh_otp(&mut self, dsid: u64) -> Result<OtpResult, VmError> {
+        self.request_otp_with(dsid, |core, (otp_ptr, otp_len), (mid_ptr, mid_len)| {
+            Ok(OtpResult {
+                otp: core.read_data(otp_ptr, otp_len)?,
+                machine_id: core.read_data(mid_ptr, mid_len)?,
+            })
+        })
+    }
+
+    /// Runs `ADIOTPRequest` and passes the OTP and machine ID the library returned,
+    /// as (address, length), to `read` before disposing of them.
+    fn request_otp_with<T>(
+        &mut self,
+        dsid: u64,
+        read: impl FnOnce(&EmuCore, (u64, usize), (u64, usize)) -> Result<T, VmError>,
+    ) -> Result<T, VmError> {
+        debug_print!("ADI.request_otp");
+        self.select_dsid_namespace(dsid);
+        self.verify_provisioning(dsid)?;
+        self.core.reset_scratch();
+        let p_otp = self.core.alloc_scratch(8)?;
+        let p_otp_len = self.core.alloc_scratch(4)?;
+        let p_mid = self.core.alloc_scratch(8)?;
+        let p_mid_len = self.core.alloc_scratch(4)?;
+
+        let ret = self.invoke_locked(
+            "ADIOTPRequest",
+            format_args!("dsid={}", dsid as i64),
             self.p_otp_request,
             &[dsid, p_mid, p_mid_len, p_otp, p_otp_len],
         )?;
-        debug_print(format!(
+        debug_print!(format!(
             "{}: {:X}={}",
             "pADIOTPRequest", ret, ret as u32 as i32
         ));
-        ensure_zero_return("ADIOTPRequest", ret)?;
+        if let Err(err) = ensure_zero_return("ADIOTPRequest", ret) {
+            if let VmError::AdiCallFailed { name, code } = err
+                && self.recover_if_corrupted(dsid, code)?
+            {
+                return Err(VmError::ProvisioningReset { name, code });
+            }
+            return Err(err);
+        }
 
         let otp_ptr = self.core.read_u64(p_otp)?;
         let otp_len = self.core.read_u32(p_otp_len)? as usize;
-        let otp = self.core.read_data(otp_ptr, otp_len)?;
+        let mid_ptr = self.core.read_u64(p_mid)?;
+        let mid_len = self.core.read_u32(p_mid_len)? as usize;
+        let result = read(&self.core, (otp_ptr, otp_len), (mid_ptr, mid_len));
+
+        self.dispose(otp_ptr);
+        self.dispose(mid_ptr);
+        result
+    }
+
+    /// Runs `provision` (typically a `ProvisioningSession`) if the machine is not
+    /// provisioned for `dsid`. Returns whether provisioning was performed.
+    pub fn ensure_provisioned<E>(
+        &mut self,
+        dsid: u64,
+        mut provision: impl FnMut(&mut Adi, u64) -> Result<(), E>,
+    ) -> Result<bool, E>
+    where
+        E: From<VmError>,
+    {
+        if self.is_machine_provisioned(dsid)? {
+            return Ok(false);
+        }
+        provision(self, dsid)?;
+        Ok(true)
+    }
+
+    /// Like [`Adi::request_otp`], but provisions first when needed and retries once
+    /// after re-provisioning if the library reports the machine as not provisioned
+    /// (or its provisioning data was reset as corrupted).
+    pub fn request_otp_provisioned<E>(
+        &mut self,
+        dsid: u64,
+        mut provision: impl FnMut(&mut Adi, u64) -> Result<(), E>,
+    ) -> Result<OtpResult, E>
+    where
+        E: From<VmError>,
+    {
+        let provisioned_now = self.ensure_provisioned(dsid, &mut provision)?;
+        match self.request_otp(dsid) {
+            Err(VmError::AdiCallFailed { code, .. })
+                if code.is_not_provisioned() && !provisioned_now =>
+            {
+                debug_print!("OTP request reported not provisioned; re-provisioning");
+                provision(self, dsid)?;
+                Ok(self.request_otp(dsid)?)
+            }
+            Err(VmError::ProvisioningReset { .. }) => {
+                debug_print!("Provisioning data was reset; re-provisioning");
+                provision(self, dsid)?;
+                Ok(self.request_otp(dsid)?)
+            }
+            Err(VmError::ProvisioningCorrupted { .. }) if self.recover_corrupted_provisioning => {
+                debug_print!("Provisioning data was corrupted and reset; re-provisioning");
+                provision(self, dsid)?;
+                Ok(self.request_otp(dsid)?)
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Requests an OTP and assembles the full anisette v3 header set for `device`.
+    pub fn get_anisette_headers(
+        &mut self,
+        dsid: u64,
+        device: &DeviceData,
+    ) -> Result<HashMap<String, String>, VmError> {
+        let otp = self.request_otp(dsid)?;
+        Ok(anisette_header_map(
+            STANDARD.encode(&otp.otp),
+            STANDARD.encode(&otp.machine_id),
+            device,
+        ))
+    }
+
+    /// Re-synchronizes machine data with the SIM blob from GrandSlam's `midSync`
+    /// endpoint; the returned MID and SRM are sent back to complete the sync.
+    pub fn synchronize(&mut self, dsid: u64, sim: &[u8]) -> Result<SynchronizeResult, VmError> {
+        debug_print!("ADI.synchronize");
+        self.select_dsid_namespace(dsid);
+        self.core.reset_scratch();
+        let p_sim = self.core.alloc_scratch_data(sim)?;
+        let p_mid = self.core.alloc_scratch(8)?;
+        let p_mid_len = self.core.alloc_scratch(4)?;
+        let p_srm = self.core.alloc_scratch(8)?;
+        let p_srm_len = self.core.alloc_scratch(4)?;
+
+        let ret = self.invoke_locked(
+            "ADISynchronize",
+            format_args!("dsid={}", dsid as i64),
+            self.p_synchronize,
+            &[
+                dsid,
+                p_sim,
+                sim.len() as u64,
+                p_mid,
+                p_mid_len,
+                p_srm,
+                p_srm_len,
+            ],
+        )?;
+        debug_print!(format!(
+            "{}: {:X}={}",
+            "pADISynchronize", ret, ret as u32 as i32
+        ));
+        ensure_zero_return("ADISynchronize", ret)?;
+        self.otp_cache.remove(&dsid);
 
         let mid_ptr = self.core.read_u64(p_mid)?;
         let mid_len = self.core.read_u32(p_mid_len)? as usize;
-        let machine_id = self.core.read_data(mid_ptr, mid_len)?;
+        let mid = self.core.read_data(mid_ptr, mid_len)?;
+
+        let srm_ptr = self.core.read_u64(p_srm)?;
+        let srm_len = self.core.read_u32(p_srm_len)? as usize;
+        let srm = self.core.read_data(srm_ptr, srm_len)?;
+
+        self.dispose(mid_ptr);
+        self.dispose(srm_ptr);
+        Ok(SynchronizeResult { mid, srm })
+    }
+}
 
-        Ok(OtpResult { otp, machine_id })
+impl Drop for Adi {
+    fn drop(&mut self) {
+        let sessions: Vec<u32> = self.session_dsids.keys().copied().collect();
+        for session in sessions {
+            if let Err(err) = self.abort_provisioning(session) {
+                debug_print!(format!(
+                    "Failed to destroy provisioning session {session}: {err}"
+                ));
+            }
+        }
     }
 }
+
+/// The first `needed` bytes of a caller's output buffer.
+fn output_slice<'a>(
+    what: &'static str,
+    buffer: &'a mut [u8],
+    needed: usize,
+) -> Result<&'a mut [u8], VmError> {
+    let capacity = buffer.len();
+    buffer.get_mut(..needed).ok_or(VmError::BufferTooSmall {
+        what,
+        needed,
+        capacity,
+    })
+}
+
+/// The v3 header set around an already base64-encoded OTP and machine ID.
+pub(crate) fn anisette_header_map(
+    otp: String,
+    machine_id: String,
+    device: &DeviceData,
+) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    headers.insert("X-Apple-I-MD".to_string(), otp);
+    headers.insert("X-Apple-I-MD-M".to_string(), machine_id);
+    headers.insert(
+        "X-Apple-I-MD-LU".to_string(),
+        device.local_user_uuid.clone(),
+    );
+    headers.insert(
+        "X-Apple-I-MD-RINFO".to_string(),
+        device.routing_info().to_string(),
+    );
+    headers.insert(
+        "X-Apple-I-Client-Time".to_string(),
+        Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+    );
+    headers.insert(
+        "X-Apple-I-TimeZone".to_string(),
+        device.time_zone().to_string(),
+    );
+    headers.insert("X-Apple-Locale".to_string(), device.locale().to_string());
+    headers.insert(
+        "X-Apple-I-SRL-NO".to_string(),
+        device.serial_number().to_string(),
+    );
+    headers.insert(
+        "X-Mme-Device-Id".to_string(),
+        device.unique_device_identifier.clone(),
+    );
+    headers.insert(
+        "X-MMe-Client-Info".to_string(),
+        device.server_friendly_description.clone(),
+    );
+    headers
+}
@@ -32,6 +32,16 @@ impl Allocator {
         self.offset = next;
         Ok(address)
     }
+
+    /// Bytes handed out so far, page-rounded.
+    pub fn used(&self) -> u64 {
+        self.offset
+    }
+
+    /// Makes the whole region available again; earlier addresses become stale.
+    pub fn reset(&mut self) {
+        self.offset = 0;
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,111 @@
+use std::io::{Cursor, Read};
+
+use anyhow::{Context, Result, bail};
+use zip::ZipArchive;
+use zip::result::ZipError;
+
+use crate::AdiInit;
+
+const STORESERVICESCORE_ENTRY: &str = "lib/arm64-v8a/libstoreservicescore.so";
+const COREADI_ENTRY: &str = "lib/arm64-v8a/libCoreADI.so";
+
+/// The two arm64-v8a native libraries the emulator needs, as found in an APK.
+#[derive(Debug, Clone)]
+pub struct ApkLibraries {
+    pub storeservicescore: Vec<u8>,
+    pub coreadi: Vec<u8>,
+}
+
+impl ApkLibraries {
+    /// Extracts the libraries from an Apple Music `.apk`.
+    pub fn from_apk(apk: &[u8]) -> Result<Self> {
+        Self::from_split_apks(&[apk])
+    }
+
+    /// Extracts the libraries from a set of split APKs (base plus
+    /// `split_config.arm64_v8a.apk`, in any order).
+    pub fn from_split_apks(apks: &[&[u8]]) -> Result<Self> {
+        let mut storeservicescore = None;
+        let mut coreadi = None;
+
+        for (index, apk) in apks.iter().enumerate() {
+            let mut archive = ZipArchive::new(Cursor::new(*apk))
+                .with_context(|| format!("APK #{index} is not a valid zip archive"))?;
+            if storeservicescore.is_none() {
+                storeservicescore = read_entry(&mut archive, STORESERVICESCORE_ENTRY)?;
+            }
+            if coreadi.is_none() {
+                coreadi = read_entry(&mut archive, COREADI_ENTRY)?;
+            }
+        }
+
+        match (storeservicescore, coreadi) {
+            (Some(storeservicescore), Some(coreadi)) => Ok(Self {
+                storeservicescore,
+                coreadi,
+            }),
+            (None, _) => bail!("no APK contains {STORESERVICESCORE_ENTRY}"),
+            (_, None) => bail!("no APK contains {COREADI_ENTRY}"),
+        }
+    }
+
+    /// Starts an [`AdiInit`] with these libraries; fill in the remaining fields as usual.
+    pub fn into_adi_init(self, library_path: impl Into<String>) -> AdiInit {
+        AdiInit {
+            storeservicescore: self.storeservicescore,
+            coreadi: self.coreadi,
+            library_path: library_path.into(),
+            ..Default::default()
+        }
+    }
+}
+
+fn read_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Option<Vec<u8>>> {
+    let mut entry = match archive.by_name(name) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("failed to open {name}")),
+    };
+
+    let mut data = Vec::with_capacity(entry.size() as usize);
+    entry
+        .read_to_end(&mut data)
+        .with_context(|| format!("failed to extract {name}"))?;
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::ZipWriter;
+    use zip::write::SimpleFileOptions;
+
+    use super::{ApkLibraries, COREADI_ENTRY, STORESERVICESCORE_ENTRY};
+
+    fn build_apk(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in entries {
+            writer
+                .start_file(*name, SimpleFileOptions::default())
+                .expect("start entry");
+            writer.write_all(data).expect("write entry");
+        }
+        writer.finish().expect("finish zip").into_inner()
+    }
+
+    #[test]
+    fn extracts_libraries_from_split_apks() {
+        let base = build_apk(&[
+            ("AndroidManifest.xml", b"manifest"),
+            (COREADI_ENTRY, b"coreadi"),
+        ]);
+        let split = build_apk(&[(STORESERVICESCORE_ENTRY, b"storeservices")]);
+
+        let libraries = ApkLibraries::from_split_apks(&[&base, &split]).expect("extract");
+        assert_eq!(libraries.storeservicescore, b"storeservices");
+        assert_eq!(libraries.coreadi, b"coreadi");
+
+        assert!(ApkLibraries::from_apk(&base).is_err());
+    }
+}
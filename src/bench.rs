@@ -0,0 +1,139 @@
+//! Harness for the Criterion benches in `benches/` (feature `bench`): a
+//! reproducible device and `Adi` setup, so provisioning recordings replay, the
+//! GSA exchange on its own, and the guest counters behind
+//! [`CallMetrics`](crate::CallMetrics).
+
+use std::env;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Result;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+use crate::adi::{Adi, AdiInit};
+use crate::clock::FixedClock;
+use crate::device::{DeviceData, DevicePreset};
+use crate::emu::EmuCore;
+use crate::errors::VmError;
+use crate::provisioning::{ProvisioningSession, ReqwestTransport};
+use crate::provisioning_protocol::{self, FinishProvisioning, SessionState};
+use crate::recording::RecordingTransport;
+use crate::transport::{HttpOptions, HttpTransport};
+use crate::vfs::MemoryFs;
+
+pub const BENCH_DSID: u64 = -2_i64 as u64;
+const BENCH_SEED: u64 = 0x616E_6973_6574_7465;
+/// 2024-01-01T00:00:00Z. The guest clock is frozen here so every run sends GSA
+/// the same provisioning request.
+const BENCH_TIME: Duration = Duration::from_secs(1_704_067_200);
+const BENCH_LIBRARY_PATH: &str = "./anisette/";
+
+/// Both native libraries, read once and cloned into each iteration.
+#[derive(Clone)]
+pub struct BenchLibraries {
+    pub storeservicescore: Vec<u8>,
+    pub coreadi: Vec<u8>,
+}
+
+impl BenchLibraries {
+    /// Reads the libraries from the directory in `ANISETTE_BENCH_LIBS`; `None`
+    /// when it is not set.
+    pub fn from_env() -> Result<Option<Self>, VmError> {
+        let Some(dir) = env::var_os("ANISETTE_BENCH_LIBS") else {
+            return Ok(None);
+        };
+        let init = AdiInit::from_library_dir(dir)?;
+        Ok(Some(Self {
+            storeservicescore: init.storeservicescore,
+            coreadi: init.coreadi,
+        }))
+    }
+
+    /// Maps and relocates both libraries into a fresh emulator, without calling
+    /// into them.
+    pub fn into_emu(self) -> Result<EmuCore, VmError> {
+        let mut core = EmuCore::new_arm64()?;
+        core.set_library_root(BENCH_LIBRARY_PATH);
+        core.register_library_blob("libstoreservicescore.so", self.storeservicescore);
+        core.register_library_blob("libCoreADI.so", self.coreadi);
+        core.load_library("libstoreservicescore.so")?;
+        core.load_library("libCoreADI.so")?;
+        Ok(core)
+    }
+
+    /// An unprovisioned `Adi` for [`device`], on an in-memory filesystem with a
+    /// seeded random source and a frozen clock.
+    pub fn into_adi(self) -> Result<Adi, VmError> {
+        let device = device();
+        let mut adi = Adi::new(AdiInit {
+            storeservicescore: self.storeservicescore,
+            coreadi: self.coreadi,
+            library_path: BENCH_LIBRARY_PATH.to_string(),
+            provisioning_path: Some(BENCH_LIBRARY_PATH.to_string()),
+            identifier: Some(device.adi_identifier),
+            guest_fs: Some(Box::new(MemoryFs::new())),
+            serial_number: device.serial_number,
+            random_seed: Some(BENCH_SEED),
+            ..Default::default()
+        })?;
+        adi.set_clock(Box::new(FixedClock::at_unix(BENCH_TIME)));
+        Ok(adi)
+    }
+}
+
+/// The device every bench `Adi` impersonates.
+pub fn device() -> DeviceData {
+    DeviceData::preset_with_rng(
+        DevicePreset::MacBookPro13_2,
+        &mut StdRng::seed_from_u64(BENCH_SEED),
+    )
+}
+
+/// Provisions [`BENCH_DSID`] as [`device`] over `transport`, e.g. a
+/// [`ReplayTransport`](crate::ReplayTransport) of [`record_provisioning`].
+pub fn provision(adi: &mut Adi, transport: impl HttpTransport) -> Result<()> {
+    let device = device();
+    ProvisioningSession::with_transport(adi, &device, transport).provision(BENCH_DSID)
+}
+
+/// Runs lookup, start and finish as [`device`] over `transport`, answering the
+/// `spim` with a fixed `cpim` instead of calling ADI. Needs neither libraries nor
+/// GSA when `transport` replays `benches/fixtures/gsa-canned.json`.
+pub fn gsa_exchange(mut transport: impl HttpTransport) -> Result<FinishProvisioning> {
+    let device = device();
+    let mut state = SessionState::default();
+    let ((), finish) =
+        provisioning_protocol::exchange(&device, &mut transport, &mut state, |spim| {
+            Ok((spim.to_vec(), ()))
+        })?;
+    Ok(finish)
+}
+
+/// Provisions a fresh bench `Adi` against GSA and saves the exchange to `path`.
+/// The recording only replays against the same libraries.
+pub fn record_provisioning(libraries: BenchLibraries, path: impl AsRef<Path>) -> Result<()> {
+    let mut adi = libraries.into_adi()?;
+    let transport = ReqwestTransport::new(&HttpOptions::default())?;
+    provision(
+        &mut adi,
+        RecordingTransport::new(transport, path.as_ref().to_path_buf()),
+    )
+}
+
+/// Guest work done by an `Adi` since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GuestCounters {
+    /// Only counted while [`Adi::set_instruction_counting`] is on.
+    pub instructions: u64,
+    /// Page-rounded, across the temporary and `malloc` arenas.
+    pub bytes_allocated: u64,
+}
+
+pub fn guest_counters(adi: &mut Adi) -> GuestCounters {
+    let (instructions, bytes_allocated) = adi.emu_mut().counters();
+    GuestCounters {
+        instructions,
+        bytes_allocated,
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::BTreeMap;
+use std::io::{Cursor, Read, Write};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::device::DeviceData;
+use crate::errors::VmError;
+use crate::library::{LibraryInfo, sha256_hex};
+use crate::state::{ProvisioningState, is_state_file};
+
+const BUNDLE_FORMAT: &str = "anisette-bundle";
+const BUNDLE_VERSION: u32 = 1;
+const MANIFEST_ENTRY: &str = "manifest.json";
+const DEVICE_ENTRY: &str = "device.json";
+
+/// `manifest.json` of a bundle. Provisioning files are stored under their guest
+/// path without the leading `./` (e.g. `anisette/adi.pb`).
+#[derive(Serialize, Deserialize)]
+struct BundleManifest {
+    format: String,
+    version: u32,
+    /// Version of this crate that wrote the bundle.
+    crate_version: String,
+    created: i64,
+    /// The libraries that produced the provisioning data.
+    libraries: Vec<BundleLibrary>,
+    /// SHA-256 of every other entry, keyed by entry name.
+    files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BundleLibrary {
+    pub(crate) name: String,
+    soname: Option<String>,
+    build_id: Option<String>,
+    pub(crate) sha256: String,
+    version: Option<String>,
+}
+
+impl From<&LibraryInfo> for BundleLibrary {
+    fn from(info: &LibraryInfo) -> Self {
+        Self {
+            name: info.name.clone(),
+            soname: info.soname.clone(),
+            build_id: info.build_id.clone(),
+            sha256: info.sha256.clone(),
+            version: info.version.map(str::to_string),
+        }
+    }
+}
+
+/// Packs `state` into a zip of `device.json`, the `adi.pb` files and a manifest.
+pub(crate) fn write_bundle(
+    state: &ProvisioningState,
+    libraries: &[LibraryInfo],
+) -> Result<Vec<u8>, VmError> {
+    let mut entries = BTreeMap::new();
+    if let Some(device) = &state.device {
+        let json = serde_json::to_vec_pretty(device).map_err(invalid_bundle)?;
+        entries.insert(DEVICE_ENTRY.to_string(), json);
+    }
+    for (path, data) in &state.files {
+        let name = path.strip_prefix("./").unwrap_or(path);
+        entries.insert(name.to_string(), data.clone());
+    }
+
+    let manifest = BundleManifest {
+        format: BUNDLE_FORMAT.to_string(),
+        version: BUNDLE_VERSION,
+        crate_version: env!("CARGO_PKG_VERSION").to_string(),
+        created: Utc::now().timestamp(),
+        libraries: libraries.iter().map(BundleLibrary::from).collect(),
+        files: entries
+            .iter()
+            .map(|(name, data)| (name.clone(), sha256_hex(data)))
+            .collect(),
+    };
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(invalid_bundle)?;
+
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    writer
+        .start_file(MANIFEST_ENTRY, options)
+        .map_err(invalid_bundle)?;
+    writer.write_all(&manifest)?;
+    for (name, data) in &entries {
+        writer.start_file(name, options).map_err(invalid_bundle)?;
+        writer.write_all(data)?;
+    }
+    Ok(writer.finish().map_err(invalid_bundle)?.into_inner())
+}
+
+/// Unpacks a bundle from [`write_bundle`], checking every entry against the
+/// manifest. Also returns the libraries the bundle was made with.
+pub(crate) fn read_bundle(
+    bytes: &[u8],
+) -> Result<(ProvisioningState, Vec<BundleLibrary>), VmError> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(invalid_bundle)?;
+    let manifest = read_entry(&mut archive, MANIFEST_ENTRY)?;
+    let manifest: BundleManifest = serde_json::from_slice(&manifest).map_err(invalid_bundle)?;
+    if manifest.format != BUNDLE_FORMAT {
+        return Err(VmError::InvalidStateBlob(format!(
+            "unexpected format '{}'",
+            manifest.format
+        )));
+    }
+    if manifest.version != BUNDLE_VERSION {
+        return Err(VmError::InvalidStateBlob(format!(
+            "unsupported bundle version {} (expected {BUNDLE_VERSION})",
+            manifest.version
+        )));
+    }
+
+    let mut state = ProvisioningState::default();
+    for (name, sha256) in &manifest.files {
+        let data = read_entry(&mut archive, name)?;
+        if sha256_hex(&data) != *sha256 {
+            return Err(VmError::InvalidStateBlob(format!(
+                "checksum mismatch for '{name}'"
+            )));
+        }
+        if name == DEVICE_ENTRY {
+            let device: DeviceData = serde_json::from_slice(&data).map_err(invalid_bundle)?;
+            state.device = Some(device);
+            continue;
+        }
+        let path = format!("./{name}");
+        if !is_state_file(&path) {
+            return Err(VmError::InvalidStateBlob(format!(
+                "unexpected file '{name}'"
+            )));
+        }
+        state.files.insert(path, data);
+    }
+    Ok((state, manifest.libraries))
+}
+
+fn read_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Vec<u8>, VmError> {
+    let mut entry = archive
+        .by_name(name)
+        .map_err(|err| VmError::InvalidStateBlob(format!("missing '{name}': {err}")))?;
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn invalid_bundle(err: impl std::fmt::Display) -> VmError {
+    VmError::InvalidStateBlob(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_bundle, write_bundle};
+    use crate::device::{DeviceData, DevicePreset};
+    use crate::state::ProvisioningState;
+
+    #[test]
+    fn bundles_round_trip() {
+        let mut state = ProvisioningState {
+            device: Some(DeviceData::preset(DevicePreset::default())),
+            ..Default::default()
+        };
+        state
+            .files
+            .insert("./anisette/adi.pb".to_string(), b"root".to_vec());
+        state
+            .files
+            .insert("./anisette/42/adi.pb".to_string(), b"dsid".to_vec());
+
+        let bundle = write_bundle(&state, &[]).expect("write");
+        let (restored, libraries) = read_bundle(&bundle).expect("read");
+        assert_eq!(restored.files, state.files);
+        assert_eq!(
+            restored.device.map(|device| device.adi_identifier),
+            state.device.map(|device| device.adi_identifier)
+        );
+        assert!(libraries.is_empty());
+
+        assert!(read_bundle(b"not a zip").is_err());
+    }
+}
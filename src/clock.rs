@@ -0,0 +1,68 @@
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Time source behind the `gettimeofday`/`clock_gettime`/`time` stubs.
+pub trait GuestClock: fmt::Debug {
+    /// Wall-clock time as a duration since the Unix epoch.
+    fn realtime(&self) -> Duration;
+    /// Monotonic time since an arbitrary fixed point.
+    fn monotonic(&self) -> Duration;
+}
+
+/// Default clock backed by the host's `SystemTime` and `Instant`.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    started: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GuestClock for SystemClock {
+    fn realtime(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+
+    fn monotonic(&self) -> Duration {
+        self.started.elapsed()
+    }
+}
+
+/// Clock frozen at a given instant, for reproducible runs.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedClock {
+    pub realtime: Duration,
+    pub monotonic: Duration,
+}
+
+impl FixedClock {
+    pub fn at_unix(realtime: Duration) -> Self {
+        Self {
+            realtime,
+            monotonic: Duration::ZERO,
+        }
+    }
+}
+
+impl GuestClock for FixedClock {
+    fn realtime(&self) -> Duration {
+        self.realtime
+    }
+
+    fn monotonic(&self) -> Duration {
+        self.monotonic
+    }
+}
@@ -0,0 +1,152 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use crate::device::DeviceData;
+
+const DEVICE_JSON: &str = "device.json";
+const DEVICE_PLIST: &str = "device.plist";
+const ADI_PB: &str = "adi.pb";
+
+/// How a device file is encoded. Provision and anisette-v3-server write
+/// `device.json` with the same keys this crate uses (`UUID`, `clientInfo`,
+/// `identifier`, `localUUID`); some ports store the same dictionary as a plist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DeviceFormat {
+    #[default]
+    Json,
+    /// XML plist; binary plists are accepted on read.
+    Plist,
+}
+
+impl DeviceFormat {
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Self::Json => DEVICE_JSON,
+            Self::Plist => DEVICE_PLIST,
+        }
+    }
+
+    /// The format `bytes` look like, from their first few bytes.
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        let trimmed = bytes.trim_ascii_start();
+        if trimmed.starts_with(b"{") {
+            Some(Self::Json)
+        } else if trimmed.starts_with(b"bplist") || trimmed.starts_with(b"<?xml") {
+            Some(Self::Plist)
+        } else {
+            None
+        }
+    }
+}
+
+impl DeviceData {
+    /// Parses a device file in any supported [`DeviceFormat`], telling them apart
+    /// by content.
+    pub fn from_device_file_bytes(bytes: &[u8]) -> Result<Self> {
+        match DeviceFormat::detect(bytes) {
+            Some(DeviceFormat::Json) => {
+                serde_json::from_slice(bytes).context("invalid device json")
+            }
+            Some(DeviceFormat::Plist) => {
+                plist::from_reader(Cursor::new(bytes)).context("invalid device plist")
+            }
+            None => bail!("unrecognized device file format"),
+        }
+    }
+
+    pub fn to_device_file_bytes(&self, format: DeviceFormat) -> Result<Vec<u8>> {
+        match format {
+            DeviceFormat::Json => Ok(serde_json::to_vec_pretty(self)?),
+            DeviceFormat::Plist => {
+                let mut bytes = Vec::new();
+                plist::to_writer_xml(&mut bytes, self)?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+/// Copies a provisioned identity (device file plus `adi.pb`) from another tool's
+/// directory into `dst_dir` in this crate's layout, so it keeps working without
+/// re-provisioning. Returns the imported device.
+pub fn import_identity(src_dir: impl AsRef<Path>, dst_dir: impl AsRef<Path>) -> Result<DeviceData> {
+    let src_dir = src_dir.as_ref();
+    let device = read_device_dir(src_dir)?;
+    let adi_pb = read_file(&src_dir.join(ADI_PB))?;
+    write_identity(dst_dir.as_ref(), &device, &adi_pb, DeviceFormat::Json)?;
+    Ok(device)
+}
+
+/// Writes the identity in `src_dir` to `dst_dir` as `format`, for use by another
+/// tool.
+pub fn export_identity(
+    src_dir: impl AsRef<Path>,
+    dst_dir: impl AsRef<Path>,
+    format: DeviceFormat,
+) -> Result<()> {
+    let src_dir = src_dir.as_ref();
+    let device = read_device_dir(src_dir)?;
+    let adi_pb = read_file(&src_dir.join(ADI_PB))?;
+    write_identity(dst_dir.as_ref(), &device, &adi_pb, format)
+}
+
+fn read_device_dir(dir: &Path) -> Result<DeviceData> {
+    for name in [DEVICE_JSON, DEVICE_PLIST] {
+        let path = dir.join(name);
+        if path.is_file() {
+            return DeviceData::from_device_file_bytes(&read_file(&path)?)
+                .with_context(|| format!("failed to parse {}", path.display()));
+        }
+    }
+    bail!("no {DEVICE_JSON} or {DEVICE_PLIST} in {}", dir.display())
+}
+
+fn write_identity(
+    dir: &Path,
+    device: &DeviceData,
+    adi_pb: &[u8],
+    format: DeviceFormat,
+) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let device_path = dir.join(format.file_name());
+    fs::write(&device_path, device.to_device_file_bytes(format)?)
+        .with_context(|| format!("failed to write {}", device_path.display()))?;
+    let adi_pb_path = dir.join(ADI_PB);
+    fs::write(&adi_pb_path, adi_pb)
+        .with_context(|| format!("failed to write {}", adi_pb_path.display()))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceFormat;
+    use crate::device::DeviceData;
+
+    #[test]
+    fn device_files_round_trip_as_json_and_plist() {
+        let device = DeviceData {
+            unique_device_identifier: "UUID".to_string(),
+            server_friendly_description: "<MacBookPro13,2>".to_string(),
+            adi_identifier: "0123456789abcdef".to_string(),
+            local_user_uuid: "LOCAL".to_string(),
+            routing_info: Some("17106176".to_string()),
+            ..Default::default()
+        };
+        for format in [DeviceFormat::Json, DeviceFormat::Plist] {
+            let bytes = device.to_device_file_bytes(format).expect("encode");
+            let decoded = DeviceData::from_device_file_bytes(&bytes).expect("decode");
+            assert_eq!(decoded.adi_identifier, device.adi_identifier);
+            assert_eq!(decoded.routing_info, device.routing_info);
+        }
+
+        let provision = br#"{"UUID":"A","clientInfo":"B","identifier":"C","localUUID":"D"}"#;
+        let decoded = DeviceData::from_device_file_bytes(provision).expect("provision json");
+        assert_eq!(decoded.local_user_uuid, "D");
+    }
+}
@@ -5,6 +5,15 @@ pub const PAGE_SIZE: u64 = 0x1000;
 pub const RETURN_ADDRESS: u64 = 0xDEAD_0000;
 pub const STACK_ADDRESS: u64 = 0xF000_0000;
 pub const STACK_SIZE: u64 = 0x10_0000;
+pub const NESTED_CALL_STACK_GAP: u64 = 0x1000;
+pub const MAX_GUEST_STRING_LEN: u64 = 0x10_0000;
+/// Bulk guest memory operations (`memcpy`, `memset`, `getrandom`) go through a
+/// host buffer of at most this size.
+pub const GUEST_MEMORY_CHUNK: u64 = 0x1_0000;
+/// Most bytes one `getrandom` call returns (32 MiB - 1, as on Linux).
+pub const MAX_GETRANDOM_LEN: u64 = 0x1FF_FFFF;
+pub const MAX_TRACE_STRING_LEN: u64 = 0x400;
+pub const FLIGHT_RECORDER_LEN: usize = 256;
 
 pub const MALLOC_ADDRESS: u64 = 0x6000_0000;
 pub const MALLOC_SIZE: u64 = 0x10_00000;
@@ -16,17 +25,64 @@ pub const IMPORT_LIBRARY_COUNT: usize = 10;
 
 pub const TEMP_ALLOC_BASE: u64 = 0x0008_0000_0000;
 pub const TEMP_ALLOC_SIZE: u64 = 0x1000_0000;
+pub const SCRATCH_SIZE: u64 = 0x10_0000;
 pub const LIB_ALLOC_BASE: u64 = 0x0010_0000;
 pub const LIB_ALLOC_SIZE: u64 = 0x9000_0000;
 pub const LIB_RESERVATION_SIZE: u64 = 0x1000_0000;
 
+pub const O_RDONLY: u64 = 0o0;
 pub const O_WRONLY: u64 = 0o1;
 pub const O_RDWR: u64 = 0o2;
 pub const O_ACCMODE: u64 = 0o3;
 pub const O_CREAT: u64 = 0o100;
+pub const O_EXCL: u64 = 0o200;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
 pub const O_NOFOLLOW: u64 = 0o100000;
 
+/// `access` modes; they line up with the rwx permission bits.
+pub const R_OK: u64 = 0o4;
+pub const W_OK: u64 = 0o2;
+pub const X_OK: u64 = 0o1;
+
+pub const EPERM: u32 = 1;
 pub const ENOENT: u32 = 2;
+pub const EINTR: u32 = 4;
+pub const EIO: u32 = 5;
+pub const EBADF: u32 = 9;
+pub const EAGAIN: u32 = 11;
+pub const ENOMEM: u32 = 12;
+pub const EACCES: u32 = 13;
+pub const EBUSY: u32 = 16;
+pub const EEXIST: u32 = 17;
+pub const ENOTDIR: u32 = 20;
+pub const EISDIR: u32 = 21;
+pub const EINVAL: u32 = 22;
+pub const EFBIG: u32 = 27;
+pub const ENOSPC: u32 = 28;
+pub const EROFS: u32 = 30;
+pub const EDEADLK: u32 = 35;
+pub const ENOTEMPTY: u32 = 39;
+
+pub const PTHREAD_KEYS_MAX: usize = 128;
+
+pub const CLOCK_REALTIME: u64 = 0;
+pub const CLOCK_MONOTONIC: u64 = 1;
+pub const CLOCK_MONOTONIC_RAW: u64 = 4;
+pub const CLOCK_REALTIME_COARSE: u64 = 5;
+pub const CLOCK_MONOTONIC_COARSE: u64 = 6;
+pub const CLOCK_BOOTTIME: u64 = 7;
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+/// Only the Windows metadata mapping synthesizes symlink modes.
+#[cfg(not(unix))]
+pub const S_IFLNK: u32 = 0o120000;
+pub const STAT_BLOCK_SIZE: u64 = 512;
+
+pub const GUEST_PROVISIONING_DIR: &str = "./anisette";
+pub const GUEST_ADI_PB_PATH: &str = "./anisette/adi.pb";
 
 pub const RET_AARCH64: [u8; 4] = [0xC0, 0x03, 0x5F, 0xD6];
 
@@ -63,5 +119,17 @@ pub const ARG_REGS: [RegisterARM64; 29] = [
     RegisterARM64::X28,
 ];
 
-pub const DEBUG_PRINT_ENABLED: bool = false;
-pub const DEBUG_TRACE_ENABLED: bool = false;
+/// Log provisioning material (spim, cpim, ptm, tk) in full instead of only its
+/// length and a digest.
+pub const DEBUG_PRINT_SECRETS: bool = false;
+
+#[cfg(not(feature = "minimal"))]
+pub const ANDROID_LOG_WARN: u32 = 5;
+pub const SIGABRT: i32 = 6;
+/// Highest signal number Linux accepts (`_NSIG - 1`).
+pub const MAX_SIGNAL: i32 = 64;
+/// Exit status a shell reports for a process killed by a signal: `128 + signo`.
+pub const SIGNAL_EXIT_BASE: i32 = 128;
+/// Number of variadic integer arguments passed in X0..X7 before spilling to the stack.
+#[cfg(not(feature = "minimal"))]
+pub const VARIADIC_REG_ARGS: usize = 8;
@@ -0,0 +1,214 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use chrono::Utc;
+use unicorn_engine::unicorn_const::Permission;
+use unicorn_engine::{RegisterARM64, Unicorn};
+
+use crate::constants::{LIB_RESERVATION_SIZE, PAGE_SIZE};
+use crate::debug::{GENERAL_REGISTERS, log_warn, reg_or_zero};
+use crate::errors::VmError;
+use crate::runtime::RuntimeState;
+
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const ET_CORE: u16 = 4;
+const EM_AARCH64: u16 = 183;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const NT_FILE: u32 = 0x4649_4C45;
+/// `pr_reg` offset and total size of the aarch64 `struct elf_prstatus`.
+const PRSTATUS_REG_OFFSET: usize = 112;
+const PRSTATUS_SIZE: usize = 392;
+const SIGSEGV: u16 = 11;
+
+/// Writes guest memory as an ELF core file that Ghidra, IDA and gdb can load:
+/// one `PT_LOAD` per mapped region inside `ranges` (every mapped region when
+/// `ranges` is empty), the registers as `NT_PRSTATUS` (with `signal` as the
+/// current signal) and the loaded libraries as `NT_FILE`.
+pub(crate) fn write_core_dump(
+    uc: &Unicorn<'_, RuntimeState>,
+    ranges: &[Range<u64>],
+    signal: u16,
+    writer: &mut dyn Write,
+) -> Result<(), VmError> {
+    let mut segments = Vec::new();
+    for region in uc.mem_regions()? {
+        let mapped = region.begin..region.end.saturating_add(1);
+        let wanted: Vec<Range<u64>> = if ranges.is_empty() {
+            vec![mapped]
+        } else {
+            ranges
+                .iter()
+                .map(|range| range.start.max(mapped.start)..range.end.min(mapped.end))
+                .filter(|range| !range.is_empty())
+                .collect()
+        };
+        for range in wanted {
+            segments.push((range, segment_flags(region.perms)));
+        }
+    }
+    segments.sort_by_key(|(range, _)| range.start);
+
+    let mut notes = Vec::new();
+    write_note(&mut notes, NT_PRSTATUS, &prstatus(uc, signal));
+    write_note(&mut notes, NT_FILE, &file_map(uc));
+
+    let phnum = segments.len() as u64 + 1;
+    let notes_offset = ELF_HEADER_SIZE + phnum * PROGRAM_HEADER_SIZE;
+    let mut out = Vec::new();
+    out.extend_from_slice(b"\x7fELF\x02\x01\x01");
+    out.resize(16, 0);
+    out.extend_from_slice(&ET_CORE.to_le_bytes());
+    out.extend_from_slice(&EM_AARCH64.to_le_bytes());
+    out.extend_from_slice(&1_u32.to_le_bytes());
+    out.extend_from_slice(&0_u64.to_le_bytes()); // e_entry
+    out.extend_from_slice(&ELF_HEADER_SIZE.to_le_bytes()); // e_phoff
+    out.extend_from_slice(&0_u64.to_le_bytes()); // e_shoff
+    out.extend_from_slice(&0_u32.to_le_bytes()); // e_flags
+    out.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(phnum as u16).to_le_bytes());
+    out.extend_from_slice(&[0; 6]); // no section headers
+
+    write_program_header(&mut out, PT_NOTE, 0, notes_offset, 0, notes.len() as u64);
+    let mut offset = notes_offset + notes.len() as u64;
+    for (range, flags) in &segments {
+        let size = range.end - range.start;
+        write_program_header(&mut out, PT_LOAD, *flags, offset, range.start, size);
+        offset += size;
+    }
+    out.extend_from_slice(&notes);
+    writer.write_all(&out)?;
+
+    for (range, _) in &segments {
+        let data = uc.mem_read_as_vec(range.start, (range.end - range.start) as usize)?;
+        writer.write_all(&data)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes a full core dump of a call that stopped on an emulation fault to
+/// `dir`, logging where it went.
+pub(crate) fn dump_fault(uc: &Unicorn<'_, RuntimeState>, dir: &Path) {
+    let path = dir.join(format!(
+        "anisette-{}.core",
+        Utc::now().format("%Y%m%dT%H%M%S%.3f")
+    ));
+    let written = File::create(&path)
+        .map_err(VmError::from)
+        .and_then(|file| write_core_dump(uc, &[], SIGSEGV, &mut BufWriter::new(file)));
+    match written {
+        Ok(()) => log_warn!(format!("Wrote core dump to {}", path.display())),
+        Err(err) => log_warn!(format!(
+            "Failed to write core dump to {}: {err}",
+            path.display()
+        )),
+    }
+}
+
+fn segment_flags(perms: Permission) -> u32 {
+    let mut flags = 0;
+    if perms.contains(Permission::EXEC) {
+        flags |= 1;
+    }
+    if perms.contains(Permission::WRITE) {
+        flags |= 2;
+    }
+    if perms.contains(Permission::READ) {
+        flags |= 4;
+    }
+    flags
+}
+
+fn write_program_header(
+    out: &mut Vec<u8>,
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    size: u64,
+) {
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    out.extend_from_slice(&address.to_le_bytes()); // p_vaddr
+    out.extend_from_slice(&address.to_le_bytes()); // p_paddr
+    out.extend_from_slice(&size.to_le_bytes()); // p_filesz
+    out.extend_from_slice(&size.to_le_bytes()); // p_memsz
+    out.extend_from_slice(&1_u64.to_le_bytes()); // p_align
+}
+
+fn write_note(out: &mut Vec<u8>, kind: u32, desc: &[u8]) {
+    out.extend_from_slice(&5_u32.to_le_bytes());
+    out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(b"CORE\0\0\0\0");
+    out.extend_from_slice(desc);
+    out.resize(out.len().next_multiple_of(4), 0);
+}
+
+/// X0-X30, SP, PC and PSTATE, laid out as `struct elf_prstatus`.
+fn prstatus(uc: &Unicorn<'_, RuntimeState>, signal: u16) -> Vec<u8> {
+    let mut desc = vec![0; PRSTATUS_SIZE];
+    desc[12..14].copy_from_slice(&signal.to_le_bytes()); // pr_cursig
+    let registers = GENERAL_REGISTERS
+        .iter()
+        .map(|(reg, _)| *reg)
+        .chain([RegisterARM64::PC, RegisterARM64::NZCV]);
+    for (index, reg) in registers.enumerate() {
+        let offset = PRSTATUS_REG_OFFSET + index * 8;
+        desc[offset..offset + 8].copy_from_slice(&reg_or_zero(uc, reg).to_le_bytes());
+    }
+    desc
+}
+
+/// Where each library was loaded, as `NT_FILE` entries.
+fn file_map(uc: &Unicorn<'_, RuntimeState>) -> Vec<u8> {
+    let libraries = &uc.get_data().loaded_libraries;
+    let mut desc = Vec::new();
+    desc.extend_from_slice(&(libraries.len() as u64).to_le_bytes());
+    desc.extend_from_slice(&PAGE_SIZE.to_le_bytes());
+    for library in libraries {
+        desc.extend_from_slice(&library.base.to_le_bytes());
+        desc.extend_from_slice(&(library.base + LIB_RESERVATION_SIZE).to_le_bytes());
+        desc.extend_from_slice(&0_u64.to_le_bytes());
+    }
+    for library in libraries {
+        desc.extend_from_slice(library.name.as_bytes());
+        desc.push(0);
+    }
+    desc
+}
+
+#[cfg(test)]
+mod tests {
+    use goblin::elf::Elf;
+    use goblin::elf::header::ET_CORE;
+    use goblin::elf::program_header::{PT_LOAD, PT_NOTE};
+
+    use crate::emu::EmuCore;
+
+    #[test]
+    fn dumps_requested_memory_as_core() {
+        let mut core = EmuCore::new_arm64().expect("emulator");
+        let address = core.alloc_data(b"anisette").expect("alloc");
+
+        let range = address..address + 8;
+        let mut dump = Vec::new();
+        core.dump_memory(std::slice::from_ref(&range), &mut dump)
+            .expect("dump");
+
+        let elf = Elf::parse(&dump).expect("valid ELF");
+        assert_eq!(elf.header.e_type, ET_CORE);
+        assert_eq!(elf.program_headers[0].p_type, PT_NOTE);
+        let load = &elf.program_headers[1];
+        assert_eq!(load.p_type, PT_LOAD);
+        assert_eq!(load.p_vaddr, address);
+        assert_eq!(&dump[load.file_range()], b"anisette");
+    }
+}
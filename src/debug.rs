@@ -1,21 +1,201 @@
 use std::fmt::Write as _;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{PoisonError, RwLock};
 
 use unicorn_engine::unicorn_const::MemType;
 use unicorn_engine::{RegisterARM64, Unicorn};
 
-use crate::constants::{DEBUG_PRINT_ENABLED, DEBUG_TRACE_ENABLED};
+use crate::constants::{DEBUG_PRINT_SECRETS, LIB_RESERVATION_SIZE};
+use crate::errors::{MemoryAccess, VmError};
+use crate::library::sha256_hex;
 use crate::runtime::RuntimeState;
+use crate::util::bytes_to_hex;
 
+/// Severity of a line passed to a [`LogCallback`].
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogLevel {
+    Trace = 0,
+    Debug = 1,
+    Info = 2,
+    Warn = 3,
+    Error = 4,
+}
+
+impl TryFrom<i32> for LogLevel {
+    type Error = i32;
 
-pub(crate) fn debug_print(message: impl AsRef<str>) {
-    if DEBUG_PRINT_ENABLED {
-        println!("{}", message.as_ref());
+    fn try_from(level: i32) -> Result<Self, i32> {
+        match level {
+            0 => Ok(Self::Trace),
+            1 => Ok(Self::Debug),
+            2 => Ok(Self::Info),
+            3 => Ok(Self::Warn),
+            4 => Ok(Self::Error),
+            other => Err(other),
+        }
     }
 }
 
-pub(crate) fn debug_trace(message: impl AsRef<str>) {
-    if DEBUG_TRACE_ENABLED {
-        println!("{}", message.as_ref());
+/// Receives log output alongside `tracing`, e.g. to forward it to the browser
+/// console or logcat from a host without a `tracing` subscriber. `message` is
+/// UTF-8, not NUL-terminated, and only valid during the call.
+pub type LogCallback = extern "C" fn(level: i32, message: *const u8, len: usize);
+
+static LOG_CALLBACK: RwLock<Option<LogCallback>> = RwLock::new(None);
+static LOG_LEVEL: AtomicI32 = AtomicI32::new(LogLevel::Warn as i32);
+
+/// Also sends log output at or above [`log_level`] to `callback`; `None` leaves
+/// it to `tracing` alone.
+pub fn set_log_callback(callback: Option<LogCallback>) {
+    *LOG_CALLBACK.write().unwrap_or_else(PoisonError::into_inner) = callback;
+}
+
+/// Drops messages below `level` before they reach the [`LogCallback`]. The
+/// default, [`LogLevel::Warn`], shows only problems; [`LogLevel::Debug`] adds ADI
+/// calls and [`LogLevel::Trace`] every hooked guest call, which is what a bug
+/// report about a failing provisioning run needs. `tracing` subscribers filter
+/// on their own, by level and by module target (e.g.
+/// `RUST_LOG=anisette_rs::stub=trace`).
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as i32, Ordering::Relaxed);
+}
+
+pub fn log_level() -> LogLevel {
+    LogLevel::try_from(LOG_LEVEL.load(Ordering::Relaxed)).unwrap_or(LogLevel::Warn)
+}
+
+/// Whether a message at `level` would reach the [`LogCallback`].
+pub(crate) fn callback_enabled(level: LogLevel) -> bool {
+    level as i32 >= LOG_LEVEL.load(Ordering::Relaxed)
+        && LOG_CALLBACK
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some()
+}
+
+pub(crate) fn forward(level: LogLevel, message: &str) {
+    if !callback_enabled(level) {
+        return;
+    }
+    let callback = *LOG_CALLBACK.read().unwrap_or_else(PoisonError::into_inner);
+    if let Some(callback) = callback {
+        callback(level as i32, message.as_ptr(), message.len());
+    }
+}
+
+/// Emits `$message` (anything `AsRef<str>`, evaluated only when someone is
+/// listening) as a `tracing` event targeted at the calling module and forwards
+/// it to the [`LogCallback`].
+macro_rules! log_event {
+    ($tracing:ident, $level:ident, $message:expr) => {
+        if ::tracing::enabled!(::tracing::Level::$tracing)
+            || $crate::debug::callback_enabled($crate::debug::LogLevel::$level)
+        {
+            let message = $message;
+            let message: &str = ::core::convert::AsRef::as_ref(&message);
+            ::tracing::event!(::tracing::Level::$tracing, "{message}");
+            $crate::debug::forward($crate::debug::LogLevel::$level, message);
+        }
+    };
+}
+
+/// ADI calls and what the stubs do.
+#[cfg(not(feature = "minimal"))]
+macro_rules! debug_print {
+    ($message:expr) => {
+        $crate::debug::log_event!(DEBUG, Debug, $message)
+    };
+}
+
+/// Every hooked guest call; far noisier than [`debug_print!`].
+#[cfg(not(feature = "minimal"))]
+macro_rules! debug_trace {
+    ($message:expr) => {
+        $crate::debug::log_event!(TRACE, Trace, $message)
+    };
+}
+
+/// With `minimal`, debug and trace messages still type-check but are never
+/// built, so their format strings and formatting code drop out of the binary.
+#[cfg(feature = "minimal")]
+macro_rules! debug_print {
+    ($message:expr) => {
+        if false {
+            let _ = $message;
+        }
+    };
+}
+
+#[cfg(feature = "minimal")]
+macro_rules! debug_trace {
+    ($message:expr) => {
+        $crate::debug::debug_print!($message)
+    };
+}
+
+/// Problems worth surfacing even with debug output off.
+macro_rules! log_warn {
+    ($message:expr) => {
+        $crate::debug::log_event!(WARN, Warn, $message)
+    };
+}
+
+pub(crate) use {debug_print, debug_trace, log_event, log_warn};
+
+/// Renders provisioning material for [`debug_print!`]: the bytes themselves only
+/// with `DEBUG_PRINT_SECRETS`, otherwise a length and digest prefix that is enough
+/// to tell two blobs apart.
+pub(crate) fn redacted(data: &[u8]) -> String {
+    if DEBUG_PRINT_SECRETS {
+        return bytes_to_hex(data);
+    }
+    let digest = sha256_hex(data);
+    format!("<{} bytes, sha256 {}>", data.len(), &digest[..16])
+}
+
+/// Messages the guest sends to logcat/syslog, at the matching [`LogLevel`] and
+/// with the `anisette_rs::guest` target.
+#[cfg(not(feature = "minimal"))]
+pub(crate) fn guest_log(priority: u32, tag: &str, message: &str) {
+    macro_rules! guest_event {
+        ($tracing:ident, $level:ident) => {
+            if ::tracing::enabled!(target: "anisette_rs::guest", ::tracing::Level::$tracing)
+                || callback_enabled(LogLevel::$level)
+            {
+                let line = format!(
+                    "[guest {}/{tag}] {}",
+                    android_priority_letter(priority),
+                    message.trim_end()
+                );
+                ::tracing::event!(
+                    target: "anisette_rs::guest",
+                    ::tracing::Level::$tracing,
+                    "{line}"
+                );
+                forward(LogLevel::$level, &line);
+            }
+        };
+    }
+    match priority {
+        0..=2 => guest_event!(TRACE, Trace),
+        3 => guest_event!(DEBUG, Debug),
+        4 => guest_event!(INFO, Info),
+        5 => guest_event!(WARN, Warn),
+        _ => guest_event!(ERROR, Error),
+    }
+}
+
+#[cfg(not(feature = "minimal"))]
+fn android_priority_letter(priority: u32) -> char {
+    match priority {
+        2 => 'V',
+        3 => 'D',
+        4 => 'I',
+        5 => 'W',
+        6 => 'E',
+        7 => 'F',
+        _ => '?',
     }
 }
 
@@ -23,91 +203,86 @@ pub(crate) fn reg_or_zero(uc: &Unicorn<'_, RuntimeState>, reg: RegisterARM64) ->
     uc.reg_read(reg).unwrap_or(0)
 }
 
-pub(crate) fn trace_mem_invalid_hook(
+/// Describes a guest access to unmapped memory as a [`VmError::EmulationFault`].
+pub(crate) fn emulation_fault(
     uc: &Unicorn<'_, RuntimeState>,
     access: MemType,
     address: u64,
-    size: usize,
-    value: i64,
-) {
+) -> Option<VmError> {
+    let access = match access {
+        MemType::READ_UNMAPPED => MemoryAccess::Read,
+        MemType::WRITE_UNMAPPED => MemoryAccess::Write,
+        MemType::FETCH_UNMAPPED => MemoryAccess::Fetch,
+        _ => return None,
+    };
     let pc = reg_or_zero(uc, RegisterARM64::PC);
-    match access {
-        MemType::READ_UNMAPPED => {
-            println!(
-                ">>> Missing memory is being READ at 0x{address:x}, data size = {size}, data value = 0x{:x}, PC=0x{pc:x}",
-                value as u64
-            );
-            dump_registers(uc, "read unmapped");
-        }
-        MemType::WRITE_UNMAPPED => {
-            println!(
-                ">>> Missing memory is being WRITE at 0x{address:x}, data size = {size}, data value = 0x{:x}, PC=0x{pc:x}",
-                value as u64
-            );
+    #[cfg(not(feature = "minimal"))]
+    let registers = GENERAL_REGISTERS
+        .iter()
+        .map(|(reg, name)| (*name, reg_or_zero(uc, *reg)))
+        .collect();
+    #[cfg(feature = "minimal")]
+    let registers = Vec::new();
+    Some(VmError::EmulationFault {
+        pc,
+        access,
+        address,
+        registers,
+        faulting_library: library_at(uc, pc),
+    })
+}
 
-            dump_registers(uc, "write unmapped");
-        }
-        MemType::FETCH_UNMAPPED => {
-            println!(
-                ">>> Missing memory is being FETCH at 0x{address:x}, data size = {size}, data value = 0x{:x}, PC=0x{pc:x}",
-                value as u64
-            );
-        }
-        _ => {}
-    }
+/// `library+0xoffset` for a guest address inside a loaded library.
+fn library_at(uc: &Unicorn<'_, RuntimeState>, address: u64) -> Option<String> {
+    uc.get_data()
+        .loaded_libraries
+        .iter()
+        .find(|library| (library.base..library.base + LIB_RESERVATION_SIZE).contains(&address))
+        .map(|library| format!("{}+0x{:X}", library.name, address - library.base))
 }
 
-pub(crate) fn dump_registers(uc: &Unicorn<'_, RuntimeState>, label: &str) {
-    // debug_print(format!());
-    println!("REGDUMP {label}");
-
-    let regs: &[(RegisterARM64, &str)] = &[
-        (RegisterARM64::X0, "X0"),
-        (RegisterARM64::X1, "X1"),
-        (RegisterARM64::X2, "X2"),
-        (RegisterARM64::X3, "X3"),
-        (RegisterARM64::X4, "X4"),
-        (RegisterARM64::X5, "X5"),
-        (RegisterARM64::X6, "X6"),
-        (RegisterARM64::X7, "X7"),
-        (RegisterARM64::X8, "X8"),
-        (RegisterARM64::X9, "X9"),
-        (RegisterARM64::X10, "X10"),
-        (RegisterARM64::X11, "X11"),
-        (RegisterARM64::X12, "X12"),
-        (RegisterARM64::X13, "X13"),
-        (RegisterARM64::X14, "X14"),
-        (RegisterARM64::X15, "X15"),
-        (RegisterARM64::X16, "X16"),
-        (RegisterARM64::X17, "X17"),
-        (RegisterARM64::X18, "X18"),
-        (RegisterARM64::X19, "X19"),
-        (RegisterARM64::X20, "X20"),
-        (RegisterARM64::X21, "X21"),
-        (RegisterARM64::X22, "X22"),
-        (RegisterARM64::X23, "X23"),
-        (RegisterARM64::X24, "X24"),
-        (RegisterARM64::X25, "X25"),
-        (RegisterARM64::X26, "X26"),
-        (RegisterARM64::X27, "X27"),
-        (RegisterARM64::X28, "X28"),
-        (RegisterARM64::FP, "FP"),
-        (RegisterARM64::LR, "LR"),
-        (RegisterARM64::SP, "SP"),
-    ];
-
-    let mut line = String::new();
-    for (i, (reg, name)) in regs.iter().enumerate() {
-        let value = reg_or_zero(uc, *reg);
-        let _ = write!(line, " {name}=0x{value:016X}");
-        if (i + 1) % 4 == 0 {
-            println!("{}", line);
-            line = String::new();
-        }
-    }
+#[cfg(not(feature = "minimal"))]
+pub(crate) const GENERAL_REGISTERS: &[(RegisterARM64, &str)] = &[
+    (RegisterARM64::X0, "X0"),
+    (RegisterARM64::X1, "X1"),
+    (RegisterARM64::X2, "X2"),
+    (RegisterARM64::X3, "X3"),
+    (RegisterARM64::X4, "X4"),
+    (RegisterARM64::X5, "X5"),
+    (RegisterARM64::X6, "X6"),
+    (RegisterARM64::X7, "X7"),
+    (RegisterARM64::X8, "X8"),
+    (RegisterARM64::X9, "X9"),
+    (RegisterARM64::X10, "X10"),
+    (RegisterARM64::X11, "X11"),
+    (RegisterARM64::X12, "X12"),
+    (RegisterARM64::X13, "X13"),
+    (RegisterARM64::X14, "X14"),
+    (RegisterARM64::X15, "X15"),
+    (RegisterARM64::X16, "X16"),
+    (RegisterARM64::X17, "X17"),
+    (RegisterARM64::X18, "X18"),
+    (RegisterARM64::X19, "X19"),
+    (RegisterARM64::X20, "X20"),
+    (RegisterARM64::X21, "X21"),
+    (RegisterARM64::X22, "X22"),
+    (RegisterARM64::X23, "X23"),
+    (RegisterARM64::X24, "X24"),
+    (RegisterARM64::X25, "X25"),
+    (RegisterARM64::X26, "X26"),
+    (RegisterARM64::X27, "X27"),
+    (RegisterARM64::X28, "X28"),
+    (RegisterARM64::FP, "FP"),
+    (RegisterARM64::LR, "LR"),
+    (RegisterARM64::SP, "SP"),
+];
 
-    if !line.is_empty() {
-        println!("{}", line);
+/// One `NAME=0x...` line per four registers.
+pub(crate) fn format_registers(registers: &[(&str, u64)]) -> String {
+    let mut out = String::new();
+    for (i, (name, value)) in registers.iter().enumerate() {
+        let separator = if i % 4 == 0 && i > 0 { '\n' } else { ' ' };
+        let _ = write!(out, "{separator}{name}=0x{value:016X}");
     }
+    out
 }
-
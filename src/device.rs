@@ -2,16 +2,230 @@ use std::fmt::Write as _;
 use std::fs;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
-use rand::RngCore;
+use anyhow::{Context, Result, bail};
+use chrono::Utc;
+use rand::seq::SliceRandom;
+use rand::{Rng, RngCore};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-const DEFAULT_CLIENT_INFO: &str =
-    "<MacBookPro13,2> <macOS;13.1;22C65> <com.apple.AuthKit/1 (com.apple.dt.Xcode/3594.4.19)>";
+use crate::compat::DeviceFormat;
+use crate::debug::debug_print;
+#[cfg(feature = "encryption")]
+use crate::encryption::StorageKey;
+use crate::errors::DeviceDataError;
+use crate::file_lock::{FileLock, write_atomic};
+use crate::identifier::ADI_IDENTIFIER_BYTES;
+
+const DEFAULT_ROUTING_INFO: &str = "17106176";
+const ADI_PB_NAME: &str = "adi.pb";
+const DEFAULT_SERIAL_NUMBER: &str = "0";
+const DEFAULT_TIME_ZONE: &str = "UTC";
+const DEFAULT_LOCALE: &str = "en_US";
+
+/// A real Apple device to impersonate. Each preset is a model identifier with an
+/// OS version and build that shipped for it, so the `clientInfo` GSA sees is
+/// plausible and not the same string every other client sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DevicePreset {
+    /// The historical default, kept for existing `device.json` files.
+    #[default]
+    MacBookPro13_2,
+    MacBookPro18_3,
+    MacBookAir10_1,
+    IMac20_1,
+    Macmini9_1,
+    /// iPhone 11 (`iPhone12,1`).
+    IPhone11,
+    /// iPhone 13 (`iPhone14,5`).
+    IPhone13,
+}
+
+struct PresetInfo {
+    model: &'static str,
+    os: &'static str,
+    os_version: &'static str,
+    os_build: &'static str,
+    /// The AuthKit client in the third `clientInfo` component.
+    client: &'static str,
+    serial: SerialFormat,
+}
+
+/// Apple serial layouts. Devices from before 2021 use 12 characters: plant,
+/// year/half, week, unit and a model-specific configuration code. Later ones
+/// get 10 random characters.
+#[derive(Clone, Copy)]
+enum SerialFormat {
+    Legacy {
+        plants: &'static [&'static str],
+        config: &'static str,
+    },
+    Randomized,
+}
+
+/// Serials use digits and capitals except `I` and `O`.
+const SERIAL_ALPHABET: &[u8] = b"0123456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+/// Year/half codes: each letter is one half-year.
+const SERIAL_YEAR_CODES: &[u8] = b"CDFGHJKLMNPQRSTVWXYZ";
+const SERIAL_WEEK_CODES: &[u8] = b"123456789CDFGHJKLMNPQRTVWXY";
+
+impl SerialFormat {
+    fn generate(self, rng: &mut impl RngCore) -> String {
+        match self {
+            Self::Legacy { plants, config } => {
+                let mut serial = plants.choose(rng).copied().unwrap_or_default().to_string();
+                serial.push(pick(rng, SERIAL_YEAR_CODES));
+                serial.push(pick(rng, SERIAL_WEEK_CODES));
+                serial.extend((0..3).map(|_| pick(rng, SERIAL_ALPHABET)));
+                serial.push_str(config);
+                serial
+            }
+            Self::Randomized => (0..10).map(|_| pick(rng, SERIAL_ALPHABET)).collect(),
+        }
+    }
+}
+
+fn pick(rng: &mut impl RngCore, alphabet: &[u8]) -> char {
+    char::from(alphabet[rng.gen_range(0..alphabet.len())])
+}
+
+const AKD_CLIENT: &str = "com.apple.akd/1.0";
+
+impl DevicePreset {
+    pub const ALL: &'static [DevicePreset] = &[
+        DevicePreset::MacBookPro13_2,
+        DevicePreset::MacBookPro18_3,
+        DevicePreset::MacBookAir10_1,
+        DevicePreset::IMac20_1,
+        DevicePreset::Macmini9_1,
+        DevicePreset::IPhone11,
+        DevicePreset::IPhone13,
+    ];
+
+    /// The preset whose model a `clientInfo` string names, if any.
+    pub fn from_client_info(client_info: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|preset| client_info.starts_with(&format!("<{}>", preset.model())))
+    }
+
+    /// A preset picked at random, for callers minting many devices.
+    pub fn random() -> Self {
+        *Self::ALL
+            .choose(&mut rand::thread_rng())
+            .unwrap_or(&Self::MacBookPro13_2)
+    }
+
+    fn info(self) -> PresetInfo {
+        const MAC_PLANTS: &[&str] = &["C02", "C07", "C17", "FVF"];
+        const IPHONE_PLANTS: &[&str] = &["DX3", "F2L", "G6T"];
+        let legacy = |plants, config| SerialFormat::Legacy { plants, config };
+        let (model, os, os_version, os_build, client, serial) = match self {
+            Self::MacBookPro13_2 => (
+                "MacBookPro13,2",
+                "macOS",
+                "13.1",
+                "22C65",
+                "com.apple.dt.Xcode/3594.4.19",
+                legacy(MAC_PLANTS, "GTFL"),
+            ),
+            Self::MacBookPro18_3 => (
+                "MacBookPro18,3",
+                "macOS",
+                "14.6.1",
+                "23G93",
+                AKD_CLIENT,
+                SerialFormat::Randomized,
+            ),
+            Self::MacBookAir10_1 => (
+                "MacBookAir10,1",
+                "macOS",
+                "13.6",
+                "22G120",
+                AKD_CLIENT,
+                legacy(MAC_PLANTS, "Q6L4"),
+            ),
+            Self::IMac20_1 => (
+                "iMac20,1",
+                "macOS",
+                "12.6",
+                "21G115",
+                AKD_CLIENT,
+                legacy(MAC_PLANTS, "PN7T"),
+            ),
+            Self::Macmini9_1 => (
+                "Macmini9,1",
+                "macOS",
+                "14.5",
+                "23F79",
+                AKD_CLIENT,
+                legacy(MAC_PLANTS, "Q6NV"),
+            ),
+            Self::IPhone11 => (
+                "iPhone12,1",
+                "iPhone OS",
+                "17.5.1",
+                "21F90",
+                AKD_CLIENT,
+                legacy(IPHONE_PLANTS, "N72J"),
+            ),
+            Self::IPhone13 => (
+                "iPhone14,5",
+                "iPhone OS",
+                "16.6",
+                "20G75",
+                AKD_CLIENT,
+                SerialFormat::Randomized,
+            ),
+        };
+        PresetInfo {
+            model,
+            os,
+            os_version,
+            os_build,
+            client,
+            serial,
+        }
+    }
+
+    /// Model identifier, e.g. `MacBookPro13,2`.
+    pub fn model(self) -> &'static str {
+        self.info().model
+    }
+
+    pub fn os_version(self) -> &'static str {
+        self.info().os_version
+    }
+
+    /// A new serial number in this model's format (12 characters before 2021,
+    /// 10 random ones after).
+    pub fn generate_serial_number(self) -> String {
+        self.generate_serial_number_with_rng(&mut rand::thread_rng())
+    }
+
+    pub fn generate_serial_number_with_rng(self, rng: &mut impl RngCore) -> String {
+        self.info().serial.generate(rng)
+    }
+
+    /// The `X-MMe-Client-Info` / `clientInfo` string.
+    pub fn client_info(self) -> String {
+        let info = self.info();
+        format!(
+            "<{}> <{};{};{}> <com.apple.AuthKit/1 ({})>",
+            info.model, info.os, info.os_version, info.os_build, info.client
+        )
+    }
+}
+
+/// Schema version written to new `device.json` files. Files without a `version`
+/// predate it and are version 0; [`DeviceData::migrate`] upgrades them.
+pub const DEVICE_SCHEMA_VERSION: u32 = 1;
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct DeviceData {
+    #[serde(default)]
+    pub version: u32,
     #[serde(rename = "UUID")]
     pub unique_device_identifier: String,
     #[serde(rename = "clientInfo")]
@@ -20,64 +234,384 @@ pub struct DeviceData {
     pub adi_identifier: String,
     #[serde(rename = "localUUID")]
     pub local_user_uuid: String,
+    /// `X-Apple-I-MD-RINFO` routing info handed out by GSA for this device.
+    #[serde(
+        rename = "routingInfo",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub routing_info: Option<String>,
+    #[serde(
+        rename = "serialNumber",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub serial_number: Option<String>,
+    /// IANA zone name for `X-Apple-I-TimeZone`.
+    #[serde(rename = "timeZone", default, skip_serializing_if = "Option::is_none")]
+    pub time_zone: Option<String>,
+    #[serde(rename = "locale", default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+}
+
+impl DeviceData {
+    /// A fresh device (new UUIDs and identifier) impersonating `preset`.
+    pub fn preset(preset: DevicePreset) -> Self {
+        Self::preset_with_rng(preset, &mut rand::thread_rng())
+    }
+
+    /// [`preset`](Self::preset) drawing every identifier from `rng`: a seeded RNG
+    /// gives reproducible devices for tests, or plug in a hardware source.
+    pub fn preset_with_rng(preset: DevicePreset, rng: &mut impl RngCore) -> Self {
+        let uuid = uuid::Builder::from_random_bytes(rng.r#gen()).into_uuid();
+        Self {
+            unique_device_identifier: uuid.to_string().to_uppercase(),
+            server_friendly_description: preset.client_info(),
+            adi_identifier: random_hex(rng, 8, false),
+            local_user_uuid: random_hex(rng, 32, true),
+            serial_number: Some(preset.generate_serial_number_with_rng(rng)),
+            version: DEVICE_SCHEMA_VERSION,
+            ..Default::default()
+        }
+    }
+
+    /// Upgrades data read from an older `device.json` to
+    /// [`DEVICE_SCHEMA_VERSION`], filling in fields added since. Returns whether
+    /// anything changed; files from a newer version are rejected.
+    pub fn migrate(&mut self) -> Result<bool> {
+        if self.version > DEVICE_SCHEMA_VERSION {
+            bail!(
+                "device schema version {} is newer than supported ({DEVICE_SCHEMA_VERSION})",
+                self.version
+            );
+        }
+        if self.version == DEVICE_SCHEMA_VERSION {
+            return Ok(false);
+        }
+
+        // 0 -> 1: serial number and explicit routing info.
+        if self.serial_number.is_none() {
+            let preset = DevicePreset::from_client_info(&self.server_friendly_description)
+                .unwrap_or_default();
+            self.serial_number = Some(preset.generate_serial_number());
+        }
+        if self.routing_info.is_none() {
+            self.routing_info = Some(DEFAULT_ROUTING_INFO.to_string());
+        }
+
+        self.version = DEVICE_SCHEMA_VERSION;
+        Ok(true)
+    }
+
+    /// Checks the identity fields the ADI library and GSA depend on, so a corrupt
+    /// or hand-edited file fails here instead of as an opaque ADI error later.
+    pub fn validate(&self) -> Result<(), DeviceDataError> {
+        if Uuid::parse_str(&self.unique_device_identifier).is_err() {
+            return Err(DeviceDataError::InvalidUuid(
+                self.unique_device_identifier.clone(),
+            ));
+        }
+        if !is_hex(&self.adi_identifier, ADI_IDENTIFIER_BYTES * 2) {
+            return Err(DeviceDataError::InvalidIdentifier(
+                self.adi_identifier.clone(),
+            ));
+        }
+        if !is_hex(&self.local_user_uuid, 64) {
+            return Err(DeviceDataError::InvalidLocalUuid(
+                self.local_user_uuid.clone(),
+            ));
+        }
+        if !is_client_info(&self.server_friendly_description) {
+            return Err(DeviceDataError::InvalidClientInfo(
+                self.server_friendly_description.clone(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Routing info to send, falling back to the value Apple's own clients start with.
+    pub fn routing_info(&self) -> &str {
+        self.routing_info.as_deref().unwrap_or(DEFAULT_ROUTING_INFO)
+    }
+
+    /// `X-Apple-I-SRL-NO`; `"0"` is what clients without a serial send. Devices from
+    /// [`DeviceData::preset`] carry a generated one.
+    pub fn serial_number(&self) -> &str {
+        self.serial_number
+            .as_deref()
+            .unwrap_or(DEFAULT_SERIAL_NUMBER)
+    }
+
+    pub fn time_zone(&self) -> &str {
+        self.time_zone.as_deref().unwrap_or(DEFAULT_TIME_ZONE)
+    }
+
+    pub fn locale(&self) -> &str {
+        self.locale.as_deref().unwrap_or(DEFAULT_LOCALE)
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Device {
-    path: PathBuf,
+    /// `None` for in-memory devices, which [`persist`](Self::persist) skips.
+    path: Option<PathBuf>,
+    /// What the file was read as, and is written back as.
+    format: DeviceFormat,
     pub data: DeviceData,
     pub initialized: bool,
+    #[cfg(feature = "encryption")]
+    key: Option<StorageKey>,
 }
 
+#[cfg(feature = "encryption")]
+const DEVICE_PURPOSE: &str = "device.json";
+
 impl Device {
+    /// Reads a device file in either [`DeviceFormat`]; [`persist`](Self::persist)
+    /// keeps the one it was read in.
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
         let path = path.as_ref().to_path_buf();
+        let Some(bytes) = read_device_file(&path)? else {
+            return Ok(Self::uninitialized(path));
+        };
+        let (device, migrated) = Self::parse(path, &bytes)?;
+        Ok(device.finish_load(migrated))
+    }
 
-        if !path.exists() {
-            return Ok(Self {
-                path,
-                data: DeviceData::default(),
-                initialized: false,
-            });
+    /// Like [`load`](Self::load), but keeps the file encrypted with `key`. A
+    /// plaintext file is still read, and encrypted by the next
+    /// [`persist`](Self::persist).
+    #[cfg(feature = "encryption")]
+    pub fn load_encrypted(path: impl AsRef<Path>, key: StorageKey) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let Some(bytes) = read_device_file(&path)? else {
+            let mut device = Self::uninitialized(path);
+            device.key = Some(key);
+            return Ok(device);
+        };
+        let bytes = if StorageKey::is_encrypted(&bytes) {
+            key.decrypt(DEVICE_PURPOSE, &bytes)?
+        } else {
+            bytes
+        };
+        let (mut device, migrated) = Self::parse(path, &bytes)?;
+        device.key = Some(key);
+        Ok(device.finish_load(migrated))
+    }
+
+    /// A freshly generated device that lives only in memory; keep
+    /// [`data`](Self::data) wherever suits (a database, browser storage).
+    pub fn ephemeral() -> Self {
+        let mut device = Self::from_data(DeviceData::default());
+        device.initialize_defaults();
+        device
+    }
+
+    /// Wraps existing data without a backing file.
+    pub fn from_data(data: DeviceData) -> Self {
+        Self {
+            path: None,
+            format: DeviceFormat::Json,
+            data,
+            initialized: true,
+            #[cfg(feature = "encryption")]
+            key: None,
         }
+    }
 
-        let bytes = fs::read(&path)
-            .with_context(|| format!("failed to read device file {}", path.display()))?;
-        let data: DeviceData = serde_json::from_slice(&bytes)
-            .with_context(|| format!("failed to parse device file {}", path.display()))?;
+    /// The backing device file, if any.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    fn uninitialized(path: PathBuf) -> Self {
+        Self {
+            path: Some(path),
+            format: DeviceFormat::Json,
+            data: DeviceData::default(),
+            initialized: false,
+            #[cfg(feature = "encryption")]
+            key: None,
+        }
+    }
 
-        Ok(Self {
-            path,
+    fn parse(path: PathBuf, bytes: &[u8]) -> Result<(Self, bool)> {
+        let mut data = DeviceData::from_device_file_bytes(bytes)
+            .with_context(|| format!("failed to parse device file {}", path.display()))?;
+        let migrated = data
+            .migrate()
+            .with_context(|| format!("failed to migrate device file {}", path.display()))?;
+        data.validate()
+            .with_context(|| format!("invalid device file {}", path.display()))?;
+        let device = Self {
+            format: DeviceFormat::detect(bytes).unwrap_or_default(),
             data,
             initialized: true,
-        })
+            ..Self::uninitialized(path)
+        };
+        Ok((device, migrated))
+    }
+
+    fn finish_load(self, migrated: bool) -> Self {
+        if migrated {
+            // Best effort: a read-only copy still loads, it just migrates again next time.
+            if let Err(err) = self.persist() {
+                debug_print!(format!("failed to persist migrated device file: {err:#}"));
+            }
+        }
+        self
     }
 
     pub fn initialize_defaults(&mut self) {
-        self.data.server_friendly_description = DEFAULT_CLIENT_INFO.to_string();
-        self.data.unique_device_identifier = Uuid::new_v4().to_string().to_uppercase();
-        self.data.adi_identifier = random_hex(8, false);
-        self.data.local_user_uuid = random_hex(32, true);
+        self.initialize_preset(DevicePreset::default());
+    }
+
+    /// Fills in fresh identifiers for `preset`, keeping routing info, locale and
+    /// the other optional fields.
+    pub fn initialize_preset(&mut self, preset: DevicePreset) {
+        self.initialize_preset_with_rng(preset, &mut rand::thread_rng());
+    }
+
+    pub fn initialize_preset_with_rng(&mut self, preset: DevicePreset, rng: &mut impl RngCore) {
+        let fresh = DeviceData::preset_with_rng(preset, rng);
+        self.data.server_friendly_description = fresh.server_friendly_description;
+        self.data.unique_device_identifier = fresh.unique_device_identifier;
+        self.data.adi_identifier = fresh.adi_identifier;
+        self.data.local_user_uuid = fresh.local_user_uuid;
+        self.data.version = fresh.version;
+        if self.data.serial_number.is_none() {
+            self.data.serial_number = fresh.serial_number;
+        }
+        self.initialized = true;
+    }
+
+    /// Replaces the identity (UUIDs, identifier, serial) with a fresh one and moves
+    /// every `adi.pb` under `provisioning_path` aside as `adi.pb.stale-<unix time>`,
+    /// then persists. Old provisioning state with new identifiers yields headers
+    /// Apple rejects, so the two must change together; re-create any [`Adi`]
+    /// using that path and provision again afterwards.
+    ///
+    /// With `keep_client_info` the device keeps impersonating the same model;
+    /// otherwise a random [`DevicePreset`] is picked.
+    ///
+    /// [`Adi`]: crate::Adi
+    pub fn regenerate(
+        &mut self,
+        keep_client_info: bool,
+        provisioning_path: impl AsRef<Path>,
+    ) -> Result<()> {
+        retire_provisioning(provisioning_path.as_ref())?;
+
+        let client_info = &self.data.server_friendly_description;
+        let preset = if keep_client_info {
+            DevicePreset::from_client_info(client_info).unwrap_or_default()
+        } else {
+            DevicePreset::random()
+        };
+        let mut fresh = DeviceData::preset(preset);
+        if keep_client_info && !client_info.is_empty() {
+            fresh.server_friendly_description = client_info.clone();
+        }
+        // Routing info was handed out for the old provisioning.
+        fresh.time_zone = self.data.time_zone.take();
+        fresh.locale = self.data.locale.take();
+        self.data = fresh;
         self.initialized = true;
+        self.persist()
     }
 
+    /// Writes the device file; a no-op for in-memory devices.
     pub fn persist(&self) -> Result<()> {
-        if let Some(parent) = self.path.parent() {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)
                 .with_context(|| format!("failed to create parent dir {}", parent.display()))?;
         }
 
-        let bytes = serde_json::to_vec_pretty(&self.data)?;
-        fs::write(&self.path, bytes)
-            .with_context(|| format!("failed to write device file {}", self.path.display()))?;
+        let bytes = self.data.to_device_file_bytes(self.format)?;
+        #[cfg(feature = "encryption")]
+        let bytes = match &self.key {
+            Some(key) => key.encrypt(DEVICE_PURPOSE, &bytes)?,
+            None => bytes,
+        };
+        let _lock = FileLock::exclusive(path)
+            .with_context(|| format!("failed to lock device file {}", path.display()))?;
+        write_atomic(path, &bytes)
+            .with_context(|| format!("failed to write device file {}", path.display()))?;
 
         Ok(())
     }
 }
 
-fn random_hex(byte_len: usize, uppercase: bool) -> String {
+/// Renames `adi.pb` in `dir` and its per-DSID subdirectories.
+fn retire_provisioning(dir: &Path) -> Result<()> {
+    let suffix = format!("stale-{}", Utc::now().timestamp());
+    let mut dirs = vec![dir.to_path_buf()];
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    for dir in dirs {
+        let adi_pb = dir.join(ADI_PB_NAME);
+        if !adi_pb.is_file() {
+            continue;
+        }
+        let backup = dir.join(format!("{ADI_PB_NAME}.{suffix}"));
+        let _lock = FileLock::exclusive(&adi_pb)
+            .with_context(|| format!("failed to lock {}", adi_pb.display()))?;
+        debug_print!(format!(
+            "Retiring {} as {}",
+            adi_pb.display(),
+            backup.display()
+        ));
+        fs::rename(&adi_pb, &backup)
+            .with_context(|| format!("failed to move {} aside", adi_pb.display()))?;
+    }
+    Ok(())
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+/// `<model> <os;version;build> <client>`; the client part may contain spaces.
+fn is_client_info(value: &str) -> bool {
+    let Some(inner) = value
+        .strip_prefix('<')
+        .and_then(|value| value.strip_suffix('>'))
+    else {
+        return false;
+    };
+    let mut parts = inner.splitn(3, "> <");
+    let (Some(model), Some(os), Some(client)) = (parts.next(), parts.next(), parts.next()) else {
+        return false;
+    };
+    let os_fields: Vec<&str> = os.split(';').collect();
+    !model.is_empty()
+        && !client.is_empty()
+        && os_fields.len() == 3
+        && os_fields.iter().all(|field| !field.is_empty())
+}
+
+fn read_device_file(path: &Path) -> Result<Option<Vec<u8>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let _lock = FileLock::shared(path)
+        .with_context(|| format!("failed to lock device file {}", path.display()))?;
+    let bytes =
+        fs::read(path).with_context(|| format!("failed to read device file {}", path.display()))?;
+    Ok(Some(bytes))
+}
+
+fn random_hex(rng: &mut impl RngCore, byte_len: usize, uppercase: bool) -> String {
     let mut bytes = vec![0_u8; byte_len];
-    rand::thread_rng().fill_bytes(&mut bytes);
+    rng.fill_bytes(&mut bytes);
 
     let mut output = String::with_capacity(byte_len * 2);
     for byte in bytes {
@@ -90,3 +624,151 @@ fn random_hex(byte_len: usize, uppercase: bool) -> String {
 
     output
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::{DEVICE_SCHEMA_VERSION, Device, DeviceData, DevicePreset};
+    use crate::compat::DeviceFormat;
+    use crate::errors::DeviceDataError;
+
+    #[test]
+    fn presets_build_consistent_client_info() {
+        assert_eq!(
+            DevicePreset::default().client_info(),
+            "<MacBookPro13,2> <macOS;13.1;22C65> <com.apple.AuthKit/1 (com.apple.dt.Xcode/3594.4.19)>"
+        );
+        for &preset in DevicePreset::ALL {
+            let device = DeviceData::preset(preset);
+            assert!(
+                device
+                    .server_friendly_description
+                    .starts_with(&format!("<{}> ", preset.model()))
+            );
+            assert!(
+                device
+                    .server_friendly_description
+                    .contains(preset.os_version())
+            );
+            assert_eq!(device.adi_identifier.len(), 16);
+
+            let serial = device.serial_number();
+            assert!(serial.len() == 10 || serial.len() == 12, "{serial}");
+            assert!(
+                serial
+                    .bytes()
+                    .all(|byte| byte.is_ascii_digit() || byte.is_ascii_uppercase())
+            );
+            assert!(!serial.contains(['I', 'O']), "{serial}");
+        }
+    }
+
+    #[test]
+    fn seeded_rng_gives_reproducible_devices() {
+        let generate = |seed| {
+            let device = DeviceData::preset_with_rng(
+                DevicePreset::IPhone11,
+                &mut StdRng::seed_from_u64(seed),
+            );
+            serde_json::to_string(&device).expect("serialize")
+        };
+        assert_eq!(generate(7), generate(7));
+        assert_ne!(generate(7), generate(8));
+    }
+
+    #[test]
+    fn version_0_files_migrate() {
+        let mut device: DeviceData = serde_json::from_str(
+            r#"{"UUID":"A","clientInfo":"<iMac20,1>","identifier":"C","localUUID":"D"}"#,
+        )
+        .expect("parse v0");
+        assert_eq!(device.version, 0);
+        assert!(device.migrate().expect("migrate"));
+        assert_eq!(device.version, DEVICE_SCHEMA_VERSION);
+        assert_eq!(device.serial_number().len(), 12);
+        assert!(device.serial_number().ends_with("PN7T"));
+        assert!(!device.migrate().expect("migrate again"));
+
+        device.version = DEVICE_SCHEMA_VERSION + 1;
+        assert!(device.migrate().is_err());
+    }
+
+    #[test]
+    fn validate_reports_the_broken_field() {
+        let mut device = DeviceData::preset(DevicePreset::default());
+        assert_eq!(device.validate(), Ok(()));
+
+        device.local_user_uuid.pop();
+        assert!(matches!(
+            device.validate(),
+            Err(DeviceDataError::InvalidLocalUuid(_))
+        ));
+
+        let mut device = DeviceData::preset(DevicePreset::IPhone13);
+        device.server_friendly_description = "<iPhone14,5> <iPhone OS;16.6>".to_string();
+        assert!(matches!(
+            device.validate(),
+            Err(DeviceDataError::InvalidClientInfo(_))
+        ));
+    }
+
+    #[test]
+    fn plist_device_files_load_and_stay_plists() {
+        let path =
+            std::env::temp_dir().join(format!("anisette-device-{}.plist", std::process::id()));
+        let data = DeviceData::preset(DevicePreset::IMac20_1);
+        let bytes = data
+            .to_device_file_bytes(DeviceFormat::Plist)
+            .expect("serialize");
+        std::fs::write(&path, bytes).expect("write device.plist");
+
+        let device = Device::load(&path).expect("load plist");
+        assert_eq!(
+            device.data.unique_device_identifier,
+            data.unique_device_identifier
+        );
+        device.persist().expect("persist");
+        let written = std::fs::read(&path).expect("read device.plist");
+        assert_eq!(DeviceFormat::detect(&written), Some(DeviceFormat::Plist));
+
+        std::fs::remove_file(&path).expect("clean up");
+    }
+
+    #[test]
+    fn regenerate_retires_provisioning() {
+        let dir = std::env::temp_dir().join(format!("anisette-regenerate-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("2")).expect("create dirs");
+        std::fs::write(dir.join("adi.pb"), b"old").expect("write adi.pb");
+        std::fs::write(dir.join("2/adi.pb"), b"old").expect("write adi.pb");
+
+        let mut device = Device::from_data(DeviceData::preset(DevicePreset::IMac20_1));
+        let old = device.data.clone();
+        device.regenerate(true, &dir).expect("regenerate");
+
+        assert_ne!(
+            device.data.unique_device_identifier,
+            old.unique_device_identifier
+        );
+        assert_ne!(device.data.adi_identifier, old.adi_identifier);
+        assert_eq!(
+            device.data.server_friendly_description,
+            old.server_friendly_description
+        );
+        assert!(!dir.join("adi.pb").exists());
+        assert!(!dir.join("2/adi.pb").exists());
+        let retired = std::fs::read_dir(&dir)
+            .expect("read dir")
+            .filter_map(Result::ok)
+            .any(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("adi.pb.stale-")
+            });
+        assert!(retired);
+
+        std::fs::remove_dir_all(&dir).expect("cleanup");
+    }
+}
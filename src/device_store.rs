@@ -0,0 +1,160 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+
+use crate::compat::import_identity;
+use crate::device::{Device, DevicePreset};
+
+const DEVICE_FILE: &str = "device.json";
+
+/// Named device profiles under one root, one directory each:
+///
+/// ```text
+/// {root}/{name}/device.json
+/// {root}/{name}/adi.pb        (the profile directory is its provisioning path)
+/// ```
+///
+/// Give [`provisioning_path`](Self::provisioning_path) to
+/// [`AdiInit::provisioning_path`](crate::AdiInit::provisioning_path) so each
+/// profile keeps its own provisioning state.
+#[derive(Debug, Clone)]
+pub struct DeviceStore {
+    root: PathBuf,
+}
+
+impl DeviceStore {
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)
+            .with_context(|| format!("failed to create device store {}", root.display()))?;
+        Ok(Self { root })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Names of the stored profiles, sorted.
+    pub fn list(&self) -> Result<Vec<String>> {
+        let entries = fs::read_dir(&self.root)
+            .with_context(|| format!("failed to list device store {}", self.root.display()))?;
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.path().join(DEVICE_FILE).is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.device_path(name).is_ok_and(|path| path.is_file())
+    }
+
+    /// Generates and persists a new profile; fails if `name` is taken.
+    pub fn create(&self, name: &str, preset: DevicePreset) -> Result<Device> {
+        if self.exists(name) {
+            bail!("device profile '{name}' already exists");
+        }
+        let mut device = Device::load(self.device_path(name)?)?;
+        device.initialize_preset(preset);
+        device.persist()?;
+        Ok(device)
+    }
+
+    pub fn load(&self, name: &str) -> Result<Device> {
+        if !self.exists(name) {
+            bail!("device profile '{name}' does not exist");
+        }
+        Device::load(self.device_path(name)?)
+    }
+
+    pub fn load_or_create(&self, name: &str, preset: DevicePreset) -> Result<Device> {
+        if self.exists(name) {
+            self.load(name)
+        } else {
+            self.create(name, preset)
+        }
+    }
+
+    /// Imports a provisioned identity from another tool's directory (see
+    /// [`import_identity`]) as profile `name`.
+    pub fn import(&self, name: &str, src_dir: impl AsRef<Path>) -> Result<Device> {
+        if self.exists(name) {
+            bail!("device profile '{name}' already exists");
+        }
+        import_identity(src_dir, self.provisioning_path(name)?)?;
+        self.load(name)
+    }
+
+    /// Removes the profile directory, including its provisioning state.
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let dir = self.provisioning_path(name)?;
+        if !dir.exists() {
+            bail!("device profile '{name}' does not exist");
+        }
+        fs::remove_dir_all(&dir)
+            .with_context(|| format!("failed to delete device profile {}", dir.display()))
+    }
+
+    /// Directory holding the profile's `device.json` and `adi.pb`.
+    pub fn provisioning_path(&self, name: &str) -> Result<PathBuf> {
+        validate_name(name)?;
+        Ok(self.root.join(name))
+    }
+
+    pub fn device_path(&self, name: &str) -> Result<PathBuf> {
+        Ok(self.provisioning_path(name)?.join(DEVICE_FILE))
+    }
+}
+
+/// Profile names become directory names, so keep them to one path component.
+fn validate_name(name: &str) -> Result<()> {
+    let valid =
+        !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', '\0']);
+    if !valid {
+        bail!("invalid device profile name '{name}'");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DeviceStore;
+    use crate::device::DevicePreset;
+
+    #[test]
+    fn profiles_are_created_listed_and_deleted() {
+        let root =
+            std::env::temp_dir().join(format!("anisette-device-store-{}", std::process::id()));
+        let store = DeviceStore::open(&root).expect("open");
+
+        let work = store
+            .create("work", DevicePreset::IMac20_1)
+            .expect("create");
+        store
+            .create("home", DevicePreset::default())
+            .expect("create");
+        assert!(store.create("work", DevicePreset::default()).is_err());
+        assert!(store.create("../escape", DevicePreset::default()).is_err());
+        assert_eq!(store.list().expect("list"), ["home", "work"]);
+
+        let loaded = store.load("work").expect("load");
+        assert_eq!(
+            loaded.data.unique_device_identifier,
+            work.data.unique_device_identifier
+        );
+
+        store.delete("work").expect("delete");
+        assert_eq!(store.list().expect("list"), ["home"]);
+        assert!(store.load("work").is_err());
+
+        std::fs::remove_dir_all(&root).expect("cleanup");
+    }
+}
@@ -1,24 +1,42 @@
-use std::collections::HashMap;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::PathBuf;
 
 use goblin::elf::program_header::PT_LOAD;
 use goblin::elf::section_header::SHN_UNDEF;
 use goblin::elf::{Elf, Reloc};
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 use unicorn_engine::unicorn_const::{Arch, HookType, Mode, Permission, uc_error};
-use unicorn_engine::{RegisterARM64, Unicorn};
+use unicorn_engine::{RegisterARM64, UcHookId, Unicorn};
 
+use crate::allocator::Allocator;
+use crate::clock::GuestClock;
 use crate::constants::{
-    ARG_REGS, IMPORT_ADDRESS, IMPORT_LIBRARY_COUNT, IMPORT_LIBRARY_STRIDE, IMPORT_SIZE,
-    LIB_RESERVATION_SIZE, MALLOC_ADDRESS, MALLOC_SIZE, PAGE_SIZE, RET_AARCH64, RETURN_ADDRESS,
-    STACK_ADDRESS, STACK_SIZE,
+    ARG_REGS, GUEST_PROVISIONING_DIR, IMPORT_ADDRESS, IMPORT_LIBRARY_COUNT, IMPORT_LIBRARY_STRIDE,
+    IMPORT_SIZE, LIB_RESERVATION_SIZE, MALLOC_ADDRESS, MALLOC_SIZE, NESTED_CALL_STACK_GAP,
+    PAGE_SIZE, RET_AARCH64, RETURN_ADDRESS, SCRATCH_SIZE, STACK_ADDRESS, STACK_SIZE,
 };
-use crate::debug::{debug_print, trace_mem_invalid_hook};
-use crate::errors::VmError;
-use crate::runtime::{LoadedLibrary, RuntimeState, SymbolEntry};
-use crate::stub::dispatch_import_stub;
+#[cfg(not(feature = "minimal"))]
+use crate::core_dump::{dump_fault, write_core_dump};
+use crate::debug::{debug_print, emulation_fault, format_registers};
+use crate::errors::{AdiErrorCode, VmError};
+use crate::flight_recorder::FlightEvent;
+use crate::idbfs::profile_mount_path;
+use crate::metrics::StubStats;
+use crate::overrides::StubOverride;
+use crate::pthread::PthreadOptions;
+use crate::runtime::{LoadedLibrary, RuntimeState, SymbolTable};
+use crate::stub::{dispatch_import_stub, map_guest_path, provisioning_root, rebase_guest_path};
+use crate::trace::SyscallTracer;
 use crate::util::{add_i64, align_down, align_up, as_usize};
+use crate::vfs::GuestFs;
 
 pub struct EmuCore {
     uc: Unicorn<'static, RuntimeState>,
+    instruction_hook: Option<UcHookId>,
+    #[cfg_attr(feature = "minimal", allow(dead_code))]
+    fault_dump_dir: Option<PathBuf>,
 }
 
 impl EmuCore {
@@ -41,7 +59,8 @@ impl EmuCore {
 
             uc.add_code_hook(base, base + IMPORT_SIZE - 1, |uc, address, _| {
                 if let Err(err) = dispatch_import_stub(uc, address) {
-                    debug_print(format!("import hook failed at 0x{address:X}: {err}"));
+                    debug_print!(format!("import hook failed at 0x{address:X}: {err}"));
+                    uc.get_data_mut().stub_error.get_or_insert(err);
                     let _ = uc.emu_stop();
                 }
             })?;
@@ -53,13 +72,30 @@ impl EmuCore {
                 | HookType::MEM_FETCH_UNMAPPED,
             1,
             0,
-            |uc, access, address, size, value| {
-                trace_mem_invalid_hook(uc, access, address, size, value);
+            |uc, access, address, _size, _value| {
+                if let Some(fault) = emulation_fault(uc, access, address) {
+                    if let VmError::EmulationFault { registers, .. } = &fault {
+                        debug_print!(format!("{fault}\n{}", format_registers(registers)));
+                    }
+                    uc.get_data_mut().stub_error.get_or_insert(fault);
+                }
                 false
             },
         )?;
 
-        Ok(Self { uc })
+        Ok(Self {
+            uc,
+            instruction_hook: None,
+            fault_dump_dir: None,
+        })
+    }
+
+    pub fn library_blob(&self, name: &str) -> Option<&[u8]> {
+        self.uc
+            .get_data()
+            .library_blobs
+            .get(name)
+            .map(Vec::as_slice)
     }
 
     pub fn register_library_blob(&mut self, name: impl Into<String>, data: Vec<u8>) {
@@ -77,6 +113,123 @@ impl EmuCore {
         self.uc.get_data_mut().library_root = Some(normalized);
     }
 
+    pub fn set_guest_fs(&mut self, guest_fs: Box<dyn GuestFs>) {
+        let state = self.uc.get_data_mut();
+        state.file_handles.clear();
+        state.guest_fs = guest_fs;
+    }
+
+    pub(crate) fn guest_fs_mut(&mut self) -> &mut dyn GuestFs {
+        self.uc.get_data_mut().guest_fs.as_mut()
+    }
+
+    /// Where a guest path currently resolves to, after the per-DSID namespace.
+    pub(crate) fn mapped_guest_path(&self, path: &str) -> String {
+        map_guest_path(&self.uc, path)
+    }
+
+    /// Host location of a guest path, when the guest filesystem has one.
+    pub(crate) fn host_path(&self, path: &str) -> Option<PathBuf> {
+        self.uc.get_data().guest_fs.host_path(path)
+    }
+
+    /// Drops descriptors the guest left open, e.g. after a failed call.
+    pub(crate) fn close_guest_files(&mut self) {
+        self.uc.get_data_mut().file_handles.clear();
+    }
+
+    pub fn set_env_var(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        let state = self.uc.get_data_mut();
+        state.environment_strings.remove(&name);
+        state.environment.insert(name, value.into());
+    }
+
+    pub fn remove_env_var(&mut self, name: &str) {
+        let state = self.uc.get_data_mut();
+        state.environment_strings.remove(name);
+        state.environment.remove(name);
+    }
+
+    /// Serial for `__system_property_get`; `None` reports Android's placeholder.
+    pub fn set_serial_number(&mut self, serial_number: Option<String>) {
+        self.uc.get_data_mut().serial_number = serial_number;
+    }
+
+    /// Makes `arc4random`/`getrandom` deterministic; `None` reseeds from host entropy.
+    pub fn set_random_seed(&mut self, seed: Option<u64>) {
+        self.uc.get_data_mut().rng = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+    }
+
+    /// Emits one JSON line per import stub call (name, decoded args, return value,
+    /// errno) to `writer`; `None` turns tracing off.
+    pub fn set_syscall_trace(&mut self, writer: Option<Box<dyn Write>>) {
+        self.uc.get_data_mut().syscall_trace = writer.map(SyscallTracer::new);
+    }
+
+    /// Routes calls to the imported `symbol` to `stub` instead of the built-in stub.
+    pub fn set_stub_override(&mut self, symbol: impl Into<String>, stub: StubOverride) {
+        self.uc
+            .get_data_mut()
+            .stub_overrides
+            .insert(symbol.into(), stub);
+    }
+
+    /// Restores the built-in stub for `symbol`; returns whether an override was set.
+    pub fn clear_stub_override(&mut self, symbol: &str) -> bool {
+        self.uc.get_data_mut().stub_overrides.remove(symbol)
+    }
+
+    pub fn set_pthread_options(&mut self, options: PthreadOptions) {
+        self.uc.get_data_mut().pthread_options = options;
+    }
+
+    pub fn set_clock(&mut self, clock: Box<dyn GuestClock>) {
+        self.uc.get_data_mut().clock = clock;
+    }
+
+    pub(crate) fn set_library_tag(&mut self, tag: Option<String>) {
+        self.uc.get_data_mut().library_tag = tag;
+    }
+
+    pub(crate) fn library_tag(&self) -> Option<&str> {
+        self.uc.get_data().library_tag.as_deref()
+    }
+
+    /// Redirects `./anisette/...` guest paths into `./anisette/{namespace}/...`.
+    pub fn set_provisioning_namespace(&mut self, namespace: Option<String>) {
+        self.uc.get_data_mut().provisioning_namespace = namespace;
+    }
+
+    /// Stores the guest's `./anisette` under `./profiles/{profile}/anisette`, where
+    /// [`init_idbfs_for_profile`](crate::init_idbfs_for_profile) mounts it. The
+    /// guest keeps seeing `./anisette`, the only directory its stubs allow.
+    pub fn set_profile(&mut self, profile: Option<&str>) -> Result<(), VmError> {
+        let root = match profile {
+            Some(profile) => Some(format!(
+                ".{}",
+                profile_mount_path(GUEST_PROVISIONING_DIR, profile).map_err(io::Error::from)?
+            )),
+            None => None,
+        };
+        self.uc.get_data_mut().provisioning_root = root;
+        Ok(())
+    }
+
+    /// Where the guest's `./anisette` is stored; see [`EmuCore::set_profile`].
+    pub(crate) fn provisioning_root(&self) -> &str {
+        provisioning_root(self.uc.get_data())
+    }
+
+    /// Where a guest path under `./anisette` that already names its DSID
+    /// namespace (as in a state bundle) is stored.
+    pub(crate) fn storage_path(&self, path: &str) -> String {
+        rebase_guest_path(self.provisioning_root(), path)
+    }
+
     pub fn load_library(&mut self, library_name: &str) -> Result<usize, VmError> {
         load_library_by_name(&mut self.uc, library_name)
     }
@@ -93,20 +246,97 @@ impl EmuCore {
         if args.len() > ARG_REGS.len() {
             return Err(VmError::TooManyArguments(args.len()));
         }
+        let _span = tracing::debug_span!("invoke_cdecl", address = %format_args!("0x{address:X}"))
+            .entered();
 
         for (index, value) in args.iter().enumerate() {
             self.uc.reg_write(ARG_REGS[index], *value)?;
-            debug_print(format!("X{index}: 0x{value:08X}"));
+            debug_print!(format!("X{index}: 0x{value:08X}"));
         }
 
-        debug_print(format!("Calling 0x{address:X}"));
+        debug_print!(format!("Calling 0x{address:X}"));
         self.uc
             .reg_write(RegisterARM64::SP, STACK_ADDRESS + STACK_SIZE)?;
         self.uc.reg_write(RegisterARM64::LR, RETURN_ADDRESS)?;
-        self.uc.emu_start(address, RETURN_ADDRESS, 0, 0)?;
+        self.uc.get_data_mut().stub_error = None;
+        if let Err(err) = run_until_return(&mut self.uc, address) {
+            #[cfg(not(feature = "minimal"))]
+            if let Some(dir) = &self.fault_dump_dir
+                && matches!(err.root(), VmError::EmulationFault { .. })
+            {
+                dump_fault(&self.uc, dir);
+            }
+            return Err(err);
+        }
         Ok(self.uc.reg_read(RegisterARM64::X0)?)
     }
 
+    /// Writes `ranges` of guest memory (all of it when empty), the registers and
+    /// the library map to `writer` as an ELF core file, for Ghidra, IDA or gdb.
+    #[cfg(not(feature = "minimal"))]
+    pub fn dump_memory(
+        &self,
+        ranges: &[Range<u64>],
+        mut writer: impl Write,
+    ) -> Result<(), VmError> {
+        write_core_dump(&self.uc, ranges, 0, &mut writer)
+    }
+
+    /// The `minimal` feature leaves the core dump writer out, so this always fails
+    /// with [`VmError::Unsupported`].
+    #[cfg(feature = "minimal")]
+    pub fn dump_memory(&self, _ranges: &[Range<u64>], _writer: impl Write) -> Result<(), VmError> {
+        Err(VmError::Unsupported("core dumps"))
+    }
+
+    /// Writes a full core dump to `dir` whenever a call stops on an emulation
+    /// fault; `None` turns it off. Ignored with the `minimal` feature.
+    pub fn set_fault_dump_dir(&mut self, dir: Option<PathBuf>) {
+        self.fault_dump_dir = dir;
+    }
+
+    /// Counts executed guest instructions, at the cost of a hook on every basic
+    /// block. Off by default.
+    pub fn set_instruction_counting(&mut self, enabled: bool) -> Result<(), VmError> {
+        match (enabled, self.instruction_hook) {
+            (true, None) => {
+                let hook = self.uc.add_block_hook(1, 0, |uc, _address, size| {
+                    // AArch64 instructions are all 4 bytes.
+                    uc.get_data_mut().instructions += u64::from(size / 4);
+                })?;
+                self.instruction_hook = Some(hook);
+            }
+            (false, Some(hook)) => {
+                self.uc.remove_hook(hook)?;
+                self.instruction_hook = None;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn stub_stats(&self) -> &StubStats {
+        &self.uc.get_data().stub_stats
+    }
+
+    pub fn reset_stub_stats(&mut self) {
+        self.uc.get_data_mut().stub_stats = StubStats::default();
+    }
+
+    /// Instructions executed and guest bytes allocated so far, for metrics.
+    pub(crate) fn counters(&self) -> (u64, u64) {
+        let state = self.uc.get_data();
+        (
+            state.instructions,
+            state.temp_allocator.used() + state.malloc_allocator.used(),
+        )
+    }
+
+    /// Recent stub calls, allocations and `emu_start` transitions, oldest first.
+    pub fn flight_record(&self) -> Vec<String> {
+        self.uc.get_data().flight_recorder.dump()
+    }
+
     pub fn alloc_data(&mut self, data: &[u8]) -> Result<u64, VmError> {
         alloc_temp_bytes(&mut self.uc, data, 0xCC)
     }
@@ -116,10 +346,36 @@ impl EmuCore {
         alloc_temp_bytes(&mut self.uc, &data, 0xAA)
     }
 
+    /// Like [`EmuCore::alloc_data`], but in the scratch region, which
+    /// [`EmuCore::reset_scratch`] reclaims. Falls back to the temp region when
+    /// the scratch region is full.
+    pub fn alloc_scratch_data(&mut self, data: &[u8]) -> Result<u64, VmError> {
+        alloc_scratch_bytes(&mut self.uc, data, 0xCC)
+    }
+
+    /// Like [`EmuCore::alloc_temporary`], in the scratch region.
+    pub fn alloc_scratch(&mut self, length: usize) -> Result<u64, VmError> {
+        let data = vec![0xAA; length.max(1)];
+        alloc_scratch_bytes(&mut self.uc, &data, 0xAA)
+    }
+
+    /// Reclaims every scratch allocation; pointers into it must not be used again.
+    pub fn reset_scratch(&mut self) {
+        if let Some(scratch) = &mut self.uc.get_data_mut().scratch_allocator {
+            scratch.reset();
+        }
+    }
+
     pub fn read_data(&self, address: u64, length: usize) -> Result<Vec<u8>, VmError> {
         Ok(self.uc.mem_read_as_vec(address, length)?)
     }
 
+    /// Fills `buffer` from guest memory at `address` without an intermediate copy.
+    pub fn read_into(&self, address: u64, buffer: &mut [u8]) -> Result<(), VmError> {
+        self.uc.mem_read(address, buffer)?;
+        Ok(())
+    }
+
     pub fn write_data(&mut self, address: u64, data: &[u8]) -> Result<(), VmError> {
         self.uc.mem_write(address, data)?;
         Ok(())
@@ -165,7 +421,10 @@ pub(crate) fn ensure_zero_return(name: &'static str, value: u64) -> Result<(), V
     if code == 0 {
         Ok(())
     } else {
-        Err(VmError::AdiCallFailed { name, code })
+        Err(VmError::AdiCallFailed {
+            name,
+            code: AdiErrorCode::from_raw(code),
+        })
     }
 }
 
@@ -177,7 +436,8 @@ fn normalize_library_root(path: &str) -> String {
 
     let mut out = String::with_capacity(trimmed.len());
     let mut prev_slash = false;
-    for ch in trimmed.chars() {
+    // Windows hosts may hand us `\`-separated paths; the guest only understands `/`.
+    for ch in trimmed.chars().map(|ch| if ch == '\\' { '/' } else { ch }) {
         if ch == '/' {
             if prev_slash {
                 continue;
@@ -208,19 +468,77 @@ fn alloc_temp_bytes(
         state.temp_allocator.alloc(length)?
     };
 
-    debug_print(format!(
+    debug_print!(format!(
         "Allocating at 0x{address:X}; bytes 0x{:X}/0x{length:X}",
         data.len()
     ));
+    record_event(
+        uc,
+        FlightEvent::Alloc {
+            allocator: "temp",
+            address,
+            size: length,
+        },
+    );
     uc.mem_map(address, as_usize(length)?, Permission::ALL)?;
+    write_padded(uc, address, length, data, padding_byte)?;
+    Ok(address)
+}
 
+fn alloc_scratch_bytes(
+    uc: &mut Unicorn<'_, RuntimeState>,
+    data: &[u8],
+    padding_byte: u8,
+) -> Result<u64, VmError> {
+    let request = data.len().max(1) as u64;
+    let length = align_up(request, PAGE_SIZE);
+    let allocated = match uc.get_data_mut().scratch_allocator.as_mut() {
+        Some(scratch) => scratch.alloc(length),
+        None => {
+            let base = uc.get_data_mut().temp_allocator.alloc(SCRATCH_SIZE)?;
+            uc.mem_map(base, as_usize(SCRATCH_SIZE)?, Permission::ALL)?;
+            let mut scratch = Allocator::new(base, SCRATCH_SIZE);
+            let allocated = scratch.alloc(length);
+            uc.get_data_mut().scratch_allocator = Some(scratch);
+            allocated
+        }
+    };
+    let address = match allocated {
+        Ok(address) => address,
+        Err(VmError::AllocatorOom { .. }) => {
+            debug_print!("Scratch region full, using the temp region");
+            return alloc_temp_bytes(uc, data, padding_byte);
+        }
+        Err(err) => return Err(err),
+    };
+    write_padded(uc, address, length, data, padding_byte)?;
+    Ok(address)
+}
+
+/// Fills `length` bytes at `address` with `data` followed by `padding_byte`.
+fn write_padded(
+    uc: &mut Unicorn<'_, RuntimeState>,
+    address: u64,
+    length: u64,
+    data: &[u8],
+    padding_byte: u8,
+) -> Result<(), VmError> {
     let mut buffer = vec![padding_byte; length as usize];
     if !data.is_empty() {
         buffer[..data.len()].copy_from_slice(data);
     }
     uc.mem_write(address, &buffer)?;
+    Ok(())
+}
 
-    Ok(address)
+pub(crate) fn alloc_guest_c_string(
+    uc: &mut Unicorn<'_, RuntimeState>,
+    value: &str,
+) -> Result<u64, VmError> {
+    let mut bytes = Vec::with_capacity(value.len() + 1);
+    bytes.extend_from_slice(value.as_bytes());
+    bytes.push(0);
+    alloc_temp_bytes(uc, &bytes, 0)
 }
 
 pub(crate) fn set_errno(uc: &mut Unicorn<'_, RuntimeState>, value: u32) -> Result<(), VmError> {
@@ -239,13 +557,84 @@ pub(crate) fn ensure_errno_address(uc: &mut Unicorn<'_, RuntimeState>) -> Result
     Ok(address)
 }
 
+/// Calls a guest function from inside an import hook, below the caller's stack frame.
+///
+/// All general-purpose registers are restored afterwards and PC is pointed at the
+/// import's return address, so the interrupted guest code resumes as if the import
+/// had returned normally (the caller still sets X0).
+pub(crate) fn invoke_nested_cdecl(
+    uc: &mut Unicorn<'_, RuntimeState>,
+    address: u64,
+    args: &[u64],
+) -> Result<u64, VmError> {
+    if args.len() > ARG_REGS.len() {
+        return Err(VmError::TooManyArguments(args.len()));
+    }
+
+    let mut saved = Vec::with_capacity(ARG_REGS.len() + 4);
+    for reg in ARG_REGS.iter().copied().chain([
+        RegisterARM64::FP,
+        RegisterARM64::LR,
+        RegisterARM64::SP,
+        RegisterARM64::NZCV,
+    ]) {
+        saved.push((reg, uc.reg_read(reg)?));
+    }
+
+    let sp = uc.reg_read(RegisterARM64::SP)?;
+    for (index, value) in args.iter().enumerate() {
+        uc.reg_write(ARG_REGS[index], *value)?;
+    }
+    uc.reg_write(
+        RegisterARM64::SP,
+        align_down(sp.saturating_sub(NESTED_CALL_STACK_GAP), 16),
+    )?;
+    uc.reg_write(RegisterARM64::LR, RETURN_ADDRESS)?;
+
+    debug_print!(format!("Nested call to 0x{address:X}"));
+    run_until_return(uc, address)?;
+    let ret = uc.reg_read(RegisterARM64::X0)?;
+
+    for (reg, value) in saved {
+        uc.reg_write(reg, value)?;
+    }
+    let return_address = uc.reg_read(RegisterARM64::LR)?;
+    uc.reg_write(RegisterARM64::PC, return_address)?;
+    Ok(ret)
+}
+
+/// Runs guest code from `address` until it returns to [`RETURN_ADDRESS`], noting
+/// both transitions in the flight recorder.
+fn run_until_return(uc: &mut Unicorn<'_, RuntimeState>, address: u64) -> Result<(), VmError> {
+    record_event(uc, FlightEvent::EmuStart { address });
+    let result = uc.emu_start(address, RETURN_ADDRESS, 0, 0);
+    let result = take_stub_error(uc).and(result.map_err(VmError::from));
+    let pc = uc.reg_read(RegisterARM64::PC).unwrap_or(0);
+    let error = result.as_ref().err().map(ToString::to_string);
+    record_event(uc, FlightEvent::EmuStop { pc, error });
+    result
+}
+
+pub(crate) fn record_event(uc: &mut Unicorn<'_, RuntimeState>, event: FlightEvent) {
+    uc.get_data_mut().flight_recorder.record(event);
+}
+
+/// Surfaces an error recorded by a hook, which can only stop emulation. It takes
+/// precedence over the generic error Unicorn returns for the same stop.
+fn take_stub_error(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    match uc.get_data_mut().stub_error.take() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
 pub(crate) fn load_library_by_name(
     uc: &mut Unicorn<'_, RuntimeState>,
     library_name: &str,
 ) -> Result<usize, VmError> {
     for (index, library) in uc.get_data().loaded_libraries.iter().enumerate() {
         if library.name == library_name {
-            debug_print("Library already loaded");
+            debug_print!("Library already loaded");
             return Ok(index);
         }
     }
@@ -266,22 +655,15 @@ pub(crate) fn load_library_by_name(
         state.library_allocator.alloc(LIB_RESERVATION_SIZE)?
     };
 
-    let mut symbols = Vec::with_capacity(elf.dynsyms.len());
-    let mut symbols_by_name = HashMap::new();
-
+    let mut symbols = SymbolTable::with_capacity(elf.dynsyms.len());
     for (index, sym) in elf.dynsyms.iter().enumerate() {
-        let name = elf.dynstrtab.get_at(sym.st_name).unwrap_or("").to_string();
+        let name = elf.dynstrtab.get_at(sym.st_name).unwrap_or("");
         let resolved = if sym.st_shndx == SHN_UNDEF as usize {
             IMPORT_ADDRESS + (library_index as u64) * IMPORT_LIBRARY_STRIDE + (index as u64) * 4
         } else {
             base.wrapping_add(sym.st_value)
         };
-
-        if !name.is_empty() {
-            symbols_by_name.entry(name.clone()).or_insert(resolved);
-        }
-
-        symbols.push(SymbolEntry { name, resolved });
+        symbols.push(name, resolved);
     }
 
     for ph in &elf.program_headers {
@@ -294,13 +676,13 @@ pub(crate) fn load_library_by_name(
             continue;
         }
 
-        debug_print(format!(
+        debug_print!(format!(
             "Mapping at 0x{map_start:X}-0x{map_end:X} (0x{seg_addr:X}-0x{:X}); bytes 0x{map_len:X}",
             seg_addr + map_len.saturating_sub(1)
         ));
 
         if ph.p_type != PT_LOAD || ph.p_memsz == 0 {
-            debug_print(format!(
+            debug_print!(format!(
                 "- Skipping p_type={} offset=0x{:X} vaddr=0x{:X}",
                 ph.p_type, ph.p_offset, ph.p_vaddr
             ));
@@ -322,20 +704,17 @@ pub(crate) fn load_library_by_name(
             return Err(VmError::InvalidElfRange);
         }
 
-        let mut bytes = vec![0_u8; map_len as usize];
-        let start_offset = (seg_addr - map_start) as usize;
-
+        // Unicorn zero-fills new mappings, so the BSS tail needs no write.
         if file_len > 0 {
+            let start_offset = (seg_addr - map_start) as usize;
             let dest_end = start_offset
                 .checked_add(file_len)
                 .ok_or(VmError::InvalidElfRange)?;
-            if dest_end > bytes.len() {
+            if dest_end > map_len as usize {
                 return Err(VmError::InvalidElfRange);
             }
-            bytes[start_offset..dest_end].copy_from_slice(&elf_data[file_offset..file_end]);
+            uc.mem_write(seg_addr, &elf_data[file_offset..file_end])?;
         }
-
-        uc.mem_write(map_start, &bytes)?;
     }
 
     for rela in elf.dynrelas.iter() {
@@ -348,8 +727,8 @@ pub(crate) fn load_library_by_name(
 
     let loaded = LoadedLibrary {
         name: library_name.to_string(),
+        base,
         symbols,
-        symbols_by_name,
     };
 
     uc.get_data_mut().loaded_libraries.push(loaded);
@@ -362,7 +741,7 @@ fn apply_relocation(
     base: u64,
     relocation: &Reloc,
     library_name: &str,
-    symbols: &[SymbolEntry],
+    symbols: &SymbolTable,
 ) -> Result<(), VmError> {
     if relocation.r_type == 0 {
         return Ok(());
@@ -371,14 +750,13 @@ fn apply_relocation(
     let relocation_addr = base.wrapping_add(relocation.r_offset);
     let addend = relocation.r_addend.unwrap_or(0);
 
-    let symbol_address = if relocation.r_sym < symbols.len() {
-        symbols[relocation.r_sym].resolved
-    } else {
-        return Err(VmError::SymbolIndexOutOfRange {
-            library: library_name.to_string(),
-            index: relocation.r_sym,
-        });
-    };
+    let symbol_address =
+        symbols
+            .resolved(relocation.r_sym)
+            .ok_or_else(|| VmError::SymbolIndexOutOfRange {
+                library: library_name.to_string(),
+                index: relocation.r_sym,
+            })?;
 
     let value = match relocation.r_type {
         goblin::elf64::reloc::R_AARCH64_ABS64 | goblin::elf64::reloc::R_AARCH64_GLOB_DAT => {
@@ -405,9 +783,8 @@ pub(crate) fn resolve_symbol_from_loaded_library_by_name(
         .ok_or(VmError::LibraryNotLoaded(library_index))?;
 
     library
-        .symbols_by_name
-        .get(symbol_name)
-        .copied()
+        .symbols
+        .find(symbol_name)
         .ok_or_else(|| VmError::SymbolNotFound {
             library: library.name.clone(),
             symbol: symbol_name.to_string(),
@@ -426,3 +803,190 @@ pub(crate) fn read_c_string(
         .ok_or(VmError::UnterminatedCString(address))?;
     Ok(String::from_utf8_lossy(&bytes[..len]).into_owned())
 }
+
+#[cfg(test)]
+mod tests {
+    use unicorn_engine::RegisterARM64;
+
+    use super::{EmuCore, ensure_errno_address, set_errno};
+    use crate::constants::{
+        ARG_REGS, EACCES, EBADF, EINVAL, ENOENT, GUEST_ADI_PB_PATH, GUEST_PROVISIONING_DIR,
+        MAX_GETRANDOM_LEN, O_CREAT, O_WRONLY, SIGABRT, SIGNAL_EXIT_BASE, W_OK, X_OK,
+    };
+    use crate::errors::VmError;
+    use crate::stub::handle_stub_by_name;
+    use crate::vfs::MemoryFs;
+
+    /// Calls the stub for `name` with `args` in X0.. and returns X0.
+    fn call_stub(core: &mut EmuCore, name: &str, args: &[u64]) -> u64 {
+        for (register, arg) in ARG_REGS.iter().zip(args) {
+            core.uc.reg_write(*register, *arg).expect("write argument");
+        }
+        handle_stub_by_name(&mut core.uc, name).expect(name);
+        core.uc.reg_read(RegisterARM64::X0).expect("read x0")
+    }
+
+    fn errno(core: &mut EmuCore) -> u32 {
+        let address = ensure_errno_address(&mut core.uc).expect("errno");
+        let bytes = core.read_data(address, 4).expect("read errno");
+        u32::from_le_bytes(bytes.try_into().expect("4 bytes"))
+    }
+
+    #[test]
+    fn scratch_is_reused_after_reset() {
+        let mut core = EmuCore::new_arm64().expect("emulator");
+        let first = core.alloc_scratch_data(b"cpim").expect("alloc");
+        let (_, allocated) = core.counters();
+
+        for _ in 0..1000 {
+            core.reset_scratch();
+            assert_eq!(core.alloc_scratch_data(b"spim").expect("alloc"), first);
+            core.alloc_scratch(8).expect("alloc");
+        }
+        assert_eq!(core.counters().1, allocated);
+        assert_eq!(core.read_data(first, 4).expect("read"), b"spim");
+    }
+
+    #[test]
+    fn profile_moves_adi_pb_without_changing_guest_paths() {
+        let mut core = EmuCore::new_arm64().expect("emulator");
+        core.set_guest_fs(Box::new(MemoryFs::new()));
+        core.set_profile(Some("work")).expect("profile");
+
+        let dir = core
+            .alloc_scratch_data(format!("{GUEST_PROVISIONING_DIR}\0").as_bytes())
+            .expect("alloc");
+        assert_eq!(call_stub(&mut core, "mkdir", &[dir, 0o755]), 0);
+        let path = core
+            .alloc_scratch_data(format!("{GUEST_ADI_PB_PATH}\0").as_bytes())
+            .expect("alloc");
+        let fd = call_stub(&mut core, "open", &[path, O_WRONLY | O_CREAT, 0o644]);
+        assert_ne!(fd, u64::MAX);
+
+        let stored = "./profiles/work/anisette/adi.pb";
+        assert_eq!(core.mapped_guest_path(GUEST_ADI_PB_PATH), stored);
+        assert!(core.guest_fs_mut().symlink_metadata(stored).is_ok());
+        assert!(
+            core.guest_fs_mut()
+                .symlink_metadata(GUEST_ADI_PB_PATH)
+                .is_err()
+        );
+
+        core.set_provisioning_namespace(Some("123".to_string()));
+        assert_eq!(
+            core.mapped_guest_path(GUEST_ADI_PB_PATH),
+            "./profiles/work/anisette/123/adi.pb"
+        );
+        assert_eq!(
+            core.storage_path("./anisette/456/adi.pb"),
+            "./profiles/work/anisette/456/adi.pb"
+        );
+        assert!(core.set_profile(Some("../home")).is_err());
+    }
+
+    #[test]
+    fn unknown_fds_fail_with_ebadf() {
+        let mut core = EmuCore::new_arm64().expect("emulator");
+        let buffer = core.alloc_scratch(16).expect("alloc");
+        let calls: [(&str, &[u64]); 6] = [
+            ("fstat", &[7, buffer]),
+            ("ftruncate", &[7, 0]),
+            ("read", &[7, buffer, 16]),
+            ("write", &[7, buffer, 16]),
+            ("fsync", &[u64::MAX]),
+            ("close", &[7]),
+        ];
+        for (name, args) in calls {
+            set_errno(&mut core.uc, 0).expect("clear errno");
+            assert_eq!(call_stub(&mut core, name, args), u64::MAX, "{name}");
+            assert_eq!(errno(&mut core), EBADF, "{name}");
+        }
+    }
+
+    #[test]
+    fn path_queries_stay_inside_anisette() {
+        let mut core = EmuCore::new_arm64().expect("emulator");
+        core.set_guest_fs(Box::new(MemoryFs::new()));
+        let stat = core.alloc_scratch(0x100).expect("alloc");
+        let dir = core
+            .alloc_scratch_data(format!("{GUEST_PROVISIONING_DIR}\0").as_bytes())
+            .expect("alloc");
+        let adi_pb = core
+            .alloc_scratch_data(format!("{GUEST_ADI_PB_PATH}\0").as_bytes())
+            .expect("alloc");
+        assert_eq!(call_stub(&mut core, "mkdir", &[dir, 0o755]), 0);
+        let fd = call_stub(&mut core, "open", &[adi_pb, O_WRONLY | O_CREAT, 0o644]);
+        assert_ne!(fd, u64::MAX);
+
+        let outside = core
+            .alloc_scratch_data(b"./anisette/../etc\0")
+            .expect("alloc");
+        for (name, args) in [
+            ("stat", [outside, stat]),
+            ("lstat", [outside, stat]),
+            ("access", [outside, 0]),
+        ] {
+            set_errno(&mut core.uc, 0).expect("clear errno");
+            assert_eq!(call_stub(&mut core, name, &args), u64::MAX, "{name}");
+            assert_eq!(errno(&mut core), ENOENT, "{name}");
+        }
+
+        assert_eq!(call_stub(&mut core, "stat", &[dir, stat]), 0);
+        assert_eq!(call_stub(&mut core, "access", &[adi_pb, W_OK]), 0);
+        assert_eq!(call_stub(&mut core, "access", &[adi_pb, X_OK]), u64::MAX);
+        assert_eq!(errno(&mut core), EACCES);
+    }
+
+    #[test]
+    fn memmove_overlaps_across_chunks() {
+        let mut core = EmuCore::new_arm64().expect("emulator");
+        let data: Vec<u8> = (0..0x30000_u32).map(|i| (i % 251) as u8).collect();
+        let base = core.alloc_temporary(data.len() + 0x100).expect("alloc");
+        core.write_data(base, &data).expect("write");
+        let length = data.len() as u64;
+
+        call_stub(&mut core, "memmove", &[base + 0x10, base, length]);
+        assert_eq!(core.read_data(base + 0x10, data.len()).expect("read"), data);
+
+        call_stub(&mut core, "memmove", &[base, base + 0x10, length]);
+        assert_eq!(core.read_data(base, data.len()).expect("read"), data);
+    }
+
+    #[test]
+    fn huge_memset_faults_instead_of_allocating() {
+        let mut core = EmuCore::new_arm64().expect("emulator");
+        let dst = core.alloc_scratch(16).expect("alloc");
+        for (register, arg) in ARG_REGS.iter().zip([dst, 0, u64::MAX / 2]) {
+            core.uc.reg_write(*register, arg).expect("write argument");
+        }
+        assert!(handle_stub_by_name(&mut core.uc, "memset").is_err());
+    }
+
+    #[test]
+    fn getrandom_returns_a_short_count_for_huge_requests() {
+        let mut core = EmuCore::new_arm64().expect("emulator");
+        let buffer = core
+            .alloc_temporary(MAX_GETRANDOM_LEN as usize)
+            .expect("alloc");
+        let count = call_stub(&mut core, "getrandom", &[buffer, u64::MAX / 2, 0]);
+        assert_eq!(count, MAX_GETRANDOM_LEN);
+    }
+
+    #[test]
+    fn raise_rejects_out_of_range_signals() {
+        let mut core = EmuCore::new_arm64().expect("emulator");
+        for signal in [-1_i64, 65, i64::from(i32::MAX)] {
+            set_errno(&mut core.uc, 0).expect("clear errno");
+            assert_eq!(call_stub(&mut core, "raise", &[signal as u64]), u64::MAX);
+            assert_eq!(errno(&mut core), EINVAL, "{signal}");
+        }
+
+        core.uc
+            .reg_write(ARG_REGS[0], SIGABRT as u64)
+            .expect("write argument");
+        assert!(matches!(
+            handle_stub_by_name(&mut core.uc, "raise"),
+            Err(VmError::GuestAborted { code }) if code == SIGNAL_EXIT_BASE + SIGABRT
+        ));
+    }
+}
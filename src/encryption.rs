@@ -0,0 +1,112 @@
+use std::fmt;
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use anyhow::{Result, anyhow, bail};
+use rand::RngCore;
+
+/// Prefix of every encrypted file, so readers can tell them from plaintext.
+const MAGIC: &[u8] = b"ANISENC1";
+const NONCE_LEN: usize = 12;
+
+/// A caller-supplied 256-bit key for keeping `device.json` and exported
+/// [`ProvisioningState`](crate::ProvisioningState) bundles encrypted at rest
+/// (AES-256-GCM).
+///
+/// Each file is `ANISENC1 || nonce || ciphertext`. The kind of file is bound in as
+/// associated data, so a device file cannot be swapped in for a state bundle.
+#[derive(Clone)]
+pub struct StorageKey([u8; 32]);
+
+impl StorageKey {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self(key)
+    }
+
+    /// A random key; store it somewhere other than the files it protects.
+    pub fn generate() -> Self {
+        let mut key = [0_u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self(key)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Whether `data` looks like output of [`encrypt`](Self::encrypt).
+    pub fn is_encrypted(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    pub(crate) fn encrypt(&self, purpose: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0_u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: purpose.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("failed to encrypt {purpose}"))?;
+
+        let mut out = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    pub(crate) fn decrypt(&self, purpose: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let Some(rest) = data.strip_prefix(MAGIC) else {
+            bail!("{purpose} is not encrypted");
+        };
+        if rest.len() < NONCE_LEN {
+            bail!("encrypted {purpose} is truncated");
+        }
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: purpose.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow!("failed to decrypt {purpose}: wrong key or corrupted data"))
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.0))
+    }
+}
+
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StorageKey(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StorageKey;
+
+    #[test]
+    fn ciphertext_is_bound_to_key_and_purpose() {
+        let key = StorageKey::generate();
+        let sealed = key.encrypt("device.json", b"{}").expect("encrypt");
+        assert!(StorageKey::is_encrypted(&sealed));
+        assert_eq!(key.decrypt("device.json", &sealed).expect("decrypt"), b"{}");
+
+        assert!(key.decrypt("state", &sealed).is_err());
+        assert!(
+            StorageKey::generate()
+                .decrypt("device.json", &sealed)
+                .is_err()
+        );
+        assert!(key.decrypt("device.json", b"{}").is_err());
+    }
+}
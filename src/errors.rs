@@ -1,3 +1,7 @@
+use std::ffi::NulError;
+use std::{fmt, io};
+
+use serde::Serialize;
 use thiserror::Error;
 use unicorn_engine::unicorn_const::uc_error;
 
@@ -21,10 +25,51 @@ pub enum VmError {
     SymbolIndexOutOfRange { library: String, index: usize },
     #[error("unsupported relocation type: {0}")]
     UnsupportedRelocation(u32),
+    #[error("unsupported {name} build (sha256 {sha256}), expected version {expected}")]
+    UnsupportedLibrary {
+        name: String,
+        sha256: String,
+        expected: String,
+    },
+    #[error("invalid ADI identifier: {0}")]
+    InvalidIdentifier(String),
+    #[error("invalid state blob: {0}")]
+    InvalidStateBlob(String),
     #[error("invalid ELF file range")]
     InvalidElfRange,
+    #[error("{name} reported corrupted provisioning data ({code}); adi.pb was reset, re-provision")]
+    ProvisioningReset {
+        name: &'static str,
+        code: AdiErrorCode,
+    },
+    #[error("provisioning data {path} is corrupted ({reason}); reset it and re-provision")]
+    ProvisioningCorrupted { path: String, reason: String },
+    /// The guest touched unmapped memory. `registers` holds X0-X28, FP, LR and SP
+    /// at the fault (nothing with the `minimal` feature); `faulting_library` is
+    /// `library+0xoffset` of the PC when it is inside a loaded library.
+    #[error(
+        "{access} of unmapped memory at 0x{address:X} (pc 0x{pc:X} in {})",
+        .faulting_library.as_deref().unwrap_or("unknown code")
+    )]
+    EmulationFault {
+        pc: u64,
+        access: MemoryAccess,
+        address: u64,
+        registers: Vec<(&'static str, u64)>,
+        faulting_library: Option<String>,
+    },
+    /// An error raised while the guest was running `call`, e.g.
+    /// `ADIProvisioningStart(dsid=-2)`.
+    #[error("while calling {call}: {source}")]
+    InCall {
+        call: String,
+        #[source]
+        source: Box<VmError>,
+    },
     #[error("unhandled import: {0}")]
     UnhandledImport(String),
+    #[error("guest aborted with code {code}")]
+    GuestAborted { code: i32 },
     #[error("invalid import address: 0x{0:X}")]
     InvalidImportAddress(u64),
     #[error("invalid dlopen handle: {0}")]
@@ -34,13 +79,24 @@ pub enum VmError {
     #[error("too many cdecl args: {0} (max 29)")]
     TooManyArguments(usize),
     #[error("adi call failed: {name} returned {code}")]
-    AdiCallFailed { name: &'static str, code: i32 },
+    AdiCallFailed {
+        name: &'static str,
+        code: AdiErrorCode,
+    },
     #[error("unterminated C string at 0x{0:X}")]
     UnterminatedCString(u64),
     #[error("empty path")]
     EmptyPath,
+    #[error("{what} buffer too small: {needed} bytes needed, {capacity} available")]
+    BufferTooSmall {
+        what: &'static str,
+        needed: usize,
+        capacity: usize,
+    },
     #[error("integer conversion failed for value: {0}")]
     IntegerOverflow(u64),
+    #[error("{0} are not available with the minimal feature")]
+    Unsupported(&'static str),
 }
 
 impl From<uc_error> for VmError {
@@ -48,3 +104,408 @@ impl From<uc_error> for VmError {
         Self::Unicorn(value)
     }
 }
+
+impl VmError {
+    /// Wraps `self` with the guest call it happened in.
+    pub fn in_call(self, call: impl Into<String>) -> Self {
+        Self::InCall {
+            call: call.into(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The error without any [`VmError::InCall`] context around it.
+    pub fn root(&self) -> &VmError {
+        match self {
+            Self::InCall { source, .. } => source.root(),
+            err => err,
+        }
+    }
+
+    /// The ADI return code behind this error, if it came from an ADI entry point.
+    pub fn adi_code(&self) -> Option<AdiErrorCode> {
+        match self.root() {
+            Self::AdiCallFailed { code, .. } | Self::ProvisioningReset { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+
+    /// What kind of failure this is; see [`ErrorCategory`].
+    pub fn category(&self) -> ErrorCategory {
+        match self.root() {
+            Self::Io(_) => ErrorCategory::Io,
+            Self::ProvisioningReset { .. } | Self::ProvisioningCorrupted { .. } => {
+                ErrorCategory::NotProvisioned
+            }
+            Self::AdiCallFailed { code, .. }
+                if code.is_not_provisioned() || code.is_corrupted_provisioning() =>
+            {
+                ErrorCategory::NotProvisioned
+            }
+            Self::AdiCallFailed { .. } => ErrorCategory::Protocol,
+            Self::Elf(_)
+            | Self::LibraryNotRegistered(_)
+            | Self::SymbolNotFound { .. }
+            | Self::UnsupportedRelocation(_)
+            | Self::UnsupportedLibrary { .. }
+            | Self::InvalidIdentifier(_)
+            | Self::InvalidStateBlob(_)
+            | Self::InvalidElfRange
+            | Self::EmptyPath
+            | Self::BufferTooSmall { .. }
+            | Self::Unsupported(_) => ErrorCategory::Configuration,
+            Self::Unicorn(_)
+            | Self::AllocatorOom { .. }
+            | Self::LibraryNotLoaded(_)
+            | Self::SymbolIndexOutOfRange { .. }
+            | Self::EmulationFault { .. }
+            | Self::InCall { .. }
+            | Self::UnhandledImport(_)
+            | Self::GuestAborted { .. }
+            | Self::InvalidImportAddress(_)
+            | Self::InvalidDlopenHandle(_)
+            | Self::InvalidFileDescriptor(_)
+            | Self::TooManyArguments(_)
+            | Self::UnterminatedCString(_)
+            | Self::IntegerOverflow(_) => ErrorCategory::Emulation,
+        }
+    }
+
+    /// Whether repeating the same call unchanged may succeed: transient I/O
+    /// failures (a lock or storage briefly unavailable) and a provisioning
+    /// session the library no longer accepts. Not-provisioned errors need
+    /// re-provisioning first, and the rest will fail the same way again.
+    pub fn is_retryable(&self) -> bool {
+        match self.root() {
+            Self::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::ResourceBusy
+            ),
+            Self::AdiCallFailed { code, .. } => *code == AdiErrorCode::TrustKeyStateMismatch,
+            _ => false,
+        }
+    }
+}
+
+/// An error in a form hosts can serialize, e.g. as the body of a failed FFI,
+/// HTTP or WASM call, instead of a flattened message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorReport {
+    /// The [`AnisetteStatus`] code.
+    pub code: i32,
+    /// `None` for failures outside the emulator, e.g. network errors.
+    pub category: Option<ErrorCategory>,
+    pub message: String,
+    /// Raw return code of the ADI entry point that failed.
+    pub adi_code: Option<i32>,
+    /// Guest calls the error happened in, outermost first.
+    pub context: Vec<String>,
+    pub retryable: bool,
+    /// Emulator events leading up to the failure, oldest first; see
+    /// [`Adi::last_flight_record`](crate::Adi::last_flight_record).
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub flight_record: Vec<String>,
+}
+
+impl From<&VmError> for ErrorReport {
+    fn from(err: &VmError) -> Self {
+        let mut context = Vec::new();
+        let mut inner = err;
+        while let VmError::InCall { call, source } = inner {
+            context.push(call.clone());
+            inner = source;
+        }
+        Self {
+            code: AnisetteStatus::from(err).code(),
+            category: Some(err.category()),
+            message: inner.to_string(),
+            adi_code: err.adi_code().map(AdiErrorCode::raw),
+            context,
+            retryable: err.is_retryable(),
+            flight_record: Vec::new(),
+        }
+    }
+}
+
+impl ErrorReport {
+    /// Reports the [`VmError`] in `err`'s chain, if any; anything else (HTTP or
+    /// protocol failures while provisioning) becomes an [`AnisetteStatus::HttpError`].
+    pub fn from_anyhow(err: &anyhow::Error) -> Self {
+        match err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<VmError>())
+        {
+            Some(vm) => Self {
+                message: format!("{err:#}"),
+                ..Self::from(vm)
+            },
+            None => Self {
+                code: AnisetteStatus::HttpError.code(),
+                category: None,
+                message: format!("{err:#}"),
+                adi_code: None,
+                context: Vec::new(),
+                retryable: false,
+                flight_record: Vec::new(),
+            },
+        }
+    }
+}
+
+/// Kind of access in a [`VmError::EmulationFault`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryAccess {
+    Read,
+    Write,
+    Fetch,
+}
+
+impl fmt::Display for MemoryAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Fetch => "fetch",
+        })
+    }
+}
+
+/// Broad kind of a [`VmError`], for deciding between retrying, re-provisioning
+/// and alerting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// The emulator or the guest library misbehaved.
+    Emulation,
+    /// Reading or writing provisioning state failed.
+    Io,
+    /// An ADI entry point rejected the call.
+    Protocol,
+    /// The machine needs (re-)provisioning for this DSID.
+    NotProvisioned,
+    /// Bad libraries, identifiers or state; retrying will not help.
+    Configuration,
+}
+
+/// Return codes of the C/WASM exports, so bindings can branch on the kind of
+/// failure instead of matching `anisette_last_error` text. Exports return 0 (or a
+/// documented non-negative value) on success and one of the negative codes on
+/// failure.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AnisetteStatus {
+    Ok = 0,
+    /// Any failure without a more specific status.
+    Error = -1,
+    /// A null pointer, invalid UTF-8 or malformed JSON was passed in.
+    InvalidArgument = -2,
+    /// No `anisette_init_*` call has succeeded yet.
+    NotInitialized = -3,
+    /// The machine needs (re-)provisioning for this DSID.
+    NotProvisioned = -4,
+    /// An ADI entry point failed; `anisette_last_adi_code` has its return code.
+    AdiError = -5,
+    IoError = -6,
+    /// The provisioning servers could not be reached or rejected a request.
+    HttpError = -7,
+    /// The call needs an earlier one first, e.g. resuming without a provisioning flow.
+    InvalidState = -8,
+    /// The emulator itself failed (bad library, unhandled import, ...).
+    EmulatorError = -9,
+    /// `adi.pb` no longer matches the checksum recorded when it was written.
+    ProvisioningCorrupted = -10,
+}
+
+impl AnisetteStatus {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+impl From<&VmError> for AnisetteStatus {
+    fn from(err: &VmError) -> Self {
+        match err.root() {
+            VmError::AdiCallFailed { code, .. } if code.is_not_provisioned() => {
+                Self::NotProvisioned
+            }
+            VmError::ProvisioningReset { .. } => Self::NotProvisioned,
+            VmError::ProvisioningCorrupted { .. } => Self::ProvisioningCorrupted,
+            VmError::AdiCallFailed { .. } => Self::AdiError,
+            VmError::Io(_) => Self::IoError,
+            VmError::InvalidIdentifier(_)
+            | VmError::InvalidStateBlob(_)
+            | VmError::EmptyPath
+            | VmError::BufferTooSmall { .. } => Self::InvalidArgument,
+            _ => Self::EmulatorError,
+        }
+    }
+}
+
+/// Why an IDBFS mount or sync failed.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum IdbfsError {
+    #[error("invalid mount path '{path}'")]
+    InvalidPath {
+        path: String,
+        #[source]
+        source: NulError,
+    },
+    #[error("invalid profile name '{0}'")]
+    InvalidProfile(String),
+    /// `FS.syncfs` reported an error, e.g. IndexedDB quota exceeded.
+    #[error("IDBFS sync failed: {0}")]
+    SyncFailed(String),
+}
+
+impl From<&IdbfsError> for AnisetteStatus {
+    fn from(err: &IdbfsError) -> Self {
+        match err {
+            IdbfsError::InvalidPath { .. } | IdbfsError::InvalidProfile(_) => Self::InvalidArgument,
+            IdbfsError::SyncFailed(_) => Self::IoError,
+        }
+    }
+}
+
+impl From<IdbfsError> for io::Error {
+    fn from(err: IdbfsError) -> Self {
+        let kind = match err {
+            IdbfsError::InvalidPath { .. } | IdbfsError::InvalidProfile(_) => {
+                io::ErrorKind::InvalidInput
+            }
+            IdbfsError::SyncFailed(_) => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Why [`DeviceData::validate`](crate::DeviceData::validate) rejected a device.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DeviceDataError {
+    #[error("UUID '{0}' is not a UUID")]
+    InvalidUuid(String),
+    #[error("identifier '{0}' is not 16 hex characters")]
+    InvalidIdentifier(String),
+    #[error("localUUID '{0}' is not 64 hex characters")]
+    InvalidLocalUuid(String),
+    #[error("clientInfo '{0}' is not of the form '<model> <os;version;build> <client>'")]
+    InvalidClientInfo(String),
+}
+
+/// Known non-zero return codes of the ADI entry points.
+///
+/// Apple does not document these; the names follow what the codes have been
+/// observed to mean in practice. Anything else is kept verbatim in `Other`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AdiErrorCode {
+    InvalidParameters,
+    InvalidParameter,
+    InvalidTrustKey,
+    TrustKeyStateMismatch,
+    InvalidInputHeader,
+    NotProvisioned,
+    ProvisioningDataMismatch,
+    InvalidInputData,
+    Other(i32),
+}
+
+impl AdiErrorCode {
+    pub fn from_raw(code: i32) -> Self {
+        match code {
+            -45001 => Self::InvalidParameters,
+            -45002 => Self::InvalidParameter,
+            -45003 => Self::InvalidTrustKey,
+            -45006 => Self::TrustKeyStateMismatch,
+            -45018 => Self::InvalidInputHeader,
+            -45061 => Self::NotProvisioned,
+            -45063 => Self::ProvisioningDataMismatch,
+            -45076 => Self::InvalidInputData,
+            other => Self::Other(other),
+        }
+    }
+
+    pub fn raw(self) -> i32 {
+        match self {
+            Self::InvalidParameters => -45001,
+            Self::InvalidParameter => -45002,
+            Self::InvalidTrustKey => -45003,
+            Self::TrustKeyStateMismatch => -45006,
+            Self::InvalidInputHeader => -45018,
+            Self::NotProvisioned => -45061,
+            Self::ProvisioningDataMismatch => -45063,
+            Self::InvalidInputData => -45076,
+            Self::Other(code) => code,
+        }
+    }
+
+    pub fn is_not_provisioned(self) -> bool {
+        self == Self::NotProvisioned
+    }
+
+    /// The stored `adi.pb` exists but cannot be used; it has to be re-provisioned.
+    pub fn is_corrupted_provisioning(self) -> bool {
+        self == Self::ProvisioningDataMismatch
+    }
+
+    /// The call was rejected because of what the caller passed in (bad buffers,
+    /// a SPIM/PTM/TK blob that does not parse, ...), not because of device state.
+    pub fn is_invalid_input(self) -> bool {
+        matches!(
+            self,
+            Self::InvalidParameters
+                | Self::InvalidParameter
+                | Self::InvalidTrustKey
+                | Self::InvalidInputHeader
+                | Self::InvalidInputData
+        )
+    }
+}
+
+impl fmt::Display for AdiErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Other(code) => write!(f, "{code}"),
+            known => write!(f, "{} ({known:?})", known.raw()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{AdiErrorCode, AnisetteStatus, ErrorCategory, ErrorReport, VmError};
+
+    #[test]
+    fn errors_are_classified() {
+        let not_provisioned = VmError::AdiCallFailed {
+            name: "ADIOTPRequest",
+            code: AdiErrorCode::NotProvisioned,
+        };
+        assert_eq!(not_provisioned.category(), ErrorCategory::NotProvisioned);
+        assert!(!not_provisioned.is_retryable());
+
+        let timeout = VmError::Io(io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(timeout.category(), ErrorCategory::Io);
+        assert!(timeout.is_retryable());
+
+        let in_call = timeout.in_call("ADIOTPRequest(dsid=-2)");
+        assert_eq!(in_call.category(), ErrorCategory::Io);
+        assert!(in_call.is_retryable());
+        assert!(
+            in_call
+                .to_string()
+                .starts_with("while calling ADIOTPRequest(dsid=-2): ")
+        );
+        let report = ErrorReport::from(&in_call);
+        assert_eq!(report.context, ["ADIOTPRequest(dsid=-2)"]);
+        assert_eq!(report.code, AnisetteStatus::IoError.code());
+        assert!(report.retryable);
+
+        let bad_blob = VmError::InvalidStateBlob("truncated".to_string());
+        assert_eq!(bad_blob.category(), ErrorCategory::Configuration);
+        assert!(!bad_blob.is_retryable());
+    }
+}
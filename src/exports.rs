@@ -1,78 +1,204 @@
+//! The C ABI. Every export takes and returns only C-compatible types (integers,
+//! pointers, `#[repr(i32)]` enums and `extern "C"` callbacks); `include/anisette.h`
+//! is generated from this module with `script/gen-header.sh`. Bump
+//! [`ANISETTE_ABI_VERSION`] on any incompatible change to a signature or to the
+//! meaning of a return value.
+
 use std::cell::RefCell;
-use std::ffi::{CStr, c_char};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString, c_char, c_void};
 use std::fs;
-use std::path::Path;
 
-use crate::{Adi, AdiInit, sync_idbfs};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::constants::GUEST_PROVISIONING_DIR;
+use crate::idbfs::profile_mount_path;
+use crate::integrity::{checksum_file, sum_path};
+use crate::persistence::{PersistenceBackend, set_persistence_backend, with_persistence};
+use crate::provisioning_protocol::ProvisioningFlow;
+use crate::state::is_adi_pb;
+use crate::{
+    Adi, AdiInit, AnisetteStatus, DeviceData, DevicePreset, ErrorReport, HttpOptions, HttpResponse,
+    IdbfsError, IdbfsSyncCallback, LogCallback, LogLevel, OpfsBackend, ProvisioningSession,
+    VmError, init_idbfs_for_path, init_idbfs_for_profile, set_log_callback, set_log_level,
+    sync_idbfs_with_callback,
+};
 
 #[derive(Default)]
 struct ExportState {
     adi: Option<Adi>,
     last_error: String,
+    last_adi_code: i32,
     cpim: Vec<u8>,
     session: u32,
     otp: Vec<u8>,
     mid: Vec<u8>,
     read_buf: Vec<u8>,
+    provisioning: Vec<u8>,
+    flow: Option<ProvisioningFlow>,
+    flow_request: Vec<u8>,
+    call_response: CString,
+    /// Profile for the next init; see `anisette_set_profile`.
+    profile: Option<String>,
 }
 
 thread_local! {
   static STATE: RefCell<ExportState> = RefCell::new(ExportState::default());
 }
 
-fn set_last_error(message: impl Into<String>) {
+/// A failed export: the status it returns plus what `anisette_last_error` and
+/// `anisette_last_adi_code` report.
+struct ExportError {
+    status: AnisetteStatus,
+    message: String,
+    adi_code: Option<i32>,
+    /// Structured form of the underlying error, for `anisette_call`. Boxed to keep
+    /// `Result<_, ExportError>` small.
+    report: Option<Box<ErrorReport>>,
+}
+
+impl ExportError {
+    fn new(status: AnisetteStatus, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            adi_code: None,
+            report: None,
+        }
+    }
+
+    fn invalid_argument(message: impl Into<String>) -> Self {
+        Self::new(AnisetteStatus::InvalidArgument, message)
+    }
+
+    fn vm(context: &str, err: VmError) -> Self {
+        Self {
+            status: AnisetteStatus::from(&err),
+            message: format!("{context}: {err}"),
+            adi_code: err.adi_code().map(|code| code.raw()),
+            report: Some(Box::new(ErrorReport::from(&err))),
+        }
+    }
+
+    fn idbfs(context: &str, err: IdbfsError) -> Self {
+        Self::new(AnisetteStatus::from(&err), format!("{context}: {err}"))
+    }
+
+    /// Provisioning errors wrap either an ADI failure or a network/protocol one.
+    fn provisioning(context: &str, err: anyhow::Error) -> Self {
+        let message = format!("{context}: {err:#}");
+        let report = Some(Box::new(ErrorReport::from_anyhow(&err)));
+        match err
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<VmError>())
+        {
+            Some(vm) => Self {
+                status: AnisetteStatus::from(vm),
+                message,
+                adi_code: vm.adi_code().map(|code| code.raw()),
+                report,
+            },
+            None => Self {
+                report,
+                ..Self::new(AnisetteStatus::HttpError, message)
+            },
+        }
+    }
+}
+
+/// Records `err` for `anisette_last_error_*` and returns its status code.
+fn set_last_error(err: impl Into<ExportError>) -> i32 {
+    let err = err.into();
     STATE.with(|state| {
-        state.borrow_mut().last_error = message.into();
+        let mut state = state.borrow_mut();
+        state.last_error = err.message;
+        state.last_adi_code = err.adi_code.unwrap_or(0);
     });
+    err.status.code()
 }
 
 fn clear_last_error() {
     STATE.with(|state| {
-        state.borrow_mut().last_error.clear();
+        let mut state = state.borrow_mut();
+        state.last_error.clear();
+        state.last_adi_code = 0;
     });
 }
 
-unsafe fn c_string(ptr: *const c_char) -> Result<String, String> {
+unsafe fn c_string(ptr: *const c_char) -> Result<String, ExportError> {
     if ptr.is_null() {
-        return Err("null C string pointer".to_string());
+        return Err(ExportError::invalid_argument("null C string pointer"));
     }
     unsafe { CStr::from_ptr(ptr) }
         .to_str()
         .map(|s| s.to_string())
-        .map_err(|e| format!("invalid utf-8 string: {e}"))
+        .map_err(|e| ExportError::invalid_argument(format!("invalid utf-8 string: {e}")))
 }
 
-unsafe fn optional_c_string(ptr: *const c_char) -> Result<Option<String>, String> {
+unsafe fn optional_c_string(ptr: *const c_char) -> Result<Option<String>, ExportError> {
     if ptr.is_null() {
         return Ok(None);
     }
     unsafe { c_string(ptr).map(Some) }
 }
 
-unsafe fn input_bytes(ptr: *const u8, len: usize) -> Result<Vec<u8>, String> {
+unsafe fn input_bytes(ptr: *const u8, len: usize) -> Result<Vec<u8>, ExportError> {
     if len == 0 {
         return Ok(Vec::new());
     }
     if ptr.is_null() {
-        return Err("null bytes pointer with non-zero length".to_string());
+        return Err(ExportError::invalid_argument(
+            "null bytes pointer with non-zero length",
+        ));
     }
     Ok(unsafe { std::slice::from_raw_parts(ptr, len) }.to_vec())
 }
 
-fn with_adi_mut<T, F>(f: F) -> Result<T, String>
+unsafe fn output_buffer<'a>(ptr: *mut u8, capacity: usize) -> Result<&'a mut [u8], ExportError> {
+    if capacity == 0 {
+        return Ok(&mut []);
+    }
+    if ptr.is_null() {
+        return Err(ExportError::invalid_argument(
+            "null buffer pointer with non-zero capacity",
+        ));
+    }
+    Ok(unsafe { std::slice::from_raw_parts_mut(ptr, capacity) })
+}
+
+unsafe fn write_len(ptr: *mut usize, len: usize) -> Result<(), ExportError> {
+    if ptr.is_null() {
+        return Err(ExportError::invalid_argument("null length pointer"));
+    }
+    unsafe { ptr.write(len) };
+    Ok(())
+}
+
+fn with_adi_mut<T, F>(f: F) -> Result<T, ExportError>
 where
-    F: FnOnce(&mut Adi) -> Result<T, String>,
+    F: FnOnce(&mut Adi) -> Result<T, ExportError>,
 {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
-        let adi = state
-            .adi
-            .as_mut()
-            .ok_or_else(|| "ADI is not initialized".to_string())?;
-        f(adi)
+        let adi = state.adi.as_mut().ok_or_else(not_initialized)?;
+        // Only a guest call made by `f` may leave a record behind; one from an
+        // earlier call would describe the wrong failure.
+        adi.take_flight_record();
+        f(adi).map_err(|mut err| {
+            if let Some(report) = &mut err.report {
+                report.flight_record = adi.take_flight_record();
+            }
+            err
+        })
     })
 }
 
+fn not_initialized() -> ExportError {
+    ExportError::new(AnisetteStatus::NotInitialized, "ADI is not initialized")
+}
+
 fn install_adi(adi: Adi) {
     STATE.with(|state| {
         let mut state = state.borrow_mut();
@@ -90,20 +216,33 @@ fn init_adi_from_parts(
     library_path: String,
     provisioning_path: Option<String>,
     identifier: Option<String>,
-) -> Result<(), String> {
+) -> Result<(), ExportError> {
+    let profile = STATE.with(|state| state.borrow().profile.clone());
     let adi = Adi::new(AdiInit {
         storeservicescore,
         coreadi,
         library_path,
         provisioning_path,
         identifier,
+        profile,
+        ..Default::default()
     })
-    .map_err(|e| format!("ADI init failed: {e}"))?;
+    .map_err(|e| ExportError::vm("ADI init failed", e))?;
 
     install_adi(adi);
     Ok(())
 }
 
+/// Version of the C ABI described by `anisette.h`.
+pub const ANISETTE_ABI_VERSION: u32 = 1;
+
+/// The [`ANISETTE_ABI_VERSION`] this library was built with; compare it against
+/// the header's before calling anything else.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_abi_version() -> u32 {
+    ANISETTE_ABI_VERSION
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn anisette_init_from_files(
     storeservices_path: *const c_char,
@@ -112,7 +251,7 @@ pub extern "C" fn anisette_init_from_files(
     provisioning_path: *const c_char,
     identifier: *const c_char,
 ) -> i32 {
-    let result = (|| -> Result<(), String> {
+    let result = (|| -> Result<(), ExportError> {
         let storeservices_path = unsafe { c_string(storeservices_path)? };
         let coreadi_path = unsafe { c_string(coreadi_path)? };
         let library_path = unsafe { c_string(library_path)? };
@@ -120,13 +259,20 @@ pub extern "C" fn anisette_init_from_files(
         let identifier = unsafe { optional_c_string(identifier)? };
 
         let storeservicescore = fs::read(&storeservices_path).map_err(|e| {
-            format!(
-                "failed to read storeservices core '{}': {e}",
-                storeservices_path
+            ExportError::new(
+                AnisetteStatus::IoError,
+                format!(
+                    "failed to read storeservices core '{}': {e}",
+                    storeservices_path
+                ),
+            )
+        })?;
+        let coreadi = fs::read(&coreadi_path).map_err(|e| {
+            ExportError::new(
+                AnisetteStatus::IoError,
+                format!("failed to read coreadi '{}': {e}", coreadi_path),
             )
         })?;
-        let coreadi = fs::read(&coreadi_path)
-            .map_err(|e| format!("failed to read coreadi '{}': {e}", coreadi_path))?;
 
         init_adi_from_parts(
             storeservicescore,
@@ -142,10 +288,7 @@ pub extern "C" fn anisette_init_from_files(
             clear_last_error();
             0
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
-        }
+        Err(err) => set_last_error(err),
     }
 }
 
@@ -159,7 +302,7 @@ pub extern "C" fn anisette_init_from_blobs(
     provisioning_path: *const c_char,
     identifier: *const c_char,
 ) -> i32 {
-    let result = (|| -> Result<(), String> {
+    let result = (|| -> Result<(), ExportError> {
         let storeservicescore = unsafe { input_bytes(storeservices_ptr, storeservices_len)? };
         let coreadi = unsafe { input_bytes(coreadi_ptr, coreadi_len)? };
         let library_path = unsafe { c_string(library_path)? };
@@ -180,18 +323,18 @@ pub extern "C" fn anisette_init_from_blobs(
             clear_last_error();
             0
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
-        }
+        Err(err) => set_last_error(err),
     }
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn anisette_set_identifier(identifier: *const c_char) -> i32 {
-    let result = (|| -> Result<(), String> {
+    let result = (|| -> Result<(), ExportError> {
         let identifier = unsafe { c_string(identifier)? };
-        with_adi_mut(|adi| adi.set_identifier(&identifier).map_err(|e| e.to_string()))
+        with_adi_mut(|adi| {
+            adi.set_identifier(&identifier)
+                .map_err(|e| ExportError::vm("set_identifier failed", e))
+        })
     })();
 
     match result {
@@ -199,18 +342,18 @@ pub extern "C" fn anisette_set_identifier(identifier: *const c_char) -> i32 {
             clear_last_error();
             0
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
-        }
+        Err(err) => set_last_error(err),
     }
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn anisette_set_provisioning_path(path: *const c_char) -> i32 {
-    let result = (|| -> Result<(), String> {
+    let result = (|| -> Result<(), ExportError> {
         let path = unsafe { c_string(path)? };
-        with_adi_mut(|adi| adi.set_provisioning_path(&path).map_err(|e| e.to_string()))
+        with_adi_mut(|adi| {
+            adi.set_provisioning_path(&path)
+                .map_err(|e| ExportError::vm("set_provisioning_path failed", e))
+        })
     })();
 
     match result {
@@ -218,10 +361,31 @@ pub extern "C" fn anisette_set_provisioning_path(path: *const c_char) -> i32 {
             clear_last_error();
             0
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
+        Err(err) => set_last_error(err),
+    }
+}
+
+/// Keeps provisioning of the next init under `./profiles/<profile>/anisette`
+/// (where `anisette_idbfs_mount` mounts that profile) while the guest still
+/// uses `./anisette`. Null clears it.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_set_profile(profile: *const c_char) -> i32 {
+    let result = (|| -> Result<(), ExportError> {
+        let profile = unsafe { optional_c_string(profile)? };
+        if let Some(profile) = &profile {
+            profile_mount_path(GUEST_PROVISIONING_DIR, profile)
+                .map_err(|e| ExportError::idbfs("invalid profile", e))?;
+        }
+        STATE.with(|state| state.borrow_mut().profile = profile);
+        Ok(())
+    })();
+
+    match result {
+        Ok(()) => {
+            clear_last_error();
+            0
         }
+        Err(err) => set_last_error(err),
     }
 }
 
@@ -229,12 +393,12 @@ pub extern "C" fn anisette_set_provisioning_path(path: *const c_char) -> i32 {
 
 #[unsafe(no_mangle)]
 pub extern "C" fn anisette_is_machine_provisioned(dsid: u64) -> i32 {
-    let result = (|| -> Result<i32, String> {
+    let result = (|| -> Result<i32, ExportError> {
         let mut out = -1;
         with_adi_mut(|adi| {
             let provisioned = adi
                 .is_machine_provisioned(dsid)
-                .map_err(|e| e.to_string())?;
+                .map_err(|e| ExportError::vm("is_machine_provisioned failed", e))?;
             out = if provisioned { 1 } else { 0 };
             Ok(())
         })?;
@@ -246,10 +410,7 @@ pub extern "C" fn anisette_is_machine_provisioned(dsid: u64) -> i32 {
             clear_last_error();
             value
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
-        }
+        Err(err) => set_last_error(err),
     }
 }
 
@@ -259,11 +420,11 @@ pub extern "C" fn anisette_start_provisioning(
     spim_ptr: *const u8,
     spim_len: usize,
 ) -> i32 {
-    let result = (|| -> Result<(), String> {
+    let result = (|| -> Result<(), ExportError> {
         let spim = unsafe { input_bytes(spim_ptr, spim_len)? };
         let out = with_adi_mut(|adi| {
             adi.start_provisioning(dsid, &spim)
-                .map_err(|e| format!("start_provisioning failed: {e}"))
+                .map_err(|e| ExportError::vm("start_provisioning failed", e))
         })?;
         STATE.with(|state| {
             let mut state = state.borrow_mut();
@@ -278,10 +439,7 @@ pub extern "C" fn anisette_start_provisioning(
             clear_last_error();
             0
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
-        }
+        Err(err) => set_last_error(err),
     }
 }
 
@@ -308,12 +466,12 @@ pub extern "C" fn anisette_end_provisioning(
     tk_ptr: *const u8,
     tk_len: usize,
 ) -> i32 {
-    let result = (|| -> Result<(), String> {
+    let result = (|| -> Result<(), ExportError> {
         let ptm = unsafe { input_bytes(ptm_ptr, ptm_len)? };
         let tk = unsafe { input_bytes(tk_ptr, tk_len)? };
         with_adi_mut(|adi| {
             adi.end_provisioning(session, &ptm, &tk)
-                .map_err(|e| format!("end_provisioning failed: {e}"))
+                .map_err(|e| ExportError::vm("end_provisioning failed", e))
         })
     })();
 
@@ -322,19 +480,170 @@ pub extern "C" fn anisette_end_provisioning(
             clear_last_error();
             0
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
+        Err(err) => set_last_error(err),
+    }
+}
+
+fn no_flow() -> ExportError {
+    ExportError::new(AnisetteStatus::InvalidState, "no provisioning in progress")
+}
+
+/// Serializes the flow's next request into `flow_request` as
+/// `{ method, url, headers: { [name]: value }, body? }`. Returns 1 when a request
+/// is pending and 0 once provisioning has finished.
+fn stage_flow_request(state: &mut ExportState) -> Result<i32, ExportError> {
+    let flow = state.flow.as_ref().ok_or_else(no_flow)?;
+    let Some(request) = flow
+        .request()
+        .map_err(|e| ExportError::provisioning("provisioning failed", e))?
+    else {
+        state.flow_request.clear();
+        return Ok(0);
+    };
+    let headers: HashMap<&str, &str> = request
+        .headers
+        .iter()
+        .map(|(name, value)| (*name, value.as_str()))
+        .collect();
+    let mut payload = json!({
+        "method": if request.body.is_some() { "POST" } else { "GET" },
+        "url": request.url,
+        "headers": headers,
+    });
+    if let Some(body) = &request.body {
+        payload["body"] = json!(body);
+    }
+    state.flow_request = payload.to_string().into_bytes();
+    Ok(1)
+}
+
+/// Starts provisioning `dsid` without blocking on HTTP: the host reads the request
+/// from `anisette_provision_request_ptr/len`, sends it however it likes (e.g. an
+/// awaited `fetch`) and hands the response to `anisette_provision_resume`.
+/// `device_json` is the `device.json` contents.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_provision_begin(dsid: u64, device_json: *const c_char) -> i32 {
+    let result = (|| -> Result<i32, ExportError> {
+        let device_json = unsafe { c_string(device_json)? };
+        let device: DeviceData = serde_json::from_str(&device_json)
+            .map_err(|e| ExportError::invalid_argument(format!("invalid device json: {e}")))?;
+        STATE.with(|state| {
+            let mut state = state.borrow_mut();
+            if state.adi.is_none() {
+                return Err(not_initialized());
+            }
+            state.flow = Some(ProvisioningFlow::new(device, dsid));
+            stage_flow_request(&mut state)
+        })
+    })();
+
+    match result {
+        Ok(value) => {
+            clear_last_error();
+            value
+        }
+        Err(err) => set_last_error(err),
+    }
+}
+
+/// Feeds the response to the pending request. `headers_json` is an optional
+/// `{ [name]: value }` object of response headers. Returns 1 when another request
+/// is pending, 0 once the device is provisioned and a negative status on error,
+/// which abandons the flow.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_provision_resume(
+    status: u32,
+    body_ptr: *const u8,
+    body_len: usize,
+    headers_json: *const c_char,
+) -> i32 {
+    let result = (|| -> Result<i32, ExportError> {
+        let body = unsafe { input_bytes(body_ptr, body_len)? };
+        let headers: HashMap<String, String> = match unsafe { optional_c_string(headers_json)? } {
+            Some(json) => serde_json::from_str(&json).map_err(|e| {
+                ExportError::invalid_argument(format!("invalid response headers json: {e}"))
+            })?,
+            None => HashMap::new(),
+        };
+        if status >= 400 {
+            return Err(ExportError::new(
+                AnisetteStatus::HttpError,
+                format!("provisioning request failed with http status {status}"),
+            ));
+        }
+        let response = HttpResponse {
+            headers: headers.into_iter().collect(),
+            body,
+        };
+        STATE.with(|state| {
+            let state = &mut *state.borrow_mut();
+            let adi = state.adi.as_mut().ok_or_else(not_initialized)?;
+            let flow = state.flow.as_mut().ok_or_else(no_flow)?;
+            flow.resume(adi, &response)
+                .map_err(|e| ExportError::provisioning("provisioning failed", e))?;
+            stage_flow_request(state)
+        })
+    })();
+
+    if !matches!(result, Ok(1)) {
+        STATE.with(|state| state.borrow_mut().flow = None);
+    }
+
+    match result {
+        Ok(value) => {
+            clear_last_error();
+            value
+        }
+        Err(err) => set_last_error(err),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_provision_request_ptr() -> *const u8 {
+    STATE.with(|state| state.borrow().flow_request.as_ptr())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_provision_request_len() -> usize {
+    STATE.with(|state| state.borrow().flow_request.len())
+}
+
+/// Runs the whole provisioning exchange for `dsid` in one blocking call: over
+/// reqwest on native builds, and through the host's synchronous
+/// `anisette_http_get` / `anisette_http_post` callbacks on WASM. `device_json` is
+/// the `device.json` contents. Hosts that can only do async HTTP should use
+/// `anisette_provision_begin` instead.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_provision(dsid: u64, device_json: *const c_char) -> i32 {
+    let result = (|| -> Result<i32, ExportError> {
+        let device_json = unsafe { c_string(device_json)? };
+        let device: DeviceData = serde_json::from_str(&device_json)
+            .map_err(|e| ExportError::invalid_argument(format!("invalid device json: {e}")))?;
+        with_adi_mut(|adi| {
+            let mut session = ProvisioningSession::new(adi, &device, HttpOptions::default())
+                .map_err(|e| ExportError::provisioning("provisioning setup failed", e))?;
+            session
+                .provision(dsid)
+                .map_err(|e| ExportError::provisioning("provisioning failed", e))?;
+            Ok(0)
+        })
+    })();
+
+    match result {
+        Ok(value) => {
+            clear_last_error();
+            value
         }
+        Err(err) => set_last_error(err),
     }
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn anisette_request_otp(dsid: u64) -> i32 {
-    let result = (|| -> Result<(), String> {
+    let result = (|| -> Result<(), ExportError> {
         let out = with_adi_mut(|adi| {
             adi.request_otp(dsid)
-                .map_err(|e| format!("request_otp failed: {e:#}"))
+                .map_err(|e| ExportError::vm("request_otp failed", e))
         })?;
         STATE.with(|state| {
             let mut state = state.borrow_mut();
@@ -349,10 +658,43 @@ pub extern "C" fn anisette_request_otp(dsid: u64) -> i32 {
             clear_last_error();
             0
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
+        Err(err) => set_last_error(err),
+    }
+}
+
+/// Like `anisette_request_otp`, but writes the OTP and machine ID straight into
+/// the caller's buffers and their lengths to `otp_len` / `mid_len`, skipping the
+/// internal copies. Returns `InvalidArgument` if a buffer is too small.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_request_otp_into(
+    dsid: u64,
+    otp_ptr: *mut u8,
+    otp_capacity: usize,
+    otp_len: *mut usize,
+    mid_ptr: *mut u8,
+    mid_capacity: usize,
+    mid_len: *mut usize,
+) -> i32 {
+    let result = (|| -> Result<(), ExportError> {
+        let otp_buf = unsafe { output_buffer(otp_ptr, otp_capacity)? };
+        let mid_buf = unsafe { output_buffer(mid_ptr, mid_capacity)? };
+        let (otp, mid) = with_adi_mut(|adi| {
+            adi.request_otp_into(dsid, otp_buf, mid_buf)
+                .map_err(|e| ExportError::vm("request_otp failed", e))
+        })?;
+        unsafe {
+            write_len(otp_len, otp)?;
+            write_len(mid_len, mid)?;
+        }
+        Ok(())
+    })();
+
+    match result {
+        Ok(()) => {
+            clear_last_error();
+            0
         }
+        Err(err) => set_last_error(err),
     }
 }
 
@@ -376,23 +718,99 @@ pub extern "C" fn anisette_get_mid_len() -> usize {
     STATE.with(|state| state.borrow().mid.len())
 }
 
+/// Copies the `adi.pb` for `dsid` into a buffer read with
+/// `anisette_export_provisioning_ptr`/`_len`, so hosts can store it themselves.
+/// Returns `NotProvisioned` if there is none.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_export_provisioning(dsid: u64) -> i32 {
+    let result = (|| -> Result<(), ExportError> {
+        let data = with_adi_mut(|adi| {
+            adi.provisioning_data(dsid)
+                .map_err(|e| ExportError::vm("export_provisioning failed", e))
+        })?
+        .ok_or_else(|| {
+            ExportError::new(
+                AnisetteStatus::NotProvisioned,
+                format!("no provisioning data for dsid {}", dsid as i64),
+            )
+        })?;
+        STATE.with(|state| state.borrow_mut().provisioning = data);
+        Ok(())
+    })();
+
+    match result {
+        Ok(()) => {
+            clear_last_error();
+            0
+        }
+        Err(err) => set_last_error(err),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_export_provisioning_ptr() -> *const u8 {
+    STATE.with(|state| state.borrow().provisioning.as_ptr())
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_export_provisioning_len() -> usize {
+    STATE.with(|state| state.borrow().provisioning.len())
+}
+
+/// Replaces the `adi.pb` for `dsid` with bytes from `anisette_export_provisioning`.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_import_provisioning(
+    dsid: u64,
+    data_ptr: *const u8,
+    data_len: usize,
+) -> i32 {
+    let result = (|| -> Result<(), ExportError> {
+        let data = unsafe { input_bytes(data_ptr, data_len)? };
+        if data.is_empty() {
+            return Err(ExportError::invalid_argument("empty provisioning data"));
+        }
+        with_adi_mut(|adi| {
+            adi.set_provisioning_data(dsid, &data)
+                .map_err(|e| ExportError::vm("import_provisioning failed", e))
+        })
+    })();
+
+    match result {
+        Ok(()) => {
+            clear_last_error();
+            0
+        }
+        Err(err) => set_last_error(err),
+    }
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn anisette_fs_write_file(
     path: *const c_char,
     data_ptr: *const u8,
     data_len: usize,
 ) -> i32 {
-    let result = (|| -> Result<(), String> {
+    let result = (|| -> Result<(), ExportError> {
         let path = unsafe { c_string(path)? };
         let data = unsafe { input_bytes(data_ptr, data_len)? };
-        let path_ref = Path::new(&path);
-        if let Some(parent) = path_ref.parent()
-            && !parent.as_os_str().is_empty() {
-                fs::create_dir_all(parent)
-                    .map_err(|e| format!("failed to create dir '{}': {e}", parent.display()))?;
-            }
-        fs::write(&path, data).map_err(|e| format!("failed to write '{path}': {e}"))?;
-        Ok(())
+        with_persistence(|backend| backend.write(&path, &data)).map_err(|e| {
+            ExportError::new(
+                AnisetteStatus::IoError,
+                format!("failed to write '{path}': {e}"),
+            )
+        })?;
+        if !is_adi_pb(&path) {
+            return Ok(());
+        }
+        // Replace the checksum too, or the next load reports this file as corrupted.
+        let sum_path = sum_path(&path);
+        let sum = checksum_file(&data, None);
+        with_persistence(|backend| backend.write(&sum_path, &sum)).map_err(|e| {
+            ExportError::new(
+                AnisetteStatus::IoError,
+                format!("failed to write '{sum_path}': {e}"),
+            )
+        })
     })();
 
     match result {
@@ -400,18 +818,20 @@ pub extern "C" fn anisette_fs_write_file(
             clear_last_error();
             0
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
-        }
+        Err(err) => set_last_error(err),
     }
 }
 
 #[unsafe(no_mangle)]
 pub extern "C" fn anisette_fs_read_file(path: *const c_char) -> i32 {
-    let result = (|| -> Result<Vec<u8>, String> {
+    let result = (|| -> Result<Vec<u8>, ExportError> {
         let path = unsafe { c_string(path)? };
-        fs::read(&path).map_err(|e| format!("failed to read '{path}': {e}"))
+        with_persistence(|backend| backend.read(&path)).map_err(|e| {
+            ExportError::new(
+                AnisetteStatus::IoError,
+                format!("failed to read '{path}': {e}"),
+            )
+        })
     })();
 
     match result {
@@ -420,10 +840,7 @@ pub extern "C" fn anisette_fs_read_file(path: *const c_char) -> i32 {
             clear_last_error();
             0
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
-        }
+        Err(err) => set_last_error(err),
     }
 }
 
@@ -439,16 +856,85 @@ pub extern "C" fn anisette_fs_read_len() -> usize {
 
 #[unsafe(no_mangle)]
 pub extern "C" fn anisette_idbfs_sync(populate_from_storage: i32) -> i32 {
-    let result = sync_idbfs(populate_from_storage != 0);
+    let result = with_persistence(|backend| backend.sync(populate_from_storage != 0));
     match result {
         Ok(()) => {
             clear_last_error();
             0
         }
-        Err(err) => {
-            set_last_error(err);
-            -1
+        Err(err) => set_last_error(ExportError::new(
+            AnisetteStatus::IoError,
+            format!("persistence sync failed: {err}"),
+        )),
+    }
+}
+
+/// Syncs IDBFS like `anisette_idbfs_sync`, but reports the outcome through
+/// `callback(user_data, error)` once `FS.syncfs` has finished; `error` is null on
+/// success. Use it to know whether `adi.pb` persisted before reporting success.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_idbfs_sync_with_callback(
+    populate_from_storage: i32,
+    callback: Option<IdbfsSyncCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    let Some(callback) = callback else {
+        return set_last_error(ExportError::invalid_argument("null sync callback"));
+    };
+    clear_last_error();
+    sync_idbfs_with_callback(populate_from_storage != 0, callback, user_data);
+    0
+}
+
+/// Mounts `path` from IndexedDB, or with a non-null `profile` mounts it under
+/// `/profiles/<profile>` in that profile's own database, and starts loading it.
+/// Several paths can be mounted; mounting one again is a no-op.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_idbfs_mount(path: *const c_char, profile: *const c_char) -> i32 {
+    let result = (|| -> Result<(), ExportError> {
+        let path = unsafe { c_string(path)? };
+        let mounted = match unsafe { optional_c_string(profile)? } {
+            Some(profile) => init_idbfs_for_profile(&path, &profile),
+            None => init_idbfs_for_path(&path),
+        };
+        mounted
+            .map(drop)
+            .map_err(|e| ExportError::idbfs(&format!("failed to mount IDBFS at '{path}'"), e))
+    })();
+
+    match result {
+        Ok(()) => {
+            clear_last_error();
+            0
         }
+        Err(err) => set_last_error(err),
+    }
+}
+
+/// Makes OPFS the persistence backend and starts loading `path` (the
+/// provisioning directory) from it; `anisette_idbfs_sync` then syncs OPFS instead
+/// of IDBFS. Fails with `IoError` where OPFS is unavailable.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_use_opfs(path: *const c_char) -> i32 {
+    let result = (|| -> Result<(), ExportError> {
+        let path = unsafe { c_string(path)? };
+        let mut backend = OpfsBackend;
+        backend.init(&path).map_err(|e| {
+            ExportError::new(
+                AnisetteStatus::IoError,
+                format!("failed to mount OPFS at '{path}': {e}"),
+            )
+        })?;
+        set_persistence_backend(backend);
+        Ok(())
+    })();
+
+    match result {
+        Ok(()) => {
+            clear_last_error();
+            0
+        }
+        Err(err) => set_last_error(err),
     }
 }
 
@@ -461,3 +947,311 @@ pub extern "C" fn anisette_last_error_ptr() -> *const u8 {
 pub extern "C" fn anisette_last_error_len() -> usize {
     STATE.with(|state| state.borrow().last_error.len())
 }
+
+/// Raw return code of the ADI call behind the last failure (e.g. -45061), or 0
+/// when it did not come from an ADI entry point.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_last_adi_code() -> i32 {
+    STATE.with(|state| state.borrow().last_adi_code)
+}
+
+/// Frees the output buffers (CPIM, OTP/MID, file reads, `adi.pb`, last error)
+/// without touching the ADI instance or a provisioning flow in progress.
+/// Pointers from earlier `*_ptr` calls are invalid afterwards.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_clear_buffers() {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.cpim = Vec::new();
+        state.session = 0;
+        state.otp = Vec::new();
+        state.mid = Vec::new();
+        state.read_buf = Vec::new();
+        state.provisioning = Vec::new();
+        state.call_response = CString::default();
+        state.last_error = String::new();
+        state.last_adi_code = 0;
+    });
+}
+
+/// Disposes the ADI instance, releasing the emulator and its guest address space,
+/// and frees every buffer. Any open provisioning session is aborted. Call an
+/// `anisette_init_*` export again to start over.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_shutdown() {
+    // Drop outside the borrow: tearing down the ADI may log through a callback
+    // that calls back into the exports.
+    let state = STATE.with(|state| std::mem::take(&mut *state.borrow_mut()));
+    drop(state);
+}
+
+/// Sends the crate's log output to `callback(level, msg_ptr, msg_len)` instead
+/// of stdout/stderr; levels are those of [`LogLevel`](crate::LogLevel). Null
+/// restores the default.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_set_log_callback(callback: Option<LogCallback>) {
+    set_log_callback(callback);
+}
+
+/// Shows log output at `level` and above (0 = trace … 4 = error; the default is
+/// 3, warnings), e.g. 0 to capture a full trace of a failing provisioning run.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_set_log_level(level: i32) -> i32 {
+    match LogLevel::try_from(level) {
+        Ok(level) => {
+            set_log_level(level);
+            clear_last_error();
+            0
+        }
+        Err(level) => set_last_error(ExportError::invalid_argument(format!(
+            "invalid log level {level}"
+        ))),
+    }
+}
+
+/// Requests accepted by `anisette_call`, tagged by `cmd`. DSIDs are signed so a
+/// JSON number can carry the machine DSID (-2), which is also the default;
+/// libraries and `adi.pb` contents are base64.
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum CallRequest {
+    Version,
+    Init {
+        storeservicescore: Option<String>,
+        storeservicescore_path: Option<String>,
+        coreadi: Option<String>,
+        coreadi_path: Option<String>,
+        library_path: String,
+        provisioning_path: Option<String>,
+        identifier: Option<String>,
+    },
+    /// Validates `device`, or generates one for `model` (a preset model
+    /// identifier such as `MacBookPro13,2`).
+    Device {
+        device: Option<DeviceData>,
+        model: Option<String>,
+    },
+    IsProvisioned {
+        #[serde(default = "machine_dsid")]
+        dsid: i64,
+    },
+    Provision {
+        #[serde(default = "machine_dsid")]
+        dsid: i64,
+        device: DeviceData,
+    },
+    Otp {
+        #[serde(default = "machine_dsid")]
+        dsid: i64,
+    },
+    Headers {
+        #[serde(default = "machine_dsid")]
+        dsid: i64,
+        device: DeviceData,
+    },
+    ExportProvisioning {
+        #[serde(default = "machine_dsid")]
+        dsid: i64,
+    },
+    ImportProvisioning {
+        #[serde(default = "machine_dsid")]
+        dsid: i64,
+        data: String,
+    },
+    SetLogLevel {
+        level: i32,
+    },
+    Shutdown,
+}
+
+fn machine_dsid() -> i64 {
+    -2
+}
+
+fn call_library(
+    name: &str,
+    blob: Option<String>,
+    path: Option<String>,
+) -> Result<Vec<u8>, ExportError> {
+    match (blob, path) {
+        (Some(blob), _) => decode_base64(name, &blob),
+        (None, Some(path)) => fs::read(&path).map_err(|e| {
+            ExportError::new(
+                AnisetteStatus::IoError,
+                format!("failed to read {name} '{path}': {e}"),
+            )
+        }),
+        (None, None) => Err(ExportError::invalid_argument(format!(
+            "missing {name} (or {name}_path)"
+        ))),
+    }
+}
+
+fn decode_base64(name: &str, value: &str) -> Result<Vec<u8>, ExportError> {
+    STANDARD
+        .decode(value)
+        .map_err(|e| ExportError::invalid_argument(format!("invalid base64 in {name}: {e}")))
+}
+
+fn dispatch(request: CallRequest) -> Result<Value, ExportError> {
+    match request {
+        CallRequest::Version => Ok(json!({
+            "abi_version": ANISETTE_ABI_VERSION,
+            "crate_version": env!("CARGO_PKG_VERSION"),
+        })),
+        CallRequest::Init {
+            storeservicescore,
+            storeservicescore_path,
+            coreadi,
+            coreadi_path,
+            library_path,
+            provisioning_path,
+            identifier,
+        } => {
+            let storeservicescore = call_library(
+                "storeservicescore",
+                storeservicescore,
+                storeservicescore_path,
+            )?;
+            let coreadi = call_library("coreadi", coreadi, coreadi_path)?;
+            init_adi_from_parts(
+                storeservicescore,
+                coreadi,
+                library_path,
+                provisioning_path,
+                identifier,
+            )?;
+            Ok(Value::Null)
+        }
+        CallRequest::Device { device, model } => {
+            let device = match device {
+                Some(mut device) => {
+                    device
+                        .migrate()
+                        .map_err(|e| ExportError::invalid_argument(format!("{e:#}")))?;
+                    device
+                        .validate()
+                        .map_err(|e| ExportError::invalid_argument(e.to_string()))?;
+                    device
+                }
+                None => {
+                    let preset = match model {
+                        Some(model) => DevicePreset::ALL
+                            .iter()
+                            .copied()
+                            .find(|preset| preset.model() == model)
+                            .ok_or_else(|| {
+                                ExportError::invalid_argument(format!("unknown model '{model}'"))
+                            })?,
+                        None => DevicePreset::default(),
+                    };
+                    DeviceData::preset(preset)
+                }
+            };
+            Ok(json!(device))
+        }
+        CallRequest::IsProvisioned { dsid } => with_adi_mut(|adi| {
+            let provisioned = adi
+                .is_machine_provisioned(dsid as u64)
+                .map_err(|e| ExportError::vm("is_machine_provisioned failed", e))?;
+            Ok(json!(provisioned))
+        }),
+        CallRequest::Provision { dsid, device } => with_adi_mut(|adi| {
+            let mut session = ProvisioningSession::new(adi, &device, HttpOptions::default())
+                .map_err(|e| ExportError::provisioning("provisioning setup failed", e))?;
+            session
+                .provision(dsid as u64)
+                .map_err(|e| ExportError::provisioning("provisioning failed", e))?;
+            Ok(json!({ "routing_info": session.routing_info() }))
+        }),
+        CallRequest::Otp { dsid } => with_adi_mut(|adi| {
+            let out = adi
+                .request_otp(dsid as u64)
+                .map_err(|e| ExportError::vm("request_otp failed", e))?;
+            Ok(json!({
+                "otp": STANDARD.encode(&out.otp),
+                "machine_id": STANDARD.encode(&out.machine_id),
+            }))
+        }),
+        CallRequest::Headers { dsid, device } => with_adi_mut(|adi| {
+            let mut session = ProvisioningSession::new(adi, &device, HttpOptions::default())
+                .map_err(|e| ExportError::provisioning("provisioning setup failed", e))?;
+            let headers = session
+                .anisette_headers(dsid as u64)
+                .map_err(|e| ExportError::provisioning("anisette headers failed", e))?;
+            Ok(json!({
+                "headers": headers,
+                "routing_info": session.routing_info(),
+            }))
+        }),
+        CallRequest::ExportProvisioning { dsid } => with_adi_mut(|adi| {
+            let data = adi
+                .provisioning_data(dsid as u64)
+                .map_err(|e| ExportError::vm("export_provisioning failed", e))?;
+            Ok(json!(data.map(|data| STANDARD.encode(data))))
+        }),
+        CallRequest::ImportProvisioning { dsid, data } => {
+            let data = decode_base64("data", &data)?;
+            with_adi_mut(|adi| {
+                adi.set_provisioning_data(dsid as u64, &data)
+                    .map_err(|e| ExportError::vm("import_provisioning failed", e))
+            })?;
+            Ok(Value::Null)
+        }
+        CallRequest::SetLogLevel { level } => {
+            let level = LogLevel::try_from(level).map_err(|level| {
+                ExportError::invalid_argument(format!("invalid log level {level}"))
+            })?;
+            set_log_level(level);
+            Ok(Value::Null)
+        }
+        CallRequest::Shutdown => {
+            anisette_shutdown();
+            Ok(Value::Null)
+        }
+    }
+}
+
+/// Runs one JSON request such as `{"cmd":"otp","dsid":-2}` (see `CallRequest`
+/// for the commands) and returns a NUL-terminated JSON response: either
+/// `{"ok":true,"result":...}` or `{"ok":false,"status":<AnisetteStatus>,
+/// "error":"...","adi_code":<n>,"report":<ErrorReport>}`, where `report` is null
+/// for argument errors and includes the emulator's `flight_record` when a
+/// library call failed. For hosts where passing many pointer/length
+/// pairs is awkward. The response stays valid until the next `anisette_call`.
+#[unsafe(no_mangle)]
+pub extern "C" fn anisette_call(request: *const c_char) -> *const c_char {
+    let result = (|| -> Result<Value, ExportError> {
+        let request = unsafe { c_string(request)? };
+        let request: CallRequest = serde_json::from_str(&request)
+            .map_err(|e| ExportError::invalid_argument(format!("invalid request: {e}")))?;
+        dispatch(request)
+    })();
+
+    let response = match result {
+        Ok(value) => {
+            clear_last_error();
+            json!({ "ok": true, "result": value })
+        }
+        Err(mut err) => {
+            let message = err.message.clone();
+            let adi_code = err.adi_code.unwrap_or(0);
+            let report = err.report.take();
+            let status = set_last_error(err);
+            json!({
+                "ok": false,
+                "status": status,
+                "error": message,
+                "adi_code": adi_code,
+                "report": report,
+            })
+        }
+    };
+    // serde_json escapes control characters, so the output has no interior NUL.
+    let response = CString::new(response.to_string()).unwrap_or_default();
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.call_response = response;
+        state.call_response.as_ptr()
+    })
+}
@@ -0,0 +1,124 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use reqwest::blocking::Client;
+
+use crate::AdiInit;
+use crate::apk::ApkLibraries;
+use crate::library::sha256_hex;
+
+/// Apple's own CDN copy of the Apple Music APK.
+pub const DEFAULT_APK_MIRROR: &str =
+    "https://apps.mzstatic.com/content/android-apple-music-apk/applemusic.apk";
+
+const STORESERVICESCORE_NAME: &str = "libstoreservicescore.so";
+const COREADI_NAME: &str = "libCoreADI.so";
+
+/// Downloads the Apple Music APK, extracts the ADI libraries and caches them.
+pub struct LibraryFetcher {
+    cache_dir: PathBuf,
+    mirror_url: String,
+    expected_sha256: Option<String>,
+    timeout: Duration,
+}
+
+impl LibraryFetcher {
+    /// `cache_dir` is normally the library path handed to [`AdiInit`].
+    pub fn new(cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            cache_dir: cache_dir.into(),
+            mirror_url: DEFAULT_APK_MIRROR.to_string(),
+            expected_sha256: None,
+            timeout: Duration::from_secs(120),
+        }
+    }
+
+    pub fn with_mirror(mut self, url: impl Into<String>) -> Self {
+        self.mirror_url = url.into();
+        self
+    }
+
+    /// Rejects a downloaded APK whose SHA-256 (hex) does not match.
+    pub fn with_sha256(mut self, hex: impl Into<String>) -> Self {
+        self.expected_sha256 = Some(hex.into().to_ascii_lowercase());
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the cached libraries, downloading and extracting them first if needed.
+    pub fn fetch(&self) -> Result<ApkLibraries> {
+        if let Some(cached) = self.load_cached()? {
+            return Ok(cached);
+        }
+
+        let apk = self.download()?;
+        let libraries = ApkLibraries::from_apk(&apk)?;
+        self.store(&libraries)?;
+        Ok(libraries)
+    }
+
+    /// Like [`LibraryFetcher::fetch`], returning an [`AdiInit`] rooted at the cache dir.
+    pub fn fetch_adi_init(&self) -> Result<AdiInit> {
+        let libraries = self.fetch()?;
+        Ok(libraries.into_adi_init(self.cache_dir.to_string_lossy()))
+    }
+
+    fn load_cached(&self) -> Result<Option<ApkLibraries>> {
+        let storeservices_path = self.cache_dir.join(STORESERVICESCORE_NAME);
+        let coreadi_path = self.cache_dir.join(COREADI_NAME);
+        if !storeservices_path.is_file() || !coreadi_path.is_file() {
+            return Ok(None);
+        }
+
+        Ok(Some(ApkLibraries {
+            storeservicescore: read(&storeservices_path)?,
+            coreadi: read(&coreadi_path)?,
+        }))
+    }
+
+    fn download(&self) -> Result<Vec<u8>> {
+        let client = Client::builder().timeout(self.timeout).build()?;
+        let response = client
+            .get(&self.mirror_url)
+            .send()
+            .with_context(|| format!("failed to download {}", self.mirror_url))?
+            .error_for_status()?;
+        let apk = response.bytes()?.to_vec();
+
+        if let Some(expected) = self.expected_sha256.as_deref() {
+            let actual = sha256_hex(&apk);
+            if actual != expected {
+                bail!(
+                    "APK from {} has SHA-256 {actual}, expected {expected}",
+                    self.mirror_url
+                );
+            }
+        }
+
+        Ok(apk)
+    }
+
+    fn store(&self, libraries: &ApkLibraries) -> Result<()> {
+        fs::create_dir_all(&self.cache_dir)
+            .with_context(|| format!("failed to create cache dir {}", self.cache_dir.display()))?;
+        write(
+            &self.cache_dir.join(STORESERVICESCORE_NAME),
+            &libraries.storeservicescore,
+        )?;
+        write(&self.cache_dir.join(COREADI_NAME), &libraries.coreadi)
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+fn write(path: &Path, data: &[u8]) -> Result<()> {
+    fs::write(path, data).with_context(|| format!("failed to write {}", path.display()))
+}
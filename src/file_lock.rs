@@ -0,0 +1,82 @@
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// An advisory lock on a `<file>.lock` sidecar, held until dropped.
+///
+/// Sidecars keep the lock independent of the data file, which is replaced by
+/// rename on write. The OS drops the lock with the process, so a crashed
+/// instance never leaves it held. Platforms without file locking run unlocked.
+#[derive(Debug)]
+pub(crate) struct FileLock {
+    _file: File,
+}
+
+impl FileLock {
+    /// Blocks until no other process holds an exclusive lock on `path`.
+    pub(crate) fn shared(path: &Path) -> io::Result<Self> {
+        Self::acquire(path, false)
+    }
+
+    /// Blocks until no other process holds any lock on `path`.
+    pub(crate) fn exclusive(path: &Path) -> io::Result<Self> {
+        Self::acquire(path, true)
+    }
+
+    fn acquire(path: &Path, exclusive: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(lock_path(path))?;
+        let locked = if exclusive {
+            file.lock()
+        } else {
+            file.lock_shared()
+        };
+        match locked {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::Unsupported => {}
+            Err(err) => return Err(err),
+        }
+        Ok(Self { _file: file })
+    }
+}
+
+pub(crate) fn lock_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".lock");
+    PathBuf::from(name)
+}
+
+/// Replaces `path` with `data` through a temporary file and a rename, so readers
+/// never see a partially written file.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp = OsString::from(path.as_os_str());
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    std::fs::write(&temp, data)?;
+    std::fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileLock, write_atomic};
+
+    #[test]
+    fn shared_locks_coexist_and_writes_replace_whole_files() {
+        let path = std::env::temp_dir().join(format!("anisette-lock-{}", std::process::id()));
+        let first = FileLock::shared(&path).expect("first shared lock");
+        let second = FileLock::shared(&path).expect("second shared lock");
+        drop((first, second));
+
+        let _exclusive = FileLock::exclusive(&path).expect("exclusive lock");
+        write_atomic(&path, b"new").expect("write");
+        assert_eq!(std::fs::read(&path).expect("read"), b"new");
+
+        std::fs::remove_file(&path).expect("cleanup");
+        std::fs::remove_file(super::lock_path(&path)).expect("cleanup lock");
+    }
+}
@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::constants::FLIGHT_RECORDER_LEN;
+
+/// Something the emulator did, kept for the post-mortem of a failed call.
+#[derive(Debug, Clone)]
+pub(crate) enum FlightEvent {
+    EmuStart {
+        address: u64,
+    },
+    EmuStop {
+        pc: u64,
+        error: Option<String>,
+    },
+    Stub {
+        name: String,
+        ret: Option<u64>,
+        error: Option<String>,
+    },
+    Alloc {
+        allocator: &'static str,
+        address: u64,
+        size: u64,
+    },
+}
+
+impl fmt::Display for FlightEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EmuStart { address } => write!(f, "emu_start 0x{address:X}"),
+            Self::EmuStop { pc, error: None } => write!(f, "emu_stop pc=0x{pc:X}"),
+            Self::EmuStop {
+                pc,
+                error: Some(error),
+            } => write!(f, "emu_stop pc=0x{pc:X}: {error}"),
+            Self::Stub {
+                name,
+                error: Some(error),
+                ..
+            } => write!(f, "{name} failed: {error}"),
+            Self::Stub {
+                name,
+                ret: Some(ret),
+                ..
+            } => write!(f, "{name} = 0x{ret:X}"),
+            Self::Stub { name, .. } => write!(f, "{name}"),
+            Self::Alloc {
+                allocator,
+                address,
+                size,
+            } => write!(f, "{allocator} 0x{size:X} bytes at 0x{address:X}"),
+        }
+    }
+}
+
+/// The last [`FLIGHT_RECORDER_LEN`] emulator events. Always on: recording is a
+/// push onto a ring, and intermittent failures cannot be re-run with tracing.
+#[derive(Debug, Default)]
+pub(crate) struct FlightRecorder {
+    events: VecDeque<(u64, FlightEvent)>,
+    seq: u64,
+}
+
+impl FlightRecorder {
+    pub(crate) fn record(&mut self, event: FlightEvent) {
+        if self.events.len() == FLIGHT_RECORDER_LEN {
+            self.events.pop_front();
+        }
+        self.seq += 1;
+        self.events.push_back((self.seq, event));
+    }
+
+    /// The recorded events, oldest first, as `#<seq> <event>` lines.
+    pub(crate) fn dump(&self) -> Vec<String> {
+        self.events
+            .iter()
+            .map(|(seq, event)| format!("#{seq} {event}"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FlightEvent, FlightRecorder};
+    use crate::constants::FLIGHT_RECORDER_LEN;
+
+    #[test]
+    fn recorder_keeps_latest_events() {
+        let mut recorder = FlightRecorder::default();
+        for address in 0..FLIGHT_RECORDER_LEN as u64 + 10 {
+            recorder.record(FlightEvent::EmuStart { address });
+        }
+        recorder.record(FlightEvent::Stub {
+            name: "open".to_string(),
+            ret: None,
+            error: Some("unsupported flags".to_string()),
+        });
+
+        let dump = recorder.dump();
+        assert_eq!(dump.len(), FLIGHT_RECORDER_LEN);
+        assert_eq!(dump[0], "#12 emu_start 0xB");
+        assert_eq!(
+            dump.last().map(String::as_str),
+            Some("#267 open failed: unsupported flags")
+        );
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, anyhow};
+
+use crate::provider::AnisetteProvider;
+
+/// Keeps a fresh anisette header set ready, so request paths never wait on the
+/// emulator.
+///
+/// A background thread owns the provider (an [`Adi`](crate::Adi) is not `Send`, so
+/// it is built on that thread, e.g. as a [`LocalAnisette`](crate::LocalAnisette))
+/// and refreshes the headers every `interval`. Pick an
+/// interval well below the OTP lifetime (~30s); the cached `X-Apple-I-Client-Time`
+/// is the time of the refresh, not of the request. The thread stops when the cache
+/// is dropped.
+pub struct HeaderCache {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<CacheState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct CacheState {
+    headers: Option<HashMap<String, String>>,
+    refreshed_at: Option<Instant>,
+    last_error: Option<String>,
+    refresh_requested: bool,
+    stopped: bool,
+}
+
+impl HeaderCache {
+    /// Starts the refresh thread. `make_provider` runs on that thread; if it fails
+    /// the error is reported through [`last_error`](Self::last_error) and no
+    /// headers are ever produced.
+    pub fn spawn<P, F>(dsid: u64, interval: Duration, make_provider: F) -> Self
+    where
+        P: AnisetteProvider,
+        F: FnOnce() -> Result<P> + Send + 'static,
+    {
+        let shared = Arc::new(Shared::default());
+        let worker = {
+            let shared = shared.clone();
+            thread::spawn(move || refresh_loop(&shared, dsid, interval, make_provider))
+        };
+        Self {
+            shared,
+            worker: Some(worker),
+        }
+    }
+
+    /// The most recent header set, without blocking on a refresh.
+    pub fn latest(&self) -> Option<HashMap<String, String>> {
+        self.shared.lock().headers.clone()
+    }
+
+    /// Age of [`latest`](Self::latest).
+    pub fn age(&self) -> Option<Duration> {
+        self.shared.lock().refreshed_at.map(|at| at.elapsed())
+    }
+
+    /// Why the last refresh failed, if it did. Earlier headers stay available.
+    pub fn last_error(&self) -> Option<String> {
+        self.shared.lock().last_error.clone()
+    }
+
+    /// Blocks until the first header set is available, e.g. at startup.
+    pub fn wait(&self, timeout: Duration) -> Result<HashMap<String, String>> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(headers) = &state.headers {
+                return Ok(headers.clone());
+            }
+            if state.stopped {
+                let reason = state
+                    .last_error
+                    .as_deref()
+                    .unwrap_or("refresh thread stopped");
+                return Err(anyhow!("no anisette headers: {reason}"));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(anyhow!("timed out waiting for anisette headers"));
+            }
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, remaining)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+    }
+
+    /// Refreshes now instead of at the next interval.
+    pub fn refresh_now(&self) {
+        self.shared.lock().refresh_requested = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Drop for HeaderCache {
+    fn drop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.changed.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn refresh_loop<P, F>(shared: &Shared, dsid: u64, interval: Duration, make_provider: F)
+where
+    P: AnisetteProvider,
+    F: FnOnce() -> Result<P>,
+{
+    let mut provider = match make_provider() {
+        Ok(provider) => provider,
+        Err(err) => {
+            let mut state = shared.lock();
+            state.last_error = Some(format!("{err:#}"));
+            state.stopped = true;
+            shared.changed.notify_all();
+            return;
+        }
+    };
+
+    loop {
+        let result = provider.anisette_headers(dsid);
+        let mut state = shared.lock();
+        match result {
+            Ok(headers) => {
+                state.headers = Some(headers);
+                state.refreshed_at = Some(Instant::now());
+                state.last_error = None;
+            }
+            Err(err) => state.last_error = Some(format!("{err:#}")),
+        }
+        shared.changed.notify_all();
+
+        let next = Instant::now() + interval;
+        while !state.stopped && !state.refresh_requested {
+            let remaining = next.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            state = shared
+                .changed
+                .wait_timeout(state, remaining)
+                .unwrap_or_else(|err| err.into_inner())
+                .0;
+        }
+        if state.stopped {
+            return;
+        }
+        state.refresh_requested = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use anyhow::Result;
+
+    use super::HeaderCache;
+    use crate::provider::{AnisetteProvider, LocalAnisette};
+    use crate::{Adi, AdiInit, Device, ReplayTransport};
+
+    struct Counter(u32);
+
+    impl AnisetteProvider for Counter {
+        fn anisette_headers(&mut self, _dsid: u64) -> Result<HashMap<String, String>> {
+            self.0 += 1;
+            Ok(HashMap::from([(
+                "X-Apple-I-MD".to_string(),
+                self.0.to_string(),
+            )]))
+        }
+    }
+
+    #[test]
+    fn cache_serves_latest_and_refreshes_on_demand() {
+        let cache = HeaderCache::spawn(0, Duration::from_secs(3600), || Ok(Counter(0)));
+        let first = cache.wait(Duration::from_secs(5)).expect("first headers");
+        assert_eq!(first["X-Apple-I-MD"], "1");
+
+        cache.refresh_now();
+        for _ in 0..500 {
+            if cache
+                .latest()
+                .is_some_and(|headers| headers["X-Apple-I-MD"] == "2")
+            {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("refresh_now did not refresh");
+    }
+
+    #[test]
+    fn cache_builds_an_owning_local_provider_on_its_thread() {
+        let cache = HeaderCache::spawn(0, Duration::from_secs(3600), || {
+            let adi = Adi::new(AdiInit::from_library_dir("./missing-libraries")?)?;
+            let transport = ReplayTransport::from_bytes(br#"{"exchanges": []}"#)?;
+            Ok(LocalAnisette::with_transport(
+                adi,
+                Device::ephemeral().data,
+                transport,
+            ))
+        });
+        let err = cache
+            .wait(Duration::from_secs(5))
+            .expect_err("no libraries");
+        assert!(
+            err.to_string()
+                .contains("libstoreservicescore.so not found")
+        );
+    }
+}
@@ -1,7 +1,17 @@
+use std::collections::BTreeSet;
 #[cfg(target_os = "emscripten")]
 use std::ffi::CString;
+use std::ffi::{CStr, c_char, c_void};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::{fs, io};
 
-fn normalize_mount_path(path: &str) -> String {
+use crate::errors::IdbfsError;
+use crate::persistence::{PersistenceBackend, write_host_file};
+
+pub(crate) fn normalize_mount_path(path: &str) -> String {
     let trimmed = path.trim();
     let no_slash = trimmed.trim_end_matches('/');
     let no_dot = no_slash.strip_prefix("./").unwrap_or(no_slash);
@@ -17,66 +27,240 @@ fn normalize_mount_path(path: &str) -> String {
 
 #[cfg(target_os = "emscripten")]
 unsafe extern "C" {
-    fn emscripten_run_script(script: *const core::ffi::c_char);
+    // Defined in script/anisette-library.js.
+    fn anisette_js_idbfs_mount(path: *const core::ffi::c_char);
+    fn anisette_js_idbfs_sync(populate: i32);
+    fn anisette_js_idbfs_sync_cb(
+        populate: i32,
+        callback: IdbfsSyncCallback,
+        user_data: *mut c_void,
+    );
 }
 
 #[cfg(target_os = "emscripten")]
-fn run_script(script: &str) -> Result<(), String> {
-    let script = CString::new(script).map_err(|e| format!("invalid JS script: {e}"))?;
+fn mount(path: &str) -> Result<(), IdbfsError> {
+    let path = CString::new(path).map_err(|source| IdbfsError::InvalidPath {
+        path: path.to_string(),
+        source,
+    })?;
     unsafe {
-        emscripten_run_script(script.as_ptr());
+        anisette_js_idbfs_mount(path.as_ptr());
     }
     Ok(())
 }
 
+#[cfg(target_os = "emscripten")]
+fn sync(populate_from_storage: bool) {
+    unsafe {
+        anisette_js_idbfs_sync(i32::from(populate_from_storage));
+    }
+}
+
+#[cfg(target_os = "emscripten")]
+fn sync_with_callback(
+    populate_from_storage: bool,
+    callback: IdbfsSyncCallback,
+    user_data: *mut c_void,
+) {
+    unsafe {
+        anisette_js_idbfs_sync_cb(i32::from(populate_from_storage), callback, user_data);
+    }
+}
+
 #[cfg(not(target_os = "emscripten"))]
-fn run_script(_script: &str) -> Result<(), String> {
+fn mount(_path: &str) -> Result<(), IdbfsError> {
     Ok(())
 }
 
-pub fn init_idbfs_for_path(path: &str) -> Result<String, String> {
+#[cfg(not(target_os = "emscripten"))]
+fn sync(_populate_from_storage: bool) {}
+
+#[cfg(not(target_os = "emscripten"))]
+fn sync_with_callback(
+    _populate_from_storage: bool,
+    callback: IdbfsSyncCallback,
+    user_data: *mut c_void,
+) {
+    callback(user_data, std::ptr::null());
+}
+
+/// Directory profile mounts are created under.
+const PROFILES_DIR: &str = "/profiles";
+
+/// Paths mounted so far; each is its own IndexedDB database.
+static MOUNTS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+/// Mounts `path` from IndexedDB and starts loading it, returning the normalized
+/// mount path. Several paths can be mounted side by side; mounting one again is a
+/// no-op, so it does not reload over unsynced changes.
+pub fn init_idbfs_for_path(path: &str) -> Result<String, IdbfsError> {
     let mount_path = normalize_mount_path(path);
-    let script = format!(
-        r#"(function() {{
-  if (typeof FS === 'undefined' || typeof IDBFS === 'undefined') {{
-    console.warn('[anisette-rs] FS/IDBFS unavailable');
-    return;
-  }}
-  var mp = "{mount_path}";
-  try {{ FS.mkdirTree(mp); }} catch (_e) {{}}
-  try {{ FS.mount(IDBFS, {{}}, mp); }} catch (_e) {{}}
-  FS.syncfs(true, function(err) {{
-    if (err) {{
-      console.error('[anisette-rs] IDBFS initial sync failed', err);
-    }} else {{
-      console.log('[anisette-rs] IDBFS ready at ' + mp);
-    }}
-  }});
-}})();"#,
-    );
-    run_script(&script)?;
+    let mut mounts = MOUNTS.lock().unwrap_or_else(PoisonError::into_inner);
+    if !mounts.contains(&mount_path) {
+        mount(&mount_path)?;
+        mounts.insert(mount_path.clone());
+    }
     Ok(mount_path)
 }
 
-pub fn sync_idbfs(populate_from_storage: bool) -> Result<(), String> {
-    let populate = if populate_from_storage {
-        "true"
+/// Like [`init_idbfs_for_path`], but mounts `path` under `/profiles/{profile}`
+/// (e.g. `/profiles/work/anisette`), so each device profile keeps its files in
+/// its own IndexedDB database. IndexedDB is already scoped to the origin and
+/// IDBFS names the database after the mount path, so the database is per origin
+/// and profile. Profiles are made of ASCII letters, digits, `-`, `_` and `.`.
+pub fn init_idbfs_for_profile(path: &str, profile: &str) -> Result<String, IdbfsError> {
+    init_idbfs_for_path(&profile_mount_path(path, profile)?)
+}
+
+/// The paths mounted by [`init_idbfs_for_path`] and [`init_idbfs_for_profile`].
+pub fn idbfs_mounts() -> Vec<String> {
+    let mounts = MOUNTS.lock().unwrap_or_else(PoisonError::into_inner);
+    mounts.iter().cloned().collect()
+}
+
+pub(crate) fn profile_mount_path(path: &str, profile: &str) -> Result<String, IdbfsError> {
+    let valid = !profile.is_empty()
+        && profile != "."
+        && profile != ".."
+        && profile
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    if !valid {
+        return Err(IdbfsError::InvalidProfile(profile.to_string()));
+    }
+    match normalize_mount_path(path).as_str() {
+        "/" => Ok(format!("{PROFILES_DIR}/{profile}")),
+        path => Ok(format!("{PROFILES_DIR}/{profile}{path}")),
+    }
+}
+
+pub fn sync_idbfs(populate_from_storage: bool) -> Result<(), IdbfsError> {
+    sync(populate_from_storage);
+    Ok(())
+}
+
+/// Receives the outcome of [`sync_idbfs_with_callback`]: `error` is null on
+/// success, otherwise a NUL-terminated message valid only during the call.
+pub type IdbfsSyncCallback = extern "C" fn(user_data: *mut c_void, error: *const c_char);
+
+/// Like [`sync_idbfs`], but calls `callback` once `FS.syncfs` has finished, so the
+/// caller knows whether `adi.pb` actually reached IndexedDB. Outside emscripten
+/// the callback runs immediately with no error.
+pub fn sync_idbfs_with_callback(
+    populate_from_storage: bool,
+    callback: IdbfsSyncCallback,
+    user_data: *mut c_void,
+) {
+    sync_with_callback(populate_from_storage, callback, user_data);
+}
+
+/// Resolves once an IDBFS sync has finished, with the error the JS side reported.
+pub fn sync_idbfs_async(populate_from_storage: bool) -> IdbfsSync {
+    let shared = Arc::new(Mutex::new(SyncState::default()));
+    let user_data = Arc::into_raw(Arc::clone(&shared)) as *mut c_void;
+    sync_with_callback(populate_from_storage, complete_sync, user_data);
+    IdbfsSync { shared }
+}
+
+/// Future returned by [`sync_idbfs_async`].
+#[derive(Debug)]
+pub struct IdbfsSync {
+    shared: Arc<Mutex<SyncState>>,
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    result: Option<Result<(), IdbfsError>>,
+    waker: Option<Waker>,
+}
+
+extern "C" fn complete_sync(user_data: *mut c_void, error: *const c_char) {
+    // Balances the `Arc::into_raw` in `sync_idbfs_async`; called exactly once.
+    let shared = unsafe { Arc::from_raw(user_data as *const Mutex<SyncState>) };
+    let result = if error.is_null() {
+        Ok(())
     } else {
-        "false"
+        let message = unsafe { CStr::from_ptr(error) }.to_string_lossy();
+        Err(IdbfsError::SyncFailed(message.into_owned()))
     };
-    let script = format!(
-        r#"(function() {{
-  if (typeof FS === 'undefined') {{
-    return;
-  }}
-  FS.syncfs({populate}, function(err) {{
-    if (err) {{
-      console.error('[anisette-rs] IDBFS sync failed', err);
-    }} else {{
-      console.log('[anisette-rs] IDBFS sync done');
-    }}
-  }});
-}})();"#,
-    );
-    run_script(&script)
+    let waker = {
+        let mut state = shared.lock().unwrap_or_else(PoisonError::into_inner);
+        state.result = Some(result);
+        state.waker.take()
+    };
+    if let Some(waker) = waker {
+        waker.wake();
+    }
+}
+
+impl Future for IdbfsSync {
+    type Output = Result<(), IdbfsError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.lock().unwrap_or_else(PoisonError::into_inner);
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Emscripten's IDBFS: the provisioning directory is mounted from IndexedDB and
+/// files live in MEMFS until `sync` writes them back. The JS side syncs
+/// asynchronously, so a successful `sync` only means it was scheduled. Outside
+/// emscripten this is plain host files.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IdbfsBackend;
+
+impl PersistenceBackend for IdbfsBackend {
+    fn init(&mut self, path: &str) -> io::Result<String> {
+        Ok(init_idbfs_for_path(path)?)
+    }
+
+    fn sync(&mut self, populate_from_storage: bool) -> io::Result<()> {
+        Ok(sync_idbfs(populate_from_storage)?)
+    }
+
+    fn read(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        write_host_file(path, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Poll, Waker};
+
+    use super::{idbfs_mounts, init_idbfs_for_profile, sync_idbfs_async};
+    use crate::errors::IdbfsError;
+
+    #[test]
+    fn async_sync_resolves_outside_emscripten() {
+        let mut sync = pin!(sync_idbfs_async(false));
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(sync.as_mut().poll(&mut cx), Poll::Ready(Ok(())));
+    }
+
+    #[test]
+    fn profiles_mount_separately() {
+        let work = init_idbfs_for_profile("./anisette/", "work").expect("work");
+        let home = init_idbfs_for_profile("./anisette/", "home").expect("home");
+        assert_eq!(work, "/profiles/work/anisette");
+        assert_eq!(home, "/profiles/home/anisette");
+        assert!(idbfs_mounts().contains(&work) && idbfs_mounts().contains(&home));
+
+        assert_eq!(
+            init_idbfs_for_profile("./anisette", "../home"),
+            Err(IdbfsError::InvalidProfile("../home".to_string()))
+        );
+        assert!(init_idbfs_for_profile("./anisette", "").is_err());
+    }
 }
@@ -0,0 +1,125 @@
+use std::convert::Infallible;
+use std::fmt;
+
+use uuid::Uuid;
+
+use crate::errors::VmError;
+use crate::util::bytes_to_hex;
+
+/// Raw length of the Android ID the library expects; it is passed hex encoded.
+pub const ADI_IDENTIFIER_BYTES: usize = 8;
+
+/// A validated identifier for `ADISetAndroidID`: 16 hex characters (8 bytes).
+///
+/// The empty identifier is accepted and means "leave the library's default".
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AdiIdentifier(String);
+
+impl AdiIdentifier {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Display for AdiIdentifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl TryFrom<&str> for AdiIdentifier {
+    type Error = VmError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(Self(String::new()));
+        }
+        if value.len() != ADI_IDENTIFIER_BYTES * 2 {
+            return Err(VmError::InvalidIdentifier(format!(
+                "expected {} hex characters, got {}",
+                ADI_IDENTIFIER_BYTES * 2,
+                value.len()
+            )));
+        }
+        if !value.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(VmError::InvalidIdentifier(format!(
+                "'{value}' is not hexadecimal"
+            )));
+        }
+        Ok(Self(value.to_string()))
+    }
+}
+
+impl TryFrom<&String> for AdiIdentifier {
+    type Error = VmError;
+
+    fn try_from(value: &String) -> Result<Self, Self::Error> {
+        Self::try_from(value.as_str())
+    }
+}
+
+impl TryFrom<String> for AdiIdentifier {
+    type Error = VmError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::try_from(value.as_str())
+    }
+}
+
+impl TryFrom<&[u8]> for AdiIdentifier {
+    type Error = VmError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let bytes: [u8; ADI_IDENTIFIER_BYTES] = value.try_into().map_err(|_| {
+            VmError::InvalidIdentifier(format!(
+                "expected {ADI_IDENTIFIER_BYTES} bytes, got {}",
+                value.len()
+            ))
+        })?;
+        Ok(Self::from(bytes))
+    }
+}
+
+impl From<[u8; ADI_IDENTIFIER_BYTES]> for AdiIdentifier {
+    fn from(value: [u8; ADI_IDENTIFIER_BYTES]) -> Self {
+        Self(bytes_to_hex(&value))
+    }
+}
+
+/// Uses the first 8 bytes of the UUID, so one UUID maps to one stable identifier.
+impl From<Uuid> for AdiIdentifier {
+    fn from(value: Uuid) -> Self {
+        let mut bytes = [0_u8; ADI_IDENTIFIER_BYTES];
+        bytes.copy_from_slice(&value.as_bytes()[..ADI_IDENTIFIER_BYTES]);
+        Self::from(bytes)
+    }
+}
+
+impl From<Infallible> for VmError {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AdiIdentifier;
+
+    #[test]
+    fn identifier_validates_and_normalizes() {
+        assert!(AdiIdentifier::try_from("0123456789abcdef").is_ok());
+        assert!(AdiIdentifier::try_from(" 0123456789abcdef\n").is_ok());
+        assert!(AdiIdentifier::try_from("0123").is_err());
+        assert!(AdiIdentifier::try_from("0123456789abcdeg").is_err());
+        assert!(AdiIdentifier::try_from("").expect("empty").is_empty());
+
+        let from_bytes = AdiIdentifier::from([0xAB, 0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(from_bytes.as_str(), "ab00010203040506");
+        assert!(AdiIdentifier::try_from(&[1_u8, 2, 3][..]).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+use crate::debug::debug_print;
+use crate::errors::VmError;
+use crate::library::sha256_hex;
+use crate::state::read_state_file;
+use crate::vfs::{GuestFs, GuestOpenOptions};
+
+/// Checksum sidecar kept next to every `adi.pb` the guest writes, so truncation
+/// by the storage layer (e.g. IndexedDB) is caught before the library turns it
+/// into an obscure ADI error code.
+const SUM_SUFFIX: &str = ".sum";
+
+#[derive(Serialize, Deserialize)]
+struct ProvisioningSum {
+    sha256: String,
+    size: u64,
+    /// CoreADI build that wrote the file.
+    library: Option<String>,
+}
+
+pub(crate) fn sum_path(adi_pb: &str) -> String {
+    format!("{adi_pb}{SUM_SUFFIX}")
+}
+
+/// Contents of the checksum sidecar for an `adi.pb` holding `data`.
+pub(crate) fn checksum_file(data: &[u8], library: Option<&str>) -> Vec<u8> {
+    let sum = ProvisioningSum {
+        sha256: sha256_hex(data),
+        size: data.len() as u64,
+        library: library.map(str::to_string),
+    };
+    serde_json::to_vec(&sum).expect("checksum serializes")
+}
+
+/// Records the checksum of `adi_pb` as it is now.
+pub(crate) fn record_checksum(
+    guest_fs: &mut dyn GuestFs,
+    adi_pb: &str,
+    library: Option<&str>,
+) -> Result<(), VmError> {
+    let data = read_state_file(guest_fs, adi_pb)?;
+    let json = checksum_file(&data, library);
+    let options = GuestOpenOptions {
+        write: true,
+        create: true,
+        truncate: true,
+        ..Default::default()
+    };
+    guest_fs
+        .open(&sum_path(adi_pb), &options)?
+        .write_all(&json)?;
+    Ok(())
+}
+
+/// Compares `adi_pb` with its recorded checksum and returns why it looks
+/// corrupted, if it does. Files without a checksum (written before it was
+/// recorded, or by another tool) and missing files pass.
+pub(crate) fn verify_checksum(
+    guest_fs: &mut dyn GuestFs,
+    adi_pb: &str,
+    library: Option<&str>,
+) -> Result<Option<String>, VmError> {
+    let sum = match read_state_file(guest_fs, &sum_path(adi_pb)) {
+        Ok(sum) => sum,
+        Err(VmError::Io(err)) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let data = match read_state_file(guest_fs, adi_pb) {
+        Ok(data) => data,
+        Err(VmError::Io(err)) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let Ok(sum) = serde_json::from_slice::<ProvisioningSum>(&sum) else {
+        return Ok(Some(format!("unreadable {}", sum_path(adi_pb))));
+    };
+
+    if data.len() as u64 != sum.size {
+        return Ok(Some(format!(
+            "{} bytes on disk, {} when written",
+            data.len(),
+            sum.size
+        )));
+    }
+    if sha256_hex(&data) != sum.sha256 {
+        return Ok(Some("checksum mismatch".to_string()));
+    }
+    if let (Some(written), Some(current)) = (sum.library.as_deref(), library)
+        && written != current
+    {
+        debug_print!(format!(
+            "{adi_pb} was written by CoreADI {written}, now running {current}"
+        ));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_checksum, verify_checksum};
+    use crate::vfs::MemoryFs;
+
+    #[test]
+    fn truncated_provisioning_is_detected() {
+        let fs = MemoryFs::new();
+        let mut guest_fs = fs.clone();
+        let path = "./anisette/adi.pb";
+        fs.write_file(path, vec![7; 64]);
+        assert_eq!(
+            verify_checksum(&mut guest_fs, path, None).expect("verify"),
+            None
+        );
+
+        record_checksum(&mut guest_fs, path, Some("abc")).expect("record");
+        assert_eq!(
+            verify_checksum(&mut guest_fs, path, Some("abc")).expect("verify"),
+            None
+        );
+
+        fs.write_file(path, vec![7; 10]);
+        assert!(
+            verify_checksum(&mut guest_fs, path, Some("abc"))
+                .expect("verify")
+                .is_some()
+        );
+    }
+}
@@ -1,28 +1,115 @@
+pub mod apk;
+#[cfg(all(feature = "bench", not(target_arch = "wasm32")))]
+pub mod bench;
 pub mod device;
 mod exports;
+#[cfg(all(feature = "fetch-libs", not(target_arch = "wasm32")))]
+pub mod fetch;
 pub mod idbfs;
 #[cfg(not(target_arch = "wasm32"))]
 pub mod provisioning;
+#[cfg(async_provisioning)]
+pub mod provisioning_async;
+pub mod provisioning_protocol;
 #[cfg(target_arch = "wasm32")]
 mod provisioning_wasm;
 
 mod adi;
 mod allocator;
+mod bundle;
+mod clock;
+mod compat;
 mod constants;
+#[cfg(not(feature = "minimal"))]
+mod core_dump;
 mod debug;
+mod device_store;
 mod emu;
+#[cfg(feature = "encryption")]
+mod encryption;
 mod errors;
+mod file_lock;
+mod flight_recorder;
+#[cfg(not(target_arch = "wasm32"))]
+mod header_cache;
+mod identifier;
+mod integrity;
+mod library;
+mod metrics;
+mod opfs;
+mod overrides;
+mod persistence;
+#[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+mod pinning;
+mod provider;
+mod pthread;
+mod recording;
+#[cfg(all(feature = "remote", not(target_arch = "wasm32")))]
+mod remote;
 mod runtime;
+mod state;
 mod stub;
+mod trace;
+mod transport;
 mod util;
+mod vfs;
+#[cfg(all(
+    feature = "wasm-bindgen",
+    target_arch = "wasm32",
+    not(target_os = "emscripten")
+))]
+mod wasm_bindings;
 
-pub use adi::{Adi, AdiInit, OtpResult, ProvisioningStartResult};
+pub use adi::{
+    Adi, AdiInit, OtpResult, ProvisioningInfo, ProvisioningStartResult, SynchronizeResult,
+};
 pub use allocator::Allocator;
-pub use device::{Device, DeviceData};
+pub use apk::ApkLibraries;
+pub use clock::{FixedClock, GuestClock, SystemClock};
+pub use compat::{DeviceFormat, export_identity, import_identity};
+pub use debug::{LogCallback, LogLevel, log_level, set_log_callback, set_log_level};
+pub use device::{DEVICE_SCHEMA_VERSION, Device, DeviceData, DevicePreset};
+pub use device_store::DeviceStore;
 pub use emu::EmuCore;
-pub use errors::VmError;
-pub use idbfs::{init_idbfs_for_path, sync_idbfs};
+#[cfg(feature = "encryption")]
+pub use encryption::StorageKey;
+pub use errors::{
+    AdiErrorCode, AnisetteStatus, DeviceDataError, ErrorCategory, ErrorReport, IdbfsError,
+    MemoryAccess, VmError,
+};
+#[cfg(all(feature = "fetch-libs", not(target_arch = "wasm32")))]
+pub use fetch::LibraryFetcher;
+#[cfg(not(target_arch = "wasm32"))]
+pub use header_cache::HeaderCache;
+pub use idbfs::{
+    IdbfsBackend, IdbfsSync, IdbfsSyncCallback, idbfs_mounts, init_idbfs_for_path,
+    init_idbfs_for_profile, sync_idbfs, sync_idbfs_async, sync_idbfs_with_callback,
+};
+pub use identifier::{ADI_IDENTIFIER_BYTES, AdiIdentifier};
+pub use library::{
+    KNOWN_GOOD_LIBRARIES, KnownLibrary, LibraryCheck, LibraryInfo, identify_library,
+};
+pub use metrics::{AdiMetrics, CallMetrics, StubStat, StubStats};
+pub use opfs::OpfsBackend;
+pub use overrides::{StubContext, StubOverride};
+pub use persistence::{
+    MemoryBackend, NativeFsBackend, PersistenceBackend, set_persistence_backend,
+};
+pub use provider::{AnisetteProvider, LocalAnisette};
 #[cfg(not(target_arch = "wasm32"))]
-pub use provisioning::ProvisioningSession;
+pub use provisioning::{ProvisioningSession, ReqwestTransport};
+#[cfg(all(async_provisioning, target_arch = "wasm32"))]
+pub use provisioning_async::{AsyncProvisioningSession, FetchTransport};
+#[cfg(all(async_provisioning, not(target_arch = "wasm32")))]
+pub use provisioning_async::{AsyncProvisioningSession, ReqwestAsyncTransport};
 #[cfg(target_arch = "wasm32")]
-pub use provisioning_wasm::ProvisioningSession;
+pub use provisioning_wasm::{JsTransport, ProvisioningSession};
+pub use pthread::PthreadOptions;
+pub use recording::{RecordingTransport, ReplayTransport};
+#[cfg(all(feature = "remote", not(target_arch = "wasm32")))]
+pub use remote::{RemoteAnisette, RemoteState};
+pub use state::ProvisioningState;
+pub use transport::{
+    AsyncHttpTransport, CertificatePinError, HttpOptions, HttpResponse, HttpTransport,
+};
+pub use vfs::{GuestFile, GuestFs, GuestMetadata, GuestOpenOptions, MemoryFs, StdFs};
@@ -0,0 +1,159 @@
+use goblin::elf::Elf;
+use goblin::elf::note::NT_GNU_BUILD_ID;
+use sha2::{Digest, Sha256};
+
+use crate::debug::{debug_print, log_warn};
+use crate::errors::VmError;
+use crate::util::bytes_to_hex;
+
+/// A library build that is known to run to completion with the current stubs.
+#[derive(Debug, Clone, Copy)]
+pub struct KnownLibrary {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub sha256: &'static str,
+}
+
+/// Builds verified against the stub set. Only add hashes of binaries that have
+/// actually completed provisioning and OTP generation under the emulator;
+/// `script/library-hashes.sh` prints the entries for a library directory.
+pub const KNOWN_GOOD_LIBRARIES: &[KnownLibrary] = &[];
+
+/// How `Adi::new` treats library blobs that are not in [`KNOWN_GOOD_LIBRARIES`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LibraryCheck {
+    /// Do not hash the libraries at all.
+    Off,
+    /// Log unknown builds and continue.
+    #[default]
+    Warn,
+    /// Refuse to load unknown builds, including every build of a library the
+    /// table has no entry for.
+    Strict,
+}
+
+/// Identification of a loaded native library, for logging which build produced
+/// a given set of anisette data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LibraryInfo {
+    pub name: String,
+    /// `DT_SONAME` from the dynamic section.
+    pub soname: Option<String>,
+    /// GNU build-id note, hex encoded.
+    pub build_id: Option<String>,
+    pub sha256: String,
+    /// Version from [`KNOWN_GOOD_LIBRARIES`]; the binaries carry no version string.
+    pub version: Option<&'static str>,
+}
+
+pub(crate) fn describe_library(name: &str, data: &[u8]) -> Result<LibraryInfo, VmError> {
+    let elf = Elf::parse(data)?;
+
+    let mut build_id = None;
+    if let Some(notes) = elf.iter_note_headers(data) {
+        for note in notes.flatten() {
+            if note.n_type == NT_GNU_BUILD_ID && note.name == "GNU" {
+                build_id = Some(bytes_to_hex(note.desc));
+                break;
+            }
+        }
+    }
+
+    Ok(LibraryInfo {
+        name: name.to_string(),
+        soname: elf.soname.map(str::to_string),
+        build_id,
+        sha256: sha256_hex(data),
+        version: identify_library(name, data).map(|known| known.version),
+    })
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    bytes_to_hex(&Sha256::digest(data))
+}
+
+/// Looks up a known build of `name` by content hash.
+pub fn identify_library(name: &str, data: &[u8]) -> Option<&'static KnownLibrary> {
+    let sha256 = sha256_hex(data);
+    KNOWN_GOOD_LIBRARIES
+        .iter()
+        .find(|known| known.name == name && known.sha256 == sha256)
+}
+
+pub(crate) fn verify_library(name: &str, data: &[u8], check: LibraryCheck) -> Result<(), VmError> {
+    verify_against(KNOWN_GOOD_LIBRARIES, name, data, check)
+}
+
+fn verify_against(
+    known: &[KnownLibrary],
+    name: &str,
+    data: &[u8],
+    check: LibraryCheck,
+) -> Result<(), VmError> {
+    if check == LibraryCheck::Off {
+        return Ok(());
+    }
+
+    let sha256 = sha256_hex(data);
+    let expected: Vec<&str> = known
+        .iter()
+        .filter(|known| known.name == name)
+        .map(|known| known.version)
+        .collect();
+    if known
+        .iter()
+        .any(|known| known.name == name && known.sha256 == sha256)
+    {
+        return Ok(());
+    }
+    if expected.is_empty() && check == LibraryCheck::Warn {
+        // Nothing to compare against for this library.
+        return Ok(());
+    }
+
+    let error = VmError::UnsupportedLibrary {
+        name: name.to_string(),
+        sha256,
+        expected: if expected.is_empty() {
+            "none known".to_string()
+        } else {
+            expected.join(", ")
+        },
+    };
+    match check {
+        LibraryCheck::Strict => Err(error),
+        _ => {
+            log_warn!(error.to_string());
+            debug_print!(format!("Continuing with unverified {name}"));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KnownLibrary, LibraryCheck, sha256_hex, verify_against};
+    use crate::errors::VmError;
+
+    #[test]
+    fn strict_rejects_unknown_builds() {
+        let good = b"known build".as_slice();
+        let known = [KnownLibrary {
+            name: "libCoreADI.so",
+            version: "test",
+            sha256: sha256_hex(good).leak(),
+        }];
+
+        let check = |known: &[KnownLibrary], data: &[u8], check| {
+            verify_against(known, "libCoreADI.so", data, check)
+        };
+        assert!(check(&known, good, LibraryCheck::Strict).is_ok());
+        assert!(matches!(
+            check(&known, b"other build", LibraryCheck::Strict),
+            Err(VmError::UnsupportedLibrary { .. })
+        ));
+        assert!(check(&[], good, LibraryCheck::Strict).is_err());
+        assert!(check(&known, b"other build", LibraryCheck::Warn).is_ok());
+        assert!(check(&[], b"other build", LibraryCheck::Off).is_ok());
+    }
+}
@@ -0,0 +1,175 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+/// Durations kept per entry point for [`CallMetrics::percentile`].
+const SAMPLE_WINDOW: usize = 1024;
+
+/// Timings and emulator counters for one ADI entry point since the `Adi` was
+/// created or [`Adi::reset_metrics`](crate::Adi::reset_metrics) was called.
+#[derive(Debug, Clone, Default)]
+pub struct CallMetrics {
+    pub calls: u64,
+    /// Calls the emulator could not finish; ADI error codes still count as calls.
+    pub failures: u64,
+    pub total: Duration,
+    pub max: Duration,
+    /// Guest instructions executed, counted only while
+    /// [`Adi::set_instruction_counting`](crate::Adi::set_instruction_counting) is on.
+    pub instructions: u64,
+    /// Guest memory allocated while the library ran, page-rounded.
+    pub bytes_allocated: u64,
+    recent: VecDeque<Duration>,
+}
+
+impl CallMetrics {
+    pub fn mean(&self) -> Option<Duration> {
+        let calls = u32::try_from(self.calls).ok().filter(|&calls| calls > 0)?;
+        Some(self.total / calls)
+    }
+
+    /// The duration `percentile` percent of the last 1024 calls finished within,
+    /// e.g. `percentile(99.0)` for p99.
+    pub fn percentile(&self, percentile: f64) -> Option<Duration> {
+        if self.recent.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.recent.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0 * sorted.len() as f64).ceil() as usize;
+        Some(sorted[rank.clamp(1, sorted.len()) - 1])
+    }
+
+    fn record(&mut self, sample: &CallSample) {
+        self.calls += 1;
+        if !sample.ok {
+            self.failures += 1;
+        }
+        self.total += sample.duration;
+        self.max = self.max.max(sample.duration);
+        self.instructions += sample.instructions;
+        self.bytes_allocated += sample.bytes_allocated;
+        if self.recent.len() == SAMPLE_WINDOW {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(sample.duration);
+    }
+}
+
+/// One call into the library, as measured by `Adi`.
+pub(crate) struct CallSample {
+    pub(crate) duration: Duration,
+    pub(crate) instructions: u64,
+    pub(crate) bytes_allocated: u64,
+    pub(crate) ok: bool,
+}
+
+/// [`CallMetrics`] per ADI entry point, keyed by name (e.g. `ADIOTPRequest`).
+#[derive(Debug, Clone, Default)]
+pub struct AdiMetrics {
+    calls: BTreeMap<&'static str, CallMetrics>,
+}
+
+impl AdiMetrics {
+    pub fn get(&self, name: &str) -> Option<&CallMetrics> {
+        self.calls.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &CallMetrics)> {
+        self.calls.iter().map(|(name, metrics)| (*name, metrics))
+    }
+
+    pub(crate) fn record(&mut self, name: &'static str, sample: CallSample) {
+        self.calls.entry(name).or_default().record(&sample);
+    }
+}
+
+/// How often one import stub ran and for how long.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StubStat {
+    pub calls: u64,
+    /// Includes guest code the stub called back into, e.g. `pthread_once` routines.
+    pub total: Duration,
+}
+
+/// [`StubStat`] per imported symbol, keyed by name (e.g. `malloc`).
+#[derive(Debug, Clone, Default)]
+pub struct StubStats {
+    stubs: BTreeMap<String, StubStat>,
+}
+
+impl StubStats {
+    pub fn get(&self, symbol: &str) -> Option<&StubStat> {
+        self.stubs.get(symbol)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &StubStat)> {
+        self.stubs
+            .iter()
+            .map(|(symbol, stat)| (symbol.as_str(), stat))
+    }
+
+    /// The stubs that took the most time first.
+    pub fn by_total_time(&self) -> Vec<(&str, &StubStat)> {
+        let mut stubs: Vec<_> = self.iter().collect();
+        stubs.sort_by_key(|(_, stat)| Reverse(stat.total));
+        stubs
+    }
+
+    pub(crate) fn record(&mut self, symbol: &str, duration: Duration) {
+        let stat = match self.stubs.get_mut(symbol) {
+            Some(stat) => stat,
+            None => self.stubs.entry(symbol.to_string()).or_default(),
+        };
+        stat.calls += 1;
+        stat.total += duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{AdiMetrics, CallSample, StubStats};
+
+    #[test]
+    fn percentiles_come_from_recent_calls() {
+        let mut metrics = AdiMetrics::default();
+        for ms in 1..=100 {
+            metrics.record(
+                "ADIOTPRequest",
+                CallSample {
+                    duration: Duration::from_millis(ms),
+                    instructions: 10,
+                    bytes_allocated: 0,
+                    ok: ms != 100,
+                },
+            );
+        }
+
+        let otp = metrics.get("ADIOTPRequest").expect("recorded");
+        assert_eq!(otp.calls, 100);
+        assert_eq!(otp.failures, 1);
+        assert_eq!(otp.instructions, 1000);
+        assert_eq!(otp.percentile(99.0), Some(Duration::from_millis(99)));
+        assert_eq!(otp.percentile(50.0), Some(Duration::from_millis(50)));
+        assert_eq!(otp.max, Duration::from_millis(100));
+        assert!(metrics.get("ADIGetLoginCode").is_none());
+    }
+
+    #[test]
+    fn stub_stats_rank_by_time() {
+        let mut stats = StubStats::default();
+        stats.record("malloc", Duration::from_micros(2));
+        stats.record("malloc", Duration::from_micros(3));
+        stats.record("open", Duration::from_micros(40));
+
+        assert_eq!(stats.get("malloc").map(|stat| stat.calls), Some(2));
+        let ranked: Vec<&str> = stats
+            .by_total_time()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(ranked, ["open", "malloc"]);
+    }
+}
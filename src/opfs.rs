@@ -0,0 +1,74 @@
+use std::{fs, io};
+
+#[cfg(target_os = "emscripten")]
+use std::ffi::CString;
+
+use crate::idbfs::normalize_mount_path;
+use crate::persistence::{PersistenceBackend, write_host_file};
+
+#[cfg(target_os = "emscripten")]
+unsafe extern "C" {
+    // Defined in script/anisette-library.js.
+    fn anisette_js_opfs_mount(path: *const core::ffi::c_char) -> i32;
+    fn anisette_js_opfs_sync(populate: i32);
+}
+
+#[cfg(target_os = "emscripten")]
+fn mount(path: &str) -> io::Result<()> {
+    let path = CString::new(path).map_err(io::Error::other)?;
+    if unsafe { anisette_js_opfs_mount(path.as_ptr()) } != 0 {
+        return Err(unavailable());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "emscripten")]
+fn sync(populate_from_storage: bool) {
+    unsafe {
+        anisette_js_opfs_sync(i32::from(populate_from_storage));
+    }
+}
+
+#[cfg(not(target_os = "emscripten"))]
+fn mount(_path: &str) -> io::Result<()> {
+    Err(unavailable())
+}
+
+#[cfg(not(target_os = "emscripten"))]
+fn sync(_populate_from_storage: bool) {}
+
+fn unavailable() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the origin private file system is not available",
+    )
+}
+
+/// The browser's Origin Private File System. As with
+/// [`IdbfsBackend`](crate::IdbfsBackend), files live in MEMFS and are mirrored to
+/// the same path in OPFS, but OPFS is
+/// faster and also works in Workers, where IDBFS sync callbacks are unreliable.
+/// Syncs run asynchronously on the JS side; `init` fails where OPFS is missing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OpfsBackend;
+
+impl PersistenceBackend for OpfsBackend {
+    fn init(&mut self, path: &str) -> io::Result<String> {
+        let mount_path = normalize_mount_path(path);
+        mount(&mount_path)?;
+        Ok(mount_path)
+    }
+
+    fn sync(&mut self, populate_from_storage: bool) -> io::Result<()> {
+        sync(populate_from_storage);
+        Ok(())
+    }
+
+    fn read(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        write_host_file(path, data)
+    }
+}
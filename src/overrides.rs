@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use unicorn_engine::{RegisterARM64, Unicorn};
+
+use crate::constants::ARG_REGS;
+use crate::emu::{read_c_string, set_errno};
+use crate::errors::VmError;
+use crate::runtime::RuntimeState;
+
+/// Replacement for a built-in import stub, registered by symbol name.
+pub type StubOverride = Box<dyn FnMut(&mut StubContext<'_, '_>) -> Result<(), VmError>>;
+
+/// Registered overrides; consulted before the built-in stubs.
+#[derive(Default)]
+pub(crate) struct StubOverrides {
+    by_name: HashMap<String, StubOverride>,
+}
+
+impl fmt::Debug for StubOverrides {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.by_name.keys()).finish()
+    }
+}
+
+impl StubOverrides {
+    pub(crate) fn insert(&mut self, name: String, stub: StubOverride) {
+        self.by_name.insert(name, stub);
+    }
+
+    pub(crate) fn remove(&mut self, name: &str) -> bool {
+        self.by_name.remove(name).is_some()
+    }
+
+    pub(crate) fn take(&mut self, name: &str) -> Option<StubOverride> {
+        if self.by_name.is_empty() {
+            return None;
+        }
+        self.by_name.remove(name)
+    }
+
+    /// Puts an override back after it ran, unless it was replaced or removed meanwhile.
+    pub(crate) fn restore(&mut self, name: &str, stub: StubOverride) {
+        self.by_name.entry(name.to_string()).or_insert(stub);
+    }
+}
+
+/// View of the emulator handed to a [`StubOverride`] while it services one import call.
+pub struct StubContext<'a, 'b> {
+    pub(crate) uc: &'a mut Unicorn<'b, RuntimeState>,
+    pub(crate) symbol: &'a str,
+}
+
+impl StubContext<'_, '_> {
+    pub fn symbol(&self) -> &str {
+        self.symbol
+    }
+
+    /// Integer argument `index` (X0..X28).
+    pub fn arg(&self, index: usize) -> Result<u64, VmError> {
+        let reg = ARG_REGS
+            .get(index)
+            .ok_or(VmError::TooManyArguments(index + 1))?;
+        Ok(self.uc.reg_read(*reg)?)
+    }
+
+    pub fn set_return(&mut self, value: u64) -> Result<(), VmError> {
+        self.uc.reg_write(RegisterARM64::X0, value)?;
+        Ok(())
+    }
+
+    pub fn set_errno(&mut self, value: u32) -> Result<(), VmError> {
+        set_errno(self.uc, value)
+    }
+
+    pub fn read_memory(&self, address: u64, length: usize) -> Result<Vec<u8>, VmError> {
+        Ok(self.uc.mem_read_as_vec(address, length)?)
+    }
+
+    pub fn write_memory(&mut self, address: u64, data: &[u8]) -> Result<(), VmError> {
+        self.uc.mem_write(address, data)?;
+        Ok(())
+    }
+
+    pub fn read_c_string(&self, address: u64, max_len: usize) -> Result<String, VmError> {
+        read_c_string(self.uc, address, max_len)
+    }
+
+    /// Runs the crate's own stub for this symbol, e.g. to wrap or post-process it.
+    pub fn call_builtin(&mut self) -> Result<(), VmError> {
+        crate::stub::handle_stub_by_name(self.uc, self.symbol)
+    }
+}
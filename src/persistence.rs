@@ -0,0 +1,183 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+
+#[cfg(not(target_os = "emscripten"))]
+use crate::debug::log_warn;
+use crate::file_lock::write_atomic;
+use crate::vfs::MemoryFs;
+
+/// Where provisioning state outlives the process.
+///
+/// The guest reads and writes files under the provisioning path as usual; the
+/// backend decides how that directory is made durable. One backend is active per
+/// process (see [`set_persistence_backend`]); it is flushed whenever the guest
+/// `fsync`s a file it changed or closes an `adi.pb` it wrote, and the
+/// `anisette_fs_*` / `anisette_idbfs_sync` exports go through it.
+pub trait PersistenceBackend: fmt::Debug + Send {
+    /// Prepares the provisioning directory at `path` and returns the path the
+    /// guest should use for it.
+    fn init(&mut self, path: &str) -> io::Result<String>;
+    /// Flushes to durable storage, or with `populate_from_storage` reloads from it.
+    fn sync(&mut self, populate_from_storage: bool) -> io::Result<()>;
+    fn read(&mut self, path: &str) -> io::Result<Vec<u8>>;
+    fn write(&mut self, path: &str, data: &[u8]) -> io::Result<()>;
+}
+
+/// Plain files on the host filesystem, which are durable as soon as they are
+/// written. The default outside emscripten.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NativeFsBackend;
+
+impl PersistenceBackend for NativeFsBackend {
+    fn init(&mut self, path: &str) -> io::Result<String> {
+        fs::create_dir_all(path)?;
+        Ok(path.to_string())
+    }
+
+    fn sync(&mut self, _populate_from_storage: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        write_host_file(path, data)
+    }
+}
+
+/// Keeps everything in a [`MemoryFs`]; pass a clone of the same `MemoryFs` as
+/// [`AdiInit::guest_fs`](crate::AdiInit::guest_fs) and persist its contents
+/// yourself (e.g. in `localStorage`).
+#[derive(Debug, Default, Clone)]
+pub struct MemoryBackend {
+    fs: MemoryFs,
+}
+
+impl MemoryBackend {
+    pub fn new(fs: MemoryFs) -> Self {
+        Self { fs }
+    }
+
+    pub fn fs(&self) -> &MemoryFs {
+        &self.fs
+    }
+}
+
+impl PersistenceBackend for MemoryBackend {
+    fn init(&mut self, path: &str) -> io::Result<String> {
+        Ok(path.to_string())
+    }
+
+    fn sync(&mut self, _populate_from_storage: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, path: &str) -> io::Result<Vec<u8>> {
+        self.fs
+            .read_file(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{path} not found")))
+    }
+
+    fn write(&mut self, path: &str, data: &[u8]) -> io::Result<()> {
+        self.fs.write_file(path, data.to_vec());
+        Ok(())
+    }
+}
+
+static BACKEND: Mutex<Option<Box<dyn PersistenceBackend>>> = Mutex::new(None);
+
+/// Replaces the process-wide backend. Without one, emscripten builds use
+/// [`IdbfsBackend`](crate::IdbfsBackend) and everything else
+/// [`NativeFsBackend`].
+pub fn set_persistence_backend(backend: impl PersistenceBackend + 'static) {
+    *BACKEND.lock().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(backend));
+}
+
+pub(crate) fn with_persistence<T>(f: impl FnOnce(&mut dyn PersistenceBackend) -> T) -> T {
+    let mut backend = BACKEND.lock().unwrap_or_else(PoisonError::into_inner);
+    f(backend.get_or_insert_with(default_backend).as_mut())
+}
+
+/// Quiet period before a scheduled sync runs, so the burst of writes around one
+/// provisioning step ends in a single sync.
+#[cfg(target_os = "emscripten")]
+const SYNC_DEBOUNCE_MS: u32 = 250;
+
+#[cfg(target_os = "emscripten")]
+unsafe extern "C" {
+    // Defined in script/anisette-library.js; calls `anisette_idbfs_sync(0)` once
+    // `delay_ms` pass without another call.
+    fn anisette_js_schedule_sync(delay_ms: u32);
+}
+
+/// Flushes the active backend soon: on emscripten after a short debounce (the JS
+/// side syncs asynchronously anyway), elsewhere right away. Failures are logged.
+#[cfg(target_os = "emscripten")]
+pub(crate) fn schedule_sync() {
+    unsafe {
+        anisette_js_schedule_sync(SYNC_DEBOUNCE_MS);
+    }
+}
+
+#[cfg(not(target_os = "emscripten"))]
+pub(crate) fn schedule_sync() {
+    if let Err(err) = with_persistence(|backend| backend.sync(false)) {
+        log_warn!(format!("Persistence sync failed: {err}"));
+    }
+}
+
+#[cfg(target_os = "emscripten")]
+fn default_backend() -> Box<dyn PersistenceBackend> {
+    Box::new(crate::idbfs::IdbfsBackend)
+}
+
+#[cfg(not(target_os = "emscripten"))]
+fn default_backend() -> Box<dyn PersistenceBackend> {
+    Box::new(NativeFsBackend)
+}
+
+/// Writes through a temporary file, creating missing parent directories.
+pub(crate) fn write_host_file(path: &str, data: &[u8]) -> io::Result<()> {
+    let path = Path::new(path);
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)?;
+    }
+    write_atomic(path, data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryBackend, NativeFsBackend, PersistenceBackend};
+    use crate::vfs::MemoryFs;
+
+    #[test]
+    fn backends_round_trip_files() {
+        let fs = MemoryFs::new();
+        let mut memory = MemoryBackend::new(fs.clone());
+        memory.write("./anisette/adi.pb", b"state").expect("write");
+        assert_eq!(
+            fs.read_file("./anisette/adi.pb").as_deref(),
+            Some(&b"state"[..])
+        );
+        assert!(memory.read("./anisette/missing").is_err());
+
+        let root =
+            std::env::temp_dir().join(format!("anisette-persistence-{}", std::process::id()));
+        let dir = root.join("anisette");
+        let mut native = NativeFsBackend;
+        let dir = native
+            .init(dir.to_str().expect("utf-8 path"))
+            .expect("init");
+        let path = format!("{dir}/adi.pb");
+        native.write(&path, b"state").expect("write");
+        assert_eq!(native.read(&path).expect("read"), b"state");
+        std::fs::remove_dir_all(&root).expect("cleanup");
+    }
+}
@@ -0,0 +1,156 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// TLS config that only accepts chains containing a pinned public key.
+pub(crate) struct PinnedTls {
+    pub(crate) config: ClientConfig,
+    /// Set by the verifier when a handshake failed the pin check, so the transport
+    /// can report [`CertificatePinError`](crate::CertificatePinError) instead of a
+    /// generic connection error.
+    pub(crate) failures: Arc<AtomicBool>,
+}
+
+/// `pins` are base64 SHA-256 digests of DER SubjectPublicKeyInfo, as in HPKP's
+/// `pin-sha256`. Without `root_pem` the chain itself is not verified, only the pins.
+pub(crate) fn pinned_tls(root_pem: Option<&[u8]>, pins: &[String]) -> Result<PinnedTls> {
+    let pins = pins
+        .iter()
+        .map(|pin| decode_pin(pin))
+        .collect::<Result<Vec<_>>>()?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let chain = match root_pem {
+        Some(pem) => {
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_slice_iter(pem) {
+                roots.add(cert.context("invalid root certificate pem")?)?;
+            }
+            Some(
+                WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                    .build()?,
+            )
+        }
+        None => None,
+    };
+
+    let failures = Arc::new(AtomicBool::new(false));
+    let verifier = PinnedVerifier {
+        chain,
+        pins,
+        provider: provider.clone(),
+        failures: failures.clone(),
+    };
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(PinnedTls { config, failures })
+}
+
+fn decode_pin(pin: &str) -> Result<[u8; 32]> {
+    let digest = STANDARD
+        .decode(pin.trim_start_matches("sha256/"))
+        .map_err(|err| anyhow!("invalid SPKI pin {pin}: {err}"))?;
+    match <[u8; 32]>::try_from(digest) {
+        Ok(digest) => Ok(digest),
+        Err(_) => bail!("invalid SPKI pin {pin}: not a SHA-256 digest"),
+    }
+}
+
+fn spki_sha256(cert: &CertificateDer<'_>) -> Option<[u8; 32]> {
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    Some(Sha256::digest(cert.public_key().raw).into())
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    chain: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<[u8; 32]>,
+    provider: Arc<CryptoProvider>,
+    failures: Arc<AtomicBool>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if let Some(chain) = &self.chain {
+            chain.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(spki_sha256)
+            .any(|digest| self.pins.contains(&digest));
+        if !pinned {
+            self.failures.store(true, Ordering::Relaxed);
+            return Err(rustls::Error::General("SPKI pin mismatch".to_string()));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_pin;
+
+    #[test]
+    fn pins_are_base64_sha256_digests() {
+        let pin = "sha256/AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=";
+        assert_eq!(decode_pin(pin).expect("valid pin"), [0; 32]);
+        assert!(decode_pin("AAAA").is_err());
+        assert!(decode_pin("not base64!").is_err());
+    }
+}
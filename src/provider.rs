@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::device::DeviceData;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::provisioning::ReqwestTransport;
+use crate::provisioning_protocol::{self, SessionState};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::HttpOptions;
+use crate::transport::HttpTransport;
+use crate::{Adi, ProvisioningSession};
+
+/// Source of anisette headers, so an application can pick local emulation or a
+/// hosted server at runtime (e.g. fall back to [`RemoteAnisette`] when the ADI
+/// libraries are not available).
+///
+/// [`RemoteAnisette`]: crate::RemoteAnisette
+pub trait AnisetteProvider {
+    /// The full v3 header set for `dsid`, provisioning first when needed.
+    fn anisette_headers(&mut self, dsid: u64) -> Result<HashMap<String, String>>;
+}
+
+impl<T: AnisetteProvider + ?Sized> AnisetteProvider for Box<T> {
+    fn anisette_headers(&mut self, dsid: u64) -> Result<HashMap<String, String>> {
+        (**self).anisette_headers(dsid)
+    }
+}
+
+impl AnisetteProvider for ProvisioningSession<'_> {
+    fn anisette_headers(&mut self, dsid: u64) -> Result<HashMap<String, String>> {
+        ProvisioningSession::anisette_headers(self, dsid)
+    }
+}
+
+/// Local emulation that owns its [`Adi`], device and transport, for places that
+/// need a `'static` provider such as [`HeaderCache::spawn`]. Otherwise it behaves
+/// like a [`ProvisioningSession`] kept open for its whole lifetime.
+///
+/// [`HeaderCache::spawn`]: crate::HeaderCache::spawn
+pub struct LocalAnisette {
+    adi: Adi,
+    device: DeviceData,
+    transport: Box<dyn HttpTransport>,
+    state: SessionState,
+}
+
+impl LocalAnisette {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(adi: Adi, device: DeviceData, options: impl Into<HttpOptions>) -> Result<Self> {
+        let transport = ReqwestTransport::new(&options.into())?;
+        Ok(Self::with_transport(adi, device, transport))
+    }
+
+    /// Provisions over a caller-supplied transport, e.g. a
+    /// [`ReplayTransport`](crate::ReplayTransport).
+    pub fn with_transport(
+        adi: Adi,
+        device: DeviceData,
+        transport: impl HttpTransport + 'static,
+    ) -> Self {
+        Self {
+            adi,
+            device,
+            transport: Box::new(transport),
+            state: SessionState::default(),
+        }
+    }
+
+    /// Routing info GSA returned so far, if any; see
+    /// [`ProvisioningSession::routing_info`].
+    pub fn routing_info(&self) -> Option<&str> {
+        self.state.routing_info.as_deref()
+    }
+
+    pub fn adi_mut(&mut self) -> &mut Adi {
+        &mut self.adi
+    }
+
+    pub fn device(&self) -> &DeviceData {
+        &self.device
+    }
+}
+
+impl AnisetteProvider for LocalAnisette {
+    fn anisette_headers(&mut self, dsid: u64) -> Result<HashMap<String, String>> {
+        provisioning_protocol::anisette_headers(
+            &mut self.adi,
+            &self.device,
+            self.transport.as_mut(),
+            &mut self.state,
+            dsid,
+        )
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashMap;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::atomic::AtomicBool;
+
+use anyhow::Result;
+#[cfg(target_arch = "wasm32")]
+use anyhow::anyhow;
+#[cfg(target_arch = "wasm32")]
+use anyhow::bail;
+#[cfg(target_arch = "wasm32")]
+use js_sys::{Function, Promise, Reflect, Uint8Array};
+#[cfg(not(target_arch = "wasm32"))]
+use reqwest::{Client, RequestBuilder};
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::{JsCast, JsValue};
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen_futures::JsFuture;
+#[cfg(target_arch = "wasm32")]
+use web_sys::{Headers, Request, RequestInit, Response};
+
+use crate::Adi;
+use crate::device::DeviceData;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::provisioning::response_headers;
+#[cfg(target_arch = "wasm32")]
+use crate::provisioning_protocol::ROUTING_INFO_HEADER;
+use crate::provisioning_protocol::{self, SessionState};
+use crate::transport::{AsyncHttpTransport, HttpOptions, HttpResponse};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::transport::{build_reqwest_client, pin_checked};
+
+#[cfg(target_arch = "wasm32")]
+use self::FetchTransport as DefaultTransport;
+#[cfg(not(target_arch = "wasm32"))]
+use self::ReqwestAsyncTransport as DefaultTransport;
+
+/// [`ProvisioningSession`](crate::ProvisioningSession) over an
+/// [`AsyncHttpTransport`]: reqwest's async client natively (feature `tokio`), `fetch`
+/// on `wasm32-unknown-unknown`.
+///
+/// Only the HTTP round-trips are awaited; the ADI calls in between run inline on the
+/// calling task. [`Adi`] is not `Send`, so the futures have to be awaited on the
+/// thread that owns the `Adi` (e.g. inside a tokio `LocalSet`).
+pub struct AsyncProvisioningSession<'a, T = DefaultTransport> {
+    adi: &'a mut Adi,
+    device: &'a DeviceData,
+    transport: T,
+    state: SessionState,
+}
+
+impl<'a> AsyncProvisioningSession<'a> {
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn new(
+        adi: &'a mut Adi,
+        device: &'a DeviceData,
+        options: impl Into<HttpOptions>,
+    ) -> Result<Self> {
+        let transport = ReqwestAsyncTransport::new(&options.into())?;
+        Ok(Self::with_transport(adi, device, transport))
+    }
+
+    /// TLS is the browser's business here, so `options` is ignored.
+    #[cfg(target_arch = "wasm32")]
+    pub fn new(
+        adi: &'a mut Adi,
+        device: &'a DeviceData,
+        _options: impl Into<HttpOptions>,
+    ) -> Result<Self> {
+        Ok(Self::with_transport(adi, device, FetchTransport))
+    }
+}
+
+impl<'a, T: AsyncHttpTransport> AsyncProvisioningSession<'a, T> {
+    /// Runs the provisioning exchange over a caller-supplied transport.
+    pub fn with_transport(adi: &'a mut Adi, device: &'a DeviceData, transport: T) -> Self {
+        Self {
+            adi,
+            device,
+            transport,
+            state: SessionState::default(),
+        }
+    }
+
+    pub async fn provision(&mut self, dsid: u64) -> Result<()> {
+        provisioning_protocol::provision_async(
+            self.adi,
+            self.device,
+            &mut self.transport,
+            &mut self.state,
+            dsid,
+        )
+        .await
+    }
+
+    /// See [`ProvisioningSession::anisette_headers`](crate::ProvisioningSession::anisette_headers).
+    pub async fn anisette_headers(&mut self, dsid: u64) -> Result<HashMap<String, String>> {
+        provisioning_protocol::anisette_headers_async(
+            self.adi,
+            self.device,
+            &mut self.transport,
+            &mut self.state,
+            dsid,
+        )
+        .await
+    }
+
+    /// See [`ProvisioningSession::routing_info`](crate::ProvisioningSession::routing_info).
+    pub fn routing_info(&self) -> Option<&str> {
+        self.state.routing_info.as_deref()
+    }
+
+    /// See [`ProvisioningSession::set_lookup_url`](crate::ProvisioningSession::set_lookup_url).
+    pub fn set_lookup_url(&mut self, url: impl Into<String>) {
+        self.state.url_bag.set_lookup_url(url.into());
+    }
+
+    /// See [`ProvisioningSession::set_url_bag_entry`](crate::ProvisioningSession::set_url_bag_entry).
+    pub fn set_url_bag_entry(&mut self, name: impl Into<String>, url: impl Into<String>) {
+        self.state.url_bag.set_entry(name.into(), url.into());
+    }
+}
+
+/// Default native async transport: reqwest's async client trusting Apple's root CA.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct ReqwestAsyncTransport {
+    client: Client,
+    pin_failures: Option<Arc<AtomicBool>>,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ReqwestAsyncTransport {
+    pub fn new(options: &HttpOptions) -> Result<Self> {
+        let (client, pin_failures) = build_reqwest_client(Client::builder(), options)?;
+        Ok(Self {
+            client,
+            pin_failures,
+        })
+    }
+
+    pub fn from_client(client: Client) -> Self {
+        Self {
+            client,
+            pin_failures: None,
+        }
+    }
+
+    async fn send(&self, url: &str, request: RequestBuilder) -> Result<HttpResponse> {
+        let response = request
+            .send()
+            .await
+            .map_err(|err| pin_checked(self.pin_failures.as_deref(), url, err))?
+            .error_for_status()?;
+        Ok(HttpResponse {
+            headers: response_headers(response.headers()),
+            body: response.bytes().await?.to_vec(),
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl AsyncHttpTransport for ReqwestAsyncTransport {
+    async fn get(&mut self, url: &str, headers: &[(&str, String)]) -> Result<HttpResponse> {
+        let request = headers
+            .iter()
+            .fold(self.client.get(url), |request, (name, value)| {
+                request.header(*name, value)
+            });
+        self.send(url, request).await
+    }
+
+    async fn post(
+        &mut self,
+        url: &str,
+        headers: &[(&str, String)],
+        body: &str,
+    ) -> Result<HttpResponse> {
+        let request = headers.iter().fold(
+            self.client.post(url).body(body.to_string()),
+            |request, (name, value)| request.header(*name, value),
+        );
+        self.send(url, request).await
+    }
+}
+
+/// Default transport on `wasm32-unknown-unknown`: the host's global `fetch`, so it
+/// works in pages, workers and Node alike.
+///
+/// Browsers drop forbidden headers (`User-Agent`, `Connection`) and GSA sends no
+/// CORS headers, so pages usually need a proxy: point the session at it with
+/// [`AsyncProvisioningSession::set_lookup_url`] and `set_url_bag_entry`. Only the
+/// routing-info response header is read back.
+#[cfg(target_arch = "wasm32")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FetchTransport;
+
+#[cfg(target_arch = "wasm32")]
+impl FetchTransport {
+    async fn send(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, String)],
+        body: Option<&str>,
+    ) -> Result<HttpResponse> {
+        let request_headers = Headers::new().map_err(js_error)?;
+        for (name, value) in headers {
+            request_headers.set(name, value).map_err(js_error)?;
+        }
+        let init = RequestInit::new();
+        init.set_method(method);
+        init.set_headers(&request_headers);
+        if let Some(body) = body {
+            init.set_body(&JsValue::from_str(body));
+        }
+        let request = Request::new_with_str_and_init(url, &init).map_err(js_error)?;
+
+        let fetch: Function = Reflect::get(&js_sys::global(), &JsValue::from_str("fetch"))
+            .map_err(js_error)?
+            .dyn_into()
+            .map_err(|_| anyhow!("global fetch is not available"))?;
+        let promise: Promise = fetch
+            .call1(&JsValue::UNDEFINED, &request)
+            .map_err(js_error)?
+            .dyn_into()
+            .map_err(js_error)?;
+        let response: Response = JsFuture::from(promise)
+            .await
+            .map_err(js_error)?
+            .dyn_into()
+            .map_err(js_error)?;
+        if !response.ok() {
+            bail!("fetch status {} for {url}", response.status());
+        }
+
+        let routing_info = response
+            .headers()
+            .get(ROUTING_INFO_HEADER)
+            .map_err(js_error)?;
+        let buffer = JsFuture::from(response.array_buffer().map_err(js_error)?)
+            .await
+            .map_err(js_error)?;
+        Ok(HttpResponse {
+            headers: routing_info
+                .map(|value| (ROUTING_INFO_HEADER.to_string(), value))
+                .into_iter()
+                .collect(),
+            body: Uint8Array::new(&buffer).to_vec(),
+        })
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl AsyncHttpTransport for FetchTransport {
+    async fn get(&mut self, url: &str, headers: &[(&str, String)]) -> Result<HttpResponse> {
+        self.send("GET", url, headers, None).await
+    }
+
+    async fn post(
+        &mut self,
+        url: &str,
+        headers: &[(&str, String)],
+        body: &str,
+    ) -> Result<HttpResponse> {
+        self.send("POST", url, headers, Some(body)).await
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn js_error(err: JsValue) -> anyhow::Error {
+    match err.as_string() {
+        Some(message) => anyhow!("fetch failed: {message}"),
+        None => anyhow!("fetch failed: {err:?}"),
+    }
+}
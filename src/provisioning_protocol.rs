@@ -0,0 +1,664 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use anyhow::{Result, anyhow, bail};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use plist::Value;
+
+use crate::Adi;
+use crate::debug::debug_print;
+use crate::device::DeviceData;
+#[cfg(async_provisioning)]
+use crate::transport::AsyncHttpTransport;
+use crate::transport::{HttpResponse, HttpTransport};
+
+const LOOKUP_URL: &str = "https://gsa.apple.com/grandslam/GsService2/lookup";
+
+/// Body of the `midStartProvisioning` request; it carries nothing device-specific.
+pub const START_PROVISIONING_BODY: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Header</key>
+  <dict/>
+  <key>Request</key>
+  <dict/>
+</dict>
+</plist>"#;
+
+pub(crate) const ROUTING_INFO_HEADER: &str = "X-Apple-I-MD-RINFO";
+
+pub(crate) const START_PROVISIONING_KEY: &str = "midStartProvisioning";
+pub(crate) const FINISH_PROVISIONING_KEY: &str = "midFinishProvisioning";
+
+/// Endpoints for the exchange: the url bag fetched from the lookup URL, with
+/// caller-supplied entries taking precedence.
+#[derive(Debug, Clone)]
+pub(crate) struct UrlBag {
+    lookup_url: String,
+    overrides: HashMap<String, String>,
+    fetched: HashMap<String, String>,
+}
+
+impl Default for UrlBag {
+    fn default() -> Self {
+        Self {
+            lookup_url: LOOKUP_URL.to_string(),
+            overrides: HashMap::new(),
+            fetched: HashMap::new(),
+        }
+    }
+}
+
+impl UrlBag {
+    pub(crate) fn lookup_url(&self) -> &str {
+        &self.lookup_url
+    }
+
+    /// Also drops a previously fetched bag, so the next exchange looks up again.
+    pub(crate) fn set_lookup_url(&mut self, url: String) {
+        self.lookup_url = url;
+        self.fetched.clear();
+    }
+
+    pub(crate) fn set_entry(&mut self, name: String, url: String) {
+        self.overrides.insert(name, url);
+    }
+
+    /// The lookup is skipped once both provisioning endpoints are known.
+    pub(crate) fn needs_lookup(&self) -> bool {
+        [START_PROVISIONING_KEY, FINISH_PROVISIONING_KEY]
+            .iter()
+            .any(|name| self.entry(name).is_none())
+    }
+
+    pub(crate) fn set_fetched(&mut self, fetched: HashMap<String, String>) {
+        self.fetched = fetched;
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Result<String> {
+        self.entry(name)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("url bag missing {name}"))
+    }
+
+    fn entry(&self, name: &str) -> Option<&str> {
+        self.overrides
+            .get(name)
+            .or_else(|| self.fetched.get(name))
+            .map(String::as_str)
+    }
+}
+
+/// What a session remembers between exchanges.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SessionState {
+    pub(crate) url_bag: UrlBag,
+    /// Routing info GSA returned; takes precedence over the device's stored value.
+    pub(crate) routing_info: Option<String>,
+}
+
+impl SessionState {
+    /// `device` with any routing info captured during this session applied.
+    pub(crate) fn device_with_routing_info(&self, device: &DeviceData) -> DeviceData {
+        let mut device = device.clone();
+        if let Some(routing_info) = &self.routing_info {
+            device.routing_info = Some(routing_info.clone());
+        }
+        device
+    }
+
+    /// Remembers the routing info carried by a GSA response, if any.
+    pub(crate) fn capture_routing_info(&mut self, response: &HttpResponse, plist: &Value) {
+        if let Some(routing_info) = routing_info_from(response, plist) {
+            self.routing_info = Some(routing_info);
+        }
+    }
+}
+
+/// The GSA provisioning exchange (lookup, start, finish) over any [`HttpTransport`].
+pub(crate) fn provision(
+    adi: &mut Adi,
+    device: &DeviceData,
+    transport: &mut dyn HttpTransport,
+    state: &mut SessionState,
+    dsid: u64,
+) -> Result<()> {
+    debug_print!(format!("ProvisioningSession.provision 0x{dsid:X}"));
+    let (session, finish) = exchange(device, transport, state, |spim| {
+        let start = adi.start_provisioning(dsid, spim)?;
+        Ok((start.cpim, start.session))
+    })?;
+    adi.end_provisioning(session, &finish.ptm, &finish.tk)?;
+    Ok(())
+}
+
+/// The HTTP side of provisioning. `start` turns the server's `spim` into a `cpim`
+/// (plus whatever it needs to finish); the caller completes with the returned
+/// `ptm`/`tk`.
+pub(crate) fn exchange<T>(
+    device: &DeviceData,
+    transport: &mut dyn HttpTransport,
+    state: &mut SessionState,
+    start: impl FnOnce(&[u8]) -> Result<(Vec<u8>, T)>,
+) -> Result<(T, FinishProvisioning)> {
+    if state.url_bag.needs_lookup() {
+        let headers = common_headers(device, state.routing_info.as_deref(), None);
+        let response = transport.get(state.url_bag.lookup_url(), &headers)?;
+        let plist = parse_plist(&response.body)?;
+        state.capture_routing_info(&response, &plist);
+        state.url_bag.set_fetched(url_bag_from_plist(&plist)?);
+    }
+
+    let start_url = state.url_bag.get(START_PROVISIONING_KEY)?;
+    let finish_url = state.url_bag.get(FINISH_PROVISIONING_KEY)?;
+
+    let start_response = post_with_time(
+        transport,
+        device,
+        state,
+        &start_url,
+        START_PROVISIONING_BODY,
+    )?;
+    let start_plist = parse_plist(&start_response.body)?;
+    state.capture_routing_info(&start_response, &start_plist);
+
+    let spim = spim_from_plist(&start_plist)?;
+    let (cpim, started) = start(&spim)?;
+
+    let finish_body = finish_provisioning_body(&cpim);
+
+    let finish_response = post_with_time(transport, device, state, &finish_url, &finish_body)?;
+    let finish_plist = parse_plist(&finish_response.body)?;
+    state.capture_routing_info(&finish_response, &finish_plist);
+
+    Ok((started, finish_from_plist(&finish_plist)?))
+}
+
+/// [`provision`] over an [`AsyncHttpTransport`].
+#[cfg(async_provisioning)]
+pub(crate) async fn provision_async(
+    adi: &mut Adi,
+    device: &DeviceData,
+    transport: &mut impl AsyncHttpTransport,
+    state: &mut SessionState,
+    dsid: u64,
+) -> Result<()> {
+    debug_print!(format!("AsyncProvisioningSession.provision 0x{dsid:X}"));
+    let mut flow = ProvisioningFlow::with_state(device.clone(), dsid, std::mem::take(state));
+    let result = async {
+        while let Some(request) = flow.request()? {
+            let response = match &request.body {
+                Some(body) => transport.post(&request.url, &request.headers, body).await?,
+                None => transport.get(&request.url, &request.headers).await?,
+            };
+            flow.resume(adi, &response)?;
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    *state = flow.into_state();
+    result
+}
+
+/// [`anisette_headers`] over an [`AsyncHttpTransport`].
+#[cfg(async_provisioning)]
+pub(crate) async fn anisette_headers_async(
+    adi: &mut Adi,
+    device: &DeviceData,
+    transport: &mut impl AsyncHttpTransport,
+    state: &mut SessionState,
+    dsid: u64,
+) -> Result<HashMap<String, String>> {
+    if !adi.is_machine_provisioned(dsid)? {
+        provision_async(adi, device, transport, state, dsid).await?;
+    }
+    let device = state.device_with_routing_info(device);
+    Ok(adi.get_anisette_headers(dsid, &device)?)
+}
+
+/// Provisions `dsid` over `transport` if needed, then returns the v3 header set.
+pub(crate) fn anisette_headers(
+    adi: &mut Adi,
+    device: &DeviceData,
+    transport: &mut dyn HttpTransport,
+    state: &mut SessionState,
+    dsid: u64,
+) -> Result<HashMap<String, String>> {
+    adi.ensure_provisioned(dsid, |adi, dsid| {
+        provision(adi, device, transport, state, dsid)
+    })?;
+    let device = state.device_with_routing_info(device);
+    Ok(adi.get_anisette_headers(dsid, &device)?)
+}
+
+fn post_with_time(
+    transport: &mut dyn HttpTransport,
+    device: &DeviceData,
+    state: &SessionState,
+    url: &str,
+    body: &str,
+) -> Result<HttpResponse> {
+    transport.post(url, &timed_headers(device, state), body)
+}
+
+fn timed_headers(device: &DeviceData, state: &SessionState) -> Vec<(&'static str, String)> {
+    let client_time = current_client_time();
+    common_headers(device, state.routing_info.as_deref(), Some(&client_time))
+}
+
+/// A request [`ProvisioningFlow`] wants sent: a GET when `body` is `None`, a POST
+/// otherwise.
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub url: String,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum FlowStage {
+    Lookup,
+    Start,
+    Finish { session: u32, body: String },
+    Done,
+}
+
+/// The provisioning exchange as a state machine that does no I/O itself: send
+/// [`request`](Self::request) however the host can and hand the answer to
+/// [`resume`](Self::resume), until no request is left.
+///
+/// Hosts that can only complete HTTP asynchronously, like a browser driving the
+/// Emscripten build, provision this way without blocking.
+pub struct ProvisioningFlow {
+    device: DeviceData,
+    dsid: u64,
+    state: SessionState,
+    stage: FlowStage,
+}
+
+impl ProvisioningFlow {
+    pub fn new(device: DeviceData, dsid: u64) -> Self {
+        Self::with_state(device, dsid, SessionState::default())
+    }
+
+    pub(crate) fn with_state(device: DeviceData, dsid: u64, state: SessionState) -> Self {
+        let stage = if state.url_bag.needs_lookup() {
+            FlowStage::Lookup
+        } else {
+            FlowStage::Start
+        };
+        Self {
+            device,
+            dsid,
+            state,
+            stage,
+        }
+    }
+
+    /// The next request to send, or `None` once provisioning has finished.
+    pub fn request(&self) -> Result<Option<HttpRequest>> {
+        let state = &self.state;
+        let request = match &self.stage {
+            FlowStage::Lookup => HttpRequest {
+                url: state.url_bag.lookup_url().to_string(),
+                headers: common_headers(&self.device, state.routing_info.as_deref(), None),
+                body: None,
+            },
+            FlowStage::Start => HttpRequest {
+                url: state.url_bag.get(START_PROVISIONING_KEY)?,
+                headers: timed_headers(&self.device, state),
+                body: Some(START_PROVISIONING_BODY.to_string()),
+            },
+            FlowStage::Finish { body, .. } => HttpRequest {
+                url: state.url_bag.get(FINISH_PROVISIONING_KEY)?,
+                headers: timed_headers(&self.device, state),
+                body: Some(body.clone()),
+            },
+            FlowStage::Done => return Ok(None),
+        };
+        Ok(Some(request))
+    }
+
+    /// Feeds the response to the last [`request`](Self::request). Returns whether
+    /// another request is pending.
+    pub fn resume(&mut self, adi: &mut Adi, response: &HttpResponse) -> Result<bool> {
+        let plist = parse_plist(&response.body)?;
+        self.state.capture_routing_info(response, &plist);
+        self.stage = match std::mem::replace(&mut self.stage, FlowStage::Done) {
+            FlowStage::Lookup => {
+                self.state.url_bag.set_fetched(url_bag_from_plist(&plist)?);
+                FlowStage::Start
+            }
+            FlowStage::Start => {
+                let start = adi.start_provisioning(self.dsid, &spim_from_plist(&plist)?)?;
+                FlowStage::Finish {
+                    session: start.session,
+                    body: finish_provisioning_body(&start.cpim),
+                }
+            }
+            FlowStage::Finish { session, .. } => {
+                let finish = finish_from_plist(&plist)?;
+                adi.end_provisioning(session, &finish.ptm, &finish.tk)?;
+                FlowStage::Done
+            }
+            FlowStage::Done => bail!("provisioning already finished"),
+        };
+        Ok(!matches!(self.stage, FlowStage::Done))
+    }
+
+    /// Routing info GSA returned during the exchange, to persist into
+    /// [`DeviceData::routing_info`].
+    pub fn routing_info(&self) -> Option<&str> {
+        self.state.routing_info.as_deref()
+    }
+
+    #[cfg(async_provisioning)]
+    pub(crate) fn into_state(self) -> SessionState {
+        self.state
+    }
+}
+
+/// `ptm` and `tk` from a `midFinishProvisioning` response, the inputs to
+/// [`Adi::end_provisioning`].
+#[derive(Debug, Clone)]
+pub struct FinishProvisioning {
+    pub ptm: Vec<u8>,
+    pub tk: Vec<u8>,
+}
+
+/// Decodes `spim` from a `midStartProvisioning` response body, the input to
+/// [`Adi::start_provisioning`].
+///
+/// With this, [`finish_provisioning_body`] and [`parse_finish_response`] a caller can
+/// run the HTTP exchange anywhere (another machine, another language) and drive
+/// only the ADI side here.
+pub fn parse_start_response(bytes: &[u8]) -> Result<Vec<u8>> {
+    spim_from_plist(&parse_plist(bytes)?)
+}
+
+/// Decodes `ptm` and `tk` from a `midFinishProvisioning` response body.
+pub fn parse_finish_response(bytes: &[u8]) -> Result<FinishProvisioning> {
+    finish_from_plist(&parse_plist(bytes)?)
+}
+
+pub(crate) fn spim_from_plist(plist: &Value) -> Result<Vec<u8>> {
+    let spim = plist_get_string_in_response(plist, "spim")?;
+    Ok(STANDARD.decode(spim.as_bytes())?)
+}
+
+pub(crate) fn finish_from_plist(plist: &Value) -> Result<FinishProvisioning> {
+    let ptm = plist_get_string_in_response(plist, "ptm")?;
+    let tk = plist_get_string_in_response(plist, "tk")?;
+    Ok(FinishProvisioning {
+        ptm: STANDARD.decode(ptm.as_bytes())?,
+        tk: STANDARD.decode(tk.as_bytes())?,
+    })
+}
+
+/// Body of the `midFinishProvisioning` request for the `cpim` returned by
+/// [`Adi::start_provisioning`].
+pub fn finish_provisioning_body(cpim: &[u8]) -> String {
+    let cpim_b64 = STANDARD.encode(cpim);
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n<plist version=\"1.0\">\n<dict>\n  <key>Header</key>\n  <dict/>\n  <key>Request</key>\n  <dict>\n    <key>cpim</key>\n    <string>{}</string>\n  </dict>\n</dict>\n</plist>",
+        cpim_b64
+    )
+}
+
+/// Headers sent with every provisioning request, impersonating `akd` on macOS.
+/// `routing_info` overrides the device's stored value.
+pub(crate) fn common_headers(
+    device: &DeviceData,
+    routing_info: Option<&str>,
+    client_time: Option<&str>,
+) -> Vec<(&'static str, String)> {
+    let mut headers = vec![
+        (
+            "User-Agent",
+            "akd/1.0 CFNetwork/1404.0.5 Darwin/22.3.0".to_string(),
+        ),
+        (
+            "Content-Type",
+            "application/x-www-form-urlencoded".to_string(),
+        ),
+        ("Connection", "keep-alive".to_string()),
+        ("X-Mme-Device-Id", device.unique_device_identifier.clone()),
+        (
+            "X-MMe-Client-Info",
+            device.server_friendly_description.clone(),
+        ),
+        ("X-Apple-I-MD-LU", device.local_user_uuid.clone()),
+        (
+            ROUTING_INFO_HEADER,
+            routing_info.unwrap_or(device.routing_info()).to_string(),
+        ),
+        ("X-Apple-I-TimeZone", device.time_zone().to_string()),
+        ("X-Apple-Locale", device.locale().to_string()),
+        ("X-Apple-I-SRL-NO", device.serial_number().to_string()),
+        ("X-Apple-Client-App-Name", "Setup".to_string()),
+    ];
+
+    if let Some(time) = client_time {
+        headers.push(("X-Apple-I-Client-Time", time.to_string()));
+    }
+
+    headers
+}
+
+pub(crate) fn url_bag_from_plist(plist: &Value) -> Result<HashMap<String, String>> {
+    let root = plist
+        .as_dictionary()
+        .ok_or_else(|| anyhow!("lookup plist root is not a dictionary"))?;
+    let urls = root
+        .get("urls")
+        .and_then(Value::as_dictionary)
+        .ok_or_else(|| anyhow!("lookup plist missing urls dictionary"))?;
+
+    let mut url_bag = HashMap::new();
+    for (name, value) in urls {
+        if let Some(url) = value.as_string() {
+            url_bag.insert(name.to_string(), url.to_string());
+        }
+    }
+
+    Ok(url_bag)
+}
+
+/// GSA sends routing info as a response header or inside the plist `Response`
+/// (as a string or an integer).
+pub(crate) fn routing_info_from(response: &HttpResponse, plist: &Value) -> Option<String> {
+    if let Some(value) = response.header(ROUTING_INFO_HEADER) {
+        return Some(value.trim().to_string());
+    }
+
+    let value = plist
+        .as_dictionary()?
+        .get("Response")
+        .and_then(Value::as_dictionary)?
+        .get(ROUTING_INFO_HEADER)?;
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Integer(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+/// Accepts XML and binary (`bplist00`) plists; GSA picks the format per request.
+pub(crate) fn parse_plist(bytes: &[u8]) -> Result<Value> {
+    Ok(Value::from_reader(Cursor::new(bytes))?)
+}
+
+pub(crate) fn plist_get_string_in_response<'a>(plist: &'a Value, key: &str) -> Result<&'a str> {
+    let root = plist
+        .as_dictionary()
+        .ok_or_else(|| anyhow!("plist root is not a dictionary"))?;
+
+    let response = root
+        .get("Response")
+        .and_then(Value::as_dictionary)
+        .ok_or_else(|| anyhow!("plist missing Response dictionary"))?;
+
+    let value = response
+        .get(key)
+        .ok_or_else(|| anyhow!("plist Response missing {key}"))?;
+
+    if let Some(text) = value.as_string() {
+        return Ok(text);
+    }
+
+    bail!("plist Response field {key} is not a string")
+}
+
+/// Local time on native hosts; the emscripten runtime has no usable time zone.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn current_client_time() -> String {
+    chrono::Local::now()
+        .format("%Y-%m-%dT%H:%M:%S%:z")
+        .to_string()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn current_client_time() -> String {
+    chrono::Utc::now()
+        .format("%Y-%m-%dT%H:%M:%S%:z")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use plist::{Dictionary, Value};
+
+    use super::{
+        ProvisioningFlow, SessionState, finish_provisioning_body, parse_finish_response,
+        parse_plist, plist_get_string_in_response, routing_info_from, url_bag_from_plist,
+    };
+    use crate::device::DeviceData;
+    use crate::transport::HttpResponse;
+
+    fn binary_plist(value: &Value) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        value.to_writer_binary(&mut bytes).expect("encode bplist");
+        assert!(bytes.starts_with(b"bplist00"));
+        bytes
+    }
+
+    #[test]
+    fn url_bag_parses_from_binary_and_xml() {
+        let mut urls = Dictionary::new();
+        urls.insert(
+            "midStartProvisioning".to_string(),
+            Value::String("https://example.invalid/start".to_string()),
+        );
+        let mut root = Dictionary::new();
+        root.insert("urls".to_string(), Value::Dictionary(urls));
+        let root = Value::Dictionary(root);
+
+        let mut xml = Vec::new();
+        root.to_writer_xml(&mut xml).expect("encode xml");
+        for bytes in [binary_plist(&root), xml] {
+            let plist = parse_plist(&bytes).expect("parse plist");
+            let url_bag = url_bag_from_plist(&plist).expect("parse url bag");
+            assert_eq!(
+                url_bag.get("midStartProvisioning").map(String::as_str),
+                Some("https://example.invalid/start")
+            );
+        }
+    }
+
+    #[test]
+    fn routing_info_comes_from_header_or_response() {
+        let mut response = Dictionary::new();
+        response.insert(
+            "X-Apple-I-MD-RINFO".to_string(),
+            Value::Integer(50660608.into()),
+        );
+        let mut root = Dictionary::new();
+        root.insert("Response".to_string(), Value::Dictionary(response));
+        let plist = Value::Dictionary(root);
+
+        let mut http = HttpResponse::default();
+        assert_eq!(
+            routing_info_from(&http, &plist).as_deref(),
+            Some("50660608")
+        );
+        http.headers
+            .push(("x-apple-i-md-rinfo".to_string(), "17106176".to_string()));
+        assert_eq!(
+            routing_info_from(&http, &plist).as_deref(),
+            Some("17106176")
+        );
+        assert_eq!(
+            routing_info_from(&HttpResponse::default(), &Value::Boolean(true)),
+            None
+        );
+    }
+
+    #[test]
+    fn response_fields_read_from_binary_plist() {
+        let mut response = Dictionary::new();
+        response.insert("spim".to_string(), Value::String("c3BpbQ==".to_string()));
+        let mut root = Dictionary::new();
+        root.insert("Response".to_string(), Value::Dictionary(response));
+        let bytes = binary_plist(&Value::Dictionary(root));
+
+        let plist = parse_plist(&bytes).expect("parse bplist");
+        assert_eq!(
+            plist_get_string_in_response(&plist, "spim").expect("spim"),
+            "c3BpbQ=="
+        );
+        assert!(plist_get_string_in_response(&plist, "ptm").is_err());
+    }
+
+    #[test]
+    fn finish_exchange_round_trips_raw_blobs() {
+        let body = finish_provisioning_body(b"cpim");
+        let request = parse_plist(body.as_bytes()).expect("parse request");
+        let cpim = request
+            .as_dictionary()
+            .and_then(|root| root.get("Request"))
+            .and_then(Value::as_dictionary)
+            .and_then(|request| request.get("cpim"))
+            .and_then(Value::as_string);
+        assert_eq!(cpim, Some("Y3BpbQ=="));
+
+        let mut response = Dictionary::new();
+        response.insert("ptm".to_string(), Value::String("cHRt".to_string()));
+        response.insert("tk".to_string(), Value::String("dGs=".to_string()));
+        let mut root = Dictionary::new();
+        root.insert("Response".to_string(), Value::Dictionary(response));
+        let finish =
+            parse_finish_response(&binary_plist(&Value::Dictionary(root))).expect("parse finish");
+        assert_eq!(finish.ptm, b"ptm");
+        assert_eq!(finish.tk, b"tk");
+    }
+
+    #[test]
+    fn flow_looks_up_unless_both_endpoints_are_known() {
+        let flow = ProvisioningFlow::new(DeviceData::default(), 0);
+        let lookup = flow.request().expect("request").expect("pending");
+        assert!(lookup.body.is_none());
+        assert!(lookup.url.ends_with("/lookup"));
+
+        let mut state = SessionState::default();
+        for name in ["midStartProvisioning", "midFinishProvisioning"] {
+            state
+                .url_bag
+                .set_entry(name.to_string(), format!("https://example.invalid/{name}"));
+        }
+        let flow = ProvisioningFlow::with_state(DeviceData::default(), 0, state);
+        let start = flow.request().expect("request").expect("pending");
+        assert_eq!(start.url, "https://example.invalid/midStartProvisioning");
+        assert!(
+            start
+                .body
+                .is_some_and(|body| body.contains("<key>Request</key>"))
+        );
+        assert!(
+            start
+                .headers
+                .iter()
+                .any(|(name, _)| *name == "X-Apple-I-Client-Time")
+        );
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use crate::constants::{EAGAIN, EBUSY, EDEADLK, EINVAL, EPERM, PTHREAD_KEYS_MAX};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PthreadOptions {
+    /// Return `EDEADLK`/`EPERM` to the guest on lock misuse instead of only logging it.
+    pub strict_locks: bool,
+    /// Run `pthread_create` start routines to completion on the single emulated thread.
+    pub run_start_routines: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LockViolation {
+    RecursiveLock,
+    UnlockWithoutLock,
+    Contended,
+}
+
+impl LockViolation {
+    pub(crate) fn error_code(self) -> u32 {
+        match self {
+            Self::RecursiveLock => EDEADLK,
+            Self::UnlockWithoutLock => EPERM,
+            Self::Contended => EBUSY,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct RwLockState {
+    readers: u32,
+    writer: bool,
+}
+
+/// Lock ownership for guest mutexes/rwlocks, keyed by their guest address.
+///
+/// There is only one emulated thread, so any attempt to take a lock that is
+/// already held can never succeed on a real device either.
+#[derive(Debug, Default)]
+pub(crate) struct LockTable {
+    mutexes: HashMap<u64, u32>,
+    rwlocks: HashMap<u64, RwLockState>,
+    next_thread_id: u64,
+}
+
+impl LockTable {
+    pub(crate) fn forget(&mut self, address: u64) {
+        self.mutexes.remove(&address);
+        self.rwlocks.remove(&address);
+    }
+
+    pub(crate) fn mutex_lock(&mut self, address: u64) -> Result<(), LockViolation> {
+        let depth = self.mutexes.entry(address).or_insert(0);
+        *depth += 1;
+        if *depth > 1 {
+            return Err(LockViolation::RecursiveLock);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn mutex_trylock(&mut self, address: u64) -> Result<(), LockViolation> {
+        let depth = self.mutexes.entry(address).or_insert(0);
+        if *depth > 0 {
+            return Err(LockViolation::Contended);
+        }
+        *depth = 1;
+        Ok(())
+    }
+
+    pub(crate) fn mutex_unlock(&mut self, address: u64) -> Result<(), LockViolation> {
+        match self.mutexes.get_mut(&address) {
+            Some(depth) if *depth > 0 => {
+                *depth -= 1;
+                Ok(())
+            }
+            _ => Err(LockViolation::UnlockWithoutLock),
+        }
+    }
+
+    pub(crate) fn rwlock_rdlock(&mut self, address: u64) -> Result<(), LockViolation> {
+        let lock = self.rwlocks.entry(address).or_default();
+        lock.readers += 1;
+        if lock.writer {
+            return Err(LockViolation::RecursiveLock);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn rwlock_wrlock(&mut self, address: u64) -> Result<(), LockViolation> {
+        let lock = self.rwlocks.entry(address).or_default();
+        let held = lock.writer || lock.readers > 0;
+        lock.writer = true;
+        if held {
+            return Err(LockViolation::RecursiveLock);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn rwlock_unlock(&mut self, address: u64) -> Result<(), LockViolation> {
+        let lock = self.rwlocks.entry(address).or_default();
+        if lock.writer {
+            lock.writer = false;
+            Ok(())
+        } else if lock.readers > 0 {
+            lock.readers -= 1;
+            Ok(())
+        } else {
+            Err(LockViolation::UnlockWithoutLock)
+        }
+    }
+
+    pub(crate) fn next_thread_id(&mut self) -> u64 {
+        self.next_thread_id += 1;
+        self.next_thread_id
+    }
+}
+
+/// `pthread_key_t` slots and their values for the single emulated thread.
+#[derive(Debug, Default)]
+pub(crate) struct TlsTable {
+    keys: HashMap<u32, u64>,
+    next_key: u32,
+}
+
+impl TlsTable {
+    pub(crate) fn create(&mut self) -> Result<u32, u32> {
+        if self.keys.len() >= PTHREAD_KEYS_MAX {
+            return Err(EAGAIN);
+        }
+        while self.keys.contains_key(&self.next_key) {
+            self.next_key = self.next_key.wrapping_add(1);
+        }
+        let key = self.next_key;
+        self.next_key = self.next_key.wrapping_add(1);
+        self.keys.insert(key, 0);
+        Ok(key)
+    }
+
+    pub(crate) fn delete(&mut self, key: u32) -> Result<(), u32> {
+        self.keys.remove(&key).map(|_| ()).ok_or(EINVAL)
+    }
+
+    pub(crate) fn get(&self, key: u32) -> u64 {
+        self.keys.get(&key).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn set(&mut self, key: u32, value: u64) -> Result<(), u32> {
+        let slot = self.keys.get_mut(&key).ok_or(EINVAL)?;
+        *slot = value;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LockTable, LockViolation};
+
+    #[test]
+    fn lock_table_flags_misuse() {
+        let mut locks = LockTable::default();
+
+        assert_eq!(locks.mutex_lock(0x1000), Ok(()));
+        assert_eq!(locks.mutex_lock(0x1000), Err(LockViolation::RecursiveLock));
+        assert_eq!(locks.mutex_unlock(0x1000), Ok(()));
+        assert_eq!(locks.mutex_unlock(0x1000), Ok(()));
+        assert_eq!(
+            locks.mutex_unlock(0x1000),
+            Err(LockViolation::UnlockWithoutLock)
+        );
+
+        assert_eq!(locks.rwlock_rdlock(0x2000), Ok(()));
+        assert_eq!(locks.rwlock_rdlock(0x2000), Ok(()));
+        assert_eq!(
+            locks.rwlock_wrlock(0x2000),
+            Err(LockViolation::RecursiveLock)
+        );
+        locks.forget(0x2000);
+        assert_eq!(
+            locks.rwlock_unlock(0x2000),
+            Err(LockViolation::UnlockWithoutLock)
+        );
+    }
+}
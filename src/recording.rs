@@ -0,0 +1,198 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+
+use crate::transport::{HttpResponse, HttpTransport};
+
+/// One request/response pair. Request headers and bodies are not stored: they
+/// carry device identifiers and per-run values (client time, cpim) that replay
+/// could not match on anyway.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Exchange {
+    method: String,
+    url: String,
+    /// Response headers.
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+    /// Base64, since bodies may be binary plists.
+    body: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cassette {
+    exchanges: Vec<Exchange>,
+}
+
+/// Wraps a transport and writes every exchange to a JSON file, for later use with
+/// [`ReplayTransport`].
+///
+/// The file is rewritten after each response, so a run that fails halfway still
+/// leaves the exchanges it got through.
+pub struct RecordingTransport<T> {
+    inner: T,
+    path: PathBuf,
+    cassette: Cassette,
+}
+
+impl<T: HttpTransport> RecordingTransport<T> {
+    pub fn new(inner: T, path: impl Into<PathBuf>) -> Self {
+        Self {
+            inner,
+            path: path.into(),
+            cassette: Cassette::default(),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn record(&mut self, method: &str, url: &str, response: &HttpResponse) -> Result<()> {
+        self.cassette.exchanges.push(Exchange {
+            method: method.to_string(),
+            url: url.to_string(),
+            headers: response.headers.clone(),
+            body: STANDARD.encode(&response.body),
+        });
+        let json = serde_json::to_vec_pretty(&self.cassette)?;
+        fs::write(&self.path, json)
+            .with_context(|| format!("failed to write recording {}", self.path.display()))
+    }
+}
+
+impl<T: HttpTransport> HttpTransport for RecordingTransport<T> {
+    fn get(&mut self, url: &str, headers: &[(&str, String)]) -> Result<HttpResponse> {
+        let response = self.inner.get(url, headers)?;
+        self.record("GET", url, &response)?;
+        Ok(response)
+    }
+
+    fn post(&mut self, url: &str, headers: &[(&str, String)], body: &str) -> Result<HttpResponse> {
+        let response = self.inner.post(url, headers, body)?;
+        self.record("POST", url, &response)?;
+        Ok(response)
+    }
+}
+
+/// Serves responses captured by [`RecordingTransport`] without touching the network.
+///
+/// Requests must arrive in the recorded order with the same method and URL. The
+/// replayed `ptm`/`tk` only satisfy ADI for the device and `adi.pb` state the
+/// recording was made with, so keep both alongside the recording.
+pub struct ReplayTransport {
+    exchanges: VecDeque<Exchange>,
+}
+
+impl ReplayTransport {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path)
+            .with_context(|| format!("failed to read recording {}", path.display()))?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let cassette: Cassette = serde_json::from_slice(bytes).context("invalid recording")?;
+        Ok(Self {
+            exchanges: cassette.exchanges.into(),
+        })
+    }
+
+    /// Recorded exchanges not yet replayed.
+    pub fn remaining(&self) -> usize {
+        self.exchanges.len()
+    }
+
+    fn next(&mut self, method: &str, url: &str) -> Result<HttpResponse> {
+        let exchange = self
+            .exchanges
+            .pop_front()
+            .ok_or_else(|| anyhow!("recording exhausted at {method} {url}"))?;
+        if exchange.method != method || exchange.url != url {
+            bail!(
+                "recording expected {} {}, got {method} {url}",
+                exchange.method,
+                exchange.url
+            );
+        }
+        Ok(HttpResponse {
+            headers: exchange.headers,
+            body: STANDARD
+                .decode(exchange.body.as_bytes())
+                .context("invalid recorded body")?,
+        })
+    }
+}
+
+impl HttpTransport for ReplayTransport {
+    fn get(&mut self, url: &str, _headers: &[(&str, String)]) -> Result<HttpResponse> {
+        self.next("GET", url)
+    }
+
+    fn post(
+        &mut self,
+        url: &str,
+        _headers: &[(&str, String)],
+        _body: &str,
+    ) -> Result<HttpResponse> {
+        self.next("POST", url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{RecordingTransport, ReplayTransport};
+    use crate::transport::{HttpResponse, HttpTransport};
+
+    struct Canned;
+
+    impl HttpTransport for Canned {
+        fn get(&mut self, url: &str, _headers: &[(&str, String)]) -> Result<HttpResponse> {
+            Ok(HttpResponse {
+                headers: vec![("X-Apple-I-MD-RINFO".to_string(), "17106176".to_string())],
+                body: url.as_bytes().to_vec(),
+            })
+        }
+
+        fn post(
+            &mut self,
+            _url: &str,
+            _headers: &[(&str, String)],
+            body: &str,
+        ) -> Result<HttpResponse> {
+            Ok(HttpResponse::new(vec![0, 0xff, body.len() as u8]))
+        }
+    }
+
+    #[test]
+    fn recorded_exchanges_replay_in_order() {
+        let path =
+            std::env::temp_dir().join(format!("anisette-recording-{}.json", std::process::id()));
+        let mut recorder = RecordingTransport::new(Canned, &path);
+        recorder
+            .get("https://example.invalid/lookup", &[])
+            .expect("get");
+        recorder
+            .post("https://example.invalid/start", &[], "abc")
+            .expect("post");
+
+        let mut replay = ReplayTransport::load(&path).expect("load");
+        std::fs::remove_file(&path).expect("cleanup");
+        assert_eq!(replay.remaining(), 2);
+
+        let lookup = replay
+            .get("https://example.invalid/lookup", &[])
+            .expect("replay get");
+        assert_eq!(lookup.body, b"https://example.invalid/lookup");
+        assert_eq!(lookup.header("x-apple-i-md-rinfo"), Some("17106176"));
+
+        assert!(replay.get("https://example.invalid/start", &[]).is_err());
+        assert_eq!(replay.remaining(), 0);
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::TcpStream;
+
+use anyhow::{Context, Result, anyhow, bail};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use plist::Data;
+use rand::RngCore;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::{Message, WebSocket};
+use uuid::Uuid;
+
+use crate::adi::anisette_header_map;
+use crate::debug::debug_print;
+use crate::device::DeviceData;
+use crate::library::sha256_hex;
+use crate::provider::AnisetteProvider;
+use crate::provisioning_protocol::{self, ROUTING_INFO_HEADER, SessionState};
+use crate::transport::HttpTransport;
+
+/// The machine-wide DSID (-2), the only namespace a v3 server provisions.
+const MACHINE_DSID: u64 = -2_i64 as u64;
+
+type Socket = WebSocket<MaybeTlsStream<TcpStream>>;
+
+/// The virtual device a v3 server provisions for this client. Persist it between
+/// runs: the `adi.pb` only matches the identifier it was provisioned with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteState {
+    /// Base64 of the 16-byte client identifier.
+    identifier: String,
+    pub device: DeviceData,
+    /// Base64 `adi.pb` returned by the server after provisioning.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    adi_pb: Option<String>,
+}
+
+impl RemoteState {
+    pub fn generate() -> Self {
+        let mut identifier = [0_u8; 16];
+        rand::thread_rng().fill_bytes(&mut identifier);
+        Self::with_identifier(identifier)
+    }
+
+    fn with_identifier(identifier: [u8; 16]) -> Self {
+        let device = DeviceData {
+            unique_device_identifier: Uuid::new_v4().to_string().to_uppercase(),
+            local_user_uuid: sha256_hex(&identifier).to_uppercase(),
+            ..Default::default()
+        };
+        Self {
+            identifier: STANDARD.encode(identifier),
+            device,
+            adi_pb: None,
+        }
+    }
+
+    pub fn is_provisioned(&self) -> bool {
+        self.adi_pb.is_some()
+    }
+
+    /// Reads omnisette's `state.plist` (`keychain_identifier` and `adi_pb` as
+    /// data), which holds the same identity. omnisette derives the rest of the
+    /// device per request, so a fresh [`DeviceData`] is generated for it.
+    pub fn from_omnisette_plist(bytes: &[u8]) -> Result<Self> {
+        let state: OmnisetteState =
+            plist::from_reader(Cursor::new(bytes)).context("invalid omnisette state.plist")?;
+        let identifier: [u8; 16] = Vec::from(state.keychain_identifier)
+            .try_into()
+            .map_err(|_| anyhow!("omnisette keychain_identifier is not 16 bytes"))?;
+        let mut remote = Self::with_identifier(identifier);
+        remote.adi_pb = state
+            .adi_pb
+            .map(|adi_pb| STANDARD.encode(Vec::from(adi_pb)));
+        Ok(remote)
+    }
+
+    /// Encodes this state as an omnisette `state.plist`.
+    pub fn to_omnisette_plist(&self) -> Result<Vec<u8>> {
+        let state = OmnisetteState {
+            keychain_identifier: STANDARD.decode(&self.identifier)?.into(),
+            adi_pb: self
+                .adi_pb
+                .as_deref()
+                .map(|adi_pb| STANDARD.decode(adi_pb).map(Data::from))
+                .transpose()?,
+        };
+        let mut bytes = Vec::new();
+        plist::to_writer_xml(&mut bytes, &state)?;
+        Ok(bytes)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct OmnisetteState {
+    keychain_identifier: Data,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    adi_pb: Option<Data>,
+}
+
+/// Anisette headers from a remote anisette v3 server (the protocol SideStore uses)
+/// instead of local emulation.
+///
+/// The server owns the ADI libraries. This side relays the GSA provisioning
+/// exchange over the server's WebSocket and keeps the resulting `adi.pb`, which is
+/// sent back with every header request.
+pub struct RemoteAnisette {
+    server_url: String,
+    client: Client,
+    gsa: Box<dyn HttpTransport>,
+    state: RemoteState,
+    session: SessionState,
+}
+
+impl RemoteAnisette {
+    /// `server_url` is the server root (e.g. `https://ani.sidestore.io`); `gsa`
+    /// carries the provisioning requests to Apple, e.g. a
+    /// [`ReqwestTransport`](crate::ReqwestTransport).
+    pub fn new(
+        server_url: impl Into<String>,
+        gsa: impl HttpTransport + 'static,
+        state: RemoteState,
+    ) -> Self {
+        Self {
+            server_url: server_url.into().trim_end_matches('/').to_string(),
+            client: Client::new(),
+            gsa: Box::new(gsa),
+            state,
+            session: SessionState::default(),
+        }
+    }
+
+    /// Current state, including anything learned from the server; persist it after
+    /// provisioning.
+    pub fn state(&self) -> &RemoteState {
+        &self.state
+    }
+
+    /// Provisions a fresh `adi.pb` through the server's provisioning session.
+    pub fn provision(&mut self) -> Result<()> {
+        debug_print!("RemoteAnisette.provision");
+        self.load_client_info()?;
+
+        let url = self.websocket_url("/v3/provisioning_session");
+        let (mut socket, _) = tungstenite::connect(url.as_str())
+            .with_context(|| format!("failed to connect to {url}"))?;
+
+        expect_result(&read_message(&mut socket)?, "GiveIdentifier")?;
+        send_message(&mut socket, json!({ "identifier": self.state.identifier }))?;
+        expect_result(&read_message(&mut socket)?, "GiveStartProvisioningData")?;
+
+        let ((), finish) = provisioning_protocol::exchange(
+            &self.state.device,
+            self.gsa.as_mut(),
+            &mut self.session,
+            |spim| {
+                send_message(&mut socket, json!({ "spim": STANDARD.encode(spim) }))?;
+                let message = read_message(&mut socket)?;
+                expect_result(&message, "GiveEndProvisioningData")?;
+                let cpim = STANDARD.decode(string_field(&message, "cpim")?)?;
+                Ok((cpim, ()))
+            },
+        )?;
+
+        send_message(
+            &mut socket,
+            json!({ "ptm": STANDARD.encode(&finish.ptm), "tk": STANDARD.encode(&finish.tk) }),
+        )?;
+        let message = read_message(&mut socket)?;
+        expect_result(&message, "ProvisioningSuccess")?;
+        self.state.adi_pb = Some(string_field(&message, "adi_pb")?.to_string());
+        if let Some(routing_info) = &self.session.routing_info {
+            self.state.device.routing_info = Some(routing_info.clone());
+        }
+
+        let _ = socket.close(None);
+        Ok(())
+    }
+
+    fn headers(&mut self) -> Result<HashMap<String, String>> {
+        if !self.state.is_provisioned() {
+            self.provision()?;
+        }
+        self.load_client_info()?;
+
+        let request = json!({
+            "identifier": self.state.identifier,
+            "adi_pb": self.state.adi_pb,
+        });
+        let response = self
+            .client
+            .post(format!("{}/v3/get_headers", self.server_url))
+            .header("Content-Type", "application/json")
+            .body(request.to_string())
+            .send()?
+            .error_for_status()?;
+        let message: Value = serde_json::from_slice(&response.bytes()?)?;
+        expect_result(&message, "Headers")?;
+
+        if let Some(routing_info) = message.get(ROUTING_INFO_HEADER).and_then(Value::as_str) {
+            self.state.device.routing_info = Some(routing_info.to_string());
+        }
+        Ok(anisette_header_map(
+            string_field(&message, "X-Apple-I-MD")?.to_string(),
+            string_field(&message, "X-Apple-I-MD-M")?.to_string(),
+            &self.state.device,
+        ))
+    }
+
+    /// The server decides which Mac it impersonates; its client info is sent to GSA
+    /// and in every header set.
+    fn load_client_info(&mut self) -> Result<()> {
+        if !self.state.device.server_friendly_description.is_empty() {
+            return Ok(());
+        }
+        let response = self
+            .client
+            .get(format!("{}/v3/client_info", self.server_url))
+            .send()?
+            .error_for_status()?;
+        let message: Value = serde_json::from_slice(&response.bytes()?)?;
+        self.state.device.server_friendly_description =
+            string_field(&message, "client_info")?.to_string();
+        Ok(())
+    }
+
+    fn websocket_url(&self, path: &str) -> String {
+        let base = if let Some(rest) = self.server_url.strip_prefix("https://") {
+            format!("wss://{rest}")
+        } else if let Some(rest) = self.server_url.strip_prefix("http://") {
+            format!("ws://{rest}")
+        } else {
+            self.server_url.clone()
+        };
+        format!("{base}{path}")
+    }
+}
+
+impl AnisetteProvider for RemoteAnisette {
+    fn anisette_headers(&mut self, dsid: u64) -> Result<HashMap<String, String>> {
+        if dsid != MACHINE_DSID {
+            bail!("anisette v3 servers only provision the machine DSID (-2), not 0x{dsid:X}");
+        }
+        self.headers()
+    }
+}
+
+fn read_message(socket: &mut Socket) -> Result<Value> {
+    loop {
+        match socket.read()? {
+            Message::Text(text) => return Ok(serde_json::from_str(text.as_str())?),
+            Message::Close(_) => bail!("anisette server closed the provisioning session"),
+            _ => {}
+        }
+    }
+}
+
+fn send_message(socket: &mut Socket, message: Value) -> Result<()> {
+    socket.send(Message::text(message.to_string()))?;
+    Ok(())
+}
+
+/// Every server message carries a `result`; anything but `expected` is an error
+/// (`Timeout`, `InvalidIdentifier`, `StartProvisioningError`, ...).
+fn expect_result(message: &Value, expected: &str) -> Result<()> {
+    let result = message.get("result").and_then(Value::as_str).unwrap_or("");
+    if result == expected {
+        return Ok(());
+    }
+    match message.get("message").and_then(Value::as_str) {
+        Some(detail) => bail!("anisette server returned {result} (expected {expected}): {detail}"),
+        None => bail!("anisette server returned {result:?} (expected {expected})"),
+    }
+}
+
+fn string_field<'a>(message: &'a Value, name: &str) -> Result<&'a str> {
+    message
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("anisette server response missing {name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    use serde_json::json;
+
+    use super::{RemoteState, expect_result};
+
+    #[test]
+    fn server_results_other_than_expected_are_errors() {
+        assert!(expect_result(&json!({ "result": "GiveIdentifier" }), "GiveIdentifier").is_ok());
+        let err = expect_result(
+            &json!({ "result": "StartProvisioningError", "message": "bad spim" }),
+            "GiveEndProvisioningData",
+        )
+        .expect_err("error result");
+        assert!(err.to_string().contains("bad spim"));
+    }
+
+    #[test]
+    fn omnisette_state_round_trips() {
+        let mut state = RemoteState::generate();
+        state.adi_pb = Some(STANDARD.encode(b"adi.pb"));
+        let plist = state.to_omnisette_plist().expect("encode");
+        assert!(String::from_utf8_lossy(&plist).contains("<data>"));
+
+        let imported = RemoteState::from_omnisette_plist(&plist).expect("decode");
+        assert_eq!(imported.identifier, state.identifier);
+        assert_eq!(imported.adi_pb, state.adi_pb);
+        assert_eq!(
+            imported.device.local_user_uuid,
+            state.device.local_user_uuid
+        );
+    }
+}
@@ -1,33 +1,129 @@
 use std::collections::HashMap;
-use std::fs::File;
+use std::ops::Range;
+
+use rand::SeedableRng;
+use rand::rngs::StdRng;
 
 use crate::allocator::Allocator;
+use crate::clock::{GuestClock, SystemClock};
 use crate::constants::{
     LIB_ALLOC_BASE, LIB_ALLOC_SIZE, MALLOC_ADDRESS, MALLOC_SIZE, TEMP_ALLOC_BASE, TEMP_ALLOC_SIZE,
 };
+use crate::errors::VmError;
+use crate::flight_recorder::FlightRecorder;
+use crate::metrics::StubStats;
+use crate::overrides::StubOverrides;
+use crate::pthread::{LockTable, PthreadOptions, TlsTable};
+use crate::trace::SyscallTracer;
+use crate::vfs::{GuestFile, GuestFs, StdFs};
 
 #[derive(Debug, Clone)]
-pub(crate) struct SymbolEntry {
-    pub(crate) name: String,
-    pub(crate) resolved: u64,
+struct SymbolEntry {
+    /// Byte range of the name in [`SymbolTable::names`].
+    name: Range<u32>,
+    resolved: u64,
+}
+
+/// A library's dynamic symbols by index. Names are packed into one string
+/// instead of being allocated per symbol, and lookups by name scan the table:
+/// only a handful of the thousands of symbols are ever resolved by name.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SymbolTable {
+    names: String,
+    entries: Vec<SymbolEntry>,
+}
+
+impl SymbolTable {
+    pub(crate) fn with_capacity(symbols: usize) -> Self {
+        Self {
+            names: String::new(),
+            entries: Vec::with_capacity(symbols),
+        }
+    }
+
+    pub(crate) fn push(&mut self, name: &str, resolved: u64) {
+        let start = self.names.len() as u32;
+        self.names.push_str(name);
+        self.entries.push(SymbolEntry {
+            name: start..self.names.len() as u32,
+            resolved,
+        });
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub(crate) fn name(&self, index: usize) -> Option<&str> {
+        let entry = self.entries.get(index)?;
+        Some(&self.names[entry.name.start as usize..entry.name.end as usize])
+    }
+
+    pub(crate) fn resolved(&self, index: usize) -> Option<u64> {
+        self.entries.get(index).map(|entry| entry.resolved)
+    }
+
+    /// Address of the first symbol called `name`.
+    pub(crate) fn find(&self, name: &str) -> Option<u64> {
+        if name.is_empty() {
+            return None;
+        }
+        (0..self.len())
+            .find(|&index| self.name(index) == Some(name))
+            .and_then(|index| self.resolved(index))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub(crate) struct LoadedLibrary {
     pub(crate) name: String,
-    pub(crate) symbols: Vec<SymbolEntry>,
-    pub(crate) symbols_by_name: HashMap<String, u64>,
+    /// Start of the library's address reservation.
+    pub(crate) base: u64,
+    pub(crate) symbols: SymbolTable,
 }
 
 #[derive(Debug)]
 pub(crate) struct RuntimeState {
     pub(crate) temp_allocator: Allocator,
+    /// Mapped once inside the temp region and reset before each ADI request, so
+    /// per-request buffers do not use up the temp region.
+    pub(crate) scratch_allocator: Option<Allocator>,
     pub(crate) library_allocator: Allocator,
     pub(crate) malloc_allocator: Allocator,
     pub(crate) errno_address: Option<u64>,
     pub(crate) library_blobs: HashMap<String, Vec<u8>>,
     pub(crate) loaded_libraries: Vec<LoadedLibrary>,
-    pub(crate) file_handles: Vec<Option<File>>,
+    pub(crate) file_handles: Vec<Option<Box<dyn GuestFile>>>,
+    pub(crate) guest_fs: Box<dyn GuestFs>,
+    /// Set when the guest modifies a file; cleared once `fsync` has persisted it.
+    pub(crate) persistence_dirty: bool,
+    /// Descriptors open for writing on an `adi.pb`, by path; closing one records
+    /// its checksum and schedules a persistence sync.
+    pub(crate) adi_pb_fds: HashMap<usize, String>,
+    /// CoreADI build recorded with `adi.pb` checksums.
+    pub(crate) library_tag: Option<String>,
+    pub(crate) provisioning_namespace: Option<String>,
+    /// Where the guest's `./anisette` is stored when a profile is set, e.g.
+    /// `./profiles/work/anisette`.
+    pub(crate) provisioning_root: Option<String>,
+    pub(crate) clock: Box<dyn GuestClock>,
+    pub(crate) locks: LockTable,
+    pub(crate) pthread_options: PthreadOptions,
+    pub(crate) tls: TlsTable,
+    pub(crate) environment: HashMap<String, String>,
+    pub(crate) environment_strings: HashMap<String, u64>,
+    /// Answer to `__system_property_get`, which the library only uses for the serial.
+    pub(crate) serial_number: Option<String>,
+    pub(crate) rng: StdRng,
+    /// First error that stopped the current `emu_start`: a failed import stub or
+    /// a fault on unmapped memory.
+    pub(crate) stub_error: Option<VmError>,
+    pub(crate) syscall_trace: Option<SyscallTracer>,
+    pub(crate) flight_recorder: FlightRecorder,
+    pub(crate) stub_stats: StubStats,
+    /// Guest instructions executed while instruction counting is on.
+    pub(crate) instructions: u64,
+    pub(crate) stub_overrides: StubOverrides,
     pub(crate) library_root: Option<String>,
 }
 
@@ -35,13 +131,56 @@ impl RuntimeState {
     pub(crate) fn new() -> Self {
         Self {
             temp_allocator: Allocator::new(TEMP_ALLOC_BASE, TEMP_ALLOC_SIZE),
+            scratch_allocator: None,
             library_allocator: Allocator::new(LIB_ALLOC_BASE, LIB_ALLOC_SIZE),
             malloc_allocator: Allocator::new(MALLOC_ADDRESS, MALLOC_SIZE),
             errno_address: None,
             library_blobs: HashMap::new(),
             loaded_libraries: Vec::new(),
             file_handles: Vec::new(),
+            guest_fs: Box::new(StdFs),
+            persistence_dirty: false,
+            adi_pb_fds: HashMap::new(),
+            library_tag: None,
+            provisioning_namespace: None,
+            provisioning_root: None,
+            clock: Box::new(SystemClock::new()),
+            locks: LockTable::default(),
+            pthread_options: PthreadOptions::default(),
+            tls: TlsTable::default(),
+            environment: HashMap::new(),
+            environment_strings: HashMap::new(),
+            serial_number: None,
+            rng: StdRng::from_entropy(),
+            stub_error: None,
+            syscall_trace: None,
+            flight_recorder: FlightRecorder::default(),
+            stub_stats: StubStats::default(),
+            instructions: 0,
+            stub_overrides: StubOverrides::default(),
             library_root: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::SymbolTable;
+
+    #[test]
+    fn symbols_resolve_by_index_and_name() {
+        let mut symbols = SymbolTable::default();
+        symbols.push("", 0);
+        symbols.push("malloc", 0x1000);
+        symbols.push("qi864985u0", 0x2000);
+        symbols.push("malloc", 0x3000);
+
+        assert_eq!(symbols.len(), 4);
+        assert_eq!(symbols.name(2), Some("qi864985u0"));
+        assert_eq!(symbols.resolved(3), Some(0x3000));
+        assert_eq!(symbols.find("malloc"), Some(0x1000));
+        assert_eq!(symbols.find(""), None);
+        assert_eq!(symbols.find("free"), None);
+        assert_eq!(symbols.name(4), None);
+    }
+}
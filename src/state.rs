@@ -0,0 +1,280 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+
+use crate::constants::{GUEST_PROVISIONING_DIR, S_IFDIR, S_IFMT};
+use crate::device::DeviceData;
+#[cfg(feature = "encryption")]
+use crate::encryption::StorageKey;
+use crate::errors::VmError;
+use crate::library::sha256_hex;
+use crate::vfs::{GuestFs, GuestOpenOptions};
+
+const STATE_FORMAT: &str = "anisette-state";
+const STATE_VERSION: u32 = 1;
+const ADI_PB_NAME: &str = "adi.pb";
+
+/// Everything needed to restore a provisioned machine elsewhere.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProvisioningState {
+    /// Guest provisioning files keyed by their guest path (e.g. `./anisette/adi.pb`).
+    pub files: BTreeMap<String, Vec<u8>>,
+    pub device: Option<DeviceData>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateEnvelope {
+    format: String,
+    version: u32,
+    sha256: String,
+    /// Base64 of the JSON-encoded [`StatePayload`]; the checksum covers these bytes.
+    payload: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StatePayload {
+    files: BTreeMap<String, String>,
+    device: Option<DeviceData>,
+}
+
+impl ProvisioningState {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, VmError> {
+        let payload = StatePayload {
+            files: self
+                .files
+                .iter()
+                .map(|(path, data)| (path.clone(), STANDARD.encode(data)))
+                .collect(),
+            device: self.device.clone(),
+        };
+        let payload = serde_json::to_vec(&payload).map_err(invalid_state)?;
+
+        let envelope = StateEnvelope {
+            format: STATE_FORMAT.to_string(),
+            version: STATE_VERSION,
+            sha256: sha256_hex(&payload),
+            payload: STANDARD.encode(&payload),
+        };
+        serde_json::to_vec(&envelope).map_err(invalid_state)
+    }
+
+    /// [`to_bytes`](Self::to_bytes) encrypted with `key`, for bundles that sit on
+    /// shared storage.
+    #[cfg(feature = "encryption")]
+    pub fn to_encrypted_bytes(&self, key: &StorageKey) -> Result<Vec<u8>, VmError> {
+        key.encrypt(STATE_FORMAT, &self.to_bytes()?)
+            .map_err(invalid_state)
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn from_encrypted_bytes(bytes: &[u8], key: &StorageKey) -> Result<Self, VmError> {
+        Self::from_bytes(&key.decrypt(STATE_FORMAT, bytes).map_err(invalid_state)?)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VmError> {
+        let envelope: StateEnvelope = serde_json::from_slice(bytes).map_err(invalid_state)?;
+        if envelope.format != STATE_FORMAT {
+            return Err(VmError::InvalidStateBlob(format!(
+                "unexpected format '{}'",
+                envelope.format
+            )));
+        }
+        if envelope.version != STATE_VERSION {
+            return Err(VmError::InvalidStateBlob(format!(
+                "unsupported version {} (expected {STATE_VERSION})",
+                envelope.version
+            )));
+        }
+
+        let payload = STANDARD
+            .decode(envelope.payload.as_bytes())
+            .map_err(invalid_state)?;
+        if sha256_hex(&payload) != envelope.sha256 {
+            return Err(VmError::InvalidStateBlob("checksum mismatch".to_string()));
+        }
+
+        let payload: StatePayload = serde_json::from_slice(&payload).map_err(invalid_state)?;
+        let mut files = BTreeMap::new();
+        for (path, data) in payload.files {
+            if !is_state_file(&path) {
+                return Err(VmError::InvalidStateBlob(format!(
+                    "unexpected file '{path}'"
+                )));
+            }
+            let data = STANDARD.decode(data.as_bytes()).map_err(invalid_state)?;
+            files.insert(path, data);
+        }
+
+        Ok(Self {
+            files,
+            device: payload.device,
+        })
+    }
+}
+
+fn invalid_state(err: impl std::fmt::Display) -> VmError {
+    VmError::InvalidStateBlob(err.to_string())
+}
+
+/// Whether `path` names an `adi.pb`, in any directory.
+pub(crate) fn is_adi_pb(path: &str) -> bool {
+    path.rsplit('/').next() == Some(ADI_PB_NAME)
+}
+
+/// `adi.pb` and its siblings, directly in the provisioning dir or in a per-DSID
+/// subdirectory of it.
+pub(crate) fn is_state_file(path: &str) -> bool {
+    let Some(rest) = path
+        .strip_prefix(GUEST_PROVISIONING_DIR)
+        .and_then(|rest| rest.strip_prefix('/'))
+    else {
+        return false;
+    };
+    let name = match rest.split_once('/') {
+        Some((namespace, name)) if !namespace.is_empty() && namespace != ".." => name,
+        Some(_) => return false,
+        None => rest,
+    };
+    name == ADI_PB_NAME
+        || name.strip_prefix(ADI_PB_NAME).is_some_and(|suffix| {
+            // `adi.pb.lock` only guards the live file; it is not state.
+            suffix.starts_with('.') && !suffix.contains('/') && suffix != ".lock"
+        })
+}
+
+/// State files stored under `root` (the guest's `./anisette`, or where a profile
+/// keeps it), keyed by their guest path so bundles move between profiles.
+pub(crate) fn collect_state_files(
+    guest_fs: &mut dyn GuestFs,
+    root: &str,
+) -> Result<BTreeMap<String, Vec<u8>>, VmError> {
+    let mut files = BTreeMap::new();
+    let entries = match guest_fs.read_dir(root) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(files),
+        Err(err) => return Err(err.into()),
+    };
+
+    for entry in entries {
+        let path = format!("{root}/{entry}");
+        let key = format!("{GUEST_PROVISIONING_DIR}/{entry}");
+        let metadata = guest_fs.symlink_metadata(&path)?;
+        if metadata.mode & S_IFMT == S_IFDIR {
+            for child in guest_fs.read_dir(&path)? {
+                let child_key = format!("{key}/{child}");
+                if is_state_file(&child_key) {
+                    let data = read_state_file(guest_fs, &format!("{path}/{child}"))?;
+                    files.insert(child_key, data);
+                }
+            }
+        } else if is_state_file(&key) {
+            files.insert(key, read_state_file(guest_fs, &path)?);
+        }
+    }
+    Ok(files)
+}
+
+/// Per-DSID namespaces (subdirectories of the provisioning dir at `root`)
+/// holding an `adi.pb`.
+pub(crate) fn provisioned_namespaces(
+    guest_fs: &mut dyn GuestFs,
+    root: &str,
+) -> Result<Vec<String>, VmError> {
+    let entries = match guest_fs.read_dir(root) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut namespaces = Vec::new();
+    for entry in entries {
+        let path = format!("{root}/{entry}/{ADI_PB_NAME}");
+        if guest_fs.metadata(&path).is_ok() {
+            namespaces.push(entry);
+        }
+    }
+    Ok(namespaces)
+}
+
+pub(crate) fn restore_state_files(
+    guest_fs: &mut dyn GuestFs,
+    files: &BTreeMap<String, Vec<u8>>,
+) -> Result<(), VmError> {
+    for (path, data) in files {
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            guest_fs.create_dir_all(parent)?;
+        }
+        let options = GuestOpenOptions {
+            write: true,
+            create: true,
+            truncate: true,
+            ..Default::default()
+        };
+        guest_fs.open(path, &options)?.write_all(data)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_state_file(guest_fs: &mut dyn GuestFs, path: &str) -> Result<Vec<u8>, VmError> {
+    let options = GuestOpenOptions {
+        read: true,
+        ..Default::default()
+    };
+    let mut file = guest_fs.open(path, &options)?;
+    let mut data = Vec::new();
+    let mut buffer = [0_u8; 4096];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        data.extend_from_slice(&buffer[..count]);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ProvisioningState, collect_state_files, restore_state_files};
+    use crate::constants::GUEST_PROVISIONING_DIR;
+    use crate::vfs::MemoryFs;
+
+    #[test]
+    fn state_blob_round_trips_and_detects_tampering() {
+        let mut source = MemoryFs::new();
+        source.write_file("./anisette/adi.pb", b"machine".to_vec());
+        source.write_file("./anisette/-2/adi.pb", b"account".to_vec());
+        source.write_file("./anisette/device.json", b"{}".to_vec());
+
+        let state = ProvisioningState {
+            files: collect_state_files(&mut source, GUEST_PROVISIONING_DIR).expect("collect"),
+            device: None,
+        };
+        assert_eq!(state.files.len(), 2);
+        let blob = state.to_bytes().expect("encode");
+
+        let restored = ProvisioningState::from_bytes(&blob).expect("decode");
+        let target = MemoryFs::new();
+        restore_state_files(&mut target.clone(), &restored.files).expect("restore");
+        assert_eq!(
+            target.read_file("./anisette/-2/adi.pb").as_deref(),
+            Some(&b"account"[..])
+        );
+
+        let mut tampered = blob.clone();
+        let index = tampered.len() - 10;
+        tampered[index] ^= 1;
+        assert!(ProvisioningState::from_bytes(&tampered).is_err());
+    }
+
+    #[test]
+    fn profile_state_is_keyed_by_guest_path() {
+        let mut source = MemoryFs::new();
+        source.write_file("./profiles/work/anisette/-2/adi.pb", b"account".to_vec());
+
+        let files = collect_state_files(&mut source, "./profiles/work/anisette").expect("collect");
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["./anisette/-2/adi.pb"]);
+    }
+}
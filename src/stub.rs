@@ -1,20 +1,39 @@
-use std::fs::{self, OpenOptions};
-use std::io::{Read, Write};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::io;
+use std::time::Instant;
+
+use rand::{Rng, RngCore};
 
 use unicorn_engine::{RegisterARM64, Unicorn};
 
+#[cfg(not(feature = "minimal"))]
+use crate::constants::{ANDROID_LOG_WARN, VARIADIC_REG_ARGS};
 use crate::constants::{
-    ENOENT, IMPORT_ADDRESS, IMPORT_LIBRARY_STRIDE, O_ACCMODE, O_CREAT, O_NOFOLLOW, O_RDWR, O_WRONLY,
+    ARG_REGS, CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_MONOTONIC_COARSE, CLOCK_MONOTONIC_RAW,
+    CLOCK_REALTIME, CLOCK_REALTIME_COARSE, EACCES, EAGAIN, EBADF, EEXIST, EFBIG, EINTR, EINVAL,
+    EIO, EISDIR, ENOENT, ENOMEM, ENOSPC, ENOTDIR, ENOTEMPTY, EPERM, EROFS, GUEST_ADI_PB_PATH,
+    GUEST_MEMORY_CHUNK, GUEST_PROVISIONING_DIR, IMPORT_ADDRESS, IMPORT_LIBRARY_STRIDE,
+    MAX_GETRANDOM_LEN, MAX_GUEST_STRING_LEN, MAX_SIGNAL, O_ACCMODE, O_APPEND, O_CREAT, O_EXCL,
+    O_NOFOLLOW, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, PAGE_SIZE, R_OK, S_IFMT, S_IFREG, SIGABRT,
+    SIGNAL_EXIT_BASE, W_OK, X_OK,
 };
+#[cfg(not(feature = "minimal"))]
+use crate::debug::guest_log;
 use crate::debug::{debug_print, debug_trace};
 use crate::emu::{
-    ensure_errno_address, load_library_by_name, read_c_string,
-    resolve_symbol_from_loaded_library_by_name, set_errno,
+    alloc_guest_c_string, ensure_errno_address, invoke_nested_cdecl, load_library_by_name,
+    read_c_string, record_event, resolve_symbol_from_loaded_library_by_name, set_errno,
 };
 use crate::errors::VmError;
+use crate::flight_recorder::FlightEvent;
+use crate::integrity::record_checksum;
+use crate::overrides::StubContext;
+use crate::persistence::{schedule_sync, with_persistence};
+use crate::pthread::{LockTable, LockViolation};
 use crate::runtime::RuntimeState;
+use crate::state::is_adi_pb;
+use crate::trace;
 use crate::util::bytes_to_hex;
+use crate::vfs::{GuestFile, GuestMetadata, GuestOpenOptions};
 
 pub fn dispatch_import_stub(
     uc: &mut Unicorn<'_, RuntimeState>,
@@ -36,20 +55,52 @@ pub fn dispatch_import_stub(
                 .get(library_index)
                 .ok_or(VmError::LibraryNotLoaded(library_index))?;
 
-            let symbol = library.symbols.get(symbol_index).ok_or_else(|| {
+            let symbol = library.symbols.name(symbol_index).ok_or_else(|| {
                 VmError::SymbolIndexOutOfRange {
                     library: library.name.clone(),
                     index: symbol_index,
                 }
             })?;
 
-            symbol.name.clone()
+            symbol.to_string()
         };
 
-    handle_stub_by_name(uc, &symbol_name)
+    let traced_call = trace::begin_call(uc, &symbol_name);
+    let started = Instant::now();
+    let overridden = uc.get_data_mut().stub_overrides.take(&symbol_name);
+    let result = match overridden {
+        Some(mut stub) => {
+            let result = stub(&mut StubContext {
+                uc,
+                symbol: &symbol_name,
+            });
+            uc.get_data_mut().stub_overrides.restore(&symbol_name, stub);
+            result
+        }
+        None => handle_stub_by_name(uc, &symbol_name),
+    };
+    uc.get_data_mut()
+        .stub_stats
+        .record(&symbol_name, started.elapsed());
+    if let Some(call) = traced_call {
+        trace::end_call(uc, &symbol_name, call, &result);
+    }
+    let (ret, error) = match &result {
+        Ok(()) => (uc.reg_read(RegisterARM64::X0).ok(), None),
+        Err(err) => (None, Some(err.to_string())),
+    };
+    record_event(
+        uc,
+        FlightEvent::Stub {
+            name: symbol_name,
+            ret,
+            error,
+        },
+    );
+    result
 }
 
-fn handle_stub_by_name(
+pub(crate) fn handle_stub_by_name(
     uc: &mut Unicorn<'_, RuntimeState>,
     symbol_name: &str,
 ) -> Result<(), VmError> {
@@ -57,34 +108,83 @@ fn handle_stub_by_name(
         "malloc" => stub_malloc(uc),
         "free" => stub_free(uc),
         "strncpy" => stub_strncpy(uc),
+        "memcpy" => stub_memcpy(uc),
+        "memmove" => stub_memmove(uc),
+        "memset" => stub_memset(uc),
+        "strlen" => stub_strlen(uc),
+        "strcmp" => stub_strcmp(uc),
         "mkdir" => stub_mkdir(uc),
         "umask" => stub_umask(uc),
         "chmod" => stub_chmod(uc),
+        "stat" => stub_stat(uc),
         "lstat" => stub_lstat(uc),
+        "access" => stub_access(uc),
         "fstat" => stub_fstat(uc),
         "open" => stub_open(uc),
+        "unlink" => stub_unlink(uc),
+        "rename" => stub_rename(uc),
         "ftruncate" => stub_ftruncate(uc),
         "read" => stub_read(uc),
         "write" => stub_write(uc),
         "close" => stub_close(uc),
+        "fsync" | "fdatasync" => stub_fsync(uc),
         "dlopen" => stub_dlopen(uc),
         "dlsym" => stub_dlsym(uc),
         "dlclose" => stub_dlclose(uc),
         "pthread_once" => stub_return_zero(uc),
-        "pthread_create" => stub_return_zero(uc),
-        "pthread_mutex_lock" => stub_return_zero(uc),
-        "pthread_rwlock_unlock" => stub_return_zero(uc),
-        "pthread_rwlock_destroy" => stub_return_zero(uc),
-        "pthread_rwlock_wrlock" => stub_return_zero(uc),
-        "pthread_rwlock_init" => stub_return_zero(uc),
-        "pthread_mutex_unlock" => stub_return_zero(uc),
-        "pthread_rwlock_rdlock" => stub_return_zero(uc),
+        "pthread_create" => stub_pthread_create(uc),
+        "pthread_mutex_init" => stub_pthread_lock_reset(uc, "pthread_mutex_init"),
+        "pthread_mutex_destroy" => stub_pthread_lock_reset(uc, "pthread_mutex_destroy"),
+        "pthread_mutex_lock" => {
+            stub_pthread_lock_op(uc, "pthread_mutex_lock", LockTable::mutex_lock)
+        }
+        "pthread_mutex_trylock" => {
+            stub_pthread_lock_op(uc, "pthread_mutex_trylock", LockTable::mutex_trylock)
+        }
+        "pthread_mutex_unlock" => {
+            stub_pthread_lock_op(uc, "pthread_mutex_unlock", LockTable::mutex_unlock)
+        }
+        "pthread_key_create" => stub_pthread_key_create(uc),
+        "pthread_key_delete" => stub_pthread_key_delete(uc),
+        "pthread_getspecific" => stub_pthread_getspecific(uc),
+        "pthread_setspecific" => stub_pthread_setspecific(uc),
+        "pthread_rwlock_init" => stub_pthread_lock_reset(uc, "pthread_rwlock_init"),
+        "pthread_rwlock_destroy" => stub_pthread_lock_reset(uc, "pthread_rwlock_destroy"),
+        "pthread_rwlock_rdlock" => {
+            stub_pthread_lock_op(uc, "pthread_rwlock_rdlock", LockTable::rwlock_rdlock)
+        }
+        "pthread_rwlock_wrlock" => {
+            stub_pthread_lock_op(uc, "pthread_rwlock_wrlock", LockTable::rwlock_wrlock)
+        }
+        "pthread_rwlock_unlock" => {
+            stub_pthread_lock_op(uc, "pthread_rwlock_unlock", LockTable::rwlock_unlock)
+        }
         "gettimeofday" => stub_gettimeofday(uc),
+        "clock_gettime" => stub_clock_gettime(uc),
+        "time" => stub_time(uc),
         "__errno" => stub_errno_location(uc),
+        "getenv" => stub_getenv(uc),
+        "setenv" => stub_setenv(uc),
+        "unsetenv" => stub_unsetenv(uc),
         "__system_property_get" => stub_system_property_get(uc),
         "arc4random" => stub_arc4random(uc),
+        "arc4random_uniform" => stub_arc4random_uniform(uc),
+        "arc4random_buf" => stub_arc4random_buf(uc),
+        "getrandom" => stub_getrandom(uc),
+        "abort" => stub_abort(uc),
+        "exit" | "_exit" => stub_exit(uc),
+        "raise" => stub_raise(uc),
+        #[cfg(not(feature = "minimal"))]
+        "__android_log_print" => stub_android_log_print(uc),
+        #[cfg(not(feature = "minimal"))]
+        "__android_log_write" => stub_android_log_write(uc),
+        #[cfg(not(feature = "minimal"))]
+        "syslog" => stub_syslog(uc),
+        // Nothing would show guest log lines, so skip reading and formatting them.
+        #[cfg(feature = "minimal")]
+        "__android_log_print" | "__android_log_write" | "syslog" => stub_return_zero(uc),
         other => {
-            debug_print(other);
+            debug_print!(other);
             Err(VmError::UnhandledImport(other.to_string()))
         }
     }
@@ -95,6 +195,118 @@ fn stub_return_zero(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     Ok(())
 }
 
+fn stub_pthread_lock_reset(uc: &mut Unicorn<'_, RuntimeState>, name: &str) -> Result<(), VmError> {
+    let address = uc.reg_read(RegisterARM64::X0)?;
+    debug_trace!(format!("{name}(0x{address:X})"));
+    uc.get_data_mut().locks.forget(address);
+    uc.reg_write(RegisterARM64::X0, 0)?;
+    Ok(())
+}
+
+fn stub_pthread_lock_op(
+    uc: &mut Unicorn<'_, RuntimeState>,
+    name: &str,
+    op: fn(&mut LockTable, u64) -> Result<(), LockViolation>,
+) -> Result<(), VmError> {
+    let address = uc.reg_read(RegisterARM64::X0)?;
+    let (result, strict) = {
+        let state = uc.get_data_mut();
+        (
+            op(&mut state.locks, address),
+            state.pthread_options.strict_locks,
+        )
+    };
+    debug_trace!(format!("{name}(0x{address:X})={result:?}"));
+
+    let code = match result {
+        Ok(()) => 0,
+        // trylock failing is ordinary behavior, not misuse.
+        Err(LockViolation::Contended) => LockViolation::Contended.error_code(),
+        Err(violation) => {
+            debug_print!(format!("{name}: {violation:?} on lock 0x{address:X}"));
+            if strict { violation.error_code() } else { 0 }
+        }
+    };
+    uc.reg_write(RegisterARM64::X0, u64::from(code))?;
+    Ok(())
+}
+
+fn stub_pthread_key_create(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let key_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let destructor = uc.reg_read(RegisterARM64::X1)?;
+    let result = uc.get_data_mut().tls.create();
+    debug_trace!(format!(
+        "pthread_key_create(0x{key_ptr:X}, 0x{destructor:X})={result:?}"
+    ));
+
+    let code = match result {
+        Ok(key) => {
+            uc.mem_write(key_ptr, &key.to_le_bytes())?;
+            0
+        }
+        Err(code) => code,
+    };
+    uc.reg_write(RegisterARM64::X0, u64::from(code))?;
+    Ok(())
+}
+
+fn stub_pthread_key_delete(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let key = uc.reg_read(RegisterARM64::X0)? as u32;
+    let result = uc.get_data_mut().tls.delete(key);
+    debug_trace!(format!("pthread_key_delete({key})={result:?}"));
+    uc.reg_write(RegisterARM64::X0, u64::from(result.err().unwrap_or(0)))?;
+    Ok(())
+}
+
+fn stub_pthread_getspecific(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let key = uc.reg_read(RegisterARM64::X0)? as u32;
+    let value = uc.get_data().tls.get(key);
+    debug_trace!(format!("pthread_getspecific({key})=0x{value:X}"));
+    uc.reg_write(RegisterARM64::X0, value)?;
+    Ok(())
+}
+
+fn stub_pthread_setspecific(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let key = uc.reg_read(RegisterARM64::X0)? as u32;
+    let value = uc.reg_read(RegisterARM64::X1)?;
+    let result = uc.get_data_mut().tls.set(key, value);
+    debug_trace!(format!(
+        "pthread_setspecific({key}, 0x{value:X})={result:?}"
+    ));
+    uc.reg_write(RegisterARM64::X0, u64::from(result.err().unwrap_or(0)))?;
+    Ok(())
+}
+
+fn stub_pthread_create(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let thread_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let start_routine = uc.reg_read(RegisterARM64::X2)?;
+    let arg = uc.reg_read(RegisterARM64::X3)?;
+    debug_trace!(format!(
+        "pthread_create(0x{thread_ptr:X}, [...], 0x{start_routine:X}, 0x{arg:X})"
+    ));
+
+    let (thread_id, run_start_routine) = {
+        let state = uc.get_data_mut();
+        (
+            state.locks.next_thread_id(),
+            state.pthread_options.run_start_routines,
+        )
+    };
+    if thread_ptr != 0 {
+        uc.mem_write(thread_ptr, &thread_id.to_le_bytes())?;
+    }
+
+    if run_start_routine {
+        let ret = invoke_nested_cdecl(uc, start_routine, &[arg])?;
+        debug_print!(format!(
+            "pthread start routine 0x{start_routine:X} returned 0x{ret:X}"
+        ));
+    }
+
+    uc.reg_write(RegisterARM64::X0, 0)?;
+    Ok(())
+}
+
 fn stub_malloc(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let request = uc.reg_read(RegisterARM64::X0)?;
     let address = {
@@ -102,7 +314,15 @@ fn stub_malloc(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
         state.malloc_allocator.alloc(request)?
     };
 
-    debug_trace(format!("malloc(0x{request:X})=0x{address:X}"));
+    debug_trace!(format!("malloc(0x{request:X})=0x{address:X}"));
+    record_event(
+        uc,
+        FlightEvent::Alloc {
+            allocator: "malloc",
+            address,
+            size: request,
+        },
+    );
     uc.reg_write(RegisterARM64::X0, address)?;
     Ok(())
 }
@@ -133,26 +353,135 @@ fn stub_strncpy(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     Ok(())
 }
 
+fn stub_memcpy(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let dst = uc.reg_read(RegisterARM64::X0)?;
+    let src = uc.reg_read(RegisterARM64::X1)?;
+    let length = uc.reg_read(RegisterARM64::X2)?;
+
+    copy_guest_memory(uc, dst, src, length)?;
+    uc.reg_write(RegisterARM64::X0, dst)?;
+    Ok(())
+}
+
+fn stub_memmove(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    // copy_guest_memory orders its chunks so overlap is safe.
+    stub_memcpy(uc)
+}
+
+/// Copies `length` bytes in chunks, so a bogus length faults on unmapped guest
+/// memory instead of first allocating that much on the host.
+fn copy_guest_memory(
+    uc: &mut Unicorn<'_, RuntimeState>,
+    dst: u64,
+    src: u64,
+    length: u64,
+) -> Result<(), VmError> {
+    // Copy from the end when the destination overlaps the tail of the source,
+    // so every chunk is read before it is overwritten.
+    let backwards = dst > src && dst - src < length;
+    let mut buffer = vec![0_u8; length.min(GUEST_MEMORY_CHUNK) as usize];
+    let mut done = 0;
+    while done < length {
+        let chunk_len = (length - done).min(GUEST_MEMORY_CHUNK);
+        let offset = if backwards {
+            length - done - chunk_len
+        } else {
+            done
+        };
+        let chunk = &mut buffer[..chunk_len as usize];
+        uc.mem_read(src + offset, chunk)?;
+        uc.mem_write(dst + offset, chunk)?;
+        done += chunk_len;
+    }
+    Ok(())
+}
+
+fn stub_memset(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let dst = uc.reg_read(RegisterARM64::X0)?;
+    let value = uc.reg_read(RegisterARM64::X1)? as u8;
+    let length = uc.reg_read(RegisterARM64::X2)?;
+
+    let chunk = vec![value; length.min(GUEST_MEMORY_CHUNK) as usize];
+    let mut done = 0;
+    while done < length {
+        let chunk_len = (length - done).min(GUEST_MEMORY_CHUNK);
+        uc.mem_write(dst + done, &chunk[..chunk_len as usize])?;
+        done += chunk_len;
+    }
+    uc.reg_write(RegisterARM64::X0, dst)?;
+    Ok(())
+}
+
+/// Reads a NUL-terminated guest string (without the terminator) one page at a
+/// time, so a string ending just before an unmapped page is still readable.
+fn read_guest_c_bytes(uc: &Unicorn<'_, RuntimeState>, address: u64) -> Result<Vec<u8>, VmError> {
+    let mut bytes = Vec::new();
+    let mut cursor = address;
+    while (bytes.len() as u64) < MAX_GUEST_STRING_LEN {
+        let chunk_len = PAGE_SIZE - (cursor % PAGE_SIZE);
+        let chunk = uc.mem_read_as_vec(cursor, chunk_len as usize)?;
+        if let Some(end) = chunk.iter().position(|byte| *byte == 0) {
+            bytes.extend_from_slice(&chunk[..end]);
+            return Ok(bytes);
+        }
+        bytes.extend_from_slice(&chunk);
+        cursor += chunk_len;
+    }
+    Err(VmError::UnterminatedCString(address))
+}
+
+fn stub_strlen(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let address = uc.reg_read(RegisterARM64::X0)?;
+    let length = read_guest_c_bytes(uc, address)?.len();
+    uc.reg_write(RegisterARM64::X0, length as u64)?;
+    Ok(())
+}
+
+fn stub_strcmp(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let lhs_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let rhs_ptr = uc.reg_read(RegisterARM64::X1)?;
+    let lhs = read_guest_c_bytes(uc, lhs_ptr)?;
+    let rhs = read_guest_c_bytes(uc, rhs_ptr)?;
+
+    let result = compare_c_bytes(&lhs, &rhs);
+    uc.reg_write(RegisterARM64::X0, result as i64 as u64)?;
+    Ok(())
+}
+
+fn compare_c_bytes(lhs: &[u8], rhs: &[u8]) -> i32 {
+    // Both slices are implicitly followed by a NUL, which compares below any byte.
+    let lhs = lhs.iter().copied().chain(std::iter::once(0));
+    let rhs = rhs.iter().copied().chain(std::iter::once(0));
+    for (left, right) in lhs.zip(rhs) {
+        if left != right || left == 0 {
+            return i32::from(left) - i32::from(right);
+        }
+    }
+    0
+}
+
 fn stub_mkdir(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let path_ptr = uc.reg_read(RegisterARM64::X0)?;
     let mode = uc.reg_read(RegisterARM64::X1)?;
     let path = read_c_string(uc, path_ptr, 0x1000)?;
-    debug_trace(format!("mkdir('{path}', {mode:#o})"));
+    debug_trace!(format!("mkdir('{path}', {mode:#o})"));
 
     // Only allow creating ./anisette directory (matches Python reference impl)
-    if path != "./anisette" {
-        debug_print(format!("mkdir: rejecting invalid path '{path}'"));
+    if path != GUEST_PROVISIONING_DIR {
+        debug_print!(format!("mkdir: rejecting invalid path '{path}'"));
         set_errno(uc, ENOENT)?;
         uc.reg_write(RegisterARM64::X0, u64::MAX)?;
         return Ok(());
     }
 
-    match fs::create_dir_all(&path) {
+    let host_path = map_guest_path(uc, &path);
+    let result = uc.get_data_mut().guest_fs.create_dir_all(&host_path);
+    match result {
         Ok(()) => {
             uc.reg_write(RegisterARM64::X0, 0)?;
         }
-        Err(_) => {
-            set_errno(uc, ENOENT)?;
+        Err(err) => {
+            set_errno(uc, errno_for_io_error(&err))?;
             uc.reg_write(RegisterARM64::X0, u64::MAX)?;
         }
     }
@@ -160,6 +489,84 @@ fn stub_mkdir(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     Ok(())
 }
 
+/// The open file behind guest descriptor `fd`, if there is one.
+fn guest_file(state: &mut RuntimeState, fd: u64) -> Option<&mut dyn GuestFile> {
+    let index = usize::try_from(fd).ok()?;
+    let file = state.file_handles.get_mut(index)?.as_mut()?;
+    Some(file.as_mut())
+}
+
+/// Fails an fd-based call the way bionic does for an unknown descriptor.
+fn reject_bad_fd(uc: &mut Unicorn<'_, RuntimeState>, fd: u64) -> Result<(), VmError> {
+    debug_print!(format!("rejecting invalid file descriptor {fd}"));
+    set_errno(uc, EBADF)?;
+    uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+    Ok(())
+}
+
+/// Translates a host I/O failure into the bionic errno the guest library expects.
+fn errno_for_io_error(err: &io::Error) -> u32 {
+    match err.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        io::ErrorKind::IsADirectory => EISDIR,
+        io::ErrorKind::NotADirectory => ENOTDIR,
+        io::ErrorKind::DirectoryNotEmpty => ENOTEMPTY,
+        io::ErrorKind::StorageFull => ENOSPC,
+        io::ErrorKind::ReadOnlyFilesystem => EROFS,
+        io::ErrorKind::FileTooLarge => EFBIG,
+        io::ErrorKind::WouldBlock => EAGAIN,
+        io::ErrorKind::Interrupted => EINTR,
+        io::ErrorKind::OutOfMemory => ENOMEM,
+        io::ErrorKind::Unsupported => EPERM,
+        _ => EIO,
+    }
+}
+
+/// Guest files the stubs may touch: `./anisette/adi.pb` plus sibling temporaries
+/// (`adi.pb.tmp`, ...) the library uses to atomically replace it.
+fn is_allowed_guest_file(path: &str) -> bool {
+    path == GUEST_ADI_PB_PATH
+        || path
+            .strip_prefix(GUEST_ADI_PB_PATH)
+            .is_some_and(|suffix| suffix.starts_with('.') && !suffix.contains('/'))
+}
+
+/// Paths `stat` and `access` may look at: the allowed files plus `./anisette`
+/// itself, which the library checks before creating it.
+fn is_allowed_guest_path(path: &str) -> bool {
+    path == GUEST_PROVISIONING_DIR || is_allowed_guest_file(path)
+}
+
+/// Applies the profile and per-DSID provisioning namespace (if any) to a guest
+/// path under `./anisette`.
+pub(crate) fn map_guest_path(uc: &Unicorn<'_, RuntimeState>, path: &str) -> String {
+    let state = uc.get_data();
+    let root = provisioning_root(state);
+    match state.provisioning_namespace.as_deref() {
+        Some(namespace) => rebase_guest_path(&format!("{root}/{namespace}"), path),
+        None => rebase_guest_path(root, path),
+    }
+}
+
+/// Where the guest's `./anisette` is stored: the profile's directory, if any.
+pub(crate) fn provisioning_root(state: &RuntimeState) -> &str {
+    state
+        .provisioning_root
+        .as_deref()
+        .unwrap_or(GUEST_PROVISIONING_DIR)
+}
+
+/// `path` with its leading `./anisette` replaced by `root`.
+pub(crate) fn rebase_guest_path(root: &str, path: &str) -> String {
+    match path.strip_prefix(GUEST_PROVISIONING_DIR) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => format!("{root}{rest}"),
+        _ => path.to_string(),
+    }
+}
+
 fn stub_umask(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     uc.reg_write(RegisterARM64::X0, 0o777)?;
     Ok(())
@@ -169,7 +576,7 @@ fn stub_chmod(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let path_ptr = uc.reg_read(RegisterARM64::X0)?;
     let mode = uc.reg_read(RegisterARM64::X1)?;
     let path = read_c_string(uc, path_ptr, 0x1000)?;
-    debug_trace(format!("chmod('{path}', {mode:#o})"));
+    debug_trace!(format!("chmod('{path}', {mode:#o})"));
     uc.reg_write(RegisterARM64::X0, 0)?;
     Ok(())
 }
@@ -201,6 +608,19 @@ fn build_python_stat_bytes(mode: u32, size: u64) -> Vec<u8> {
     stat
 }
 
+/// Backends that cannot report a file type (or permissions) still describe a file
+/// the guest can use: default to a regular `0644` file.
+fn with_file_type(mode: u32) -> u32 {
+    if mode & S_IFMT != 0 {
+        return mode;
+    }
+    let permissions = match mode & 0o7777 {
+        0 => 0o644,
+        permissions => permissions,
+    };
+    S_IFREG | permissions
+}
+
 fn write_python_stat(
     uc: &mut Unicorn<'_, RuntimeState>,
     out_ptr: u64,
@@ -209,18 +629,19 @@ fn write_python_stat(
     stat_blksize: u64,
     stat_blocks: u64,
 ) -> Result<(), VmError> {
-    debug_print(format!("{size} {stat_blksize} {stat_blocks}"));
+    debug_print!(format!("{size} {stat_blksize} {stat_blocks}"));
 
     let fake_blksize = 512_u64;
     let fake_blocks = size.div_ceil(512);
-    debug_print(format!("{size} {fake_blksize} {fake_blocks}"));
+    debug_print!(format!("{size} {fake_blksize} {fake_blocks}"));
 
-    debug_print(format!("0x{mode:X} = {mode}"));
+    let mode = with_file_type(mode);
+    debug_print!(format!("0x{mode:X} = {mode}"));
     let stat_bytes = build_python_stat_bytes(mode, size);
-    debug_print(format!("{}", stat_bytes.len()));
-    debug_print(format!("Write to ptr: 0x{out_ptr:X}"));
+    debug_print!(format!("{}", stat_bytes.len()));
+    debug_print!(format!("Write to ptr: 0x{out_ptr:X}"));
     uc.mem_write(out_ptr, &stat_bytes)?;
-    debug_print("Stat struct written to guest memory");
+    debug_print!("Stat struct written to guest memory");
     Ok(())
 }
 
@@ -228,35 +649,27 @@ fn stat_path_into_guest(
     uc: &mut Unicorn<'_, RuntimeState>,
     path: &str,
     out_ptr: u64,
+    follow_symlinks: bool,
 ) -> Result<(), VmError> {
-    let metadata = match fs::symlink_metadata(path) {
+    let metadata = {
+        let guest_fs = &mut uc.get_data_mut().guest_fs;
+        if follow_symlinks {
+            guest_fs.metadata(path)
+        } else {
+            guest_fs.symlink_metadata(path)
+        }
+    };
+    let metadata = match metadata {
         Ok(metadata) => metadata,
-        Err(_) => {
-            debug_print(format!("Unable to stat '{path}'"));
-            set_errno(uc, ENOENT)?;
+        Err(err) => {
+            debug_print!(format!("Unable to stat '{path}'"));
+            set_errno(uc, errno_for_io_error(&err))?;
             uc.reg_write(RegisterARM64::X0, u64::MAX)?;
             return Ok(());
         }
     };
 
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::MetadataExt;
-        write_python_stat(
-            uc,
-            out_ptr,
-            metadata.mode(),
-            metadata.size(),
-            metadata.blksize(),
-            metadata.blocks(),
-        )?;
-    }
-
-    #[cfg(not(unix))]
-    {
-        write_python_stat(uc, out_ptr, 0, metadata.len(), 0, 0)?;
-    }
-
+    write_guest_metadata(uc, out_ptr, &metadata)?;
     uc.reg_write(RegisterARM64::X0, 0)?;
     Ok(())
 }
@@ -266,63 +679,115 @@ fn stat_fd_into_guest(
     fd: u64,
     out_ptr: u64,
 ) -> Result<(), VmError> {
-    let fd_index = usize::try_from(fd).map_err(|_| VmError::InvalidFileDescriptor(fd))?;
-
     let metadata = {
-        let state = uc.get_data_mut();
-        let slot = state
-            .file_handles
-            .get_mut(fd_index)
-            .ok_or(VmError::InvalidFileDescriptor(fd))?;
-        let file = slot.as_mut().ok_or(VmError::InvalidFileDescriptor(fd))?;
+        let Some(file) = guest_file(uc.get_data_mut(), fd) else {
+            return reject_bad_fd(uc, fd);
+        };
         file.metadata()
     };
 
     let metadata = match metadata {
         Ok(metadata) => metadata,
-        Err(_) => {
-            debug_print(format!("Unable to stat '{fd}'"));
-            set_errno(uc, ENOENT)?;
+        Err(err) => {
+            debug_print!(format!("Unable to stat '{fd}'"));
+            set_errno(uc, errno_for_io_error(&err))?;
             uc.reg_write(RegisterARM64::X0, u64::MAX)?;
             return Ok(());
         }
     };
 
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::MetadataExt;
-        write_python_stat(
-            uc,
-            out_ptr,
-            metadata.mode(),
-            metadata.size(),
-            metadata.blksize(),
-            metadata.blocks(),
-        )?;
-    }
-
-    #[cfg(not(unix))]
-    {
-        write_python_stat(uc, out_ptr, 0, metadata.len(), 0, 0)?;
-    }
-
+    write_guest_metadata(uc, out_ptr, &metadata)?;
     uc.reg_write(RegisterARM64::X0, 0)?;
     Ok(())
 }
+
+fn write_guest_metadata(
+    uc: &mut Unicorn<'_, RuntimeState>,
+    out_ptr: u64,
+    metadata: &GuestMetadata,
+) -> Result<(), VmError> {
+    write_python_stat(
+        uc,
+        out_ptr,
+        metadata.mode,
+        metadata.size,
+        metadata.blksize,
+        metadata.blocks,
+    )
+}
+
 fn stub_lstat(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let path_ptr = uc.reg_read(RegisterARM64::X0)?;
     let out_ptr = uc.reg_read(RegisterARM64::X1)?;
     let path = read_c_string(uc, path_ptr, 0x1000)?;
-    debug_trace(format!(
+    debug_trace!(format!(
         "lstat(0x{path_ptr:X}:'{path}', [x1:0x{out_ptr:X}])"
     ));
-    stat_path_into_guest(uc, &path, out_ptr)
+    if !is_allowed_guest_path(&path) {
+        debug_print!(format!("lstat: rejecting invalid path '{path}'"));
+        set_errno(uc, ENOENT)?;
+        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        return Ok(());
+    }
+    let path = map_guest_path(uc, &path);
+    stat_path_into_guest(uc, &path, out_ptr, false)
+}
+
+fn stub_stat(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let path_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let out_ptr = uc.reg_read(RegisterARM64::X1)?;
+    let path = read_c_string(uc, path_ptr, 0x1000)?;
+    debug_trace!(format!("stat(0x{path_ptr:X}:'{path}', [x1:0x{out_ptr:X}])"));
+    if !is_allowed_guest_path(&path) {
+        debug_print!(format!("stat: rejecting invalid path '{path}'"));
+        set_errno(uc, ENOENT)?;
+        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        return Ok(());
+    }
+    let path = map_guest_path(uc, &path);
+    stat_path_into_guest(uc, &path, out_ptr, true)
+}
+
+fn stub_access(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let path_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let mode = uc.reg_read(RegisterARM64::X1)?;
+    let path = read_c_string(uc, path_ptr, 0x1000)?;
+    debug_trace!(format!("access('{path}', {mode:#o})"));
+    if !is_allowed_guest_path(&path) {
+        debug_print!(format!("access: rejecting invalid path '{path}'"));
+        set_errno(uc, ENOENT)?;
+        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        return Ok(());
+    }
+
+    let path = map_guest_path(uc, &path);
+    let metadata = uc.get_data_mut().guest_fs.metadata(&path);
+    match metadata {
+        Ok(metadata) => {
+            // The guest runs as the file's owner, so only the owner bits count.
+            let owner = u64::from(metadata.mode >> 6) & (R_OK | W_OK | X_OK);
+            if mode & (R_OK | W_OK | X_OK) & !owner != 0 {
+                debug_print!(format!("access: '{path}' denies {mode:#o}"));
+                set_errno(uc, EACCES)?;
+                uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+            } else {
+                uc.reg_write(RegisterARM64::X0, 0)?;
+            }
+        }
+        Err(err) => {
+            debug_print!(format!("access: '{path}' does not exist"));
+            set_errno(uc, errno_for_io_error(&err))?;
+            uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        }
+    }
+
+    Ok(())
 }
 
 fn stub_fstat(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let fd = uc.reg_read(RegisterARM64::X0)?;
     let out_ptr = uc.reg_read(RegisterARM64::X1)?;
-    debug_trace(format!("fstat({fd}, [...])"));
+    debug_trace!(format!("fstat({fd}, [...])"));
     stat_fd_into_guest(uc, fd, out_ptr)
 }
 
@@ -335,67 +800,144 @@ fn stub_open(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
         return Err(VmError::EmptyPath);
     }
 
-    debug_trace(format!("open('{path}', {flags:#o}, {mode:#o})"));
+    debug_trace!(format!("open('{path}', {flags:#o}, {mode:#o})"));
     // Only allow access to ./anisette/adi.pb (matches Python reference impl)
-    if path != "./anisette/adi.pb" {
-        debug_print(format!("open: rejecting invalid path '{path}'"));
-        set_errno(uc, ENOENT)?;
-        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
-        return Ok(());
-    }
-
-    if flags != O_NOFOLLOW && flags != (O_NOFOLLOW | O_CREAT | O_WRONLY) {
-        debug_print(format!("open: rejecting unsupported flags {flags:#o}"));
+    if !is_allowed_guest_file(&path) {
+        debug_print!(format!("open: rejecting invalid path '{path}'"));
         set_errno(uc, ENOENT)?;
         uc.reg_write(RegisterARM64::X0, u64::MAX)?;
         return Ok(());
     }
 
-    let mut options = OpenOptions::new();
+    let mut options = GuestOpenOptions::default();
     let access_mode = flags & O_ACCMODE;
-    let _write_only = access_mode == O_WRONLY;
-    let create = (flags & O_CREAT) != 0;
+    let append = (flags & O_APPEND) != 0;
 
     match access_mode {
-        0 => {
-            options.read(true);
+        O_RDONLY => {
+            options.read = true;
         }
         O_WRONLY => {
-            options.write(true).truncate(true);
+            options.write = true;
+            // Write-only opens replace adi.pb wholesale (matches Python reference impl)
+            options.truncate = !append;
         }
         O_RDWR => {
-            options.read(true).write(true);
+            options.read = true;
+            options.write = true;
         }
         _ => {
-            set_errno(uc, ENOENT)?;
+            debug_print!(format!("open: rejecting invalid access mode {flags:#o}"));
+            set_errno(uc, EINVAL)?;
             uc.reg_write(RegisterARM64::X0, u64::MAX)?;
             return Ok(());
         }
     }
 
-    if create {
-        options.create(true).read(true).write(true);
-        if let Some(parent) = std::path::Path::new(&path).parent() {
-            let _ = fs::create_dir_all(parent);
+    if (flags & O_TRUNC) != 0 && access_mode != O_RDONLY && !append {
+        options.truncate = true;
+    }
+    options.append = append;
+
+    let path = map_guest_path(uc, &path);
+    if (flags & O_CREAT) != 0 {
+        if (flags & O_EXCL) != 0 {
+            options.create_new = true;
+        } else {
+            options.create = true;
+        }
+        // Host backends need write access to create a file, whatever the guest asked for.
+        options.read = true;
+        options.write = true;
+        if let Some(parent) = std::path::Path::new(&path).parent()
+            && let Some(parent) = parent.to_str()
+        {
+            let _ = uc.get_data_mut().guest_fs.create_dir_all(parent);
         }
     }
 
     if (flags & O_NOFOLLOW) == 0 {
-        debug_trace("open without O_NOFOLLOW");
+        debug_trace!("open without O_NOFOLLOW");
     }
 
-    match options.open(&path) {
+    let opened = uc.get_data_mut().guest_fs.open(&path, &options);
+    match opened {
         Ok(file) => {
             let fd = {
                 let state = uc.get_data_mut();
                 state.file_handles.push(Some(file));
-                (state.file_handles.len() - 1) as u64
+                let fd = state.file_handles.len() - 1;
+                if access_mode != O_RDONLY && is_adi_pb(&path) {
+                    state.adi_pb_fds.insert(fd, path.clone());
+                }
+                fd as u64
             };
 
             uc.reg_write(RegisterARM64::X0, fd)?;
         }
-        Err(_) => {
-            set_errno(uc, ENOENT)?;
+        Err(err) => {
+            set_errno(uc, errno_for_io_error(&err))?;
+            uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn stub_unlink(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let path_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let path = read_c_string(uc, path_ptr, 0x1000)?;
+    debug_trace!(format!("unlink('{path}')"));
+
+    if !is_allowed_guest_file(&path) {
+        debug_print!(format!("unlink: rejecting invalid path '{path}'"));
+        set_errno(uc, ENOENT)?;
+        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        return Ok(());
+    }
+
+    let path = map_guest_path(uc, &path);
+    let result = uc.get_data_mut().guest_fs.remove_file(&path);
+    match result {
+        Ok(()) => {
+            uc.get_data_mut().persistence_dirty = true;
+            uc.reg_write(RegisterARM64::X0, 0)?;
+        }
+        Err(err) => {
+            set_errno(uc, errno_for_io_error(&err))?;
+            uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn stub_rename(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let from_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let to_ptr = uc.reg_read(RegisterARM64::X1)?;
+    let from = read_c_string(uc, from_ptr, 0x1000)?;
+    let to = read_c_string(uc, to_ptr, 0x1000)?;
+    debug_trace!(format!("rename('{from}', '{to}')"));
+
+    if !is_allowed_guest_file(&from) || !is_allowed_guest_file(&to) {
+        debug_print!(format!(
+            "rename: rejecting invalid paths '{from}' -> '{to}'"
+        ));
+        set_errno(uc, ENOENT)?;
+        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        return Ok(());
+    }
+
+    let from = map_guest_path(uc, &from);
+    let to = map_guest_path(uc, &to);
+    let result = uc.get_data_mut().guest_fs.rename(&from, &to);
+    match result {
+        Ok(()) => {
+            uc.get_data_mut().persistence_dirty = true;
+            uc.reg_write(RegisterARM64::X0, 0)?;
+        }
+        Err(err) => {
+            set_errno(uc, errno_for_io_error(&err))?;
             uc.reg_write(RegisterARM64::X0, u64::MAX)?;
         }
     }
@@ -406,23 +948,21 @@ fn stub_open(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
 fn stub_ftruncate(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let fd = uc.reg_read(RegisterARM64::X0)?;
     let length = uc.reg_read(RegisterARM64::X1)?;
-    debug_trace(format!("ftruncate({fd}, {length})"));
-    let fd_index = usize::try_from(fd).map_err(|_| VmError::InvalidFileDescriptor(fd))?;
-
+    debug_trace!(format!("ftruncate({fd}, {length})"));
     let result = {
-        let state = uc.get_data_mut();
-        let slot = state
-            .file_handles
-            .get_mut(fd_index)
-            .ok_or(VmError::InvalidFileDescriptor(fd))?;
-        let file = slot.as_mut().ok_or(VmError::InvalidFileDescriptor(fd))?;
+        let Some(file) = guest_file(uc.get_data_mut(), fd) else {
+            return reject_bad_fd(uc, fd);
+        };
         file.set_len(length)
     };
 
     match result {
-        Ok(()) => uc.reg_write(RegisterARM64::X0, 0)?,
-        Err(_) => {
-            set_errno(uc, ENOENT)?;
+        Ok(()) => {
+            uc.get_data_mut().persistence_dirty = true;
+            uc.reg_write(RegisterARM64::X0, 0)?;
+        }
+        Err(err) => {
+            set_errno(uc, errno_for_io_error(&err))?;
             uc.reg_write(RegisterARM64::X0, u64::MAX)?;
         }
     }
@@ -435,27 +975,22 @@ fn stub_read(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let buf_ptr = uc.reg_read(RegisterARM64::X1)?;
     let count = uc.reg_read(RegisterARM64::X2)? as usize;
 
-    let fd_index = usize::try_from(fd).map_err(|_| VmError::InvalidFileDescriptor(fd))?;
-
     let mut buffer = vec![0_u8; count];
 
     let read_size = {
-        let state = uc.get_data_mut();
-        let slot = state
-            .file_handles
-            .get_mut(fd_index)
-            .ok_or(VmError::InvalidFileDescriptor(fd))?;
-        let file = slot.as_mut().ok_or(VmError::InvalidFileDescriptor(fd))?;
+        let Some(file) = guest_file(uc.get_data_mut(), fd) else {
+            return reject_bad_fd(uc, fd);
+        };
         file.read(&mut buffer)
     };
-    debug_trace(format!("read({fd}, 0x{buf_ptr:X}, {count})={read_size:?}"));
+    debug_trace!(format!("read({fd}, 0x{buf_ptr:X}, {count})={read_size:?}"));
     match read_size {
         Ok(read_size) => {
             uc.mem_write(buf_ptr, &buffer[..read_size])?;
             uc.reg_write(RegisterARM64::X0, read_size as u64)?;
         }
-        Err(_) => {
-            set_errno(uc, ENOENT)?;
+        Err(err) => {
+            set_errno(uc, errno_for_io_error(&err))?;
             uc.reg_write(RegisterARM64::X0, u64::MAX)?;
         }
     }
@@ -467,25 +1002,23 @@ fn stub_write(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let fd = uc.reg_read(RegisterARM64::X0)?;
     let buf_ptr = uc.reg_read(RegisterARM64::X1)?;
     let count = uc.reg_read(RegisterARM64::X2)? as usize;
-    debug_trace(format!("write({fd}, 0x{buf_ptr:X}, {count})"));
-    let fd_index = usize::try_from(fd).map_err(|_| VmError::InvalidFileDescriptor(fd))?;
-
+    debug_trace!(format!("write({fd}, 0x{buf_ptr:X}, {count})"));
     let bytes = uc.mem_read_as_vec(buf_ptr, count)?;
 
     let write_size = {
-        let state = uc.get_data_mut();
-        let slot = state
-            .file_handles
-            .get_mut(fd_index)
-            .ok_or(VmError::InvalidFileDescriptor(fd))?;
-        let file = slot.as_mut().ok_or(VmError::InvalidFileDescriptor(fd))?;
+        let Some(file) = guest_file(uc.get_data_mut(), fd) else {
+            return reject_bad_fd(uc, fd);
+        };
         file.write_all(&bytes)
     };
 
     match write_size {
-        Ok(()) => uc.reg_write(RegisterARM64::X0, count as u64)?,
-        Err(_) => {
-            set_errno(uc, ENOENT)?;
+        Ok(()) => {
+            uc.get_data_mut().persistence_dirty = true;
+            uc.reg_write(RegisterARM64::X0, count as u64)?;
+        }
+        Err(err) => {
+            set_errno(uc, errno_for_io_error(&err))?;
             uc.reg_write(RegisterARM64::X0, u64::MAX)?;
         }
     }
@@ -493,16 +1026,62 @@ fn stub_write(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     Ok(())
 }
 
+fn stub_fsync(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let fd = uc.reg_read(RegisterARM64::X0)?;
+    debug_trace!(format!("fsync({fd})"));
+    let result = {
+        let Some(file) = guest_file(uc.get_data_mut(), fd) else {
+            return reject_bad_fd(uc, fd);
+        };
+        file.sync_all()
+    };
+    if let Err(err) = result {
+        set_errno(uc, errno_for_io_error(&err))?;
+        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        return Ok(());
+    }
+
+    // Changes are only durable once the persistence backend has been flushed (on
+    // emscripten, IDBFS syncs asynchronously, so this just schedules it).
+    if std::mem::take(&mut uc.get_data_mut().persistence_dirty)
+        && let Err(err) = with_persistence(|backend| backend.sync(false))
+    {
+        debug_print!(format!("fsync: persistence sync failed: {err}"));
+        uc.get_data_mut().persistence_dirty = true;
+        set_errno(uc, EIO)?;
+        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        return Ok(());
+    }
+
+    uc.reg_write(RegisterARM64::X0, 0)?;
+    Ok(())
+}
+
 fn stub_close(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let fd = uc.reg_read(RegisterARM64::X0)?;
-    let fd_index = usize::try_from(fd).map_err(|_| VmError::InvalidFileDescriptor(fd))?;
+    if guest_file(uc.get_data_mut(), fd).is_none() {
+        return reject_bad_fd(uc, fd);
+    }
 
+    let fd_index = fd as usize;
     let state = uc.get_data_mut();
-    let slot = state
-        .file_handles
-        .get_mut(fd_index)
-        .ok_or(VmError::InvalidFileDescriptor(fd))?;
-    *slot = None;
+    state.file_handles[fd_index] = None;
+
+    // A rewritten adi.pb is new provisioning state: checksum it so corruption in
+    // storage is caught on load, and persist it without relying on the caller (or
+    // the library's fsync) to sync at the right moment.
+    if let Some(adi_pb) = state.adi_pb_fds.remove(&fd_index) {
+        let library = state.library_tag.clone();
+        if let Err(err) = record_checksum(state.guest_fs.as_mut(), &adi_pb, library.as_deref()) {
+            debug_print!(format!(
+                "close: recording checksum of {adi_pb} failed: {err}"
+            ));
+        }
+        if state.persistence_dirty {
+            state.persistence_dirty = false;
+            schedule_sync();
+        }
+    }
 
     uc.reg_write(RegisterARM64::X0, 0)?;
     Ok(())
@@ -512,8 +1091,8 @@ fn stub_dlopen(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let path_ptr = uc.reg_read(RegisterARM64::X0)?;
     let path = read_c_string(uc, path_ptr, 0x1000)?;
 
-    let library_name = path.rsplit('/').next().ok_or(VmError::EmptyPath)?;
-    debug_trace(format!("dlopen('{path}' ({library_name}))"));
+    let library_name = path.rsplit(['/', '\\']).next().ok_or(VmError::EmptyPath)?;
+    debug_trace!(format!("dlopen('{path}' ({library_name}))"));
     let library_index = load_library_by_name(uc, library_name)?;
 
     uc.reg_write(RegisterARM64::X0, (library_index + 1) as u64)?;
@@ -533,7 +1112,7 @@ fn stub_dlsym(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     {
         let state = uc.get_data();
         if let Some(library) = state.loaded_libraries.get(library_index) {
-            debug_trace(format!(
+            debug_trace!(format!(
                 "dlsym({handle:X} ({}), '{}')",
                 library.name, symbol_name
             ));
@@ -542,7 +1121,7 @@ fn stub_dlsym(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
 
     let symbol_address =
         resolve_symbol_from_loaded_library_by_name(uc, library_index, &symbol_name)?;
-    debug_print(format!("Found at 0x{symbol_address:X}"));
+    debug_print!(format!("Found at 0x{symbol_address:X}"));
     uc.reg_write(RegisterARM64::X0, symbol_address)?;
     Ok(())
 }
@@ -555,23 +1134,21 @@ fn stub_dlclose(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
 fn stub_gettimeofday(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let time_ptr = uc.reg_read(RegisterARM64::X0)?;
     let tz_ptr = uc.reg_read(RegisterARM64::X1)?;
-    debug_trace(format!("gettimeofday(0x{time_ptr:X}, 0x{tz_ptr:X})"));
+    debug_trace!(format!("gettimeofday(0x{time_ptr:X}, 0x{tz_ptr:X})"));
     if tz_ptr != 0 {
         return Err(VmError::UnhandledImport(format!(
             "gettimeofday tz pointer must be null, got 0x{tz_ptr:X}"
         )));
     }
 
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default();
+    let now = uc.get_data().clock.realtime();
     let sec = now.as_secs();
     let usec = now.subsec_micros() as i64;
 
     let mut timeval = [0_u8; 16];
     timeval[0..8].copy_from_slice(&sec.to_le_bytes());
     timeval[8..16].copy_from_slice(&usec.to_le_bytes());
-    debug_print(format!(
+    debug_print!(format!(
         "{{'tv_sec': {sec}, 'tv_usec': {usec}}} {} {}",
         bytes_to_hex(&timeval),
         timeval.len()
@@ -583,34 +1160,398 @@ fn stub_gettimeofday(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError>
     Ok(())
 }
 
+fn stub_clock_gettime(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let clock_id = uc.reg_read(RegisterARM64::X0)?;
+    let timespec_ptr = uc.reg_read(RegisterARM64::X1)?;
+    debug_trace!(format!("clock_gettime({clock_id}, 0x{timespec_ptr:X})"));
+
+    let now = {
+        let clock = &uc.get_data().clock;
+        match clock_id {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE => Some(clock.realtime()),
+            CLOCK_MONOTONIC | CLOCK_MONOTONIC_RAW | CLOCK_MONOTONIC_COARSE | CLOCK_BOOTTIME => {
+                Some(clock.monotonic())
+            }
+            _ => None,
+        }
+    };
+    let Some(now) = now else {
+        debug_print!(format!("clock_gettime: unsupported clock id {clock_id}"));
+        set_errno(uc, EINVAL)?;
+        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        return Ok(());
+    };
+
+    let mut timespec = [0_u8; 16];
+    timespec[0..8].copy_from_slice(&now.as_secs().to_le_bytes());
+    timespec[8..16].copy_from_slice(&u64::from(now.subsec_nanos()).to_le_bytes());
+
+    uc.mem_write(timespec_ptr, &timespec)?;
+    uc.reg_write(RegisterARM64::X0, 0)?;
+    Ok(())
+}
+
+fn stub_time(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let out_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let sec = uc.get_data().clock.realtime().as_secs();
+    debug_trace!(format!("time(0x{out_ptr:X})={sec}"));
+
+    if out_ptr != 0 {
+        uc.mem_write(out_ptr, &sec.to_le_bytes())?;
+    }
+    uc.reg_write(RegisterARM64::X0, sec)?;
+    Ok(())
+}
+
 fn stub_errno_location(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     if uc.get_data().errno_address.is_none() {
-        debug_print("Checking errno before first error (!)");
+        debug_print!("Checking errno before first error (!)");
     }
     let errno_address = ensure_errno_address(uc)?;
     uc.reg_write(RegisterARM64::X0, errno_address)?;
     Ok(())
 }
 
+fn stub_getenv(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let name_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let name = read_c_string(uc, name_ptr, 0x1000)?;
+
+    let value = uc.get_data().environment.get(&name).cloned();
+    debug_trace!(format!("getenv('{name}')={value:?}"));
+    let Some(value) = value else {
+        uc.reg_write(RegisterARM64::X0, 0)?;
+        return Ok(());
+    };
+
+    // Hand out one stable guest copy per variable until it is changed again.
+    let cached = uc.get_data().environment_strings.get(&name).copied();
+    let address = match cached {
+        Some(address) => address,
+        None => {
+            let address = alloc_guest_c_string(uc, &value)?;
+            uc.get_data_mut().environment_strings.insert(name, address);
+            address
+        }
+    };
+    uc.reg_write(RegisterARM64::X0, address)?;
+    Ok(())
+}
+
+fn stub_setenv(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let name_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let value_ptr = uc.reg_read(RegisterARM64::X1)?;
+    let overwrite = uc.reg_read(RegisterARM64::X2)? as u32 != 0;
+    let name = read_c_string(uc, name_ptr, 0x1000)?;
+    let value = read_c_string(uc, value_ptr, 0x1000)?;
+    debug_trace!(format!("setenv('{name}', '{value}', {overwrite})"));
+
+    if name.is_empty() || name.contains('=') {
+        set_errno(uc, EINVAL)?;
+        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        return Ok(());
+    }
+
+    let state = uc.get_data_mut();
+    if overwrite || !state.environment.contains_key(&name) {
+        state.environment_strings.remove(&name);
+        state.environment.insert(name, value);
+    }
+    uc.reg_write(RegisterARM64::X0, 0)?;
+    Ok(())
+}
+
+fn stub_unsetenv(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let name_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let name = read_c_string(uc, name_ptr, 0x1000)?;
+    debug_trace!(format!("unsetenv('{name}')"));
+
+    let state = uc.get_data_mut();
+    state.environment_strings.remove(&name);
+    state.environment.remove(&name);
+    uc.reg_write(RegisterARM64::X0, 0)?;
+    Ok(())
+}
+
 fn stub_system_property_get(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
     let name_ptr = uc.reg_read(RegisterARM64::X0)?;
     let name = read_c_string(uc, name_ptr, 0x1000)?;
-    debug_trace(format!("__system_property_get({name}, [...])"));
+    debug_trace!(format!("__system_property_get({name}, [...])"));
     let value_ptr = uc.reg_read(RegisterARM64::X1)?;
-    let value = b"no s/n number";
-    uc.mem_write(value_ptr, value)?;
-    uc.reg_write(RegisterARM64::X0, value.len() as u64)?;
+    let value = uc
+        .get_data()
+        .serial_number
+        .clone()
+        .unwrap_or_else(|| "no s/n number".to_string());
+    // Property values are at most PROP_VALUE_MAX (92) bytes including the NUL.
+    let mut bytes = value.into_bytes();
+    bytes.truncate(91);
+    let len = bytes.len();
+    bytes.push(0);
+    uc.mem_write(value_ptr, &bytes)?;
+    uc.reg_write(RegisterARM64::X0, len as u64)?;
     Ok(())
 }
 
 fn stub_arc4random(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
-    uc.reg_write(RegisterARM64::X0, 0xDEAD_BEEF)?;
+    let value = uc.get_data_mut().rng.next_u32();
+    debug_trace!(format!("arc4random()=0x{value:08X}"));
+    uc.reg_write(RegisterARM64::X0, u64::from(value))?;
+    Ok(())
+}
+
+fn stub_arc4random_uniform(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let upper_bound = uc.reg_read(RegisterARM64::X0)? as u32;
+    let value = if upper_bound < 2 {
+        0
+    } else {
+        uc.get_data_mut().rng.gen_range(0..upper_bound)
+    };
+    debug_trace!(format!("arc4random_uniform({upper_bound})={value}"));
+    uc.reg_write(RegisterARM64::X0, u64::from(value))?;
+    Ok(())
+}
+
+/// Fills guest memory a chunk at a time, so a bogus length faults on unmapped
+/// memory instead of first allocating that much on the host.
+fn fill_guest_random(
+    uc: &mut Unicorn<'_, RuntimeState>,
+    buf_ptr: u64,
+    length: u64,
+) -> Result<(), VmError> {
+    let mut chunk = vec![0_u8; length.min(GUEST_MEMORY_CHUNK) as usize];
+    let mut done = 0;
+    while done < length {
+        let chunk_len = (length - done).min(GUEST_MEMORY_CHUNK) as usize;
+        uc.get_data_mut().rng.fill_bytes(&mut chunk[..chunk_len]);
+        uc.mem_write(buf_ptr + done, &chunk[..chunk_len])?;
+        done += chunk_len as u64;
+    }
+    Ok(())
+}
+
+fn stub_arc4random_buf(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let buf_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let length = uc.reg_read(RegisterARM64::X1)?;
+    debug_trace!(format!("arc4random_buf(0x{buf_ptr:X}, {length})"));
+    fill_guest_random(uc, buf_ptr, length)?;
+    Ok(())
+}
+
+fn stub_getrandom(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let buf_ptr = uc.reg_read(RegisterARM64::X0)?;
+    let flags = uc.reg_read(RegisterARM64::X2)?;
+    // Larger requests are cut short, like the real syscall does.
+    let length = uc.reg_read(RegisterARM64::X1)?.min(MAX_GETRANDOM_LEN);
+    debug_trace!(format!("getrandom(0x{buf_ptr:X}, {length}, {flags:#x})"));
+    fill_guest_random(uc, buf_ptr, length)?;
+    uc.reg_write(RegisterARM64::X0, length)?;
+    Ok(())
+}
+
+fn stub_abort(_uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    debug_print!("abort()");
+    Err(VmError::GuestAborted {
+        code: SIGNAL_EXIT_BASE + SIGABRT,
+    })
+}
+
+fn stub_exit(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let code = uc.reg_read(RegisterARM64::X0)? as i32;
+    debug_print!(format!("exit({code})"));
+    Err(VmError::GuestAborted { code })
+}
+
+fn stub_raise(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let signal = uc.reg_read(RegisterARM64::X0)? as i32;
+    debug_print!(format!("raise({signal})"));
+    if signal == 0 {
+        uc.reg_write(RegisterARM64::X0, 0)?;
+        return Ok(());
+    }
+    if !(1..=MAX_SIGNAL).contains(&signal) {
+        set_errno(uc, EINVAL)?;
+        uc.reg_write(RegisterARM64::X0, u64::MAX)?;
+        return Ok(());
+    }
+    Err(VmError::GuestAborted {
+        code: SIGNAL_EXIT_BASE + signal,
+    })
+}
+
+/// Reads the `index`-th integer argument of a variadic AAPCS64 call made to an import.
+#[cfg(not(feature = "minimal"))]
+fn read_variadic_arg(uc: &Unicorn<'_, RuntimeState>, index: usize) -> Result<u64, VmError> {
+    if index < VARIADIC_REG_ARGS {
+        return Ok(uc.reg_read(ARG_REGS[index])?);
+    }
+    let sp = uc.reg_read(RegisterARM64::SP)?;
+    let offset = ((index - VARIADIC_REG_ARGS) * 8) as u64;
+    let bytes = uc.mem_read_as_vec(sp + offset, 8)?;
+    let mut value = [0_u8; 8];
+    value.copy_from_slice(&bytes);
+    Ok(u64::from_le_bytes(value))
+}
+
+/// Formats a guest printf-style message whose variadic arguments start at `first_arg`.
+#[cfg(not(feature = "minimal"))]
+fn format_guest_message(
+    uc: &Unicorn<'_, RuntimeState>,
+    format_ptr: u64,
+    first_arg: usize,
+) -> Result<String, VmError> {
+    let format = read_guest_c_bytes(uc, format_ptr)?;
+    let mut next_index = first_arg;
+    format_printf(
+        &format,
+        &mut || {
+            let value = read_variadic_arg(uc, next_index);
+            next_index += 1;
+            value
+        },
+        &mut |address| {
+            read_guest_c_bytes(uc, address)
+                .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        },
+    )
+}
+
+/// Minimal printf: integer, string, char and pointer conversions. Floating-point
+/// arguments live in the SIMD registers and are rendered as `?`.
+#[cfg(not(feature = "minimal"))]
+fn format_printf(
+    format: &[u8],
+    next_arg: &mut dyn FnMut() -> Result<u64, VmError>,
+    read_string: &mut dyn FnMut(u64) -> Result<String, VmError>,
+) -> Result<String, VmError> {
+    let mut output = Vec::with_capacity(format.len());
+    let mut index = 0;
+    while index < format.len() {
+        let byte = format[index];
+        index += 1;
+        if byte != b'%' {
+            output.push(byte);
+            continue;
+        }
+
+        let spec_start = index;
+        while index < format.len() && b"-+ #0123456789.*".contains(&format[index]) {
+            if format[index] == b'*' {
+                next_arg()?;
+            }
+            index += 1;
+        }
+        let mut long_count = 0;
+        while index < format.len() && b"hlzjt".contains(&format[index]) {
+            if matches!(format[index], b'l' | b'z' | b'j' | b't') {
+                long_count += 1;
+            }
+            index += 1;
+        }
+        let Some(&conversion) = format.get(index) else {
+            output.extend_from_slice(&format[spec_start - 1..]);
+            break;
+        };
+        index += 1;
+
+        let rendered = match conversion {
+            b'%' => "%".to_string(),
+            b'd' | b'i' => {
+                let value = next_arg()?;
+                if long_count > 0 {
+                    (value as i64).to_string()
+                } else {
+                    (value as i32).to_string()
+                }
+            }
+            b'u' | b'x' | b'X' | b'o' => {
+                let mut value = next_arg()?;
+                if long_count == 0 {
+                    value &= u64::from(u32::MAX);
+                }
+                match conversion {
+                    b'x' => format!("{value:x}"),
+                    b'X' => format!("{value:X}"),
+                    b'o' => format!("{value:o}"),
+                    _ => value.to_string(),
+                }
+            }
+            b'p' => format!("0x{:x}", next_arg()?),
+            b'c' => char::from(next_arg()? as u8).to_string(),
+            b's' => match next_arg()? {
+                0 => "(null)".to_string(),
+                address => read_string(address)?,
+            },
+            b'f' | b'F' | b'e' | b'E' | b'g' | b'G' | b'a' | b'A' => "?".to_string(),
+            _ => String::from_utf8_lossy(&format[spec_start - 1..index]).into_owned(),
+        };
+        output.extend_from_slice(rendered.as_bytes());
+    }
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+#[cfg(not(feature = "minimal"))]
+fn read_log_tag(uc: &Unicorn<'_, RuntimeState>, tag_ptr: u64) -> Result<String, VmError> {
+    if tag_ptr == 0 {
+        return Ok(String::new());
+    }
+    Ok(String::from_utf8_lossy(&read_guest_c_bytes(uc, tag_ptr)?).into_owned())
+}
+
+#[cfg(not(feature = "minimal"))]
+fn stub_android_log_print(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let priority = uc.reg_read(RegisterARM64::X0)? as u32;
+    let tag_ptr = uc.reg_read(RegisterARM64::X1)?;
+    let format_ptr = uc.reg_read(RegisterARM64::X2)?;
+
+    let tag = read_log_tag(uc, tag_ptr)?;
+    let message = format_guest_message(uc, format_ptr, 3)?;
+    guest_log(priority, &tag, &message);
+
+    uc.reg_write(RegisterARM64::X0, message.len() as u64)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "minimal"))]
+fn stub_android_log_write(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    let priority = uc.reg_read(RegisterARM64::X0)? as u32;
+    let tag_ptr = uc.reg_read(RegisterARM64::X1)?;
+    let text_ptr = uc.reg_read(RegisterARM64::X2)?;
+
+    let tag = read_log_tag(uc, tag_ptr)?;
+    let text = String::from_utf8_lossy(&read_guest_c_bytes(uc, text_ptr)?).into_owned();
+    guest_log(priority, &tag, &text);
+
+    uc.reg_write(RegisterARM64::X0, text.len() as u64)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "minimal"))]
+fn stub_syslog(uc: &mut Unicorn<'_, RuntimeState>) -> Result<(), VmError> {
+    // syslog's LOG_EMERG..LOG_DEBUG (0..7) run in the opposite direction to Android's.
+    let level = (uc.reg_read(RegisterARM64::X0)? & 0x7) as u32;
+    let format_ptr = uc.reg_read(RegisterARM64::X1)?;
+
+    let message = format_guest_message(uc, format_ptr, 2)?;
+    let priority = match level {
+        0..=2 => 7,
+        3 => 6,
+        4 => ANDROID_LOG_WARN,
+        5 | 6 => 4,
+        _ => 3,
+    };
+    guest_log(priority, "syslog", &message);
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Allocator;
+    use std::io;
+
+    #[cfg(not(feature = "minimal"))]
+    use super::format_printf;
+    use super::{compare_c_bytes, errno_for_io_error, with_file_type};
+    use crate::allocator::Allocator;
+    use crate::constants::{EACCES, EIO, ENOENT, ENOSPC};
 
     #[test]
     fn allocator_aligns_to_pages() {
@@ -621,4 +1562,45 @@ mod tests {
         assert_eq!(a, 0x1000_0000);
         assert_eq!(b, 0x1000_1000);
     }
+
+    #[test]
+    fn io_errors_map_to_bionic_errno() {
+        let cases = [
+            (io::ErrorKind::NotFound, ENOENT),
+            (io::ErrorKind::PermissionDenied, EACCES),
+            (io::ErrorKind::StorageFull, ENOSPC),
+            (io::ErrorKind::UnexpectedEof, EIO),
+        ];
+        for (kind, errno) in cases {
+            assert_eq!(errno_for_io_error(&io::Error::from(kind)), errno);
+        }
+    }
+
+    #[test]
+    fn compare_c_bytes_matches_strcmp_sign() {
+        assert_eq!(compare_c_bytes(b"adi", b"adi"), 0);
+        assert!(compare_c_bytes(b"adi", b"adi.pb") < 0);
+        assert!(compare_c_bytes(b"b", b"a") > 0);
+        assert!(compare_c_bytes(b"\xff", b"a") > 0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "minimal"))]
+    fn format_printf_expands_integer_and_string_args() {
+        let mut args = [0xFFFF_FFFF_u64, 0x2A, 0x1000, 0x7F].into_iter();
+        let message = format_printf(
+            b"code=%d hex=%x name=%s %lu%%",
+            &mut || Ok(args.next().unwrap_or(0)),
+            &mut |address| Ok(format!("str@{address:x}")),
+        )
+        .expect("format");
+        assert_eq!(message, "code=-1 hex=2a name=str@1000 127%");
+    }
+
+    #[test]
+    fn stat_mode_gets_a_file_type() {
+        assert_eq!(with_file_type(0), 0o100644);
+        assert_eq!(with_file_type(0o600), 0o100600);
+        assert_eq!(with_file_type(0o040755), 0o040755);
+    }
 }
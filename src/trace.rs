@@ -0,0 +1,174 @@
+use std::fmt;
+use std::io::Write;
+
+use serde::Serialize;
+use serde_json::Value;
+use unicorn_engine::{RegisterARM64, Unicorn};
+
+use crate::constants::{ARG_REGS, MAX_TRACE_STRING_LEN};
+use crate::debug::debug_print;
+use crate::errors::VmError;
+use crate::runtime::RuntimeState;
+
+/// How a stub argument is rendered in the trace.
+#[derive(Debug, Clone, Copy)]
+enum ArgKind {
+    Int,
+    Hex,
+    Oct,
+    Str,
+}
+
+/// Argument layout of each stubbed import, mirroring the stub implementations.
+fn arg_kinds(name: &str) -> &'static [ArgKind] {
+    use ArgKind::{Hex, Int, Oct, Str};
+    match name {
+        "malloc" | "umask" | "arc4random_uniform" | "exit" | "_exit" | "raise" => &[Int],
+        "free" | "dlclose" | "time" | "pthread_once" => &[Hex],
+        "strncpy" | "memcpy" | "memmove" => &[Hex, Hex, Int],
+        "memset" => &[Hex, Hex, Int],
+        "strlen" | "unlink" | "getenv" | "unsetenv" => &[Str],
+        "strcmp" | "rename" => &[Str, Str],
+        "mkdir" | "chmod" | "access" => &[Str, Oct],
+        "stat" | "lstat" | "__system_property_get" => &[Str, Hex],
+        "fstat" => &[Int, Hex],
+        "open" => &[Str, Oct, Oct],
+        "ftruncate" => &[Int, Int],
+        "read" | "write" => &[Int, Hex, Int],
+        "close" | "fsync" | "fdatasync" => &[Int],
+        "dlopen" => &[Str, Hex],
+        "dlsym" => &[Hex, Str],
+        "gettimeofday" => &[Hex, Hex],
+        "clock_gettime" => &[Int, Hex],
+        "setenv" => &[Str, Str, Int],
+        "arc4random_buf" => &[Hex, Int],
+        "getrandom" => &[Hex, Int, Hex],
+        "__android_log_print" | "__android_log_write" => &[Int, Str, Str],
+        "syslog" => &[Int, Str],
+        "pthread_create" => &[Hex, Hex, Hex, Hex],
+        "pthread_key_create" => &[Hex, Hex],
+        "pthread_key_delete" | "pthread_getspecific" => &[Int],
+        "pthread_setspecific" => &[Int, Hex],
+        name if name.starts_with("pthread_") => &[Hex],
+        _ => &[],
+    }
+}
+
+#[derive(Serialize)]
+struct TraceEvent<'a> {
+    seq: u64,
+    name: &'a str,
+    args: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ret: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errno: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Arguments captured on entry to a stub, before it can clobber X0..X7.
+pub(crate) struct PendingCall {
+    args: Vec<Value>,
+    errno_before: Option<u32>,
+}
+
+/// Writes one JSON object per stub invocation to a caller-supplied writer.
+pub(crate) struct SyscallTracer {
+    writer: Box<dyn Write>,
+    seq: u64,
+}
+
+impl fmt::Debug for SyscallTracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyscallTracer")
+            .field("seq", &self.seq)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SyscallTracer {
+    pub(crate) fn new(writer: Box<dyn Write>) -> Self {
+        Self { writer, seq: 0 }
+    }
+}
+
+pub(crate) fn begin_call(uc: &Unicorn<'_, RuntimeState>, name: &str) -> Option<PendingCall> {
+    uc.get_data().syscall_trace.as_ref()?;
+
+    let args = arg_kinds(name)
+        .iter()
+        .zip(ARG_REGS)
+        .map(|(kind, reg)| decode_arg(uc, *kind, reg))
+        .collect();
+    Some(PendingCall {
+        args,
+        errno_before: read_errno(uc),
+    })
+}
+
+pub(crate) fn end_call(
+    uc: &mut Unicorn<'_, RuntimeState>,
+    name: &str,
+    call: PendingCall,
+    result: &Result<(), VmError>,
+) {
+    let (ret, errno, error) = match result {
+        Ok(()) => {
+            let ret = uc.reg_read(RegisterARM64::X0).unwrap_or(0) as i64;
+            let errno = read_errno(uc).filter(|errno| Some(*errno) != call.errno_before);
+            (Some(ret), errno, None)
+        }
+        Err(err) => (None, None, Some(err.to_string())),
+    };
+
+    let Some(tracer) = uc.get_data_mut().syscall_trace.as_mut() else {
+        return;
+    };
+    tracer.seq += 1;
+    let event = TraceEvent {
+        seq: tracer.seq,
+        name,
+        args: call.args,
+        ret,
+        errno,
+        error,
+    };
+
+    let written = serde_json::to_writer(&mut tracer.writer, &event)
+        .map_err(std::io::Error::from)
+        .and_then(|()| tracer.writer.write_all(b"\n"));
+    if let Err(err) = written {
+        debug_print!(format!("syscall trace disabled: {err}"));
+        uc.get_data_mut().syscall_trace = None;
+    }
+}
+
+fn decode_arg(uc: &Unicorn<'_, RuntimeState>, kind: ArgKind, reg: RegisterARM64) -> Value {
+    let raw = uc.reg_read(reg).unwrap_or(0);
+    match kind {
+        ArgKind::Int => Value::from(raw as i64),
+        ArgKind::Hex => Value::from(format!("0x{raw:X}")),
+        ArgKind::Oct => Value::from(format!("{raw:#o}")),
+        ArgKind::Str if raw == 0 => Value::Null,
+        ArgKind::Str => Value::from(read_trace_string(uc, raw)),
+    }
+}
+
+/// Best-effort string read: stops at the first NUL, unmapped page, or the length cap.
+fn read_trace_string(uc: &Unicorn<'_, RuntimeState>, address: u64) -> String {
+    let mut bytes = Vec::new();
+    for offset in 0..MAX_TRACE_STRING_LEN {
+        match uc.mem_read_as_vec(address + offset, 1) {
+            Ok(byte) if byte[0] != 0 => bytes.push(byte[0]),
+            _ => break,
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn read_errno(uc: &Unicorn<'_, RuntimeState>) -> Option<u32> {
+    let address = uc.get_data().errno_address?;
+    let bytes = uc.mem_read_as_vec(address, 4).ok()?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
@@ -0,0 +1,298 @@
+use std::path::PathBuf;
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::Result;
+#[cfg(not(target_arch = "wasm32"))]
+use anyhow::bail;
+#[cfg(not(target_arch = "wasm32"))]
+use reqwest::Certificate;
+use thiserror::Error;
+
+#[cfg(not(target_arch = "wasm32"))]
+use crate::debug::log_warn;
+#[cfg(all(feature = "rustls", not(target_arch = "wasm32")))]
+use crate::pinning::pinned_tls;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::provisioning::load_apple_root_pem;
+
+/// HTTP client used by [`ProvisioningSession`](crate::ProvisioningSession) to talk
+/// to GSA.
+///
+/// The crate ships a reqwest-based transport on native targets and one that calls
+/// back into JS on WASM; implement this to add a proxy, sign requests or record
+/// traffic without touching the provisioning protocol itself.
+pub trait HttpTransport {
+    /// Non-success statuses should be reported as errors.
+    fn get(&mut self, url: &str, headers: &[(&str, String)]) -> Result<HttpResponse>;
+
+    fn post(&mut self, url: &str, headers: &[(&str, String)], body: &str) -> Result<HttpResponse>;
+}
+
+impl<T: HttpTransport + ?Sized> HttpTransport for Box<T> {
+    fn get(&mut self, url: &str, headers: &[(&str, String)]) -> Result<HttpResponse> {
+        (**self).get(url, headers)
+    }
+
+    fn post(&mut self, url: &str, headers: &[(&str, String)], body: &str) -> Result<HttpResponse> {
+        (**self).post(url, headers, body)
+    }
+}
+
+/// [`HttpTransport`] for hosts that can only complete requests asynchronously, such
+/// as `fetch` in browsers.
+///
+/// The futures are not required to be `Send`: sessions await them on the thread
+/// that owns the [`Adi`](crate::Adi), which is not `Send` either.
+#[allow(async_fn_in_trait)]
+pub trait AsyncHttpTransport {
+    /// Non-success statuses should be reported as errors.
+    async fn get(&mut self, url: &str, headers: &[(&str, String)]) -> Result<HttpResponse>;
+
+    async fn post(
+        &mut self,
+        url: &str,
+        headers: &[(&str, String)],
+        body: &str,
+    ) -> Result<HttpResponse>;
+}
+
+/// A successful response. Only a few headers matter to the protocol (routing info),
+/// so transports that cannot see them may leave `headers` empty.
+#[derive(Debug, Clone, Default)]
+pub struct HttpResponse {
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(body: Vec<u8>) -> Self {
+        Self {
+            headers: Vec::new(),
+            body,
+        }
+    }
+
+    /// First header named `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Connection settings for the built-in provisioning HTTP clients.
+///
+/// Converts from the `Option<PathBuf>` that `ProvisioningSession::new` used to take,
+/// so passing just a root certificate path keeps working.
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    /// PEM file with Apple's root CA. When unset the bundled copy is used (feature
+    /// `bundled-apple-root`), then `apple-root.pem` in the working directory.
+    pub apple_root_pem: Option<PathBuf>,
+    /// Skip certificate verification when no Apple root is available. Only for
+    /// debugging: anyone on the path can then read and forge the exchange.
+    pub danger_accept_invalid_certs: bool,
+    /// Total time allowed per request; `None` waits forever.
+    pub timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    /// How long an idle pooled connection is kept alive for reuse.
+    pub pool_idle_timeout: Option<Duration>,
+    /// TCP keep-alive probe interval; off when `None`.
+    pub tcp_keepalive: Option<Duration>,
+    /// Base64 SHA-256 digests of SubjectPublicKeyInfo (HPKP `pin-sha256` values). When
+    /// non-empty, a server chain must contain one of these keys in addition to passing
+    /// normal verification. Needs the `rustls` feature.
+    pub pinned_spki_sha256: Vec<String>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            apple_root_pem: None,
+            danger_accept_invalid_certs: false,
+            timeout: Some(DEFAULT_TIMEOUT),
+            connect_timeout: None,
+            pool_idle_timeout: Some(DEFAULT_POOL_IDLE_TIMEOUT),
+            tcp_keepalive: None,
+            pinned_spki_sha256: Vec::new(),
+        }
+    }
+}
+
+impl From<Option<PathBuf>> for HttpOptions {
+    fn from(apple_root_pem: Option<PathBuf>) -> Self {
+        Self {
+            apple_root_pem,
+            ..Default::default()
+        }
+    }
+}
+
+/// A server presented a chain matching none of [`HttpOptions::pinned_spki_sha256`].
+///
+/// Transports return it inside the `anyhow::Error`; use `err.downcast_ref()` to tell
+/// it apart from ordinary connection failures.
+#[derive(Debug, Error)]
+#[error("certificate for {url} does not match any pinned SPKI hash")]
+pub struct CertificatePinError {
+    pub url: String,
+}
+
+/// Turns a request error into [`CertificatePinError`] if the pinning verifier
+/// rejected the handshake.
+pub(crate) fn pin_checked(
+    failures: Option<&AtomicBool>,
+    url: &str,
+    err: impl Into<anyhow::Error>,
+) -> anyhow::Error {
+    if failures.is_some_and(|failures| failures.swap(false, Ordering::Relaxed)) {
+        return CertificatePinError {
+            url: url.to_string(),
+        }
+        .into();
+    }
+    err.into()
+}
+
+/// The settings [`build_reqwest_client`] applies, over reqwest's blocking and
+/// async builders, which share them but not a type.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) trait ReqwestBuilder: Sized {
+    type Client;
+
+    fn timeouts(self, options: &HttpOptions) -> Self;
+    fn root_certificate(self, certificate: Certificate) -> Self;
+    fn accept_invalid_certs(self) -> Self;
+    #[cfg(feature = "rustls")]
+    fn rustls(self) -> Self;
+    #[cfg(feature = "rustls")]
+    fn preconfigured_tls(self, config: rustls::ClientConfig) -> Self;
+    fn build(self) -> reqwest::Result<Self::Client>;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ReqwestBuilder for reqwest::blocking::ClientBuilder {
+    type Client = reqwest::blocking::Client;
+
+    fn timeouts(self, options: &HttpOptions) -> Self {
+        // The blocking client has a default timeout of its own, so `None` has to be
+        // passed through to disable it.
+        let builder = self
+            .timeout(options.timeout)
+            .pool_idle_timeout(options.pool_idle_timeout)
+            .tcp_keepalive(options.tcp_keepalive);
+        match options.connect_timeout {
+            Some(connect_timeout) => builder.connect_timeout(connect_timeout),
+            None => builder,
+        }
+    }
+
+    fn root_certificate(self, certificate: Certificate) -> Self {
+        self.add_root_certificate(certificate)
+    }
+
+    fn accept_invalid_certs(self) -> Self {
+        self.danger_accept_invalid_certs(true)
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls(self) -> Self {
+        self.use_rustls_tls()
+    }
+
+    #[cfg(feature = "rustls")]
+    fn preconfigured_tls(self, config: rustls::ClientConfig) -> Self {
+        self.use_preconfigured_tls(config)
+    }
+
+    fn build(self) -> reqwest::Result<Self::Client> {
+        reqwest::blocking::ClientBuilder::build(self)
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ReqwestBuilder for reqwest::ClientBuilder {
+    type Client = reqwest::Client;
+
+    fn timeouts(self, options: &HttpOptions) -> Self {
+        let mut builder = self
+            .pool_idle_timeout(options.pool_idle_timeout)
+            .tcp_keepalive(options.tcp_keepalive);
+        if let Some(timeout) = options.timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(connect_timeout) = options.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        builder
+    }
+
+    fn root_certificate(self, certificate: Certificate) -> Self {
+        self.add_root_certificate(certificate)
+    }
+
+    fn accept_invalid_certs(self) -> Self {
+        self.danger_accept_invalid_certs(true)
+    }
+
+    #[cfg(feature = "rustls")]
+    fn rustls(self) -> Self {
+        self.use_rustls_tls()
+    }
+
+    #[cfg(feature = "rustls")]
+    fn preconfigured_tls(self, config: rustls::ClientConfig) -> Self {
+        self.use_preconfigured_tls(config)
+    }
+
+    fn build(self) -> reqwest::Result<Self::Client> {
+        reqwest::ClientBuilder::build(self)
+    }
+}
+
+/// Applies `options` to a reqwest builder and picks its trust: Apple's root,
+/// pinned keys, or (only when allowed) none. Also returns the pin failure flag
+/// for [`pin_checked`] when pinning is on.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn build_reqwest_client<B: ReqwestBuilder>(
+    builder: B,
+    options: &HttpOptions,
+) -> Result<(B::Client, Option<Arc<AtomicBool>>)> {
+    let builder = builder.timeouts(options);
+    // Prefer rustls when both backends end up enabled through feature unification.
+    #[cfg(feature = "rustls")]
+    let builder = builder.rustls();
+
+    let root_pem = load_apple_root_pem(options.apple_root_pem.as_deref())?;
+    if root_pem.is_none() {
+        if !options.danger_accept_invalid_certs {
+            bail!("Apple root certificate not found; set HttpOptions::apple_root_pem");
+        }
+        log_warn!("apple-root.pem not found, falling back to insecure TLS mode");
+    }
+
+    if !options.pinned_spki_sha256.is_empty() {
+        #[cfg(feature = "rustls")]
+        {
+            let pinned = pinned_tls(root_pem.as_deref(), &options.pinned_spki_sha256)?;
+            let client = builder.preconfigured_tls(pinned.config).build()?;
+            return Ok((client, Some(pinned.failures)));
+        }
+        #[cfg(not(feature = "rustls"))]
+        bail!("certificate pinning requires the rustls feature");
+    }
+
+    let builder = match root_pem {
+        Some(pem) => builder.root_certificate(Certificate::from_pem(&pem)?),
+        None => builder.accept_invalid_certs(),
+    };
+    Ok((builder.build()?, None))
+}
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// reqwest's own default.
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
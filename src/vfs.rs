@@ -0,0 +1,464 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+#[cfg(not(unix))]
+use crate::constants::S_IFLNK;
+use crate::constants::{S_IFDIR, S_IFREG, STAT_BLOCK_SIZE};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GuestOpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub create: bool,
+    pub create_new: bool,
+    pub truncate: bool,
+    pub append: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GuestMetadata {
+    pub mode: u32,
+    pub size: u64,
+    pub blksize: u64,
+    pub blocks: u64,
+    /// Host timestamps, where the backend knows them; never shown to the guest.
+    pub created: Option<SystemTime>,
+    pub modified: Option<SystemTime>,
+}
+
+/// An open file handed out by a [`GuestFs`]; backs one guest file descriptor.
+pub trait GuestFile: fmt::Debug {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+    fn set_len(&mut self, len: u64) -> io::Result<()>;
+    fn metadata(&self) -> io::Result<GuestMetadata>;
+    /// Flushes written data to durable storage; in-memory backends have nothing to do.
+    fn sync_all(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Filesystem seen by the emulated library through the file-related stubs.
+///
+/// Paths are passed through exactly as the guest wrote them (e.g. `./anisette/adi.pb`).
+pub trait GuestFs: fmt::Debug {
+    fn create_dir_all(&mut self, path: &str) -> io::Result<()>;
+    fn open(&mut self, path: &str, options: &GuestOpenOptions) -> io::Result<Box<dyn GuestFile>>;
+    fn symlink_metadata(&mut self, path: &str) -> io::Result<GuestMetadata>;
+    /// Like [`GuestFs::symlink_metadata`] but follows symlinks; backends without
+    /// links can rely on the default.
+    fn metadata(&mut self, path: &str) -> io::Result<GuestMetadata> {
+        self.symlink_metadata(path)
+    }
+    fn remove_file(&mut self, path: &str) -> io::Result<()>;
+    fn rename(&mut self, from: &str, to: &str) -> io::Result<()>;
+    /// Names (not paths) of the entries directly inside `path`. Only needed for
+    /// exporting state, so backends may leave it unsupported.
+    fn read_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        let _ = path;
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+    /// Where `path` lives on the host, for backends that store files there; used
+    /// to lock `adi.pb` against other processes.
+    fn host_path(&self, path: &str) -> Option<std::path::PathBuf> {
+        let _ = path;
+        None
+    }
+}
+
+/// Default backend: guest paths map directly onto the host filesystem via `std::fs`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdFs;
+
+/// Guest paths always use `/`; convert them to the host's separator.
+#[cfg(windows)]
+fn host_path(path: &str) -> std::path::PathBuf {
+    std::path::PathBuf::from(path.replace('/', std::path::MAIN_SEPARATOR_STR))
+}
+
+#[cfg(not(windows))]
+fn host_path(path: &str) -> &std::path::Path {
+    std::path::Path::new(path)
+}
+
+impl GuestFs for StdFs {
+    fn host_path(&self, path: &str) -> Option<std::path::PathBuf> {
+        Some(host_path(path).into())
+    }
+
+    fn create_dir_all(&mut self, path: &str) -> io::Result<()> {
+        fs::create_dir_all(host_path(path))
+    }
+
+    fn open(&mut self, path: &str, options: &GuestOpenOptions) -> io::Result<Box<dyn GuestFile>> {
+        let file = OpenOptions::new()
+            .read(options.read)
+            .write(options.write)
+            .create(options.create)
+            .create_new(options.create_new)
+            .truncate(options.truncate)
+            .append(options.append)
+            .open(host_path(path))?;
+        Ok(Box::new(StdFile(file)))
+    }
+
+    fn symlink_metadata(&mut self, path: &str) -> io::Result<GuestMetadata> {
+        fs::symlink_metadata(host_path(path)).map(|metadata| to_guest_metadata(&metadata))
+    }
+
+    fn metadata(&mut self, path: &str) -> io::Result<GuestMetadata> {
+        fs::metadata(host_path(path)).map(|metadata| to_guest_metadata(&metadata))
+    }
+
+    fn remove_file(&mut self, path: &str) -> io::Result<()> {
+        fs::remove_file(host_path(path))
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        fs::rename(host_path(from), host_path(to))
+    }
+
+    fn read_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        fs::read_dir(host_path(path))?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+struct StdFile(File);
+
+impl GuestFile for StdFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.write_all(buf)
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.0.set_len(len)
+    }
+
+    fn metadata(&self) -> io::Result<GuestMetadata> {
+        self.0
+            .metadata()
+            .map(|metadata| to_guest_metadata(&metadata))
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.0.sync_all()
+    }
+}
+
+#[cfg(unix)]
+fn to_guest_metadata(metadata: &fs::Metadata) -> GuestMetadata {
+    use std::os::unix::fs::MetadataExt;
+    GuestMetadata {
+        mode: metadata.mode(),
+        size: metadata.size(),
+        blksize: metadata.blksize(),
+        blocks: metadata.blocks(),
+        created: metadata.created().ok(),
+        modified: metadata.modified().ok(),
+    }
+}
+
+/// Hosts without POSIX metadata (Windows) get a mode synthesized from the file
+/// type and read-only flag, since the library rejects a stat with no type bits.
+#[cfg(not(unix))]
+fn to_guest_metadata(metadata: &fs::Metadata) -> GuestMetadata {
+    let file_type = metadata.file_type();
+    let mode = if file_type.is_dir() {
+        S_IFDIR | 0o755
+    } else if file_type.is_symlink() {
+        S_IFLNK | 0o777
+    } else if metadata.permissions().readonly() {
+        S_IFREG | 0o444
+    } else {
+        S_IFREG | 0o644
+    };
+    let size = metadata.len();
+    GuestMetadata {
+        mode,
+        size,
+        blksize: STAT_BLOCK_SIZE,
+        blocks: size.div_ceil(STAT_BLOCK_SIZE),
+        created: metadata.created().ok(),
+        modified: metadata.modified().ok(),
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryFsInner {
+    files: HashMap<String, Vec<u8>>,
+    dirs: BTreeSet<String>,
+}
+
+/// Backend that keeps every guest file in a shared in-memory map.
+///
+/// Clones share the same storage, so keep a clone around to `export`/`import`
+/// the blobs (typically `./anisette/adi.pb`) after handing one to the emulator.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryFs {
+    inner: Arc<Mutex<MemoryFsInner>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn export(&self) -> HashMap<String, Vec<u8>> {
+        self.lock().files.clone()
+    }
+
+    pub fn import(&self, files: HashMap<String, Vec<u8>>) {
+        let mut inner = self.lock();
+        for (path, data) in files {
+            register_parent_dirs(&mut inner.dirs, &path);
+            inner.files.insert(path, data);
+        }
+    }
+
+    pub fn read_file(&self, path: &str) -> Option<Vec<u8>> {
+        self.lock().files.get(path).cloned()
+    }
+
+    pub fn write_file(&self, path: &str, data: Vec<u8>) {
+        let mut inner = self.lock();
+        register_parent_dirs(&mut inner.dirs, path);
+        inner.files.insert(path.to_string(), data);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MemoryFsInner> {
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl GuestFs for MemoryFs {
+    fn create_dir_all(&mut self, path: &str) -> io::Result<()> {
+        let mut inner = self.lock();
+        register_parent_dirs(&mut inner.dirs, path);
+        inner.dirs.insert(path.trim_end_matches('/').to_string());
+        Ok(())
+    }
+
+    fn open(&mut self, path: &str, options: &GuestOpenOptions) -> io::Result<Box<dyn GuestFile>> {
+        let mut inner = self.lock();
+        if inner.dirs.contains(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                path.to_string(),
+            ));
+        }
+
+        match inner.files.get_mut(path) {
+            Some(_) if options.create_new => {
+                return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+            }
+            Some(data) => {
+                if options.truncate {
+                    data.clear();
+                }
+            }
+            None if options.create || options.create_new => {
+                register_parent_dirs(&mut inner.dirs, path);
+                inner.files.insert(path.to_string(), Vec::new());
+            }
+            None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+
+        Ok(Box::new(MemoryFile {
+            fs: self.clone(),
+            path: path.to_string(),
+            position: 0,
+            options: *options,
+        }))
+    }
+
+    fn symlink_metadata(&mut self, path: &str) -> io::Result<GuestMetadata> {
+        let inner = self.lock();
+        if let Some(data) = inner.files.get(path) {
+            return Ok(memory_metadata(S_IFREG | 0o644, data.len() as u64));
+        }
+        if inner.dirs.contains(path.trim_end_matches('/')) {
+            return Ok(memory_metadata(S_IFDIR | 0o755, 0));
+        }
+        Err(io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn remove_file(&mut self, path: &str) -> io::Result<()> {
+        match self.lock().files.remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> io::Result<()> {
+        let mut inner = self.lock();
+        let data = inner
+            .files
+            .remove(from)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        register_parent_dirs(&mut inner.dirs, to);
+        inner.files.insert(to.to_string(), data);
+        Ok(())
+    }
+
+    fn read_dir(&mut self, path: &str) -> io::Result<Vec<String>> {
+        let inner = self.lock();
+        let dir = path.trim_end_matches('/');
+        if !inner.dirs.contains(dir) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+
+        let prefix = format!("{dir}/");
+        let children = inner.files.keys().chain(inner.dirs.iter());
+        let mut names: Vec<String> = children
+            .filter_map(|child| child.strip_prefix(&prefix))
+            .filter(|name| !name.is_empty() && !name.contains('/'))
+            .map(str::to_string)
+            .collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+}
+
+#[derive(Debug)]
+struct MemoryFile {
+    fs: MemoryFs,
+    path: String,
+    position: usize,
+    options: GuestOpenOptions,
+}
+
+impl MemoryFile {
+    fn with_data<T>(&self, f: impl FnOnce(&mut Vec<u8>) -> T) -> io::Result<T> {
+        let mut inner = self.fs.lock();
+        let data = inner
+            .files
+            .get_mut(&self.path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(f(data))
+    }
+}
+
+impl GuestFile for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.options.read {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        let position = self.position;
+        let count = self.with_data(|data| {
+            let available = data.get(position..).unwrap_or_default();
+            let count = available.len().min(buf.len());
+            buf[..count].copy_from_slice(&available[..count]);
+            count
+        })?;
+        self.position += count;
+        Ok(count)
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if !self.options.write {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        let append = self.options.append;
+        let mut position = self.position;
+        self.with_data(|data| {
+            if append {
+                position = data.len();
+            }
+            let end = position + buf.len();
+            if data.len() < end {
+                data.resize(end, 0);
+            }
+            data[position..end].copy_from_slice(buf);
+        })?;
+        self.position = position + buf.len();
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: u64) -> io::Result<()> {
+        if !self.options.write {
+            return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+        }
+        let len = usize::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.with_data(|data| data.resize(len, 0))
+    }
+
+    fn metadata(&self) -> io::Result<GuestMetadata> {
+        self.with_data(|data| memory_metadata(S_IFREG | 0o644, data.len() as u64))
+    }
+}
+
+fn memory_metadata(mode: u32, size: u64) -> GuestMetadata {
+    GuestMetadata {
+        mode,
+        size,
+        blksize: STAT_BLOCK_SIZE,
+        blocks: size.div_ceil(STAT_BLOCK_SIZE),
+        ..Default::default()
+    }
+}
+
+fn register_parent_dirs(dirs: &mut BTreeSet<String>, path: &str) {
+    let mut current = path.trim_end_matches('/');
+    while let Some((parent, _)) = current.rsplit_once('/') {
+        if parent.is_empty() || parent == "." {
+            break;
+        }
+        dirs.insert(parent.to_string());
+        current = parent;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GuestFs, GuestOpenOptions, MemoryFs};
+
+    #[test]
+    fn memory_fs_round_trips_written_files() {
+        let exported = {
+            let mut fs = MemoryFs::new();
+            let options = GuestOpenOptions {
+                read: true,
+                write: true,
+                create: true,
+                truncate: true,
+                ..Default::default()
+            };
+            let mut file = fs.open("./anisette/adi.pb", &options).expect("open");
+            file.write_all(b"provisioned").expect("write");
+            file.set_len(4).expect("truncate");
+
+            let metadata = fs.symlink_metadata("./anisette").expect("parent dir");
+            assert_eq!(metadata.mode & 0o170000, 0o040000);
+            fs.export()
+        };
+
+        let mut restored = MemoryFs::new();
+        restored.import(exported);
+        let mut file = restored
+            .open(
+                "./anisette/adi.pb",
+                &GuestOpenOptions {
+                    read: true,
+                    ..Default::default()
+                },
+            )
+            .expect("reopen");
+        let mut buf = [0_u8; 16];
+        let count = file.read(&mut buf).expect("read");
+        assert_eq!(&buf[..count], b"prov");
+    }
+}
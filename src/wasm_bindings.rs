@@ -0,0 +1,161 @@
+use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::rc::Rc;
+
+use js_sys::{Object, Promise, Reflect};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::future_to_promise;
+
+use crate::constants::GUEST_PROVISIONING_DIR;
+use crate::device::{Device, DeviceData};
+use crate::provisioning_async::AsyncProvisioningSession;
+use crate::{Adi, AdiInit, HttpOptions, MemoryFs};
+
+/// The machine-wide DSID, used when `getHeaders` is called without one.
+const MACHINE_DSID: i64 = -2;
+const BUSY: &str = "another call is still running";
+
+/// Anisette for web apps on `wasm32-unknown-unknown`, provisioning over `fetch`.
+///
+/// Everything lives in memory: persist [`deviceJson`](Self::device_json) and
+/// [`exportState`](Self::export_state) yourself (e.g. in IndexedDB) and pass them
+/// back to [`init`](Self::init) and [`importState`](Self::import_state).
+#[wasm_bindgen]
+pub struct Anisette {
+    /// Empty while `getHeaders` is running: it takes the state out for the
+    /// duration of the call instead of holding a borrow across `.await`.
+    inner: Rc<RefCell<Option<Inner>>>,
+}
+
+struct Inner {
+    adi: Adi,
+    device: DeviceData,
+}
+
+impl Inner {
+    async fn anisette_headers(&mut self, dsid: u64) -> anyhow::Result<HashMap<String, String>> {
+        let mut session =
+            AsyncProvisioningSession::new(&mut self.adi, &self.device, HttpOptions::default())?;
+        let headers = session.anisette_headers(dsid).await?;
+        let routing_info = session.routing_info().map(str::to_string);
+        if routing_info.is_some() {
+            self.device.routing_info = routing_info;
+        }
+        Ok(headers)
+    }
+}
+
+#[wasm_bindgen]
+impl Anisette {
+    /// Takes the `libstoreservicescore.so` and `libCoreADI.so` bytes and an
+    /// optional `device.json`; without one a new device identity is generated.
+    pub fn init(
+        storeservicescore: Vec<u8>,
+        coreadi: Vec<u8>,
+        device_json: Option<String>,
+    ) -> Result<Anisette, JsError> {
+        let device = match device_json {
+            Some(json) => {
+                let mut device: DeviceData = serde_json::from_str(&json)?;
+                device.migrate().map_err(js_error)?;
+                device.validate()?;
+                device
+            }
+            None => Device::ephemeral().data,
+        };
+
+        let adi = Adi::new(AdiInit {
+            storeservicescore,
+            coreadi,
+            library_path: format!("{GUEST_PROVISIONING_DIR}/"),
+            provisioning_path: Some(format!("{GUEST_PROVISIONING_DIR}/")),
+            identifier: Some(device.adi_identifier.clone()),
+            guest_fs: Some(Box::new(MemoryFs::new())),
+            serial_number: device.serial_number.clone(),
+            ..Default::default()
+        })
+        .map_err(js_error)?;
+
+        Ok(Self {
+            inner: Rc::new(RefCell::new(Some(Inner { adi, device }))),
+        })
+    }
+
+    /// Resolves to the anisette v3 headers for `dsid` (default -2), provisioning
+    /// first if needed.
+    #[wasm_bindgen(js_name = getHeaders)]
+    pub fn get_headers(&self, dsid: Option<i64>) -> Promise {
+        let state = Rc::clone(&self.inner);
+        let dsid = dsid.unwrap_or(MACHINE_DSID) as u64;
+        future_to_promise(async move {
+            let mut inner = state
+                .borrow_mut()
+                .take()
+                .ok_or_else(|| JsValue::from(js_error(BUSY)))?;
+            let result = inner.anisette_headers(dsid).await;
+            *state.borrow_mut() = Some(inner);
+            let headers = result.map_err(js_value_error)?;
+
+            let object = Object::new();
+            for (name, value) in headers {
+                Reflect::set(&object, &JsValue::from(name), &JsValue::from(value))?;
+            }
+            Ok(object.into())
+        })
+    }
+
+    #[wasm_bindgen(js_name = isProvisioned)]
+    pub fn is_provisioned(&self, dsid: Option<i64>) -> Result<bool, JsError> {
+        let mut inner = self.borrow_mut()?;
+        let dsid = dsid.unwrap_or(MACHINE_DSID) as u64;
+        inner.adi.is_machine_provisioned(dsid).map_err(js_error)
+    }
+
+    /// The device identity, including routing info learned while provisioning.
+    #[wasm_bindgen(js_name = deviceJson)]
+    pub fn device_json(&self) -> Result<String, JsError> {
+        Ok(serde_json::to_string_pretty(&self.borrow_mut()?.device)?)
+    }
+
+    /// Provisioning state from [`Adi::export_state`], with the device included.
+    #[wasm_bindgen(js_name = exportState)]
+    pub fn export_state(&self) -> Result<Vec<u8>, JsError> {
+        let mut inner = self.borrow_mut()?;
+        let Inner { adi, device } = &mut *inner;
+        adi.export_state(Some(device)).map_err(js_error)
+    }
+
+    /// Restores a blob from [`exportState`](Self::export_state), replacing the
+    /// device identity when the blob carries one.
+    #[wasm_bindgen(js_name = importState)]
+    pub fn import_state(&self, state: Vec<u8>) -> Result<(), JsError> {
+        let mut inner = self.borrow_mut()?;
+        if let Some(device) = inner.adi.import_state(&state).map_err(js_error)? {
+            inner
+                .adi
+                .set_identifier(&device.adi_identifier)
+                .map_err(js_error)?;
+            inner.device = device;
+        }
+        Ok(())
+    }
+}
+
+impl Anisette {
+    fn borrow_mut(&self) -> Result<RefMut<'_, Inner>, JsError> {
+        self.inner
+            .try_borrow_mut()
+            .ok()
+            .and_then(|inner| RefMut::filter_map(inner, Option::as_mut).ok())
+            .ok_or_else(|| js_error(BUSY))
+    }
+}
+
+fn js_error(err: impl Display) -> JsError {
+    JsError::new(&format!("{err:#}"))
+}
+
+fn js_value_error(err: impl Display) -> JsValue {
+    js_error(err).into()
+}